@@ -1,11 +1,11 @@
 #![no_main]
 
 use std::collections::BTreeMap;
-use std::ops::Bound;
+use std::ops::{Bound, RangeBounds};
 
 use ekv::config::{MAX_KEY_SIZE, MAX_VALUE_SIZE};
 use ekv::flash::MemFlash;
-use ekv::{Config, Database, ReadError, WriteError};
+use ekv::{Config, Cursor, Database, ReadError, WriteError};
 use embassy_sync::blocking_mutex::raw::NoopRawMutex;
 use libfuzzer_sys::arbitrary::Arbitrary;
 use libfuzzer_sys::fuzz_target;
@@ -14,6 +14,12 @@ fuzz_target!(|data: Input| fuzz(data));
 
 #[derive(Arbitrary, Debug)]
 struct Input {
+    /// See `Config::wear_leveling_threshold`. Kept small, so relocating gets exercised.
+    wear_leveling_threshold: Option<u8>,
+    /// See `Config::verify_writes`.
+    verify_writes: bool,
+    /// See `Config::redundant_meta`.
+    redundant_meta: bool,
     ops: Vec<Op>,
 }
 
@@ -21,14 +27,51 @@ struct Input {
 enum Op {
     Insert(InsertOp),
     Delete(DeleteOp),
+    DeleteRange(DeleteRangeOp),
+    DeletePrefix(PrefixOp),
     Read(ReadOp),
+    ReadAt(ReadAtOp),
     ReadRange(ReadRangeOp),
+    ReadPrefix(PrefixOp),
+    CompactStep,
+    CompactFull,
+    Stats,
+    WearStats,
+    Check,
+    Transaction(TransactionOp),
 }
 
 #[derive(Arbitrary, Debug)]
 struct InsertOp {
     key: u16,
     value_len: usize,
+    /// If not zero, write the value in pieces of this size with `begin_value`.
+    piece_len: u8,
+    /// Reserve space for the write first. If it succeeds, the write must not fail with `Full`.
+    reserve: bool,
+}
+
+/// Several ops in a single write transaction, with reads seeing its writes.
+#[derive(Arbitrary, Debug)]
+struct TransactionOp {
+    ops: Vec<TxOp>,
+    /// Commit the transaction at the end, instead of dropping it.
+    commit: bool,
+}
+
+#[derive(Arbitrary, Debug)]
+enum TxOp {
+    Write(WriteOp),
+    Delete(DeleteOp),
+    DeleteRange(DeleteRangeOp),
+    Read(ReadOp),
+    ReadRange(ReadRangeOp),
+}
+
+#[derive(Arbitrary, Debug)]
+struct WriteOp {
+    key: u16,
+    value_len: usize,
 }
 
 #[derive(Arbitrary, Debug)]
@@ -36,15 +79,39 @@ struct DeleteOp {
     key: u16,
 }
 
+#[derive(Arbitrary, Debug)]
+struct DeleteRangeOp {
+    lower_bound: Bound<u16>,
+    upper_bound: Bound<u16>,
+}
+
+#[derive(Arbitrary, Debug)]
+struct PrefixOp {
+    /// First byte of the keys. If not set, the prefix is empty.
+    prefix: Option<u8>,
+}
+
 #[derive(Arbitrary, Debug)]
 struct ReadOp {
     key: u16,
 }
 
+#[derive(Arbitrary, Debug)]
+struct ReadAtOp {
+    key: u16,
+    offset: u16,
+    buf_len: u8,
+}
+
 #[derive(Arbitrary, Debug)]
 struct ReadRangeOp {
     lower_bound: Bound<u16>,
     upper_bound: Bound<u16>,
+    rev: bool,
+    /// If set, seek the cursor to this key before iterating.
+    seek: Option<u16>,
+    /// Read only the keys with `next_key`.
+    keys_only: bool,
 }
 
 fn fuzz(ops: Input) {
@@ -61,7 +128,10 @@ fn fuzz(ops: Input) {
 
 async fn fuzz_inner(ops: Input, dump: bool) {
     let mut f = MemFlash::new();
-    let config = Config::default();
+    let mut config = Config::default();
+    config.wear_leveling_threshold = ops.wear_leveling_threshold.map(|n| 1 + u32::from(n % 8));
+    config.verify_writes = ops.verify_writes;
+    config.redundant_meta = ops.redundant_meta;
     let db = Database::<_, NoopRawMutex>::new(&mut f, config);
     db.format().await.unwrap();
 
@@ -79,16 +149,33 @@ async fn fuzz_inner(ops: Input, dump: bool) {
                 op.value_len %= MAX_VALUE_SIZE + 1;
 
                 let key = op.key.to_be_bytes();
-                let mut val = vec![0; op.value_len];
-                let val_num = i.to_be_bytes();
-                let n = val_num.len().min(val.len());
-                val[..n].copy_from_slice(&val_num[..n]);
+                let val = make_value(i, op.value_len);
 
                 // Write to DB
                 let mut wtx = db.write_transaction().await;
-                match wtx.write(&key, &val).await {
+                if op.reserve {
+                    match wtx.reserve(1, key.len() + val.len()).await {
+                        Ok(()) => {}
+                        Err(WriteError::Full) => continue,
+                        Err(e) => panic!("reserve error: {:?}", e),
+                    }
+                }
+                let res = match op.piece_len as usize {
+                    0 => wtx.write(&key, &val).await,
+                    piece_len => {
+                        async {
+                            let mut w = wtx.begin_value(&key, val.len()).await?;
+                            for piece in val.chunks(piece_len) {
+                                w.write(piece).await?;
+                            }
+                            w.finish().await
+                        }
+                        .await
+                    }
+                };
+                match res {
                     Ok(()) => {}
-                    Err(WriteError::Full) => continue,
+                    Err(WriteError::Full) if !op.reserve => continue,
                     Err(e) => panic!("write error: {:?}", e),
                 }
                 wtx.commit().await.unwrap();
@@ -111,6 +198,164 @@ async fn fuzz_inner(ops: Input, dump: bool) {
                 // Write to mirror
                 m.remove(&key[..]);
             }
+            Op::DeleteRange(op) => {
+                let mut lower_buf = [0; 2];
+                let lower_bound = op.lower_bound.map(|k| {
+                    lower_buf = k.to_be_bytes();
+                    &lower_buf[..]
+                });
+                let mut upper_buf = [0; 2];
+                let upper_bound = op.upper_bound.map(|k| {
+                    upper_buf = k.to_be_bytes();
+                    &upper_buf[..]
+                });
+
+                // Write to DB
+                let mut wtx = db.write_transaction().await;
+                match wtx.delete_range((lower_bound, upper_bound)).await {
+                    Ok(()) => {}
+                    Err(WriteError::Full) => continue,
+                    Err(e) => panic!("delete_range error: {:?}", e),
+                }
+                wtx.commit().await.unwrap();
+
+                // Write to mirror. Unlike `BTreeMap::range`, empty and reversed ranges are allowed.
+                m.retain(|k, _| !(lower_bound, upper_bound).contains(&&k[..]));
+            }
+            Op::DeletePrefix(op) => {
+                let prefix = op.prefix.as_slice();
+
+                // Write to DB
+                let mut wtx = db.write_transaction().await;
+                match wtx.delete_prefix(prefix).await {
+                    Ok(()) => {}
+                    Err(WriteError::Full) => continue,
+                    Err(e) => panic!("delete_prefix error: {:?}", e),
+                }
+                wtx.commit().await.unwrap();
+
+                // Write to mirror
+                m.retain(|k, _| !k.starts_with(prefix));
+            }
+            Op::Transaction(op) => {
+                // Mirror of the database as seen by the transaction, and the keys it can write next.
+                let mut tx_m = m.clone();
+                let mut next = Some(Bound::Unbounded);
+
+                let mut wtx = db.write_transaction().await;
+                let mut ok = true;
+                for (j, op) in op.ops.into_iter().enumerate() {
+                    log::info!("---------------------------------------------------- tx op: {:?}", op);
+
+                    match op {
+                        TxOp::Write(op) => {
+                            let key = op.key.to_be_bytes();
+                            let val = make_value((i << 16) | j, op.value_len % (MAX_VALUE_SIZE + 1));
+                            ok = check_write(wtx.write(&key, &val).await, &mut next, &key);
+                            if ok {
+                                tx_m.insert(key.to_vec(), val);
+                            }
+                        }
+                        TxOp::Delete(op) => {
+                            let key = op.key.to_be_bytes();
+                            ok = check_write(wtx.delete(&key).await, &mut next, &key);
+                            if ok {
+                                tx_m.remove(&key[..]);
+                            }
+                        }
+                        TxOp::DeleteRange(op) => {
+                            let (lower_bound, upper_bound) = key_bounds(op.lower_bound, op.upper_bound);
+                            let bounds = (
+                                lower_bound.as_ref().map(|k| &k[..]),
+                                upper_bound.as_ref().map(|k| &k[..]),
+                            );
+                            let res = wtx.delete_range(bounds).await;
+
+                            // The range deletes from `start` to `end`, 2-byte keys always have a successor.
+                            let start = match lower_bound {
+                                Bound::Included(k) => k.to_vec(),
+                                Bound::Excluded(k) => [&k[..], &[0]].concat(),
+                                Bound::Unbounded => Vec::new(),
+                            };
+                            let end = match upper_bound {
+                                Bound::Included(k) => Some([&k[..], &[0]].concat()),
+                                Bound::Excluded(k) => Some(k.to_vec()),
+                                Bound::Unbounded => None,
+                            };
+                            if end.as_ref().is_some_and(|end| start >= *end) {
+                                // Empty ranges are a no-op.
+                                assert_eq!(res, Ok(()));
+                                continue;
+                            }
+                            ok = check_write(res, &mut next, &start);
+                            if ok {
+                                next = end.map(Bound::Included);
+                                tx_m.retain(|k, _| !bounds.contains(&&k[..]));
+                            }
+                        }
+                        TxOp::Read(op) => {
+                            let key = op.key.to_be_bytes();
+                            let mut buf = [0; MAX_VALUE_SIZE];
+                            let got_val = match wtx.read(&key, &mut buf).await {
+                                Ok(n) => Some(&buf[..n]),
+                                Err(ReadError::KeyNotFound) => None,
+                                Err(e) => panic!("read error: {:?}", e),
+                            };
+                            assert_eq!(got_val, tx_m.get(&key[..]).map(|v| &v[..]));
+                        }
+                        TxOp::ReadRange(op) => {
+                            if !valid_range(&op) {
+                                continue;
+                            }
+                            let (lower_bound, upper_bound) = key_bounds(op.lower_bound, op.upper_bound);
+                            let bounds = (
+                                lower_bound.as_ref().map(|k| &k[..]),
+                                upper_bound.as_ref().map(|k| &k[..]),
+                            );
+                            let cur = match op.rev {
+                                false => wtx.read_range(bounds).await,
+                                true => wtx.read_range_rev(bounds).await,
+                            };
+                            match cur {
+                                Ok(cur) => check_range(cur, &op, &tx_m).await,
+                                Err(e) => panic!("read_range error: {:?}", e),
+                            }
+                        }
+                    }
+                    if !ok {
+                        break;
+                    }
+                }
+
+                // A failed write cancels the transaction.
+                if ok && op.commit {
+                    wtx.commit().await.unwrap();
+                    m = tx_m;
+                }
+            }
+            Op::CompactStep => {
+                db.compact_step().await.unwrap();
+            }
+            Op::CompactFull => {
+                db.compact_full().await.unwrap();
+                assert!(!db.compact_step().await.unwrap());
+            }
+            Op::Stats => {
+                let stats = db.stats().await.unwrap();
+                assert_eq!(stats.used_pages + stats.free_pages, stats.page_count);
+                assert_eq!(stats.live_bytes + stats.garbage_bytes, stats.total_file_bytes());
+                assert_eq!(stats.live_bytes == 0, m.is_empty());
+            }
+            Op::WearStats => {
+                let stats = db.wear_stats().await.unwrap();
+                assert!(stats.min_erase_count <= stats.max_erase_count);
+                assert!(stats.total_erase_count >= stats.min_erase_count as u64 * stats.page_count as u64);
+                assert!(stats.total_erase_count <= stats.max_erase_count as u64 * stats.page_count as u64);
+            }
+            Op::Check => {
+                let report = db.check().await.unwrap();
+                assert!(report.is_ok(), "{:?}", report);
+            }
             Op::Read(op) => {
                 let key = op.key.to_be_bytes();
 
@@ -127,57 +372,72 @@ async fn fuzz_inner(ops: Input, dump: bool) {
 
                 assert_eq!(got_val, want_val);
             }
-            Op::ReadRange(op) => {
-                // ignore reversed ranges, otherwise BTreeMap::range panics.
-                if let (Bound::Excluded(l) | Bound::Included(l), Bound::Excluded(u) | Bound::Included(u)) =
-                    (op.lower_bound, op.upper_bound)
-                {
-                    if l > u {
-                        continue;
-                    }
-                }
-                // Tis also panics....
-                if let (Bound::Excluded(l), Bound::Excluded(u)) = (op.lower_bound, op.upper_bound) {
-                    if l == u {
-                        continue;
-                    }
-                }
+            Op::ReadAt(op) => {
+                let key = op.key.to_be_bytes();
+                let offset = op.offset as usize;
+                let buf_len = (op.buf_len as usize).min(buf.len());
 
-                let mut lower_buf = [0; 2];
-                let lower_bound = op.lower_bound.map(|k| {
-                    lower_buf = k.to_be_bytes();
-                    &lower_buf[..]
-                });
-                let mut upper_buf = [0; 2];
-                let upper_bound = op.upper_bound.map(|k| {
-                    upper_buf = k.to_be_bytes();
-                    &upper_buf[..]
+                // Read from DB
+                let rtx = db.read_transaction().await;
+                let got_len = match rtx.value_len(&key).await {
+                    Ok(n) => Some(n),
+                    Err(ReadError::KeyNotFound) => None,
+                    Err(e) => panic!("value_len error: {:?}", e),
+                };
+                let got_val = match rtx.read_at(&key, offset, &mut buf[..buf_len]).await {
+                    Ok(n) => Some(&buf[..n]),
+                    Err(ReadError::KeyNotFound) => None,
+                    Err(e) => panic!("read_at error: {:?}", e),
+                };
+
+                // Read from mirror
+                let want_val = m.get(&key[..]).map(|v| {
+                    let v = &v[offset.min(v.len())..];
+                    &v[..v.len().min(buf_len)]
                 });
 
+                assert_eq!(got_len, m.get(&key[..]).map(|v| v.len()));
+                assert_eq!(got_val, want_val);
+            }
+            Op::ReadRange(op) => {
+                if !valid_range(&op) {
+                    continue;
+                }
+                let (lower_bound, upper_bound) = key_bounds(op.lower_bound, op.upper_bound);
+                let bounds = (
+                    lower_bound.as_ref().map(|k| &k[..]),
+                    upper_bound.as_ref().map(|k| &k[..]),
+                );
+
                 // Get cursor from DB
                 let rtx = db.read_transaction().await;
-                let mut cur = match rtx.read_range((lower_bound, upper_bound)).await {
-                    Ok(cur) => cur,
-                    Err(e) => panic!("read_range error: {:?}", e),
+                let cur = match op.rev {
+                    false => rtx.read_range(bounds).await,
+                    true => rtx.read_range_rev(bounds).await,
                 };
+                match cur {
+                    Ok(cur) => check_range(cur, &op, &m).await,
+                    Err(e) => panic!("read_range error: {:?}", e),
+                }
+            }
+            Op::ReadPrefix(op) => {
+                let prefix = op.prefix.as_slice();
 
-                // iterate the mirror map.
-                for (want_k, want_v) in m.range((
-                    op.lower_bound.map(|k| k.to_be_bytes().to_vec()),
-                    op.upper_bound.map(|k| k.to_be_bytes().to_vec()),
-                )) {
+                let rtx = db.read_transaction().await;
+                let mut cur = match rtx.read_prefix(prefix).await {
+                    Ok(cur) => cur,
+                    Err(e) => panic!("read_prefix error: {:?}", e),
+                };
+                for (want_k, want_v) in m.iter().filter(|(k, _)| k.starts_with(prefix)) {
                     match cur.next(&mut kbuf, &mut buf).await {
                         Err(e) => panic!("Cursor::next error: {:?}", e),
                         Ok(None) => panic!("Cursor returned None too early."),
                         Ok(Some((klen, vlen))) => {
-                            let got_k = &kbuf[..klen];
-                            let got_v = &buf[..vlen];
-                            assert_eq!(want_k, got_k);
-                            assert_eq!(want_v, got_v);
+                            assert_eq!(want_k, &kbuf[..klen]);
+                            assert_eq!(want_v, &buf[..vlen]);
                         }
                     }
                 }
-
                 match cur.next(&mut kbuf, &mut buf).await {
                     Err(e) => panic!("Cursor::next error: {:?}", e),
                     Ok(None) => {}
@@ -205,3 +465,106 @@ async fn fuzz_inner(ops: Input, dump: bool) {
         }
     }
 }
+
+fn make_value(n: usize, len: usize) -> Vec<u8> {
+    let mut val = vec![0; len];
+    let val_num = n.to_be_bytes();
+    let n = val_num.len().min(val.len());
+    val[..n].copy_from_slice(&val_num[..n]);
+    val
+}
+
+fn key_bounds(lower_bound: Bound<u16>, upper_bound: Bound<u16>) -> (Bound<[u8; 2]>, Bound<[u8; 2]>) {
+    (lower_bound.map(u16::to_be_bytes), upper_bound.map(u16::to_be_bytes))
+}
+
+/// Check the result of a write to a transaction that can write keys from `next` on, and update `next`.
+///
+/// Returns whether the write succeeded. If not, the transaction is canceled.
+fn check_write<E: std::fmt::Debug>(
+    res: Result<(), WriteError<E>>,
+    next: &mut Option<Bound<Vec<u8>>>,
+    key: &[u8],
+) -> bool {
+    let sorted = match next {
+        None => false,
+        Some(next) => (next.as_ref(), Bound::Unbounded).contains(&key.to_vec()),
+    };
+    match res {
+        Ok(()) if sorted => {
+            *next = Some(Bound::Excluded(key.to_vec()));
+            true
+        }
+        Err(WriteError::NotSorted) if !sorted => false,
+        Err(WriteError::Full) => false,
+        res => panic!("write error: {:?}, sorted: {}", res, sorted),
+    }
+}
+
+/// Whether a range can be iterated in the mirror, `BTreeMap::range` panics on reversed ranges.
+fn valid_range(op: &ReadRangeOp) -> bool {
+    match (op.lower_bound, op.upper_bound) {
+        (Bound::Excluded(l) | Bound::Included(l), Bound::Excluded(u) | Bound::Included(u)) if l > u => false,
+        // Tis also panics....
+        (Bound::Excluded(l), Bound::Excluded(u)) if l == u => false,
+        _ => true,
+    }
+}
+
+/// Check a cursor opened for `op` returns the entries in the mirror `m`.
+async fn check_range(
+    mut cur: Cursor<'_, &mut MemFlash, NoopRawMutex>,
+    op: &ReadRangeOp,
+    m: &BTreeMap<Vec<u8>, Vec<u8>>,
+) {
+    let mut kbuf = [0; MAX_KEY_SIZE];
+    let mut buf = [0; MAX_VALUE_SIZE];
+
+    if let Some(seek) = op.seek {
+        if let Err(e) = cur.seek(&seek.to_be_bytes()).await {
+            panic!("Cursor::seek error: {:?}", e)
+        }
+    }
+
+    // iterate the mirror map.
+    let mut want: Vec<_> = m
+        .range((
+            op.lower_bound.map(|k| k.to_be_bytes().to_vec()),
+            op.upper_bound.map(|k| k.to_be_bytes().to_vec()),
+        ))
+        .collect();
+    if op.rev {
+        want.reverse();
+    }
+    if let Some(seek) = op.seek {
+        let seek = seek.to_be_bytes();
+        want.retain(|(k, _)| match op.rev {
+            false => k[..] >= seek[..],
+            true => k[..] <= seek[..],
+        });
+    }
+    for (want_k, want_v) in want {
+        let res = match op.keys_only {
+            false => cur.next(&mut kbuf, &mut buf).await,
+            true => cur.next_key(&mut kbuf).await,
+        };
+        match res {
+            Err(e) => panic!("Cursor::next error: {:?}", e),
+            Ok(None) => panic!("Cursor returned None too early."),
+            Ok(Some((klen, vlen))) => {
+                let got_k = &kbuf[..klen];
+                assert_eq!(want_k, got_k);
+                assert_eq!(want_v.len(), vlen);
+                if !op.keys_only {
+                    assert_eq!(want_v, &buf[..vlen]);
+                }
+            }
+        }
+    }
+
+    match cur.next(&mut kbuf, &mut buf).await {
+        Err(e) => panic!("Cursor::next error: {:?}", e),
+        Ok(None) => {}
+        Ok(Some(_)) => panic!("Cursor::next didn't return None when it should."),
+    }
+}
@@ -35,7 +35,7 @@ def feature(name, default, min=None, max=None, pow2=None, vals=None, factors=[])
     )
 
 
-feature("align", default=4, vals=[1, 2, 4])
+feature("align", default=4, vals=[1, 2, 4, 8, 16, 32])
 feature("page_size", default=4096, min=128, max=65536, pow2=True)
 feature("max_page_count", default=256, min=1,
         max=65536, pow2=True, factors=[3, 5, 9])
@@ -13,6 +13,8 @@ pub enum PageState {
 pub struct Allocator {
     page_count: usize,
     pages: [u8; (MAX_PAGE_COUNT + 7) / 8],
+    /// Retired pages. They're always marked as used, so they're never allocated.
+    bad: [u8; (MAX_PAGE_COUNT + 7) / 8],
     used: usize,
     next_page_id: usize,
 }
@@ -22,6 +24,7 @@ impl Allocator {
         Self {
             page_count: 0,
             pages: [0u8; (MAX_PAGE_COUNT + 7) / 8],
+            bad: [0u8; (MAX_PAGE_COUNT + 7) / 8],
             used: 0,
             next_page_id: 0,
         }
@@ -42,15 +45,12 @@ impl Allocator {
         }
     }
 
+    /// Mark all pages as free, except the retired ones.
     pub fn reset(&mut self, page_count: usize, random_seed: u32) {
         self.page_count = page_count;
         self.next_page_id = random_seed as usize % page_count;
-        self.used = 0;
-        self.pages.fill(0x00);
-    }
-
-    pub fn allocate(&mut self) -> PageID {
-        unwrap!(self.try_allocate())
+        self.pages = self.bad;
+        self.used = self.bad_pages();
     }
 
     pub fn try_allocate(&mut self) -> Option<PageID> {
@@ -78,8 +78,12 @@ impl Allocator {
         }
     }
 
+    /// Mark a page as used. Retired pages already are, but they can still have data written before retiring them.
     pub fn mark_used(&mut self, page_id: PageID) -> Result<(), CorruptedError> {
         assert!(page_id.index() < self.page_count, "out of bounds");
+        if self.is_bad(page_id) {
+            return Ok(());
+        }
         if self.get_bit(page_id.index()) != PageState::Free {
             corrupted!();
         }
@@ -89,8 +93,12 @@ impl Allocator {
         Ok(())
     }
 
+    /// Free a page. Retired pages stay used.
     pub fn free(&mut self, page_id: PageID) -> Result<(), CorruptedError> {
         assert!(page_id.index() < self.page_count, "out of bounds");
+        if self.is_bad(page_id) {
+            return Ok(());
+        }
         if self.get_bit(page_id.index()) != PageState::Used {
             corrupted!();
         }
@@ -107,6 +115,37 @@ impl Allocator {
         self.get_bit(page_id.index()) == PageState::Used
     }
 
+    /// Retire a page, so it's never allocated again.
+    pub fn retire(&mut self, page_id: PageID) {
+        assert!(page_id.index() < self.page_count, "out of bounds");
+        if self.is_bad(page_id) {
+            return;
+        }
+
+        let i = page_id.index();
+        self.bad[i / 8] |= 1 << (i % 8);
+        if self.get_bit(i) == PageState::Free {
+            self.set_bit(i, PageState::Used);
+            self.used += 1;
+        }
+    }
+
+    pub fn is_bad(&self, page_id: PageID) -> bool {
+        let i = page_id.index();
+        (self.bad[i / 8] >> (i % 8)) & 1 != 0
+    }
+
+    /// Retired pages, in ascending order.
+    pub fn bad_page_ids(&self) -> impl Iterator<Item = PageID> + '_ {
+        (0..self.page_count)
+            .map(|i| PageID::from_raw(i as RawPageID).unwrap())
+            .filter(|&p| self.is_bad(p))
+    }
+
+    pub fn bad_pages(&self) -> usize {
+        self.bad_page_ids().count()
+    }
+
     #[allow(unused)]
     pub fn used_pages(&self) -> usize {
         self.used
@@ -121,6 +160,44 @@ impl Allocator {
     }
 }
 
+/// Set of pages.
+pub struct PageSet {
+    pages: [u8; (MAX_PAGE_COUNT + 7) / 8],
+}
+
+impl PageSet {
+    pub const fn new() -> Self {
+        Self {
+            pages: [0u8; (MAX_PAGE_COUNT + 7) / 8],
+        }
+    }
+
+    pub fn contains(&self, page_id: PageID) -> bool {
+        let i = page_id.index();
+        (self.pages[i / 8] >> (i % 8)) & 1 != 0
+    }
+
+    /// Add a page to the set. Returns whether it wasn't in it already.
+    pub fn insert(&mut self, page_id: PageID) -> bool {
+        let i = page_id.index();
+        let inserted = !self.contains(page_id);
+        self.pages[i / 8] |= 1 << (i % 8);
+        inserted
+    }
+
+    pub fn remove(&mut self, page_id: PageID) {
+        let i = page_id.index();
+        self.pages[i / 8] &= !(1 << (i % 8));
+    }
+
+    /// Pages in the set, in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = PageID> + '_ {
+        (0..MAX_PAGE_COUNT)
+            .map(|i| PageID::from_raw(i as RawPageID).unwrap())
+            .filter(|&p| self.contains(p))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -204,6 +281,28 @@ mod tests {
         a.mark_used(page(2)).unwrap_err();
     }
 
+    #[test_log::test]
+    fn test_retire() {
+        let mut a = Allocator::new();
+        a.reset(5, 0);
+        a.mark_used(page(1)).unwrap();
+        a.retire(page(1));
+        a.retire(page(3));
+        assert_eq!(a.bad_pages(), 2);
+        assert_eq!(a.used_pages(), 2);
+
+        // Retired pages stay used when freed, and when resetting.
+        a.free(page(1)).unwrap();
+        assert_eq!(a.is_used(page(1)), true);
+        a.reset(5, 0);
+        assert_eq!(a.used_pages(), 2);
+        a.mark_used(page(3)).unwrap();
+        assert_eq!(a.try_allocate(), Some(page(0)));
+        assert_eq!(a.try_allocate(), Some(page(2)));
+        assert_eq!(a.try_allocate(), Some(page(4)));
+        assert_eq!(a.try_allocate(), None);
+    }
+
     #[test_log::test]
     #[should_panic(expected = "out of bounds")]
     fn test_is_used_out_of_bounds() {
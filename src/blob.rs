@@ -0,0 +1,776 @@
+//! Values bigger than [`MAX_VALUE_SIZE`].
+//!
+//! Blobs are opt-in: they need [`Config::blobs`](crate::Config::blobs) to be enabled, otherwise the
+//! blob methods panic.
+//!
+//! [`WriteTransaction::write_blob`] writes values of up to [`MAX_BLOB_SIZE`] bytes. Values that fit
+//! in `MAX_VALUE_SIZE` are stored as a regular record. Bigger values are split in parts, each stored
+//! as a separate record:
+//!
+//! - The blob header, with key `key ++ [0, 0, 0]`. Its value is the blob length, as a little-endian `u32`.
+//! - Part `i`, with key `key ++ [0] ++ (i as u16).to_be_bytes()`, for `i` starting at 1. Its value is
+//!   the next `MAX_VALUE_SIZE` bytes of the blob, or less for the last part.
+//!
+//! Reading blobs with [`ReadTransaction::read_blob`], [`ReadTransaction::read_blob_at`] or
+//! [`Cursor::next_blob`](crate::Cursor::next_blob) reassembles the parts. They also read values written
+//! with [`WriteTransaction::write`], so blob keys and regular keys can be mixed freely. Writing and deleting
+//! blobs writes and deletes all their parts within the write transaction, so it's atomic. They don't read the
+//! previous value: they delete the regular record or the parts that are left with a delete and a range
+//! tombstone.
+//!
+//! Blob keys can be at most [`MAX_BLOB_KEY_SIZE`] bytes long. Keys with a zero byte third from the end,
+//! the shape of the keys of the headers and parts, are reserved: writing or deleting them with
+//! [`WriteTransaction::write`], [`WriteTransaction::delete`], [`WriteTransaction::begin_value`] or the
+//! blob methods fails with [`WriteError::ReservedKey`]. Enabling blobs on a database that already
+//! has such keys makes them read as corrupted blobs.
+//!
+//! A blob stored in parts sorts among the keys starting with `key ++ [0]`, not at `key`. Don't use
+//! other keys with that prefix along with it: cursors return them before the blob or in the middle of
+//! its parts, where [`Cursor::next_blob`](crate::Cursor::next_blob) fails with
+//! [`CursorError::Corrupted`](crate::CursorError::Corrupted). Without
+//! [`Config::unsorted_writes`](crate::Config::unsorted_writes), they also can't be written after the
+//! blob in the same transaction. For example, writing a blob with key `a`, and then a value with key
+//! `a\0`, fails with [`WriteError::NotSorted`].
+//!
+//! Range bounds are compared against the keys of the parts. A range with `Bound::Included(key)` as
+//! upper bound doesn't include the blob with that key, and one with `Bound::Excluded(key)` as lower
+//! bound does. [`WriteTransaction::delete_range`] fails with [`WriteError::ReservedKey`] if a bound
+//! falls between the keys of the header and the last possible part of a blob, so that it can't leave
+//! one corrupted. [`WriteTransaction::delete_prefix`] with the blob key deletes the whole blob.
+
+use core::ops::{Bound, RangeBounds};
+
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use heapless::Vec;
+
+use crate::config::{MAX_KEY_SIZE, MAX_VALUE_SIZE};
+use crate::flash::Flash;
+use crate::record::RecordKind;
+use crate::{Database, ReadError, ReadTransaction, WriteError, WriteTransaction};
+
+const KEY_SUFFIX_SIZE: usize = 3;
+pub(crate) const HEADER_SIZE: usize = 4;
+const MAX_PART_COUNT: usize = u16::MAX as usize;
+
+/// Maximum supported blob key size.
+pub const MAX_BLOB_KEY_SIZE: usize = MAX_KEY_SIZE.saturating_sub(KEY_SUFFIX_SIZE);
+
+/// Maximum supported blob size.
+pub const MAX_BLOB_SIZE: usize = {
+    let max = MAX_PART_COUNT * MAX_VALUE_SIZE;
+    if max < u32::MAX as usize {
+        max
+    } else {
+        u32::MAX as usize
+    }
+};
+
+/// Key of part `part` of the blob with key `key`. Part 0 is the header.
+pub(crate) fn part_key(key: &[u8], part: u16) -> Vec<u8, MAX_KEY_SIZE> {
+    let mut res = unwrap!(Vec::from_slice(key));
+    unwrap!(res.push(0));
+    unwrap!(res.extend_from_slice(&part.to_be_bytes()));
+    res
+}
+
+/// Whether `key` has the shape of the key of a blob header or part.
+pub(crate) fn is_reserved_key(key: &[u8]) -> bool {
+    key.len().checked_sub(KEY_SUFFIX_SIZE).is_some_and(|n| key[n] == 0)
+}
+
+/// Whether a range bound at `key` falls between the header and the last possible part of a blob.
+/// `after` is whether the bound is right after `key`, instead of right before it.
+fn splits_blob(key: &[u8], after: bool) -> bool {
+    (0..key.len().min(MAX_BLOB_KEY_SIZE + 1))
+        .filter(|&n| key[n] == 0 && !is_reserved_key(&key[..n]))
+        .any(|n| {
+            let first = part_key(&key[..n], 0);
+            let last = part_key(&key[..n], MAX_PART_COUNT as u16);
+            match after {
+                false => first[..] < *key && *key <= last[..],
+                true => first[..] <= *key && *key < last[..],
+            }
+        })
+}
+
+/// Panic if blobs are disabled in `db`, see [`Config::blobs`](crate::Config::blobs).
+pub(crate) fn assert_enabled<F: Flash, M: RawMutex>(db: &Database<F, M>) {
+    assert!(db.blobs, "blobs are disabled, see `Config::blobs`");
+}
+
+/// If the record with key `key` and a value of `value_len` bytes is a blob header, get the blob key length.
+pub(crate) fn header_key_len(key: &[u8], value_len: usize) -> Option<usize> {
+    match key.len().checked_sub(KEY_SUFFIX_SIZE) {
+        Some(n) if key[n..] == [0; KEY_SUFFIX_SIZE] && value_len == HEADER_SIZE => Some(n),
+        _ => None,
+    }
+}
+
+/// Decode the blob length from the value of a blob header.
+pub(crate) fn decode_header(value: &[u8]) -> Option<usize> {
+    let len = u32::from_le_bytes(value[..HEADER_SIZE].try_into().unwrap()) as usize;
+    // Blobs that fit in a single record are stored as regular records.
+    (MAX_VALUE_SIZE < len && len <= MAX_BLOB_SIZE).then_some(len)
+}
+
+/// If `key` is the key of a blob part other than the header, get the blob key length and the part index.
+pub(crate) fn part_index(key: &[u8]) -> Option<(usize, u16)> {
+    let n = key.len().checked_sub(KEY_SUFFIX_SIZE)?;
+    let part = u16::from_be_bytes([key[n + 1], key[n + 2]]);
+    (key[n] == 0 && part != 0).then_some((n, part))
+}
+
+/// Get the blob length from the index and value length of its last part.
+pub(crate) fn len_from_last_part(part: u16, value_len: usize) -> Option<usize> {
+    let len = (part as usize - 1) * MAX_VALUE_SIZE + value_len;
+    (value_len != 0 && MAX_VALUE_SIZE < len && len <= MAX_BLOB_SIZE).then_some(len)
+}
+
+pub(crate) fn part_count(len: usize) -> usize {
+    len.div_ceil(MAX_VALUE_SIZE)
+}
+
+/// Record reads, implemented by both read and write transactions.
+trait RecordRead {
+    type Error;
+    async fn read_record_at(
+        &mut self,
+        key: &[u8],
+        offset: usize,
+        value: &mut [u8],
+    ) -> Result<usize, ReadError<Self::Error>>;
+    async fn record_len(&mut self, key: &[u8]) -> Result<usize, ReadError<Self::Error>>;
+}
+
+impl<'a, 'b, F: Flash + 'a, M: RawMutex + 'a> RecordRead for &'b ReadTransaction<'a, F, M> {
+    type Error = F::Error;
+    async fn read_record_at(
+        &mut self,
+        key: &[u8],
+        offset: usize,
+        value: &mut [u8],
+    ) -> Result<usize, ReadError<F::Error>> {
+        self.read_at(key, offset, value).await
+    }
+    async fn record_len(&mut self, key: &[u8]) -> Result<usize, ReadError<F::Error>> {
+        self.value_len(key).await
+    }
+}
+
+impl<'a, F: Flash + 'a, M: RawMutex + 'a> RecordRead for WriteTransaction<'a, F, M> {
+    type Error = F::Error;
+    async fn read_record_at(
+        &mut self,
+        key: &[u8],
+        offset: usize,
+        value: &mut [u8],
+    ) -> Result<usize, ReadError<F::Error>> {
+        self.read_at(key, offset, value).await
+    }
+    async fn record_len(&mut self, key: &[u8]) -> Result<usize, ReadError<F::Error>> {
+        self.value_len(key).await
+    }
+}
+
+/// Get the length of a blob, and whether it's stored in parts.
+async fn blob_len<T: RecordRead>(t: &mut T, key: &[u8]) -> Result<(usize, bool), ReadError<T::Error>> {
+    if key.len() > MAX_BLOB_KEY_SIZE {
+        return Err(ReadError::KeyTooBig);
+    }
+
+    let mut header = [0; HEADER_SIZE];
+    match t.read_record_at(&part_key(key, 0), 0, &mut header).await {
+        Ok(HEADER_SIZE) => match decode_header(&header) {
+            Some(len) => Ok((len, true)),
+            None => Err(ReadError::Corrupted),
+        },
+        Ok(_) => Err(ReadError::Corrupted),
+        Err(ReadError::KeyNotFound) => Ok((t.record_len(key).await?, false)),
+        Err(e) => Err(e),
+    }
+}
+
+async fn read_blob_at<T: RecordRead>(
+    t: &mut T,
+    key: &[u8],
+    offset: usize,
+    value: &mut [u8],
+) -> Result<usize, ReadError<T::Error>> {
+    let blob = blob_len(t, key).await?;
+    read_parts(t, key, blob, offset, value).await
+}
+
+async fn read_blob<T: RecordRead>(t: &mut T, key: &[u8], value: &mut [u8]) -> Result<usize, ReadError<T::Error>> {
+    let blob = blob_len(t, key).await?;
+    let len = blob.0;
+    if len > value.len() {
+        return Err(ReadError::BufferTooSmall);
+    }
+    read_parts(t, key, blob, 0, &mut value[..len]).await
+}
+
+/// Read a blob starting at `offset`, given its length and whether it's stored in parts.
+async fn read_parts<T: RecordRead>(
+    t: &mut T,
+    key: &[u8],
+    (len, in_parts): (usize, bool),
+    offset: usize,
+    value: &mut [u8],
+) -> Result<usize, ReadError<T::Error>> {
+    if !in_parts {
+        return t.read_record_at(key, offset, value).await;
+    }
+
+    let n = value.len().min(len.saturating_sub(offset));
+    let mut done = 0;
+    while done < n {
+        let pos = offset + done;
+        let part = (pos / MAX_VALUE_SIZE + 1) as u16;
+        let got = match t
+            .read_record_at(&part_key(key, part), pos % MAX_VALUE_SIZE, &mut value[done..n])
+            .await
+        {
+            Ok(0) | Err(ReadError::KeyNotFound) => return Err(ReadError::Corrupted),
+            Ok(got) => got,
+            Err(e) => return Err(e),
+        };
+        done += got;
+    }
+    Ok(n)
+}
+
+impl<'a, F: Flash + 'a, M: RawMutex + 'a> ReadTransaction<'a, F, M> {
+    /// Read a blob from the database.
+    ///
+    /// The value is stored in the `value` buffer, and the length is returned.
+    /// Values written with [`WriteTransaction::write`] are read too. See the [module docs](self) for details.
+    pub async fn read_blob(&self, key: &[u8], value: &mut [u8]) -> Result<usize, ReadError<F::Error>> {
+        assert_enabled(self.db);
+        read_blob(&mut &*self, key, value).await
+    }
+
+    /// Read part of a blob from the database, starting at `offset` bytes into it.
+    ///
+    /// Same as [`read_at`](Self::read_at), for blobs.
+    pub async fn read_blob_at(
+        &self,
+        key: &[u8],
+        offset: usize,
+        value: &mut [u8],
+    ) -> Result<usize, ReadError<F::Error>> {
+        assert_enabled(self.db);
+        read_blob_at(&mut &*self, key, offset, value).await
+    }
+
+    /// Get the length of a blob in the database.
+    pub async fn blob_len(&self, key: &[u8]) -> Result<usize, ReadError<F::Error>> {
+        assert_enabled(self.db);
+        Ok(blob_len(&mut &*self, key).await?.0)
+    }
+}
+
+impl<'a, F: Flash + 'a, M: RawMutex + 'a> WriteTransaction<'a, F, M> {
+    /// Read a blob from the database.
+    ///
+    /// Reads see the writes and deletes done so far in this transaction, even
+    /// though they're not committed yet. See [`ReadTransaction::read_blob`].
+    pub async fn read_blob(&mut self, key: &[u8], value: &mut [u8]) -> Result<usize, ReadError<F::Error>> {
+        assert_enabled(self.db);
+        read_blob(self, key, value).await
+    }
+
+    /// Read part of a blob from the database, starting at `offset` bytes into it.
+    ///
+    /// Reads see the writes and deletes done so far in this transaction, even
+    /// though they're not committed yet. See [`ReadTransaction::read_blob_at`].
+    pub async fn read_blob_at(
+        &mut self,
+        key: &[u8],
+        offset: usize,
+        value: &mut [u8],
+    ) -> Result<usize, ReadError<F::Error>> {
+        assert_enabled(self.db);
+        read_blob_at(self, key, offset, value).await
+    }
+
+    /// Get the length of a blob in the database.
+    ///
+    /// Reads see the writes and deletes done so far in this transaction, even
+    /// though they're not committed yet.
+    pub async fn blob_len(&mut self, key: &[u8]) -> Result<usize, ReadError<F::Error>> {
+        assert_enabled(self.db);
+        Ok(blob_len(self, key).await?.0)
+    }
+
+    /// Write a blob to the database.
+    ///
+    /// If the key was already present, either as a blob or a regular value, the previous value is overwritten.
+    /// Values longer than [`MAX_VALUE_SIZE`] are split in parts. See the [module docs](self) for details.
+    ///
+    /// Keys must be written in lexicographically ascending order, unless
+    /// [`Config::unsorted_writes`](crate::Config::unsorted_writes) is enabled.
+    pub async fn write_blob(&mut self, key: &[u8], value: &[u8]) -> Result<(), WriteError<F::Error>> {
+        assert_enabled(self.db);
+        if key.len() > MAX_BLOB_KEY_SIZE {
+            return Err(WriteError::KeyTooBig);
+        }
+        if value.len() > MAX_BLOB_SIZE {
+            return Err(WriteError::ValueTooBig);
+        }
+        if is_reserved_key(key) {
+            return Err(WriteError::ReservedKey);
+        }
+
+        // The previous value isn't read, it's overwritten whatever it was: reading it in the middle of
+        // the transaction would need a snapshot of it.
+        if value.len() <= MAX_VALUE_SIZE {
+            self.write_inner(key, value, RecordKind::Value).await?;
+            return self.delete_parts(key, 0).await;
+        }
+
+        self.write_inner(key, &[], RecordKind::Delete).await?;
+        self.write_inner(
+            &part_key(key, 0),
+            &(value.len() as u32).to_le_bytes(),
+            RecordKind::Value,
+        )
+        .await?;
+        for (i, chunk) in value.chunks(MAX_VALUE_SIZE).enumerate() {
+            self.write_inner(&part_key(key, i as u16 + 1), chunk, RecordKind::Value)
+                .await?;
+        }
+        self.delete_parts(key, part_count(value.len()) + 1).await
+    }
+
+    /// Delete a blob from the database.
+    ///
+    /// Regular values are deleted too. If the key was not present, this is a no-op.
+    pub async fn delete_blob(&mut self, key: &[u8]) -> Result<(), WriteError<F::Error>> {
+        assert_enabled(self.db);
+        if key.len() > MAX_BLOB_KEY_SIZE {
+            return Err(WriteError::KeyTooBig);
+        }
+        if is_reserved_key(key) {
+            return Err(WriteError::ReservedKey);
+        }
+
+        self.write_inner(key, &[], RecordKind::Delete).await?;
+        self.delete_parts(key, 0).await
+    }
+
+    /// Fail with [`WriteError::ReservedKey`] if blobs are enabled and `key` is reserved for their records.
+    pub(crate) fn check_reserved_key(&self, key: &[u8]) -> Result<(), WriteError<F::Error>> {
+        // Too big keys are rejected by the write itself.
+        match self.db.blobs && key.len() <= MAX_KEY_SIZE && is_reserved_key(key) {
+            true => Err(WriteError::ReservedKey),
+            false => Ok(()),
+        }
+    }
+
+    /// Fail with [`WriteError::ReservedKey`] if blobs are enabled and a bound of `range` falls between the
+    /// records of a blob, so deleting the range would leave it corrupted.
+    pub(crate) fn check_reserved_range<'k>(
+        &self,
+        range: &impl RangeBounds<&'k [u8]>,
+    ) -> Result<(), WriteError<F::Error>> {
+        if !self.db.blobs {
+            return Ok(());
+        }
+        let start = match range.start_bound() {
+            Bound::Included(k) => splits_blob(k, false),
+            Bound::Excluded(k) => splits_blob(k, true),
+            Bound::Unbounded => false,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(k) => splits_blob(k, true),
+            Bound::Excluded(k) => splits_blob(k, false),
+            Bound::Unbounded => false,
+        };
+        match start || end {
+            true => Err(WriteError::ReservedKey),
+            false => Ok(()),
+        }
+    }
+
+    /// Delete the parts of a blob from part `from` on, with a range tombstone up to the last possible one.
+    async fn delete_parts(&mut self, key: &[u8], from: usize) -> Result<(), WriteError<F::Error>> {
+        if from > MAX_PART_COUNT {
+            return Ok(());
+        }
+        let first = part_key(key, from as u16);
+        let last = part_key(key, MAX_PART_COUNT as u16);
+        self.delete_range_inner(&first[..]..=&last[..]).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+
+    use super::*;
+    use crate::flash::MemFlash;
+    use crate::{Config, CursorError, Database};
+
+    const BIG: usize = 3 * MAX_VALUE_SIZE + 5;
+
+    fn blob_config() -> Config {
+        Config {
+            blobs: true,
+            ..Config::default()
+        }
+    }
+
+    fn value(len: usize, seed: u8) -> std::vec::Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8 ^ seed).collect()
+    }
+
+    async fn check_blob(db: &Database<impl Flash, NoopRawMutex>, key: &[u8], value: &[u8]) {
+        let rtx = db.read_transaction().await;
+        let mut buf = [0; BIG + 10];
+        assert_eq!(rtx.blob_len(key).await.unwrap(), value.len());
+        let n = rtx.read_blob(key, &mut buf).await.unwrap();
+        assert_eq!(&buf[..n], value)
+    }
+
+    /// Get all entries with `Cursor::next_blob`, and the count of records with `Cursor::next`.
+    async fn read_all(
+        db: &Database<impl Flash, NoopRawMutex>,
+    ) -> (std::vec::Vec<(std::vec::Vec<u8>, std::vec::Vec<u8>)>, usize) {
+        let rtx = db.read_transaction().await;
+        let mut kbuf = [0; MAX_KEY_SIZE];
+        let mut vbuf = [0; BIG + 10];
+
+        let mut entries = std::vec::Vec::new();
+        let mut cursor = rtx.read_all().await.unwrap();
+        while let Some((klen, vlen)) = cursor.next_blob(&mut kbuf, &mut vbuf).await.unwrap() {
+            entries.push((kbuf[..klen].to_vec(), vbuf[..vlen].to_vec()));
+        }
+
+        // Reverse cursors return the same, reversed.
+        let mut rev_entries = std::vec::Vec::new();
+        let mut cursor = rtx.read_all_rev().await.unwrap();
+        while let Some((klen, vlen)) = cursor.next_blob(&mut kbuf, &mut vbuf).await.unwrap() {
+            rev_entries.push((kbuf[..klen].to_vec(), vbuf[..vlen].to_vec()));
+        }
+        rev_entries.reverse();
+        assert_eq!(entries, rev_entries);
+
+        let mut count = 0;
+        let mut cursor = rtx.read_all().await.unwrap();
+        while cursor.next(&mut kbuf, &mut vbuf).await.unwrap().is_some() {
+            count += 1;
+        }
+
+        (entries, count)
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_blob() {
+        let mut f = MemFlash::new();
+        let db = Database::<_, NoopRawMutex>::new(&mut f, blob_config());
+        db.format().await.unwrap();
+
+        let big = value(BIG, 0);
+        let mut wtx = db.write_transaction().await;
+        wtx.write(b"a", b"1234").await.unwrap();
+        wtx.write_blob(b"b", &big).await.unwrap();
+        wtx.write_blob(b"c", b"5678").await.unwrap();
+        wtx.write(b"d", b"").await.unwrap();
+        wtx.commit().await.unwrap();
+
+        check_blob(&db, b"a", b"1234").await;
+        check_blob(&db, b"b", &big).await;
+        check_blob(&db, b"c", b"5678").await;
+        check_blob(&db, b"d", b"").await;
+
+        let rtx = db.read_transaction().await;
+        assert_eq!(rtx.blob_len(b"e").await, Err(ReadError::KeyNotFound));
+        assert_eq!(
+            rtx.read_blob(b"b", &mut [0; BIG - 1]).await,
+            Err(ReadError::BufferTooSmall)
+        );
+
+        // Partial reads, crossing part boundaries.
+        let mut buf = [0; MAX_VALUE_SIZE + 2];
+        for offset in [0, 1, MAX_VALUE_SIZE - 1, MAX_VALUE_SIZE, BIG - 3, BIG, BIG + 1] {
+            let n = rtx.read_blob_at(b"b", offset, &mut buf).await.unwrap();
+            assert_eq!(&buf[..n], &big[offset.min(BIG)..(offset + buf.len()).min(BIG)]);
+        }
+        let n = rtx.read_blob_at(b"a", 1, &mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"234");
+        drop(rtx);
+
+        let (entries, count) = read_all(&db).await;
+        assert_eq!(
+            entries,
+            [
+                (b"a".to_vec(), b"1234".to_vec()),
+                (b"b".to_vec(), big.clone()),
+                (b"c".to_vec(), b"5678".to_vec()),
+                (b"d".to_vec(), b"".to_vec()),
+            ]
+        );
+        // Header and 4 parts for "b".
+        assert_eq!(count, 8);
+
+        // Reads within the write transaction see the pending blobs.
+        let big2 = value(BIG, 1);
+        let mut wtx = db.write_transaction().await;
+        wtx.write_blob(b"e", &big2).await.unwrap();
+        let mut buf = [0; BIG];
+        assert_eq!(wtx.read_blob(b"e", &mut buf).await, Ok(BIG));
+        assert_eq!(&buf[..], &big2[..]);
+        assert_eq!(wtx.read_blob_at(b"e", 2, &mut buf[..2]).await, Ok(2));
+        assert_eq!(&buf[..2], &big2[2..4]);
+        assert_eq!(wtx.blob_len(b"b").await, Ok(BIG));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_blob_overwrite() {
+        let mut f = MemFlash::new();
+        let db = Database::<_, NoopRawMutex>::new(&mut f, blob_config());
+        db.format().await.unwrap();
+
+        // (value, record count)
+        let steps = [
+            (value(BIG, 0), 5),
+            (value(BIG - MAX_VALUE_SIZE, 1), 4),
+            (value(MAX_VALUE_SIZE, 2), 1),
+            (value(MAX_VALUE_SIZE + 1, 3), 3),
+            (value(BIG, 4), 5),
+            (value(0, 5), 1),
+        ];
+        for (v, want_count) in steps {
+            let mut wtx = db.write_transaction().await;
+            wtx.write_blob(b"foo", &v).await.unwrap();
+            wtx.commit().await.unwrap();
+
+            check_blob(&db, b"foo", &v).await;
+            let (entries, count) = read_all(&db).await;
+            assert_eq!(entries, [(b"foo".to_vec(), v)]);
+            assert_eq!(count, want_count);
+        }
+
+        for v in [value(BIG, 6), value(1, 7)] {
+            let mut wtx = db.write_transaction().await;
+            wtx.write_blob(b"foo", &v).await.unwrap();
+            wtx.commit().await.unwrap();
+
+            let mut wtx = db.write_transaction().await;
+            wtx.delete_blob(b"foo").await.unwrap();
+            wtx.delete_blob(b"zzz").await.unwrap();
+            wtx.commit().await.unwrap();
+
+            assert_eq!(
+                db.read_transaction().await.blob_len(b"foo").await,
+                Err(ReadError::KeyNotFound)
+            );
+            assert_eq!(read_all(&db).await, (vec![], 0));
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_blob_reserved_keys() {
+        let mut f = MemFlash::new();
+        let db = Database::<_, NoopRawMutex>::new(&mut f, blob_config());
+        db.format().await.unwrap();
+
+        let big = value(BIG, 0);
+        let mut wtx = db.write_transaction().await;
+        wtx.write_blob(b"foo", &big).await.unwrap();
+        wtx.commit().await.unwrap();
+
+        // Keys shaped like the records of a blob, whether there's one or not.
+        let mut wtx = db.write_transaction().await;
+        for key in [
+            &part_key(b"foo", 0)[..],
+            &part_key(b"foo", 2)[..],
+            b"bar\0\0\0",
+            b"\0xy",
+        ] {
+            assert_eq!(wtx.write(key, b"x").await, Err(WriteError::ReservedKey));
+            assert_eq!(wtx.delete(key).await, Err(WriteError::ReservedKey));
+            assert_eq!(wtx.begin_value(key, 1).await.err(), Some(WriteError::ReservedKey));
+            assert_eq!(wtx.write_blob(key, b"x").await, Err(WriteError::ReservedKey));
+            assert_eq!(wtx.delete_blob(key).await, Err(WriteError::ReservedKey));
+        }
+
+        // Other keys with zero bytes are fine.
+        wtx.write(b"\0", b"x").await.unwrap();
+        wtx.write(b"\0\0", b"x").await.unwrap();
+        wtx.write(b"bar\0zzz", b"1234").await.unwrap();
+        wtx.commit().await.unwrap();
+
+        check_blob(&db, b"foo", &big).await;
+        let (entries, _) = read_all(&db).await;
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[3], (b"foo".to_vec(), big.clone()));
+
+        // Without blobs, no keys are reserved.
+        drop(db);
+        let db = Database::<_, NoopRawMutex>::new(&mut f, Config::default());
+        let mut wtx = db.write_transaction().await;
+        wtx.write(b"bar\0\0\0", &(BIG as u32).to_le_bytes()).await.unwrap();
+        wtx.delete(&part_key(b"foo", 2)).await.unwrap();
+        wtx.commit().await.unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_blob_delete_range() {
+        let mut f = MemFlash::new();
+        let db = Database::<_, NoopRawMutex>::new(&mut f, blob_config());
+        db.format().await.unwrap();
+
+        let big = value(BIG, 0);
+        let mut wtx = db.write_transaction().await;
+        wtx.write(b"a", b"1234").await.unwrap();
+        wtx.write_blob(b"b", &big).await.unwrap();
+        wtx.write(b"c", b"5678").await.unwrap();
+        wtx.commit().await.unwrap();
+
+        // Ranges with a bound between the records of the blob.
+        let header = part_key(b"b", 0);
+        let part = part_key(b"b", 2);
+        let last = part_key(b"b", MAX_PART_COUNT as u16);
+        let mut wtx = db.write_transaction().await;
+        assert_eq!(
+            wtx.delete_range(&b"a"[..]..&part[..]).await,
+            Err(WriteError::ReservedKey)
+        );
+        assert_eq!(
+            wtx.delete_range(&b"a"[..]..=&header[..]).await,
+            Err(WriteError::ReservedKey)
+        );
+        assert_eq!(
+            wtx.delete_range(&part[..]..&b"c"[..]).await,
+            Err(WriteError::ReservedKey)
+        );
+        assert_eq!(wtx.delete_range(&b"b\0\x01"[..]..).await, Err(WriteError::ReservedKey));
+        assert_eq!(
+            wtx.delete_range((Bound::Excluded(&header[..]), Bound::Unbounded)).await,
+            Err(WriteError::ReservedKey)
+        );
+        assert_eq!(wtx.delete_range(..&last[..]).await, Err(WriteError::ReservedKey));
+        assert_eq!(wtx.delete_prefix(&part[..3]).await, Err(WriteError::ReservedKey));
+        wtx.commit().await.unwrap();
+        check_blob(&db, b"b", &big).await;
+
+        // Ranges around it.
+        let mut wtx = db.write_transaction().await;
+        wtx.delete_range(&b"a"[..]..&header[..]).await.unwrap();
+        wtx.commit().await.unwrap();
+        check_blob(&db, b"b", &big).await;
+        assert_eq!(read_all(&db).await.0.len(), 2);
+
+        let mut wtx = db.write_transaction().await;
+        wtx.delete_range((Bound::Excluded(&last[..]), Bound::Unbounded))
+            .await
+            .unwrap();
+        wtx.commit().await.unwrap();
+        check_blob(&db, b"b", &big).await;
+        assert_eq!(read_all(&db).await, (vec![(b"b".to_vec(), big.clone())], 5));
+
+        let mut wtx = db.write_transaction().await;
+        wtx.delete_prefix(b"b").await.unwrap();
+        wtx.commit().await.unwrap();
+        assert_eq!(read_all(&db).await, (vec![], 0));
+
+        // Without blobs, ranges aren't checked.
+        drop(db);
+        let db = Database::<_, NoopRawMutex>::new(&mut f, Config::default());
+        let mut wtx = db.write_transaction().await;
+        wtx.delete_range(&b"a"[..]..&part[..]).await.unwrap();
+        wtx.commit().await.unwrap();
+    }
+
+    #[cfg(feature = "unsorted-writes")]
+    #[test_log::test(tokio::test)]
+    async fn test_blob_overwrite_unsorted() {
+        let mut f = MemFlash::new();
+        let config = Config {
+            unsorted_writes: true,
+            ..blob_config()
+        };
+        let db = Database::<_, NoopRawMutex>::new(&mut f, config);
+        db.format().await.unwrap();
+
+        // Overwrites within the same transaction delete the parts left by the previous ones.
+        let small = value(MAX_VALUE_SIZE + 1, 1);
+        let mut wtx = db.write_transaction().await;
+        wtx.write_blob(b"foo", &value(BIG, 0)).await.unwrap();
+        wtx.write_blob(b"bar", b"1234").await.unwrap();
+        wtx.write_blob(b"foo", &small).await.unwrap();
+        let mut buf = [0; BIG];
+        assert_eq!(wtx.read_blob(b"foo", &mut buf).await, Ok(small.len()));
+        assert_eq!(&buf[..small.len()], &small[..]);
+        wtx.commit().await.unwrap();
+
+        check_blob(&db, b"foo", &small).await;
+        assert_eq!(
+            read_all(&db).await,
+            (vec![(b"bar".to_vec(), b"1234".to_vec()), (b"foo".to_vec(), small)], 4)
+        );
+
+        let mut wtx = db.write_transaction().await;
+        wtx.write_blob(b"foo", &value(BIG, 2)).await.unwrap();
+        wtx.write_blob(b"foo", b"x").await.unwrap();
+        wtx.commit().await.unwrap();
+        assert_eq!(
+            read_all(&db).await,
+            (
+                vec![(b"bar".to_vec(), b"1234".to_vec()), (b"foo".to_vec(), b"x".to_vec())],
+                2
+            )
+        );
+    }
+
+    #[test_log::test(tokio::test)]
+    #[should_panic(expected = "blobs are disabled")]
+    async fn test_blob_disabled() {
+        let mut f = MemFlash::new();
+        let db = Database::<_, NoopRawMutex>::new(&mut f, Config::default());
+        db.format().await.unwrap();
+
+        let mut wtx = db.write_transaction().await;
+        let _ = wtx.write_blob(b"foo", b"x").await;
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_blob_cursor_buffer_too_small() {
+        let mut f = MemFlash::new();
+        let db = Database::<_, NoopRawMutex>::new(&mut f, blob_config());
+        db.format().await.unwrap();
+
+        let big = value(BIG, 0);
+        let mut wtx = db.write_transaction().await;
+        wtx.write_blob(b"foo", &big).await.unwrap();
+        wtx.commit().await.unwrap();
+
+        let rtx = db.read_transaction().await;
+        let mut cursor = rtx.read_all().await.unwrap();
+        let mut kbuf = [0; MAX_KEY_SIZE];
+        let mut vbuf = [0; BIG];
+        assert_eq!(
+            cursor.next_blob(&mut kbuf[..2], &mut vbuf).await,
+            Err(CursorError::KeyBufferTooSmall)
+        );
+        assert_eq!(
+            cursor.next_blob(&mut kbuf, &mut vbuf[..BIG - 1]).await,
+            Err(CursorError::ValueBufferTooSmall)
+        );
+        assert_eq!(cursor.next_blob(&mut kbuf, &mut vbuf).await, Ok(Some((3, BIG))));
+        assert_eq!(&kbuf[..3], b"foo");
+        assert_eq!(&vbuf[..], &big[..]);
+        assert_eq!(cursor.next_blob(&mut kbuf, &mut vbuf).await, Ok(None));
+
+        let mut cursor = rtx.read_all_rev().await.unwrap();
+        assert_eq!(
+            cursor.next_blob(&mut kbuf, &mut vbuf[..BIG - 1]).await,
+            Err(CursorError::ValueBufferTooSmall)
+        );
+        assert_eq!(cursor.next_blob(&mut kbuf, &mut vbuf).await, Ok(Some((3, BIG))));
+        assert_eq!(&kbuf[..3], b"foo");
+        assert_eq!(&vbuf[..], &big[..]);
+        assert_eq!(cursor.next_blob(&mut kbuf, &mut vbuf).await, Ok(None));
+    }
+}
@@ -0,0 +1,206 @@
+//! Encryption and authentication of the stored data.
+//!
+//! With the `encryption` Cargo feature, `ekv` can encrypt and authenticate everything it stores
+//! in flash, using an AEAD (Authenticated Encryption with Associated Data) cipher such as AES-GCM
+//! or ChaCha20-Poly1305. `ekv` doesn't implement any cipher itself. Implement [`Cipher`] with
+//! the crypto library or hardware accelerator of your choice, and set it in
+//! [`Config::cipher`](crate::Config::cipher).
+//!
+//! - Chunk data is encrypted. This covers all keys and values, and the file table in the meta page.
+//! - Page headers and chunk headers are authenticated, but not encrypted. They contain only
+//!   the database structure (page counts, sequence numbers, lengths), not user data.
+//! - Every page header and chunk is encrypted with a different nonce, built from the page ID,
+//!   a generation number increased on every page write, and the offset within the page.
+//!   Generations are stored in the headers, and the highest one on flash is found again when mounting
+//!   or formatting, so nonces are never reused.
+//! - Authentication failures are reported as `Corrupted` errors, same as CRC failures. Mounting
+//!   with the wrong key fails with [`MountError::Corrupted`](crate::MountError::Corrupted).
+//!
+//! Encrypted pages are bigger than unencrypted ones: headers grow by the tag and generation, and
+//! each page writer needs a RAM buffer of [`MAX_CHUNK_SIZE`](crate::config::MAX_CHUNK_SIZE),
+//! because chunks can only be encrypted once they're complete.
+//!
+//! Enabling the feature changes the on-disk format even if no cipher is set, in which case data is
+//! stored unencrypted and unauthenticated.
+
+use core::fmt;
+
+use crate::flash::{Flash, PageID};
+use crate::page::PageFlash;
+
+/// Nonce size for the [`Cipher`], in bytes.
+pub const NONCE_SIZE: usize = 12;
+
+/// Authentication tag size for the [`Cipher`], in bytes.
+pub const TAG_SIZE: usize = 16;
+
+/// Error returned by [`Cipher::decrypt`] when authentication fails.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DecryptError;
+
+/// AEAD cipher used to encrypt the database.
+///
+/// It must implement an AEAD with 96-bit nonces and 128-bit tags, such as AES-GCM or
+/// ChaCha20-Poly1305, with a key that's the same every time the database is mounted.
+///
+/// `ekv` guarantees each nonce is used only once for a given key.
+pub trait Cipher: Sync {
+    /// Encrypt `data` in place, and return the tag authenticating it together with `aad`.
+    fn encrypt(&self, nonce: &[u8; NONCE_SIZE], aad: &[u8], data: &mut [u8]) -> [u8; TAG_SIZE];
+
+    /// Check `tag` authenticates `data` and `aad`, and decrypt `data` in place.
+    ///
+    /// If the check fails, return [`DecryptError`]. The contents of `data` are then unspecified.
+    fn decrypt(
+        &self,
+        nonce: &[u8; NONCE_SIZE],
+        aad: &[u8],
+        data: &mut [u8],
+        tag: &[u8; TAG_SIZE],
+    ) -> Result<(), DecryptError>;
+}
+
+// Allow `Config` to derive these traits. Ciphers are compared by identity.
+
+impl fmt::Debug for dyn Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Cipher")
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for dyn Cipher {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "Cipher")
+    }
+}
+
+impl PartialEq for dyn Cipher {
+    fn eq(&self, other: &Self) -> bool {
+        core::ptr::addr_eq(self, other)
+    }
+}
+
+impl Eq for dyn Cipher {}
+
+/// Build the nonce for the page header (`offset == 0`) or chunk at `offset`.
+pub(crate) fn nonce(page_id: PageID, generation: u32, offset: usize) -> [u8; NONCE_SIZE] {
+    let mut nonce = [0; NONCE_SIZE];
+    nonce[0..4].copy_from_slice(&(page_id.index() as u32).to_le_bytes());
+    nonce[4..8].copy_from_slice(&generation.to_le_bytes());
+    nonce[8..12].copy_from_slice(&(offset as u32).to_le_bytes());
+    nonce
+}
+
+/// Generations higher than this are ignored when looking for the highest one on flash.
+///
+/// No flash survives 2^31 page writes, so they can only come from corrupted or deliberately
+/// manipulated flash. Ignoring them ensures the generation counter never wraps around.
+pub(crate) const MAX_GENERATION: u32 = u32::MAX / 2;
+
+/// Flash, plus the cipher and the generation counter.
+pub(crate) struct CipherFlash<F> {
+    pub(crate) flash: F,
+    pub(crate) cipher: Option<&'static dyn Cipher>,
+    /// Generation for the next page write. Higher than all the generations on flash.
+    pub(crate) generation: u32,
+}
+
+impl<F> CipherFlash<F> {
+    pub(crate) const fn new(flash: F, cipher: Option<&'static dyn Cipher>) -> Self {
+        Self {
+            flash,
+            cipher,
+            generation: 0,
+        }
+    }
+}
+
+impl<F: Flash> Flash for CipherFlash<F> {
+    type Error = F::Error;
+    fn page_count(&self) -> usize {
+        self.flash.page_count()
+    }
+    async fn erase(&mut self, page_id: PageID) -> Result<(), Self::Error> {
+        self.flash.erase(page_id).await
+    }
+    async fn read(&mut self, page_id: PageID, offset: usize, data: &mut [u8]) -> Result<(), Self::Error> {
+        self.flash.read(page_id, offset, data).await
+    }
+    async fn write(&mut self, page_id: PageID, offset: usize, data: &[u8]) -> Result<(), Self::Error> {
+        self.flash.write(page_id, offset, data).await
+    }
+}
+
+impl<F: Flash> PageFlash for CipherFlash<F> {
+    fn cipher(&self) -> Option<&'static dyn Cipher> {
+        self.cipher
+    }
+
+    fn next_generation(&mut self) -> u32 {
+        let generation = self.generation;
+        self.generation += 1;
+        generation
+    }
+}
+
+/// Toy cipher for tests. NOT SECURE.
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    pub(crate) struct TestCipher {
+        pub(crate) key: u32,
+    }
+
+    pub(crate) static CIPHER: TestCipher = TestCipher { key: 0x12345678 };
+
+    impl TestCipher {
+        fn hash(&self, nonce: &[u8; NONCE_SIZE], seed: u32, data: &[u8]) -> u32 {
+            // FNV-1a
+            let mut hash: u32 = 0x811c9dc5 ^ self.key ^ seed;
+            for &b in nonce.iter().chain(data) {
+                hash ^= b as u32;
+                hash = hash.wrapping_mul(0x01000193);
+            }
+            hash
+        }
+
+        fn keystream(&self, nonce: &[u8; NONCE_SIZE], data: &mut [u8]) {
+            for (i, b) in data.iter_mut().enumerate() {
+                *b ^= self.hash(nonce, 0x8000_0000 | i as u32, &[]) as u8;
+            }
+        }
+
+        fn tag(&self, nonce: &[u8; NONCE_SIZE], aad: &[u8], data: &[u8]) -> [u8; TAG_SIZE] {
+            let mut tag = [0; TAG_SIZE];
+            for (i, t) in tag.chunks_mut(4).enumerate() {
+                let h = self.hash(nonce, i as u32, aad) ^ self.hash(nonce, 0x100 | i as u32, data);
+                t.copy_from_slice(&h.to_le_bytes());
+            }
+            tag
+        }
+    }
+
+    impl Cipher for TestCipher {
+        fn encrypt(&self, nonce: &[u8; NONCE_SIZE], aad: &[u8], data: &mut [u8]) -> [u8; TAG_SIZE] {
+            self.keystream(nonce, data);
+            self.tag(nonce, aad, data)
+        }
+
+        fn decrypt(
+            &self,
+            nonce: &[u8; NONCE_SIZE],
+            aad: &[u8],
+            data: &mut [u8],
+            tag: &[u8; TAG_SIZE],
+        ) -> Result<(), DecryptError> {
+            if self.tag(nonce, aad, data) != *tag {
+                return Err(DecryptError);
+            }
+            self.keystream(nonce, data);
+            Ok(())
+        }
+    }
+}
@@ -19,14 +19,18 @@
 //! Changing ANY of these configuration settings changes the on-disk format of the database. If you change
 //! them, you won't be able to read databases written with a different configuration.
 //!
-//! Currently, mounting doesn't check the on-disk database uses the same configuration. Mounting a database
-//! with a different configuration might succeed and then cause fun errors later, perhaps very rarely.
+//! The settings that affect the on-disk format are fingerprinted and stored in the meta page when formatting.
+//! Mounting a database written with a different configuration fails with
+//! [`MountError::ConfigMismatch`](crate::MountError::ConfigMismatch). Note that with a different `PAGE_SIZE`,
+//! `CRC` or `encryption` setting the meta page might not be found at all, in which case mounting fails with
+//! [`MountError::Corrupted`](crate::MountError::Corrupted) instead.
+//!
 //! Always remember to format your flash device every time you change them.
 
 use core::mem::size_of;
 
 use crate::file::{DataHeader, MetaHeader, PAGE_MAX_PAYLOAD_SIZE};
-use crate::record::RecordHeader;
+use crate::record::{RecordHeader, RecordKind};
 
 mod raw {
     #![allow(unused)]
@@ -49,10 +53,11 @@ pub const CRC: bool = raw::CRC;
 /// Flash alignment for reads and writes.
 ///
 /// All flash `read` and `write` operations will be done with address and size
-/// multiples of this value. Useful when the flash supports writing with 16-bit
-/// or 32-bit word granularity.
+/// multiples of this value. Useful when the flash can only be written with
+/// 16-bit, 32-bit or bigger word granularity. Headers are padded to this value,
+/// so higher values waste a bit more flash space.
 ///
-/// Supported values: 1, 2 or 4.
+/// Supported values: 1, 2, 4, 8, 16 or 32.
 ///
 /// Default: 4
 pub const ALIGN: usize = raw::ALIGN;
@@ -142,6 +147,17 @@ pub const BRANCHING_FACTOR: usize = raw::BRANCHING_FACTOR;
 pub(crate) const LEVEL_COUNT: usize = MAX_PAGE_COUNT.ilog(BRANCHING_FACTOR) as usize;
 pub(crate) const FILE_COUNT: usize = BRANCHING_FACTOR * LEVEL_COUNT + 1;
 
+// Max number of sorted runs a write transaction with unsorted writes can have. Runs are merged
+// in tiers of BRANCHING_FACTOR, like the file tree, so this is only reached with huge transactions.
+pub(crate) const RUN_COUNT: usize = (BRANCHING_FACTOR - 1) * LEVEL_COUNT + 1;
+// Scratch files hold the sorted runs, plus one extra used as destination when merging them.
+// They only exist in RAM, they're never written to the meta page. Only needed for unsorted writes.
+pub(crate) const SCRATCH_FILE_COUNT: usize = match cfg!(feature = "unsorted-writes") {
+    true => RUN_COUNT + 1,
+    false => 0,
+};
+pub(crate) const ALL_FILE_COUNT: usize = FILE_COUNT + SCRATCH_FILE_COUNT;
+
 // ======== Key-value database parameters
 
 /// Maximum supported key size.
@@ -159,7 +175,10 @@ pub const MAX_KEY_SIZE: usize = raw::MAX_KEY_SIZE;
 pub const MAX_VALUE_SIZE: usize = raw::MAX_VALUE_SIZE;
 
 pub(crate) const KEY_SIZE_BITS: u32 = (MAX_KEY_SIZE + 1).next_power_of_two().ilog2();
-pub(crate) const VALUE_SIZE_BITS: u32 = (MAX_VALUE_SIZE + 1).next_power_of_two().ilog2();
+// At least 2 bits, since tombstones use value lengths up to 2 to tell their kind.
+pub(crate) const VALUE_SIZE_BITS: u32 = (if MAX_VALUE_SIZE > 2 { MAX_VALUE_SIZE } else { 2 } + 1)
+    .next_power_of_two()
+    .ilog2();
 const RECORD_HEADER_BITS: u32 = 1 + KEY_SIZE_BITS + VALUE_SIZE_BITS;
 pub(crate) const RECORD_HEADER_SIZE: usize = (RECORD_HEADER_BITS as usize + 7) / 8;
 
@@ -179,20 +198,77 @@ pub const SCRATCH_PAGE_COUNT: usize = raw::SCRATCH_PAGE_COUNT;
 // There's no point in leaving more pages.
 pub(crate) const MIN_FREE_PAGE_COUNT_COMPACT: usize = 1;
 
-const MAX_RECORD_SIZE: usize = RecordHeader {
-    is_delete: false,
-    key_len: MAX_KEY_SIZE,
-    value_len: MAX_VALUE_SIZE,
-}
-.record_size();
+// Pages written to the destination file by a single `Database::compact_step` call, before stopping.
+// It can go over by the size of the record it's merging, and the range tombstones around it.
+pub(crate) const COMPACT_STEP_PAGE_COUNT: usize = 4;
+
+// Size of the biggest range start or range end tombstone.
+pub(crate) const MAX_RANGE_TOMBSTONE_SIZE: usize =
+    RecordHeader::tombstone(RecordKind::RangeEnd, MAX_KEY_SIZE).record_size();
+
+// Size of the biggest record. It's a value of MAX_VALUE_SIZE, or a range end tombstone if values are smaller than it.
+pub(crate) const MAX_RECORD_SIZE: usize = {
+    let value = RecordHeader {
+        kind: RecordKind::Value,
+        key_len: MAX_KEY_SIZE,
+        value_len: MAX_VALUE_SIZE,
+    }
+    .record_size();
+    if value > MAX_RANGE_TOMBSTONE_SIZE {
+        value
+    } else {
+        MAX_RANGE_TOMBSTONE_SIZE
+    }
+};
 
 // Compaction will be triggered when there's this amount of free pages left or less.
-// The calculation here guarantees progressive compaction will never get stuck.
+// The calculation here guarantees progressive compaction will never get stuck: a compaction step
+// writes at most a record and a range tombstone before it, or two range tombstones leaving a range
+// open and then the record ending it.
 pub(crate) const MIN_FREE_PAGE_COUNT: usize = 1
     + SCRATCH_PAGE_COUNT
     + MIN_FREE_PAGE_COUNT_COMPACT
     + BRANCHING_FACTOR
-    + (MAX_RECORD_SIZE + PAGE_MAX_PAYLOAD_SIZE - 1) / PAGE_MAX_PAYLOAD_SIZE; // ceil(MAX_RECORD_SIZE/PAGE_MAX_PAYLOAD_SIZE)
+    + (MAX_RECORD_SIZE + 2 * MAX_RANGE_TOMBSTONE_SIZE + PAGE_MAX_PAYLOAD_SIZE - 1) / PAGE_MAX_PAYLOAD_SIZE; // ceil((MAX_RECORD_SIZE+2*MAX_RANGE_TOMBSTONE_SIZE)/PAGE_MAX_PAYLOAD_SIZE)
+
+// ======== On-disk format
+
+// Version of the on-disk format, stored in the meta page. Bump it on incompatible changes to the
+// format of a released version.
+pub(crate) const FORMAT_VERSION: u32 = 1;
+
+// Fingerprint of the settings that affect the on-disk format, stored in the meta page.
+// SCRATCH_PAGE_COUNT is left out on purpose: it only affects page allocation, so it can be
+// changed without reformatting.
+pub(crate) const CONFIG_FINGERPRINT: u32 = {
+    let params = [
+        CRC as u32,
+        cfg!(feature = "encryption") as u32,
+        ALIGN as u32,
+        PAGE_SIZE as u32,
+        MAX_PAGE_COUNT as u32,
+        ERASE_VALUE as u32,
+        MAX_CHUNK_SIZE as u32,
+        BRANCHING_FACTOR as u32,
+        MAX_KEY_SIZE as u32,
+        MAX_VALUE_SIZE as u32,
+    ];
+
+    // FNV-1a
+    let mut hash: u32 = 0x811c9dc5;
+    let mut i = 0;
+    while i < params.len() {
+        let bytes = params[i].to_le_bytes();
+        let mut j = 0;
+        while j < bytes.len() {
+            hash ^= bytes[j] as u32;
+            hash = hash.wrapping_mul(0x01000193);
+            j += 1;
+        }
+        i += 1;
+    }
+    hash
+};
 
 #[allow(clippy::assertions_on_constants)]
 const _CHECKS: () = {
@@ -200,10 +276,10 @@ const _CHECKS: () = {
 
     core::assert!(BRANCHING_FACTOR >= 2 && BRANCHING_FACTOR <= 4);
 
-    // Only align 1, 2, 4 is supported for now.
-    // We only align data. Header sizes are aligned to 4 but not 8.
-    // Supporting higher aligns would require aligning the headers as well.
-    core::assert!(ALIGN == 1 || ALIGN == 2 || ALIGN == 4);
+    // Headers are padded to ALIGN, so any power of two works in principle. Cap it to
+    // keep the padding waste reasonable.
+    core::assert!(ALIGN.is_power_of_two() && ALIGN <= 32);
+    core::assert!(PAGE_SIZE % ALIGN == 0);
 
     // assert MIN_FREE_PAGE_COUNT is reasonable.
     // If it's too big relative to the total flash size, we'll waste a lot of space!
@@ -218,6 +294,11 @@ const _CHECKS: () = {
     core::assert!(RECORD_HEADER_SIZE <= 4);
 
     core::assert!(MAX_CHUNK_SIZE % ALIGN == 0);
+
+    // Merging runs needs at least BRANCHING_FACTOR of them.
+    core::assert!(RUN_COUNT >= BRANCHING_FACTOR);
+    // File IDs are u8.
+    core::assert!(ALL_FILE_COUNT <= u8::MAX as _);
 };
 
 /// Dump the compile-time configuration to `log` or `defmt`.
@@ -238,6 +319,10 @@ pub fn dump() {
         size_of::<DataHeader>(),
         PAGE_MAX_PAYLOAD_SIZE
     );
+    debug!(
+        "format_version={}, config_fingerprint={:08x}",
+        FORMAT_VERSION, CONFIG_FINGERPRINT
+    );
     debug!("skiplist_len={}, skiplist_shift={}", SKIPLIST_LEN, SKIPLIST_SHIFT);
     debug!(
         "branching_factor={}, level_count={}, file_count={}",
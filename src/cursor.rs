@@ -4,59 +4,295 @@ use core::ops::Bound;
 use embassy_sync::blocking_mutex::raw::RawMutex;
 use heapless::Vec;
 
-use crate::config::{FILE_COUNT, MAX_KEY_SIZE, RECORD_HEADER_SIZE};
+use crate::config::{ALL_FILE_COUNT, FILE_COUNT, MAX_KEY_SIZE, MAX_VALUE_SIZE, RECORD_HEADER_SIZE, SCRATCH_FILE_COUNT};
 use crate::errors::{no_eof, CursorError, Error};
-use crate::file::{DehydratedFileReader, FileID, FileSearcher, SeekDirection};
+use crate::file::{DehydratedFileReader, FileID, FileSearcher, SeekDirection, PAGE_MAX_PAYLOAD_SIZE};
 use crate::flash::Flash;
 use crate::page::ReadError as PageReadError;
-use crate::record::{Inner, RecordHeader};
-use crate::Database;
+use crate::record::{Clip, Inner, RecordHeader, RecordKind};
+use crate::{blob, Database};
 
 /// Cursor for a range read.
 ///
-/// Returned by [`ReadTransaction::read_all()`](crate::ReadTransaction::read_all) and [`ReadTransaction::read_range()`](crate::ReadTransaction::read_range).
+/// Returned by [`ReadTransaction::read_all()`](crate::ReadTransaction::read_all), [`ReadTransaction::read_range()`](crate::ReadTransaction::read_range),
+/// [`WriteTransaction::read_all()`](crate::WriteTransaction::read_all) and [`WriteTransaction::read_range()`](crate::WriteTransaction::read_range),
+/// and their `_rev` counterparts, for reading in descending order. Also returned by [`ReadTransaction::read_prefix()`](crate::ReadTransaction::read_prefix)
+/// and [`WriteTransaction::read_prefix()`](crate::WriteTransaction::read_prefix).
 pub struct Cursor<'a, F: Flash + 'a, M: RawMutex + 'a> {
     db: &'a Database<F, M>,
-    upper_bound: Bound<&'a [u8]>,
+    /// Bound where the iteration starts. The lower bound, or the upper bound if `rev`.
+    start_bound: Bound<&'a [u8]>,
+    /// Bound where the iteration ends. The upper bound, or the lower bound if `rev`.
+    end_bound: Bound<&'a [u8]>,
+    /// Only keys starting with this are returned. Not supported if `rev`.
+    prefix: &'a [u8],
+    /// Whether the keys are returned in descending order.
+    rev: bool,
+    /// For each file, reader at the next record to return. If `rev`, the records are
+    /// returned from the last to the first, so each one is found by a new search.
     readers: [Option<DehydratedFileReader>; FILE_COUNT],
+    /// For each file, whether the keys before its next record are deleted by a range start. It's the last
+    /// record returned from the file, or the next one if `rev`. Kept after the file's reader is removed.
+    in_range: [bool; FILE_COUNT],
+    /// State for the scratch files, set if the cursor sees the pending writes of a write transaction.
+    scratch: Option<&'a mut ScratchReaders>,
+    /// Keys the sources of the in-progress compaction don't apply to.
+    clip: Option<Clip>,
+}
+
+/// Cursor state for the scratch files, which hold the sorted runs of a write transaction. Same as
+/// [`Cursor::readers`] and [`Cursor::in_range`], owned by the write transaction so that only its
+/// cursors pay for it.
+#[derive(Clone)]
+pub(crate) struct ScratchReaders {
+    readers: [Option<DehydratedFileReader>; SCRATCH_FILE_COUNT],
+    in_range: [bool; SCRATCH_FILE_COUNT],
+}
+
+impl ScratchReaders {
+    pub(crate) fn new() -> Self {
+        Self {
+            readers: core::array::from_fn(|_| None),
+            in_range: [false; SCRATCH_FILE_COUNT],
+        }
+    }
 }
 
 impl<'a, F: Flash + 'a, M: RawMutex + 'a> Cursor<'a, F, M> {
     pub(crate) async fn new(
         db: &'a Database<F, M>,
-        lower_bound: Bound<&[u8]>,
+        lower_bound: Bound<&'a [u8]>,
         upper_bound: Bound<&'a [u8]>,
+        prefix: &'a [u8],
+        scratch: Option<&'a mut ScratchReaders>,
+        rev: bool,
     ) -> Result<Self, Error<F::Error>> {
-        let inner = &mut *db.inner.lock().await;
+        assert!(!rev || prefix.is_empty());
+        let (start_bound, end_bound) = match rev {
+            false => (lower_bound, upper_bound),
+            true => (upper_bound, lower_bound),
+        };
+        let mut this = Self {
+            db,
+            start_bound,
+            end_bound,
+            prefix,
+            rev,
+            readers: core::array::from_fn(|_| None),
+            in_range: [false; FILE_COUNT],
+            scratch,
+            clip: None,
+        };
+        this.seek_bound(start_bound).await?;
+        Ok(this)
+    }
+
+    /// Move the cursor to `key`.
+    ///
+    /// The next entry returned is the first one with a key equal or greater than `key`, or equal or lower
+    /// for cursors returned by the `_rev` methods. The range of the cursor still applies: seeking to a key
+    /// before the start of the range moves the cursor to the start of the range, and seeking to a key
+    /// after its end makes the cursor return no more entries.
+    ///
+    /// The cursor can be moved both forward and backward. Moving a cursor forward by a few keys only reads
+    /// the records it skips. Otherwise, and always for cursors returned by the `_rev` methods, it does a new
+    /// search in each file, which costs as much as opening a new cursor.
+    pub async fn seek(&mut self, key: &[u8]) -> Result<(), CursorError<F::Error>> {
+        let before_start = match (self.start_bound, self.rev) {
+            (Bound::Included(b), false) => key < b,
+            (Bound::Excluded(b), false) => key <= b,
+            (Bound::Included(b), true) => key > b,
+            (Bound::Excluded(b), true) => key >= b,
+            (Bound::Unbounded, _) => false,
+        };
+        let bound = match before_start {
+            true => self.start_bound,
+            false => Bound::Included(key),
+        };
+        if before_start || self.rev || !self.seek_forward(key).await? {
+            self.seek_bound(bound).await?;
+        }
+        Ok(())
+    }
+
+    /// Move each file forward from its next record to the first key equal or greater than `key`. Files with
+    /// more than a page of records to skip are searched instead.
+    ///
+    /// Returns false without moving the cursor if `key` might be behind it, which is when it's not after
+    /// the next record of any file.
+    async fn seek_forward(&mut self, key: &[u8]) -> Result<bool, Error<F::Error>> {
+        let inner = &mut *self.db.inner.lock().await;
+        inner.files.remount_if_dirty(&mut inner.readers[0]).await?;
+        let m = &mut inner.files;
+
+        let mut key_buf = [0u8; MAX_KEY_SIZE];
+        let mut header = [0; RECORD_HEADER_SIZE];
+
+        // No file has keys between the last entry returned and the lowest next record, so `key` is ahead
+        // of the cursor if it's after any next record.
+        let mut ahead = false;
+        for i in 0..self.file_count() {
+            if let Some(r) = self.reader(i) {
+                let mut r = m.read_rehydrated(&mut inner.readers[0], r).await?;
+
+                // read header
+                match r.read(m, &mut header).await {
+                    Ok(()) => {}
+                    Err(PageReadError::Eof) => continue,
+                    Err(e) => return Err(no_eof(e)),
+                };
+                let header = RecordHeader::decode(header)?;
+
+                // Read key
+                let got_key = &mut key_buf[..header.key_len];
+                r.read(m, got_key).await.map_err(no_eof)?;
+
+                if *got_key < *key {
+                    ahead = true;
+                    break;
+                }
+            }
+        }
+        if !ahead {
+            return Ok(false);
+        }
+
+        let pending = self.pending();
+        for i in 0..self.file_count() {
+            let Some(r) = self.reader(i) else { continue };
+            let m = &mut inner.files;
+            let mut r = m.read_rehydrated(&mut inner.readers[0], r).await?;
+
+            // Linear search, until the first key not before `key` or a page of records skipped.
+            let mut skipped = 0;
+            let far = loop {
+                let dehydrated = r.dehydrate();
+
+                // read header
+                match r.read(m, &mut header).await {
+                    Ok(()) => {}
+                    Err(PageReadError::Eof) => {
+                        // reached EOF, remove this file.
+                        *self.reader(i) = None;
+                        break false;
+                    }
+                    Err(e) => return Err(no_eof(e)),
+                };
+                let header = RecordHeader::decode(header)?;
+
+                // Read key
+                let got_key = &mut key_buf[..header.key_len];
+                r.read(m, got_key).await.map_err(no_eof)?;
+
+                if *got_key >= *key {
+                    *self.reader(i) = Some(dehydrated);
+                    break false;
+                }
+                *self.in_range(i) = header.kind == RecordKind::RangeStart;
+
+                r.skip(m, header.value_len).await.map_err(no_eof)?;
+                skipped += RECORD_HEADER_SIZE + header.key_len + header.value_len;
+                if skipped > PAGE_MAX_PAYLOAD_SIZE {
+                    break true;
+                }
+            };
+
+            if far {
+                let (reader, in_range) = inner.search_lower_bound_file(i as FileID, pending, key, true).await?;
+                *self.reader(i) = reader;
+                *self.in_range(i) = in_range;
+            }
+        }
+        Ok(true)
+    }
+
+    /// Seek each file to the first key matching `bound` as a lower bound, or the last key
+    /// matching it as an upper bound if rev.
+    async fn seek_bound(&mut self, bound: Bound<&[u8]>) -> Result<(), Error<F::Error>> {
+        let inner = &mut *self.db.inner.lock().await;
         inner.files.remount_if_dirty(&mut inner.readers[0]).await?;
+        self.clip = inner.compact_clip().await?;
 
-        // Open and seek each file to the first key matching lower_bound.
-        let mut readers: Vec<Option<DehydratedFileReader>, FILE_COUNT> = Vec::new();
-        for i in 0..FILE_COUNT {
+        let pending = self.pending();
+        for i in 0..self.file_count() {
             let file_id = i as FileID;
-            let r = match lower_bound {
+            let (reader, in_range) = match bound {
+                _ if self.rev => (inner.search_upper_bound_file(file_id, pending, bound).await?, false),
                 Bound::Excluded(k) | Bound::Included(k) => {
-                    let included = matches!(lower_bound, Bound::Included(_));
-                    inner.search_lower_bound_file(file_id, k, included).await?
+                    let included = matches!(bound, Bound::Included(_));
+                    inner.search_lower_bound_file(file_id, pending, k, included).await?
                 }
-                Bound::Unbounded => Some(inner.files.read(&mut inner.readers[0], file_id).dehydrate()),
+                Bound::Unbounded => match pending {
+                    true => (
+                        Some(inner.files.read_pending(&mut inner.readers[0], file_id).dehydrate()),
+                        false,
+                    ),
+                    false => (
+                        Some(inner.files.read(&mut inner.readers[0], file_id).dehydrate()),
+                        false,
+                    ),
+                },
             };
-            let _ = readers.push(r);
+            *self.reader(i) = reader;
+            *self.in_range(i) = in_range;
         }
-        let Ok(readers) = readers.into_array() else {
-            unreachable!()
-        };
+        Ok(())
+    }
 
-        Ok(Self {
-            db,
-            upper_bound,
-            readers,
-        })
+    /// Whether the cursor sees the pending writes of a write transaction.
+    fn pending(&self) -> bool {
+        self.scratch.is_some()
+    }
+
+    /// Amount of files read. Scratch files hold the sorted runs of a write transaction, so they're only
+    /// read if pending.
+    fn file_count(&self) -> usize {
+        match self.pending() {
+            true => ALL_FILE_COUNT,
+            false => FILE_COUNT,
+        }
+    }
+
+    /// Reader at the next record to return from file `i`, see [`readers`](Self::readers).
+    fn reader(&mut self, i: usize) -> &mut Option<DehydratedFileReader> {
+        match i.checked_sub(FILE_COUNT) {
+            None => &mut self.readers[i],
+            Some(j) => &mut unwrap!(self.scratch.as_deref_mut()).readers[j],
+        }
+    }
+
+    /// Whether the keys before the next record of file `i` are deleted by a range start, see
+    /// [`in_range`](Self::in_range).
+    fn in_range(&mut self, i: usize) -> &mut bool {
+        match i.checked_sub(FILE_COUNT) {
+            None => &mut self.in_range[i],
+            Some(j) => &mut unwrap!(self.scratch.as_deref_mut()).in_range[j],
+        }
+    }
+
+    /// Move the cursor back to a position saved from `readers`, `in_range` and `scratch`.
+    fn restore(
+        &mut self,
+        readers: [Option<DehydratedFileReader>; FILE_COUNT],
+        in_range: [bool; FILE_COUNT],
+        scratch: Option<ScratchReaders>,
+    ) {
+        self.readers = readers;
+        self.in_range = in_range;
+        if let (Some(s), Some(saved)) = (self.scratch.as_deref_mut(), scratch) {
+            *s = saved;
+        }
+    }
+
+    /// Whether the records in file `i` don't apply to `key`, see [`Clip`].
+    fn clipped(&self, i: usize, key: &[u8]) -> bool {
+        self.clip.as_ref().is_some_and(|clip| clip.hides(i as FileID, key))
     }
 
     /// Get the next key/value entry.
     ///
-    /// If the cursor has not reached the end, the next entry in lexicographically ascending order is read into the start of the `key` and `value` buffers.
+    /// If the cursor has not reached the end, the next entry in lexicographically ascending order
+    /// (descending for cursors returned by the `_rev` methods) is read into the start of the `key` and `value` buffers.
     /// The respective lengths are returned: `Ok(Some((key_len, value_len)))`.
     ///
     /// If the cursor has reached the end of the iteration, `Ok(None)` is returned.
@@ -65,6 +301,27 @@ impl<'a, F: Flash + 'a, M: RawMutex + 'a> Cursor<'a, F, M> {
         key: &mut [u8],
         value: &mut [u8],
     ) -> Result<Option<(usize, usize)>, CursorError<F::Error>> {
+        self.next_inner(key, Some(value)).await
+    }
+
+    /// Get the next key, without reading its value.
+    ///
+    /// Same as [`next`](Self::next), except the value is skipped instead of read, which is faster for big values.
+    /// The lengths of the key and the value are returned: `Ok(Some((key_len, value_len)))`.
+    pub async fn next_key(&mut self, key: &mut [u8]) -> Result<Option<(usize, usize)>, CursorError<F::Error>> {
+        self.next_inner(key, None).await
+    }
+
+    /// Get the next entry. The value is read into `value` if it's set, otherwise it's skipped.
+    async fn next_inner(
+        &mut self,
+        key: &mut [u8],
+        mut value: Option<&mut [u8]>,
+    ) -> Result<Option<(usize, usize)>, CursorError<F::Error>> {
+        if self.rev {
+            return self.next_rev(key, value).await;
+        }
+
         let inner = &mut *self.db.inner.lock().await;
         let m = &mut inner.files;
 
@@ -73,12 +330,12 @@ impl<'a, F: Flash + 'a, M: RawMutex + 'a> Cursor<'a, F, M> {
 
         // loop to retry if found record is deleted.
         loop {
-            let mut is_lowest = [false; FILE_COUNT];
+            let mut is_lowest = [false; ALL_FILE_COUNT];
             let mut lowest_key: Vec<u8, MAX_KEY_SIZE> = Vec::new();
             let mut found = false;
 
-            for i in 0..FILE_COUNT {
-                if let Some(r) = &self.readers[i] {
+            for i in 0..self.file_count() {
+                if let Some(r) = self.reader(i) {
                     let mut r = m.read_rehydrated(&mut inner.readers[0], r).await?;
 
                     // read header
@@ -86,7 +343,7 @@ impl<'a, F: Flash + 'a, M: RawMutex + 'a> Cursor<'a, F, M> {
                         Ok(()) => {}
                         Err(PageReadError::Eof) => {
                             // reached EOF, remove this file.
-                            self.readers[i] = None;
+                            *self.reader(i) = None;
                             continue;
                         }
                         Err(e) => return Err(no_eof(e).into()),
@@ -97,14 +354,15 @@ impl<'a, F: Flash + 'a, M: RawMutex + 'a> Cursor<'a, F, M> {
                     let got_key = &mut key_buf[..header.key_len];
                     r.read(m, got_key).await.map_err(no_eof)?;
 
-                    let finished = match self.upper_bound {
+                    let finished = match self.end_bound {
                         Bound::Included(key) => key < got_key,
                         Bound::Excluded(key) => key <= got_key,
                         Bound::Unbounded => false,
                     };
-                    if finished {
+                    // Keys are sorted, so once one doesn't start with the prefix, none of the following do.
+                    if finished || !got_key.starts_with(self.prefix) {
                         // reached the upper bound, remove this file.
-                        self.readers[i] = None;
+                        *self.reader(i) = None;
                         continue;
                     }
 
@@ -132,15 +390,17 @@ impl<'a, F: Flash + 'a, M: RawMutex + 'a> Cursor<'a, F, M> {
             }
 
             // Advance all files matching the lowest key.
-            // read the value from the highest file id (newer file).
+            // read the value from the highest file id (newer file) saying something about the key,
+            // which can also be a file with a range deleting it.
             // if key is deleted, do another loop.
-            let mut is_highest_file = true;
+            let mut decided = false;
             let mut result = None;
-            for i in (0..FILE_COUNT).rev() {
+            for i in (0..self.file_count()).rev() {
                 if !is_lowest[i] {
+                    decided |= *self.in_range(i) && !self.clipped(i, &lowest_key);
                     continue;
                 }
-                let r = self.readers[i].as_ref().unwrap();
+                let r = self.reader(i).as_ref().unwrap();
                 let mut r = m.read_rehydrated(&mut inner.readers[0], r).await?;
 
                 // read header
@@ -148,7 +408,7 @@ impl<'a, F: Flash + 'a, M: RawMutex + 'a> Cursor<'a, F, M> {
                     Ok(()) => {}
                     Err(PageReadError::Eof) => {
                         // reached EOF, remove this file.
-                        self.readers[i] = None;
+                        *self.reader(i) = None;
                         continue;
                     }
                     Err(e) => return Err(no_eof(e).into()),
@@ -158,24 +418,147 @@ impl<'a, F: Flash + 'a, M: RawMutex + 'a> Cursor<'a, F, M> {
                 // Skip key
                 r.skip(m, header.key_len).await.map_err(no_eof)?;
 
-                if is_highest_file && !header.is_delete {
+                if !decided && header.kind == RecordKind::Value {
                     // read value
                     if header.key_len > key.len() {
                         return Err(CursorError::KeyBufferTooSmall);
                     }
-                    if header.value_len > value.len() {
-                        return Err(CursorError::ValueBufferTooSmall);
+                    match &mut value {
+                        Some(value) if header.value_len > value.len() => {
+                            return Err(CursorError::ValueBufferTooSmall);
+                        }
+                        Some(value) => r.read(m, &mut value[..header.value_len]).await.map_err(no_eof)?,
+                        None => r.skip(m, header.value_len).await.map_err(no_eof)?,
                     }
                     key[..header.key_len].copy_from_slice(&lowest_key);
-                    r.read(m, &mut value[..header.value_len]).await.map_err(no_eof)?;
                     result = Some((header.key_len, header.value_len))
                 } else {
                     // skip value
                     r.skip(m, header.value_len).await.map_err(no_eof)?;
                 }
 
-                self.readers[i] = Some(r.dehydrate());
-                is_highest_file = false;
+                *self.reader(i) = Some(r.dehydrate());
+                *self.in_range(i) = header.kind == RecordKind::RangeStart;
+                decided |= header.kind != RecordKind::RangeEnd && !self.clipped(i, &lowest_key);
+            }
+
+            // if key was not deleted, return it.
+            if result.is_some() {
+                return Ok(result);
+            }
+        }
+    }
+
+    async fn next_rev(
+        &mut self,
+        key: &mut [u8],
+        mut value: Option<&mut [u8]>,
+    ) -> Result<Option<(usize, usize)>, CursorError<F::Error>> {
+        let inner = &mut *self.db.inner.lock().await;
+
+        let mut key_buf = [0u8; MAX_KEY_SIZE];
+        let mut header = [0; RECORD_HEADER_SIZE];
+
+        // loop to retry if found record is deleted.
+        loop {
+            let mut is_highest = [false; ALL_FILE_COUNT];
+            let mut highest_key: Vec<u8, MAX_KEY_SIZE> = Vec::new();
+            let mut found = false;
+
+            for i in 0..self.file_count() {
+                if let Some(r) = self.reader(i) {
+                    let m = &mut inner.files;
+                    let mut r = m.read_rehydrated(&mut inner.readers[0], r).await?;
+
+                    // read header
+                    r.read(m, &mut header).await.map_err(no_eof)?;
+                    let header = RecordHeader::decode(header)?;
+                    *self.in_range(i) = header.kind == RecordKind::RangeStart;
+
+                    // Read key
+                    let got_key = &mut key_buf[..header.key_len];
+                    r.read(m, got_key).await.map_err(no_eof)?;
+
+                    let finished = match self.end_bound {
+                        Bound::Included(key) => key > got_key,
+                        Bound::Excluded(key) => key >= got_key,
+                        Bound::Unbounded => false,
+                    };
+                    if finished {
+                        // reached the lower bound, remove this file. If it's a range, it still
+                        // deletes the keys after it.
+                        *self.reader(i) = None;
+                        continue;
+                    }
+
+                    let ordering = match found {
+                        false => Ordering::Greater,
+                        true => got_key[..].cmp(&highest_key[..]),
+                    };
+                    found = true;
+                    match ordering {
+                        Ordering::Greater => {
+                            highest_key = unwrap!(Vec::from_slice(got_key));
+                            is_highest.fill(false);
+                            is_highest[i] = true;
+                        }
+                        Ordering::Equal => {
+                            is_highest[i] = true;
+                        }
+                        Ordering::Less => {}
+                    }
+                }
+            }
+
+            if !found {
+                return Ok(None);
+            }
+
+            // Move all files matching the highest key to their previous record.
+            // read the value from the highest file id (newer file) saying something about the key,
+            // which can also be a file with a range deleting it.
+            // if key is deleted, do another loop.
+            let mut decided = false;
+            let mut result = None;
+            for i in (0..self.file_count()).rev() {
+                if !is_highest[i] {
+                    decided |= *self.in_range(i) && !self.clipped(i, &highest_key);
+                    continue;
+                }
+
+                if !decided {
+                    let m = &mut inner.files;
+                    let r = self.reader(i).as_ref().unwrap();
+                    let mut r = m.read_rehydrated(&mut inner.readers[0], r).await?;
+
+                    r.read(m, &mut header).await.map_err(no_eof)?;
+                    let header = RecordHeader::decode(header)?;
+                    decided = header.kind != RecordKind::RangeEnd && !self.clipped(i, &highest_key);
+
+                    if header.kind == RecordKind::Value {
+                        // read value
+                        if header.key_len > key.len() {
+                            return Err(CursorError::KeyBufferTooSmall);
+                        }
+                        match &mut value {
+                            Some(value) if header.value_len > value.len() => {
+                                return Err(CursorError::ValueBufferTooSmall);
+                            }
+                            Some(value) => {
+                                r.skip(m, header.key_len).await.map_err(no_eof)?;
+                                r.read(m, &mut value[..header.value_len]).await.map_err(no_eof)?;
+                            }
+                            None => {}
+                        }
+                        key[..header.key_len].copy_from_slice(&highest_key);
+                        result = Some((header.key_len, header.value_len))
+                    }
+                }
+
+                *self.reader(i) = inner
+                    .search_upper_bound_file(i as FileID, self.pending(), Bound::Excluded(&highest_key))
+                    .await?;
+                *self.in_range(i) = false;
             }
 
             // if key was not deleted, return it.
@@ -184,16 +567,184 @@ impl<'a, F: Flash + 'a, M: RawMutex + 'a> Cursor<'a, F, M> {
             }
         }
     }
+
+    /// Get the next key/value entry, reassembling blobs.
+    ///
+    /// Same as [`next`](Self::next), except blobs written with [`WriteTransaction::write_blob`](crate::WriteTransaction::write_blob)
+    /// are returned as a single entry with the whole value, instead of one entry per part.
+    /// See the [`blob`](crate::blob) module for details.
+    pub async fn next_blob(
+        &mut self,
+        key: &mut [u8],
+        value: &mut [u8],
+    ) -> Result<Option<(usize, usize)>, CursorError<F::Error>> {
+        blob::assert_enabled(self.db);
+
+        // Restored if the buffers are too small, so that the entry can be retried.
+        let readers = self.readers.clone();
+        let in_range = self.in_range;
+        let scratch = self.scratch.as_deref().cloned();
+
+        let mut key_buf = [0u8; MAX_KEY_SIZE];
+        let Some((key_len, value_len)) = self.next(&mut key_buf, value).await? else {
+            return Ok(None);
+        };
+
+        // The first record of a blob is its header, or its last part if rev.
+        let blob = match self.rev {
+            false => blob::header_key_len(&key_buf[..key_len], value_len).map(|n| (n, blob::decode_header(value))),
+            true => {
+                blob::part_index(&key_buf[..key_len]).map(|(n, last)| (n, blob::len_from_last_part(last, value_len)))
+            }
+        };
+        let Some((blob_key_len, len)) = blob else {
+            // Regular record.
+            if key_len > key.len() {
+                self.restore(readers, in_range, scratch);
+                return Err(CursorError::KeyBufferTooSmall);
+            }
+            key[..key_len].copy_from_slice(&key_buf[..key_len]);
+            return Ok(Some((key_len, value_len)));
+        };
+
+        let Some(len) = len else {
+            return Err(CursorError::Corrupted);
+        };
+        if blob_key_len > key.len() {
+            self.restore(readers, in_range, scratch);
+            return Err(CursorError::KeyBufferTooSmall);
+        }
+        if len > value.len() {
+            self.restore(readers, in_range, scratch);
+            return Err(CursorError::ValueBufferTooSmall);
+        }
+        let key = &mut key[..blob_key_len];
+        key.copy_from_slice(&key_buf[..blob_key_len]);
+
+        let value = &mut value[..len];
+        let part_count = blob::part_count(len);
+        if self.rev {
+            // Move the last part to its place, then read the rest backwards.
+            value.copy_within(..value_len, len - value_len);
+            for i in (1..part_count).rev() {
+                let part = &mut value[(i - 1) * MAX_VALUE_SIZE..][..MAX_VALUE_SIZE];
+                self.next_blob_record(key, i, part).await?;
+            }
+            let mut header = [0; blob::HEADER_SIZE];
+            self.next_blob_record(key, 0, &mut header).await?;
+            if blob::decode_header(&header) != Some(len) {
+                return Err(CursorError::Corrupted);
+            }
+        } else {
+            for (i, part) in value.chunks_mut(MAX_VALUE_SIZE).enumerate() {
+                self.next_blob_record(key, i + 1, part).await?;
+            }
+        }
+
+        Ok(Some((blob_key_len, len)))
+    }
+
+    /// Read the next record, which must be part `part` of the blob with key `key`, with a value of `value.len()` bytes.
+    async fn next_blob_record(
+        &mut self,
+        key: &[u8],
+        part: usize,
+        value: &mut [u8],
+    ) -> Result<(), CursorError<F::Error>> {
+        let mut key_buf = [0u8; MAX_KEY_SIZE];
+        let part_key = blob::part_key(key, part as u16);
+        match self.next(&mut key_buf, value).await {
+            Ok(Some((k, v))) if key_buf[..k] == part_key[..] && v == value.len() => Ok(()),
+            Ok(_) | Err(CursorError::ValueBufferTooSmall) => Err(CursorError::Corrupted),
+            Err(e) => Err(e),
+        }
+    }
 }
 
 impl<F: Flash> Inner<F> {
+    /// Search the last record in a file with a key matching `bound`, as an upper bound.
+    async fn search_upper_bound_file(
+        &mut self,
+        file_id: FileID,
+        pending: bool,
+        bound: Bound<&[u8]>,
+    ) -> Result<Option<DehydratedFileReader>, Error<F::Error>> {
+        let r = match pending {
+            true => self.files.read_pending(&mut self.readers[0], file_id),
+            false => self.files.read(&mut self.readers[0], file_id),
+        };
+        let m = &mut self.files;
+        let mut s = FileSearcher::new(r);
+
+        let mut key_buf = [0u8; MAX_KEY_SIZE];
+        let mut header = [0; RECORD_HEADER_SIZE];
+
+        let matches = |got_key: &[u8]| match bound {
+            Bound::Included(k) => got_key <= k,
+            Bound::Excluded(k) => got_key < k,
+            Bound::Unbounded => true,
+        };
+
+        // Binary search. It stops at the last record boundary found with a matching key,
+        // or the file start, so the record we want is at or after it.
+        let mut ok = s.start(m).await?;
+        while ok {
+            s.reader().read(m, &mut header).await.map_err(no_eof)?;
+            let header = RecordHeader::decode(header)?;
+
+            // Read key
+            let got_key = &mut key_buf[..header.key_len];
+            s.reader().read(m, got_key).await.map_err(no_eof)?;
+
+            let dir = match matches(got_key) {
+                true => SeekDirection::Right,
+                false => SeekDirection::Left,
+            };
+            ok = s.seek(m, dir).await?;
+        }
+
+        let r = s.reader();
+
+        // Linear search, until the first key not matching.
+        let mut found = None;
+        loop {
+            let dehydrated = r.dehydrate();
+
+            match r.read(m, &mut header).await {
+                Ok(()) => {}
+                Err(PageReadError::Eof) => return Ok(found),
+                Err(e) => return Err(no_eof(e)),
+            };
+            let header = RecordHeader::decode(header)?;
+
+            // Read key
+            let got_key = &mut key_buf[..header.key_len];
+            r.read(m, got_key).await.map_err(no_eof)?;
+
+            if !matches(got_key) {
+                return Ok(found);
+            }
+            found = Some(dehydrated);
+
+            r.skip(m, header.value_len).await.map_err(no_eof)?;
+        }
+    }
+
+    /// Search the first record in a file with a key matching `bound_key` as a lower bound.
+    ///
+    /// Also returns whether the keys before that record are deleted by a range start, or all the keys
+    /// after the last record if there's none.
     async fn search_lower_bound_file(
         &mut self,
         file_id: FileID,
+        pending: bool,
         bound_key: &[u8],
         bound_included: bool,
-    ) -> Result<Option<DehydratedFileReader>, Error<F::Error>> {
-        let r = self.files.read(&mut self.readers[0], file_id);
+    ) -> Result<(Option<DehydratedFileReader>, bool), Error<F::Error>> {
+        let r = match pending {
+            true => self.files.read_pending(&mut self.readers[0], file_id),
+            false => self.files.read(&mut self.readers[0], file_id),
+        };
         let m = &mut self.files;
         let mut s = FileSearcher::new(r);
 
@@ -207,7 +758,7 @@ impl<F: Flash> Inner<F> {
 
             match s.reader().read(m, &mut header).await {
                 Ok(()) => {}
-                Err(PageReadError::Eof) => return Ok(None), // not found
+                Err(PageReadError::Eof) => return Ok((None, false)), // not found
                 Err(e) => return Err(no_eof(e)),
             };
             let header = RecordHeader::decode(header)?;
@@ -221,11 +772,11 @@ impl<F: Flash> Inner<F> {
                 Ordering::Equal => {
                     // if equal is allowed, return it.
                     if bound_included {
-                        return Ok(Some(dehydrated));
+                        return Ok((Some(dehydrated), false));
                     }
                     // otherwise return the next key.
                     s.reader().skip(m, header.value_len).await.map_err(no_eof)?;
-                    return Ok(Some(s.reader().dehydrate()));
+                    return Ok((Some(s.reader().dehydrate()), header.kind == RecordKind::RangeStart));
                 }
                 Ordering::Less => SeekDirection::Right,
                 Ordering::Greater => SeekDirection::Left,
@@ -237,13 +788,14 @@ impl<F: Flash> Inner<F> {
 
         let r = s.reader();
 
-        // Linear search
+        // Linear search. It goes through the record before the one we want, if any.
+        let mut in_range = false;
         loop {
             let dehydrated = r.dehydrate();
 
             match r.read(m, &mut header).await {
                 Ok(()) => {}
-                Err(PageReadError::Eof) => return Ok(None), // not found
+                Err(PageReadError::Eof) => return Ok((None, in_range)), // not found
                 Err(e) => return Err(no_eof(e)),
             };
             let header = RecordHeader::decode(header)?;
@@ -257,14 +809,14 @@ impl<F: Flash> Inner<F> {
                 Ordering::Equal => {
                     // if equal is allowed, return it.
                     if bound_included {
-                        return Ok(Some(dehydrated));
+                        return Ok((Some(dehydrated), in_range));
                     }
                     // otherwise return the next key.
                     s.reader().skip(m, header.value_len).await.map_err(no_eof)?;
-                    return Ok(Some(s.reader().dehydrate()));
+                    return Ok((Some(s.reader().dehydrate()), header.kind == RecordKind::RangeStart));
                 }
-                Ordering::Less => {}                              // keep going
-                Ordering::Greater => return Ok(Some(dehydrated)), // done
+                Ordering::Less => in_range = header.kind == RecordKind::RangeStart, // keep going
+                Ordering::Greater => return Ok((Some(dehydrated), in_range)),       // done
             }
 
             r.skip(m, header.value_len).await.map_err(no_eof)?;
@@ -280,7 +832,7 @@ mod tests {
     use embassy_sync::blocking_mutex::raw::NoopRawMutex;
 
     use super::*;
-    use crate::config::MAX_VALUE_SIZE;
+    use crate::config::{MAX_PAGE_COUNT, MAX_VALUE_SIZE};
     use crate::flash::MemFlash;
     use crate::Config;
 
@@ -307,10 +859,15 @@ mod tests {
         }
     }
 
+    /// Check both the ascending and the descending cursors.
     async fn check_read_all(db: &Database<impl Flash, NoopRawMutex>, entries: &[(&[u8], &[u8])]) {
         let rtx = db.read_transaction().await;
         let cursor = rtx.read_all().await.unwrap();
-        check_cursor(cursor, entries).await
+        check_cursor(cursor, entries).await;
+
+        let rev: std::vec::Vec<_> = entries.iter().rev().copied().collect();
+        let cursor = rtx.read_all_rev().await.unwrap();
+        check_cursor(cursor, &rev).await
     }
 
     async fn check_read_range(
@@ -321,7 +878,11 @@ mod tests {
     ) {
         let rtx = db.read_transaction().await;
         let cursor = rtx.read_range((lower, upper)).await.unwrap();
-        check_cursor(cursor, entries).await
+        check_cursor(cursor, entries).await;
+
+        let rev: std::vec::Vec<_> = entries.iter().rev().copied().collect();
+        let cursor = rtx.read_range_rev((lower, upper)).await.unwrap();
+        check_cursor(cursor, &rev).await
     }
 
     #[test_log::test(tokio::test)]
@@ -383,6 +944,43 @@ mod tests {
         check_read_all(&db, rows).await;
     }
 
+    #[test_log::test(tokio::test)]
+    async fn test_delete_range() {
+        let mut f = MemFlash::new();
+        let db = Database::new(&mut f, Config::default());
+        db.format().await.unwrap();
+
+        let mut wtx = db.write_transaction().await;
+        wtx.write(b"a", b"1").await.unwrap();
+        wtx.write(b"b", b"2").await.unwrap();
+        wtx.write(b"c", b"3").await.unwrap();
+        wtx.write(b"d", b"4").await.unwrap();
+        wtx.write(b"e", b"5").await.unwrap();
+        wtx.commit().await.unwrap();
+
+        let mut wtx = db.write_transaction().await;
+        wtx.delete_range(&b"b"[..]..&b"e"[..]).await.unwrap();
+        wtx.commit().await.unwrap();
+
+        let mut wtx = db.write_transaction().await;
+        wtx.write(b"c", b"6").await.unwrap();
+        wtx.commit().await.unwrap();
+
+        let rows: &[(&[u8], &[u8])] = &[(b"a", b"1"), (b"c", b"6"), (b"e", b"5")];
+        check_read_all(&db, rows).await;
+        check_read_range(&db, Included(b"b"), Excluded(b"e"), &rows[1..2]).await;
+        check_read_range(&db, Excluded(b"c"), Unbounded, &rows[2..]).await;
+        check_read_range(&db, Unbounded, Included(b"d"), &rows[..2]).await;
+
+        // A range with no end deletes all the keys after it.
+        let mut wtx = db.write_transaction().await;
+        wtx.delete_range(&b"d"[..]..).await.unwrap();
+        wtx.commit().await.unwrap();
+
+        check_read_all(&db, &rows[..2]).await;
+        check_read_range(&db, Included(b"c"), Unbounded, &rows[1..2]).await;
+    }
+
     #[test_log::test(tokio::test)]
     async fn test_delete_empty() {
         let mut f = MemFlash::new();
@@ -584,4 +1182,292 @@ mod tests {
         check_read_range(&db, Excluded(b"aa"), Included(b"ba"), &[]).await;
         check_read_range(&db, Included(b"ax"), Included(b"ba"), &[]).await;
     }
+
+    #[test_log::test(tokio::test)]
+    async fn test_write_transaction() {
+        let mut f = MemFlash::new();
+        let db = Database::new(&mut f, Config::default());
+        db.format().await.unwrap();
+
+        let mut wtx = db.write_transaction().await;
+        wtx.write(b"aa", b"a").await.unwrap();
+        wtx.write(b"bb", b"b").await.unwrap();
+        wtx.write(b"cc", b"c").await.unwrap();
+        wtx.commit().await.unwrap();
+
+        let mut wtx = db.write_transaction().await;
+
+        // No writes yet, sees the committed data.
+        let rows: &[(&[u8], &[u8])] = &[(b"aa", b"a"), (b"bb", b"b"), (b"cc", b"c")];
+        check_cursor(wtx.read_all().await.unwrap(), rows).await;
+
+        wtx.write(b"aa", b"x").await.unwrap();
+        wtx.delete(b"bb").await.unwrap();
+        wtx.write(b"bc", b"y").await.unwrap();
+
+        // The write transaction sees its own writes and deletes.
+        let rows: &[(&[u8], &[u8])] = &[(b"aa", b"x"), (b"bc", b"y"), (b"cc", b"c")];
+        check_cursor(wtx.read_all().await.unwrap(), rows).await;
+        let rows: &[(&[u8], &[u8])] = &[(b"bc", b"y"), (b"cc", b"c")];
+        let cursor = wtx.read_range((Excluded(&b"aa"[..]), Unbounded)).await.unwrap();
+        check_cursor(cursor, rows).await;
+        let rows: &[(&[u8], &[u8])] = &[(b"aa", b"x")];
+        let cursor = wtx.read_range((Unbounded, Included(&b"bb"[..]))).await.unwrap();
+        check_cursor(cursor, rows).await;
+        let rows: &[(&[u8], &[u8])] = &[(b"cc", b"c"), (b"bc", b"y"), (b"aa", b"x")];
+        check_cursor(wtx.read_all_rev().await.unwrap(), rows).await;
+        let rows: &[(&[u8], &[u8])] = &[(b"bc", b"y"), (b"aa", b"x")];
+        let cursor = wtx.read_range_rev((Unbounded, Excluded(&b"cc"[..]))).await.unwrap();
+        check_cursor(cursor, rows).await;
+
+        // Read transactions don't.
+        let rows: &[(&[u8], &[u8])] = &[(b"aa", b"a"), (b"bb", b"b"), (b"cc", b"c")];
+        check_read_all(&db, rows).await;
+
+        wtx.write(b"dd", b"d").await.unwrap();
+        wtx.commit().await.unwrap();
+
+        let rows: &[(&[u8], &[u8])] = &[(b"aa", b"x"), (b"bc", b"y"), (b"cc", b"c"), (b"dd", b"d")];
+        check_read_all(&db, rows).await;
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_rev_many() {
+        let mut f = MemFlash::new();
+        let db = Database::new(&mut f, Config::default());
+        db.format().await.unwrap();
+
+        let mut m = std::collections::BTreeMap::new();
+        let key = |i: u32| format!("key{:03}", i).into_bytes();
+
+        // Spread the keys over several files, with overwrites and deletes.
+        for (start, step, delete) in [(0, 2, false), (1, 2, false), (0, 3, true), (0, 5, false), (2, 7, true)] {
+            let mut wtx = db.write_transaction().await;
+            for i in (start..60).step_by(step) {
+                if delete {
+                    wtx.delete(&key(i)).await.unwrap();
+                    m.remove(&key(i));
+                } else {
+                    let value = format!("{}-{}", i, step).into_bytes();
+                    wtx.write(&key(i), &value).await.unwrap();
+                    m.insert(key(i), value);
+                }
+            }
+            wtx.commit().await.unwrap();
+        }
+
+        let rows: std::vec::Vec<(&[u8], &[u8])> = m.iter().map(|(k, v)| (&k[..], &v[..])).collect();
+        check_read_all(&db, &rows).await;
+
+        let (lower, upper) = (key(13), key(47));
+        let rows: std::vec::Vec<(&[u8], &[u8])> = m
+            .range(lower.clone()..upper.clone())
+            .map(|(k, v)| (&k[..], &v[..]))
+            .collect();
+        check_read_range(&db, Included(&lower), Excluded(&upper), &rows).await;
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_seek() {
+        let mut f = MemFlash::new();
+        let db = Database::<_, NoopRawMutex>::new(&mut f, Config::default());
+        db.format().await.unwrap();
+
+        let mut wtx = db.write_transaction().await;
+        wtx.write(b"aa", b"a").await.unwrap();
+        wtx.write(b"cc", b"c").await.unwrap();
+        wtx.write(b"ee", b"e").await.unwrap();
+        wtx.commit().await.unwrap();
+
+        let mut wtx = db.write_transaction().await;
+        wtx.write(b"bb", b"b").await.unwrap();
+        wtx.delete(b"cc").await.unwrap();
+        wtx.write(b"dd", b"d").await.unwrap();
+        wtx.commit().await.unwrap();
+
+        let mut kbuf = [0; MAX_KEY_SIZE];
+        let mut vbuf = [0; MAX_VALUE_SIZE];
+
+        let rtx = db.read_transaction().await;
+        let mut cursor = rtx.read_range(&b"bb"[..]..&b"ee"[..]).await.unwrap();
+        cursor.seek(b"c").await.unwrap();
+        assert_eq!(cursor.next(&mut kbuf, &mut vbuf).await.unwrap(), Some((2, 1)));
+        assert_eq!(&kbuf[..2], b"dd");
+        assert_eq!(cursor.next(&mut kbuf, &mut vbuf).await.unwrap(), None);
+
+        // seeking backward, before the start of the range.
+        cursor.seek(b"a").await.unwrap();
+        assert_eq!(cursor.next(&mut kbuf, &mut vbuf).await.unwrap(), Some((2, 1)));
+        assert_eq!(&kbuf[..2], b"bb");
+
+        // seeking to an exact key.
+        cursor.seek(b"dd").await.unwrap();
+        assert_eq!(cursor.next(&mut kbuf, &mut vbuf).await.unwrap(), Some((2, 1)));
+        assert_eq!(&kbuf[..2], b"dd");
+
+        // seeking past the end of the range.
+        cursor.seek(b"ee").await.unwrap();
+        assert_eq!(cursor.next(&mut kbuf, &mut vbuf).await.unwrap(), None);
+
+        let mut cursor = rtx.read_range_rev(&b"bb"[..]..=&b"dd"[..]).await.unwrap();
+        cursor.seek(b"cz").await.unwrap();
+        assert_eq!(cursor.next(&mut kbuf, &mut vbuf).await.unwrap(), Some((2, 1)));
+        assert_eq!(&kbuf[..2], b"bb");
+        assert_eq!(cursor.next(&mut kbuf, &mut vbuf).await.unwrap(), None);
+
+        // seeking after the start of the range.
+        cursor.seek(b"zz").await.unwrap();
+        assert_eq!(cursor.next(&mut kbuf, &mut vbuf).await.unwrap(), Some((2, 1)));
+        assert_eq!(&kbuf[..2], b"dd");
+
+        // seeking past the end of the range.
+        cursor.seek(b"b").await.unwrap();
+        assert_eq!(cursor.next(&mut kbuf, &mut vbuf).await.unwrap(), None);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_seek_forward() {
+        let mut f = MemFlash::new();
+        let db = Database::<_, NoopRawMutex>::new(&mut f, Config::default());
+        db.format().await.unwrap();
+
+        // Records taking about an eighth of the flash, keys are 4 bytes and values 4 bytes.
+        let count = (MAX_PAGE_COUNT * PAGE_MAX_PAYLOAD_SIZE / 8 / (RECORD_HEADER_SIZE + 8)).min(1000) as u32;
+        let mut wtx = db.write_transaction().await;
+        for i in 0..count {
+            wtx.write(&(2 * i).to_be_bytes(), &i.to_be_bytes()).await.unwrap();
+        }
+        wtx.commit().await.unwrap();
+
+        // delete the multiples of 3 in a second file.
+        let mut wtx = db.write_transaction().await;
+        for i in (0..count).step_by(3) {
+            wtx.delete(&(2 * i).to_be_bytes()).await.unwrap();
+        }
+        wtx.commit().await.unwrap();
+
+        // The first key present that's equal or greater than `key`.
+        let want = |key: u32| (key.div_ceil(2)..count).find(|i| i % 3 != 0).map(|i| 2 * i);
+
+        let mut kbuf = [0; MAX_KEY_SIZE];
+        let mut vbuf = [0; MAX_VALUE_SIZE];
+
+        let rtx = db.read_transaction().await;
+        let mut cursor = rtx.read_all().await.unwrap();
+
+        // small steps read the records skipped, big ones search. Odd keys aren't in the db.
+        let steps = [5, 6, 7, 40, 41, count + 1, count + 10, 2 * count - 1, 3];
+        for key in steps {
+            cursor.seek(&u32::to_be_bytes(key)).await.unwrap();
+            let got = cursor.next(&mut kbuf, &mut vbuf).await.unwrap();
+            assert_eq!(
+                got.map(|_| u32::from_be_bytes(kbuf[..4].try_into().unwrap())),
+                want(key),
+                "seek {key}"
+            );
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_next_key() {
+        let mut f = MemFlash::new();
+        let db = Database::<_, NoopRawMutex>::new(&mut f, Config::default());
+        db.format().await.unwrap();
+
+        let mut wtx = db.write_transaction().await;
+        wtx.write(b"aa", &[1; MAX_VALUE_SIZE]).await.unwrap();
+        wtx.write(b"bb", b"").await.unwrap();
+        wtx.write(b"cc", b"ccc").await.unwrap();
+        wtx.commit().await.unwrap();
+
+        let mut kbuf = [0; MAX_KEY_SIZE];
+        let mut vbuf = [0; MAX_VALUE_SIZE];
+
+        let rtx = db.read_transaction().await;
+        for rev in [false, true] {
+            let mut cursor = match rev {
+                false => rtx.read_all().await.unwrap(),
+                true => rtx.read_all_rev().await.unwrap(),
+            };
+            let mut got = std::vec::Vec::new();
+            while let Some((klen, vlen)) = cursor.next_key(&mut kbuf).await.unwrap() {
+                got.push((kbuf[..klen].to_vec(), vlen));
+            }
+            let mut expected = vec![
+                (b"aa".to_vec(), MAX_VALUE_SIZE),
+                (b"bb".to_vec(), 0),
+                (b"cc".to_vec(), 3),
+            ];
+            if rev {
+                expected.reverse();
+            }
+            assert_eq!(got, expected);
+        }
+
+        // mixing next_key and next.
+        let mut cursor = rtx.read_all().await.unwrap();
+        assert_eq!(cursor.next_key(&mut kbuf).await.unwrap(), Some((2, MAX_VALUE_SIZE)));
+        assert_eq!(cursor.next_key(&mut kbuf).await.unwrap(), Some((2, 0)));
+        assert_eq!(cursor.next(&mut kbuf, &mut vbuf).await.unwrap(), Some((2, 3)));
+        assert_eq!(&vbuf[..3], b"ccc");
+        assert_eq!(cursor.next_key(&mut kbuf).await.unwrap(), None);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_prefix() {
+        let mut f = MemFlash::new();
+        let db = Database::new(&mut f, Config::default());
+        db.format().await.unwrap();
+
+        let mut wtx = db.write_transaction().await;
+        wtx.write(b"cfg", b"0").await.unwrap();
+        wtx.write(b"cfg/", b"1").await.unwrap();
+        wtx.write(b"cfg/wifi/pass", b"2").await.unwrap();
+        wtx.write(b"cfg/wifi/ssid", b"3").await.unwrap();
+        wtx.write(b"cfg0", b"4").await.unwrap();
+        wtx.write(b"x\xff", b"5").await.unwrap();
+        wtx.write(b"x\xff\xff", b"6").await.unwrap();
+        wtx.write(b"y", b"7").await.unwrap();
+        wtx.commit().await.unwrap();
+
+        let check = |prefix: &'static [u8], entries: &'static [(&'static [u8], &'static [u8])]| {
+            let db = &db;
+            async move {
+                let rtx = db.read_transaction().await;
+                check_cursor(rtx.read_prefix(prefix).await.unwrap(), entries).await;
+            }
+        };
+
+        check(b"cfg/wifi/", &[(b"cfg/wifi/pass", b"2"), (b"cfg/wifi/ssid", b"3")]).await;
+        check(
+            b"cfg/",
+            &[(b"cfg/", b"1"), (b"cfg/wifi/pass", b"2"), (b"cfg/wifi/ssid", b"3")],
+        )
+        .await;
+        check(b"cfg/wifi/ssid/", &[]).await;
+        check(b"x\xff", &[(b"x\xff", b"5"), (b"x\xff\xff", b"6")]).await;
+        check(b"x\xff\xff", &[(b"x\xff\xff", b"6")]).await;
+        check(b"z", &[]).await;
+        check(
+            b"",
+            &[
+                (b"cfg", b"0"),
+                (b"cfg/", b"1"),
+                (b"cfg/wifi/pass", b"2"),
+                (b"cfg/wifi/ssid", b"3"),
+                (b"cfg0", b"4"),
+                (b"x\xff", b"5"),
+                (b"x\xff\xff", b"6"),
+                (b"y", b"7"),
+            ],
+        )
+        .await;
+
+        // The write transaction cursor sees the pending writes.
+        let mut wtx = db.write_transaction().await;
+        wtx.write(b"cfg/a", b"8").await.unwrap();
+        wtx.delete(b"cfg/wifi/pass").await.unwrap();
+        let rows: &[(&[u8], &[u8])] = &[(b"cfg/", b"1"), (b"cfg/a", b"8"), (b"cfg/wifi/ssid", b"3")];
+        check_cursor(wtx.read_prefix(b"cfg/").await.unwrap(), rows).await;
+    }
 }
@@ -31,6 +31,10 @@ pub enum FormatError<E> {
 pub enum MountError<E> {
     /// Database is corrupted, or not formatted yet.
     Corrupted,
+    /// Database was formatted with a different [compile-time configuration](crate::config).
+    ConfigMismatch,
+    /// Database was formatted with an unsupported on-disk format version.
+    UnsupportedVersion,
     /// Some operation on the underlying [`Flash`](crate::flash::Flash) failed.
     Flash(E),
 }
@@ -44,7 +48,57 @@ impl<E> From<Error<E>> for MountError<E> {
     }
 }
 
-/// Error returned by [`ReadTransaction::read`](crate::ReadTransaction::read).
+// When mounting lazily, the database is unusable all the same, so these are reported as corruption.
+impl<E> From<MountError<E>> for Error<E> {
+    fn from(e: MountError<E>) -> Self {
+        match e {
+            MountError::Flash(e) => Self::Flash(e),
+            MountError::Corrupted | MountError::ConfigMismatch | MountError::UnsupportedVersion => Self::Corrupted,
+        }
+    }
+}
+
+/// Error returned by [`Database::salvage`](crate::Database::salvage).
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SalvageError<E> {
+    /// The storage is full. The salvaged records are written while keeping the pages not salvaged yet.
+    Full,
+    /// The database being written got corrupted.
+    Corrupted,
+    /// Some operation on the underlying [`Flash`](crate::flash::Flash) failed.
+    Flash(E),
+}
+
+impl<E> From<Error<E>> for SalvageError<E> {
+    fn from(e: Error<E>) -> Self {
+        match e {
+            Error::Flash(e) => Self::Flash(e),
+            Error::Corrupted => Self::Corrupted,
+        }
+    }
+}
+
+impl<E> From<FormatError<E>> for SalvageError<E> {
+    fn from(e: FormatError<E>) -> Self {
+        match e {
+            FormatError::Flash(e) => Self::Flash(e),
+        }
+    }
+}
+
+impl<E> From<WriteError<E>> for SalvageError<E> {
+    fn from(e: WriteError<E>) -> Self {
+        match e {
+            WriteError::Full => Self::Full,
+            WriteError::Flash(e) => Self::Flash(e),
+            _ => Self::Corrupted,
+        }
+    }
+}
+
+/// Error returned by [`ReadTransaction::read`](crate::ReadTransaction::read), [`WriteTransaction::read`](crate::WriteTransaction::read)
+/// and their `read_at` and `value_len` counterparts.
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum ReadError<E> {
@@ -54,6 +108,8 @@ pub enum ReadError<E> {
     KeyTooBig,
     /// The requested key was found, but the value was larger than the provided buffer.
     BufferTooSmall,
+    /// Transaction is canceled. See [`WriteTransaction`](crate::WriteTransaction) for details.
+    TransactionCanceled,
     /// Database is corrupted, or not formatted yet.
     Corrupted,
     /// Some operation on the underlying [`Flash`](crate::flash::Flash) failed.
@@ -69,19 +125,31 @@ impl<E> From<Error<E>> for ReadError<E> {
     }
 }
 
-/// Error returned by [`WriteTransaction::write`](crate::WriteTransaction::write).
+impl<E> From<MountError<E>> for ReadError<E> {
+    fn from(e: MountError<E>) -> Self {
+        Error::from(e).into()
+    }
+}
+
+/// Error returned by [`WriteTransaction::write`](crate::WriteTransaction::write) and [`ValueWriter`](crate::ValueWriter).
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum WriteError<E> {
     /// The key is not lexicographically larger than the last written key in this transaction.
     ///
     /// Writes in a transaction must be sorted in ascending order, and you may not write the same
-    /// key twice.
+    /// key twice. This requirement can be lifted with [`Config::unsorted_writes`](crate::Config::unsorted_writes).
     NotSorted,
     /// The key is larger than [`MAX_KEY_SIZE`](crate::config::MAX_KEY_SIZE)
     KeyTooBig,
     /// The value is larger than [`MAX_VALUE_SIZE`](crate::config::MAX_VALUE_SIZE)
     ValueTooBig,
+    /// The data written with a [`ValueWriter`](crate::ValueWriter) doesn't add up to the value length
+    /// passed to [`WriteTransaction::begin_value`](crate::WriteTransaction::begin_value).
+    ValueLengthMismatch,
+    /// The key is reserved for the records of blobs, or the range to delete includes only some of
+    /// them. See [`Config::blobs`](crate::Config::blobs).
+    ReservedKey,
     /// Transaction is canceled. See [`WriteTransaction`](crate::WriteTransaction) for details.
     TransactionCanceled,
     /// The database storage is full.
@@ -101,6 +169,12 @@ impl<E> From<Error<E>> for WriteError<E> {
     }
 }
 
+impl<E> From<MountError<E>> for WriteError<E> {
+    fn from(e: MountError<E>) -> Self {
+        Error::from(e).into()
+    }
+}
+
 /// Error returned by [`WriteTransaction::commit`](crate::WriteTransaction::commit).
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
@@ -122,7 +196,8 @@ impl<E> From<Error<E>> for CommitError<E> {
     }
 }
 
-/// Error returned by [`Cursor::next`](crate::Cursor::next).
+/// Error returned by [`Cursor::next`](crate::Cursor::next), [`WriteTransaction::read_all`](crate::WriteTransaction::read_all),
+/// [`WriteTransaction::read_range`](crate::WriteTransaction::read_range) and [`WriteTransaction::read_prefix`](crate::WriteTransaction::read_prefix).
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum CursorError<E> {
@@ -130,6 +205,8 @@ pub enum CursorError<E> {
     KeyBufferTooSmall,
     /// The provided buffer for the value was too small.
     ValueBufferTooSmall,
+    /// Transaction is canceled. See [`WriteTransaction`](crate::WriteTransaction) for details.
+    TransactionCanceled,
     /// Database is corrupted, or not formatted yet.
     Corrupted,
     /// Some operation on the underlying [`Flash`](crate::flash::Flash) failed.
@@ -162,6 +239,12 @@ impl<E> From<CorruptedError> for PageReadError<E> {
     }
 }
 
+impl<E> From<CorruptedError> for MountError<E> {
+    fn from(_: CorruptedError) -> Self {
+        Self::Corrupted
+    }
+}
+
 impl<E> From<CorruptedError> for ReadError<E> {
     fn from(_: CorruptedError) -> Self {
         Self::Corrupted
@@ -1,27 +1,50 @@
 use core::fmt;
-use core::mem::size_of;
 
-use crate::alloc::Allocator;
+use crate::alloc::{Allocator, PageSet};
+#[cfg(feature = "encryption")]
+use crate::cipher::{Cipher, CipherFlash};
 use crate::config::*;
 use crate::errors::*;
 use crate::flash::Flash;
 use crate::page;
 pub use crate::page::ReadError;
-use crate::page::{ChunkHeader, DehydratedPageReader, Header, PageHeader, PageReader, PageWriter};
+use crate::page::{DehydratedPageReader, Header, PageReader, PageWriter, PendingChunk};
+use crate::record::{CheckProblemKind, CheckReport, SalvageReport};
 use crate::types::{OptionPageID, PageID};
 
 // Number of chunks + chunk headers per page.
 const CHUNKS_PER_PAGE: usize =
-    (PAGE_SIZE - PageHeader::SIZE - size_of::<DataHeader>()) / (page::MAX_CHUNK_SIZE + ChunkHeader::SIZE);
+    (PAGE_SIZE - page::page_header_size::<DataHeader>()) / (page::MAX_CHUNK_SIZE + page::CHUNK_HEADER_SIZE);
 // Size of the last chunk + chunk header.
 const CHUNKS_REMAINDER: usize =
-    (PAGE_SIZE - PageHeader::SIZE - size_of::<DataHeader>()) % (page::MAX_CHUNK_SIZE + ChunkHeader::SIZE);
+    (PAGE_SIZE - page::page_header_size::<DataHeader>()) % (page::MAX_CHUNK_SIZE + page::CHUNK_HEADER_SIZE);
 // Bytes in max chunks + remainder chunk without the last chunk header.
 pub const PAGE_MAX_PAYLOAD_SIZE: usize =
-    (CHUNKS_PER_PAGE * page::MAX_CHUNK_SIZE) + CHUNKS_REMAINDER.saturating_sub(ChunkHeader::SIZE);
+    (CHUNKS_PER_PAGE * page::MAX_CHUNK_SIZE) + CHUNKS_REMAINDER.saturating_sub(page::CHUNK_HEADER_SIZE);
 
 pub type FileID = u8;
 
+// File ID of meta page entries listing a retired page, in `last_page_id`.
+const BAD_PAGE_FILE_ID: FileID = FileID::MAX;
+
+// File ID of the meta page entry with the wear leveling state, in `first_seq`. See
+// `FileManager::set_wear_leveling_erase_count`.
+const WEAR_LEVELING_FILE_ID: FileID = FileID::MAX - 2;
+
+// File ID to read the page chain being salvaged with, see `FileManager::salvage_next`.
+const SALVAGE_FILE_ID: FileID = FileID::MAX - 1;
+
+// Max amount of retired pages. They're listed in the meta page, along with the file metas and the
+// wear leveling state, and all of them must fit in a single chunk so that writing them is atomic.
+pub(crate) const MAX_BAD_PAGE_COUNT: usize = {
+    let size = if page::MAX_CHUNK_SIZE < PAGE_MAX_PAYLOAD_SIZE {
+        page::MAX_CHUNK_SIZE
+    } else {
+        PAGE_MAX_PAYLOAD_SIZE
+    };
+    (size / FileMeta::SIZE).saturating_sub(FILE_COUNT + 1)
+};
+
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum SeekDirection {
     Left,
@@ -33,6 +56,12 @@ pub enum SeekDirection {
 pub struct MetaHeader {
     page_count: u32,
     seq: Seq,
+    /// Times this page has been erased, including the erase before writing it.
+    erase_count: u32,
+    /// On-disk format version, see [`FORMAT_VERSION`].
+    format_version: u32,
+    /// Fingerprint of the compile-time configuration, see [`CONFIG_FINGERPRINT`].
+    config_fingerprint: u32,
 }
 
 unsafe impl page::Header for MetaHeader {
@@ -44,6 +73,8 @@ unsafe impl page::Header for MetaHeader {
 #[repr(C)]
 pub struct DataHeader {
     seq: Seq,
+    /// Times this page has been erased, including the erase before writing it.
+    erase_count: u32,
 
     /// skiplist[0] = previous page, always.
     /// skiplist[i] = latest page that contains a byte with seq multiple of 2**(SKIPLIST_SHIFT+i)
@@ -89,27 +120,146 @@ impl FileState {
     };
 }
 
+/// Snapshot of a file that is being written but not committed yet.
+///
+/// Taken by [`FileWriter::snapshot`], so that readers opened with [`FileManager::read_pending`]
+/// can see the uncommitted data.
+#[derive(Debug, Clone, Copy)]
+struct PendingFile {
+    file_id: FileID,
+    state: FileState,
+    /// Page currently being written, the seq it starts at, and its chunk that's not committed yet.
+    /// Its header is not written yet, unless it's the last page of the file being appended to in place.
+    tail: Option<(PageID, Seq, PendingChunk)>,
+}
+
+/// Pages of a corrupted database being salvaged, see [`FileManager::salvage_start`].
+///
+/// They make up chains, like the pages of a file: each page continues the one in its `skiplist[0]`
+/// if that one is valid, ends at its seq, and the skiplists match. A chain is walked back from its
+/// last page, until a page that doesn't continue another one.
+pub struct Salvage {
+    /// Pages with a valid header and chunks, not freed yet.
+    kept: PageSet,
+    /// Pages continuing the one in their `skiplist[0]`.
+    linked: PageSet,
+    /// Last pages of the chains not salvaged yet, that aren't in the file table.
+    heads: PageSet,
+    /// Last pages of the chains with the pages of a file in the file table before one that's unreadable.
+    continued: PageSet,
+    /// Pages in more than one chain. They're freed at the end, the rest once their chain is salvaged.
+    shared: PageSet,
+    /// Last page and first seq of the files in the file table.
+    files: [Option<(PageID, Seq)>; FILE_COUNT],
+    /// Next file in the file table to salvage, after the heads.
+    next_file: usize,
+    /// Next chain of the file being salvaged, and the file's first seq.
+    next_page: Option<(PageID, Seq)>,
+    /// Chain being salvaged, and the seq it starts being walked at.
+    current: Option<(PageID, Seq)>,
+    /// Whether the chain being salvaged is part of a file in the file table.
+    current_in_file_table: bool,
+}
+
+impl Salvage {
+    fn new() -> Self {
+        Self {
+            kept: PageSet::new(),
+            linked: PageSet::new(),
+            heads: PageSet::new(),
+            continued: PageSet::new(),
+            shared: PageSet::new(),
+            files: [None; FILE_COUNT],
+            next_file: 0,
+            next_page: None,
+            current: None,
+            current_in_file_table: false,
+        }
+    }
+
+    /// Whether the chain being salvaged is part of a file in the file table. If it's not, it's
+    /// likely stale.
+    pub fn in_file_table(&self) -> bool {
+        self.current_in_file_table
+    }
+}
+
+/// Erase counts of the pages, see [`FileManager::erase_counts`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EraseCounts {
+    pub page_count: usize,
+    pub min: u32,
+    pub max: u32,
+    pub total: u64,
+}
+
+// With encryption, the page layer needs the cipher and generation counter along with the flash.
+#[cfg(not(feature = "encryption"))]
+type PageFlashOf<F> = F;
+#[cfg(feature = "encryption")]
+type PageFlashOf<F> = CipherFlash<F>;
+
 pub struct FileManager<F: Flash> {
-    flash: F,
-    files: [FileState; FILE_COUNT],
+    flash: PageFlashOf<F>,
+    /// State of all files. The first `FILE_COUNT` are persisted in the meta page, the rest are scratch files,
+    /// which only exist with the `unsorted-writes` feature.
+    files: [FileState; ALL_FILE_COUNT],
     meta_page_id: PageID,
+    /// Copy of the meta page, with the same seq and file table. Only used with redundant meta pages,
+    /// or until the meta pages are rewritten if it was found on mount.
+    meta_copy_page_id: Option<PageID>,
     meta_seq: Seq,
+    /// Whether the next commit must write new meta pages, instead of appending to the current ones.
+    meta_rewrite: bool,
     dirty: bool,
     alloc: Allocator,
     random: u32,
+    pending: Option<PendingFile>,
+    /// Highest erase count in the page headers when they were first scanned. Pages with no valid header
+    /// are assumed to have been erased this many times.
+    unknown_erase_count: Option<u32>,
+    /// Highest erase count of all pages. Found by scanning the page headers when first needed, then kept
+    /// up to date on erases.
+    max_erase_count: Option<u32>,
+    /// Whether pages were retired since the meta page was last written.
+    bad_pages_dirty: bool,
+    /// Highest erase count when the last relocation for wear leveling started. Persisted in the meta page.
+    wear_leveling_erase_count: Option<u32>,
+    /// Whether `wear_leveling_erase_count` changed since the meta page was last written.
+    wear_leveling_dirty: bool,
+    /// Read back all page writes, to check they were written correctly.
+    verify_writes: bool,
+    /// Write the meta page twice.
+    redundant_meta: bool,
+    /// Page chain being salvaged, read as file `SALVAGE_FILE_ID`.
+    salvage: FileState,
 }
 
 impl<F: Flash> FileManager<F> {
     pub fn new(flash: F, random_seed: u32) -> Self {
         assert!(FILE_COUNT * FileMeta::SIZE <= page::MAX_CHUNK_SIZE);
+        assert!(ALL_FILE_COUNT <= SALVAGE_FILE_ID as usize);
+        #[cfg(feature = "encryption")]
+        let flash = CipherFlash::new(flash, None);
         Self {
             flash,
             random: random_seed,
             meta_page_id: PageID::zero(),
+            meta_copy_page_id: None,
             meta_seq: Seq::ZERO,
-            files: [FileState::EMPTY; FILE_COUNT],
+            meta_rewrite: false,
+            files: [FileState::EMPTY; ALL_FILE_COUNT],
             dirty: true,
             alloc: Allocator::new(),
+            pending: None,
+            unknown_erase_count: None,
+            max_erase_count: None,
+            bad_pages_dirty: false,
+            wear_leveling_erase_count: None,
+            wear_leveling_dirty: false,
+            verify_writes: false,
+            redundant_meta: false,
+            salvage: FileState::EMPTY,
         }
     }
 
@@ -119,7 +269,7 @@ impl<F: Flash> FileManager<F> {
         self.random
     }
 
-    fn page_count(&self) -> usize {
+    pub fn page_count(&self) -> usize {
         self.alloc.page_count()
     }
 
@@ -132,219 +282,1024 @@ impl<F: Flash> FileManager<F> {
         self.alloc.free_pages()
     }
 
-    pub fn flash(&self) -> &F {
-        &self.flash
+    /// Highest erase count when the last relocation for wear leveling started, if any.
+    pub fn wear_leveling_erase_count(&self) -> Option<u32> {
+        self.wear_leveling_erase_count
     }
 
-    pub fn flash_mut(&mut self) -> &mut F {
-        &mut self.flash
+    /// Set the highest erase count when a relocation for wear leveling starts. It's persisted in the
+    /// meta page with the next commit, so relocations can be limited across reboots.
+    pub fn set_wear_leveling_erase_count(&mut self, erase_count: u32) {
+        self.wear_leveling_erase_count = Some(erase_count);
+        self.wear_leveling_dirty = true;
     }
 
-    pub fn is_empty(&self, file_id: FileID) -> bool {
-        self.files[file_id as usize].last_page.is_none()
+    /// Count of pages retired because erasing or writing them failed.
+    pub fn bad_pages(&self) -> usize {
+        self.alloc.bad_pages()
     }
 
-    pub fn read<'a>(&mut self, r: &'a mut PageReader, file_id: FileID) -> FileReader<'a> {
-        assert!(!self.dirty);
-        FileReader::new(self, r, file_id)
+    /// Read the erase count of a page from its header, if it has a valid one.
+    async fn read_erase_count(&mut self, page_id: PageID) -> Result<Option<u32>, F::Error> {
+        match self.read_header::<MetaHeader>(page_id).await {
+            Ok(h) => return Ok(Some(h.erase_count)),
+            Err(Error::Flash(e)) => return Err(e),
+            Err(Error::Corrupted) => {}
+        }
+        match self.read_header::<DataHeader>(page_id).await {
+            Ok(h) => Ok(Some(h.erase_count)),
+            Err(Error::Flash(e)) => Err(e),
+            Err(Error::Corrupted) => Ok(None),
+        }
     }
 
-    pub async fn write(&mut self, r: &mut PageReader, file_id: FileID) -> Result<FileWriter, Error<F::Error>> {
-        assert!(!self.dirty);
-        FileWriter::new(self, r, file_id).await
+    /// Scan the erase counts of all pages, from their headers. Retired pages are left out: they're never
+    /// erased again, so they'd keep the highest count even once the others are less worn.
+    ///
+    /// Pages with no valid header, for example if power was lost before writing it, get the highest
+    /// count found on the first scan. This can overestimate their wear, but never underestimates it by
+    /// more than the erases since their header was last written.
+    pub async fn erase_counts(&mut self) -> Result<EraseCounts, F::Error> {
+        let mut counts = EraseCounts {
+            page_count: 0,
+            min: u32::MAX,
+            max: 0,
+            total: 0,
+        };
+        let mut unknown = 0;
+        for page_id in 0..self.page_count() {
+            let page_id = PageID::from_raw(page_id as _).unwrap();
+            if self.alloc.is_bad(page_id) {
+                continue;
+            }
+            match self.read_erase_count(page_id).await? {
+                Some(count) => {
+                    counts.min = counts.min.min(count);
+                    counts.max = counts.max.max(count);
+                    counts.total += count as u64;
+                }
+                None => unknown += 1,
+            }
+            counts.page_count += 1;
+        }
+
+        let unknown_count = *self.unknown_erase_count.get_or_insert(counts.max);
+        if unknown != 0 {
+            counts.min = counts.min.min(unknown_count);
+            counts.max = counts.max.max(unknown_count);
+            counts.total += unknown as u64 * unknown_count as u64;
+        }
+        if counts.page_count == 0 {
+            counts.min = 0;
+        }
+        self.max_erase_count = Some(counts.max);
+        Ok(counts)
     }
 
-    pub async fn read_rehydrated<'a>(
-        &mut self,
-        r: &'a mut PageReader,
-        dehydrated: &DehydratedFileReader,
-    ) -> Result<FileReader<'a>, Error<F::Error>> {
-        assert!(!self.dirty);
+    /// Highest erase count of all pages, except the retired ones.
+    pub async fn max_erase_count(&mut self) -> Result<u32, F::Error> {
+        match self.max_erase_count {
+            Some(max) => Ok(max),
+            None => Ok(self.erase_counts().await?.max),
+        }
+    }
 
-        Ok(FileReader {
-            file_id: dehydrated.file_id,
-            state: match &dehydrated.state {
-                DehydratedReaderState::Created => ReaderState::Created,
-                DehydratedReaderState::Finished => ReaderState::Finished,
-                DehydratedReaderState::Reading(s, rs) => {
-                    r.rehydrate_from(&mut self.flash, rs).await?;
-                    ReaderState::Reading(s.clone())
+    /// Erase count to write in the header of `page_id`, when erasing it.
+    async fn next_erase_count(&mut self, page_id: PageID) -> Result<u32, F::Error> {
+        let count = match self.read_erase_count(page_id).await? {
+            Some(count) => count,
+            None => match self.unknown_erase_count {
+                Some(count) => count,
+                None => {
+                    self.erase_counts().await?;
+                    // NOTE(unwrap): set by the scan.
+                    self.unknown_erase_count.unwrap()
                 }
             },
-            r,
-        })
+        };
+        let count = count.saturating_add(1);
+        if let Some(max) = &mut self.max_erase_count {
+            *max = (*max).max(count);
+        }
+        Ok(count)
     }
 
-    pub fn file_flags(&self, file_id: FileID) -> u8 {
-        self.files[file_id as usize].flags
+    /// Pretend one in every `step` free pages has been erased `count` more times than the most erased page.
+    ///
+    /// This erases them, and writes a page header with the new erase count.
+    #[cfg(test)]
+    pub async fn wear_free_pages(&mut self, count: u32, step: usize) {
+        let count = self.max_erase_count().await.unwrap() + count;
+        let mut free = 0;
+        for page_id in 0..self.page_count() {
+            let page_id = PageID::from_raw(page_id as _).unwrap();
+            if !self.alloc.is_used(page_id) {
+                if free % step == 0 {
+                    self.wear_page(page_id, count).await;
+                }
+                free += 1;
+            }
+        }
     }
 
-    pub fn files_with_flag(&self, flag: u8) -> impl Iterator<Item = FileID> + '_ {
-        (0..FILE_COUNT as FileID).filter(move |&i| self.file_flags(i) & flag != 0)
+    /// Erase a free page, and write a data page header with the given erase count, with no data.
+    #[cfg(test)]
+    pub async fn wear_page(&mut self, page_id: PageID, count: u32) {
+        assert!(!self.alloc.is_used(page_id));
+        let mut w = PageWriter::<DataHeader>::new();
+        w.open(&mut self.flash, page_id).await;
+        let h = DataHeader {
+            seq: Seq::ZERO,
+            erase_count: count,
+            skiplist: [OptionPageID::none(); SKIPLIST_LEN],
+            record_boundary: u16::MAX,
+        };
+        w.write_header(&mut self.flash, h).await.unwrap();
+        let max = self.max_erase_count().await.unwrap();
+        self.max_erase_count = Some(max.max(count));
     }
 
-    pub async fn format(&mut self) -> Result<(), FormatError<F::Error>> {
-        self.dirty = true;
-
-        let page_count = self.flash.page_count();
-
-        let random_seed = self.random();
-        self.alloc.reset(page_count, random_seed);
-        self.files.fill(FileState::EMPTY);
-        self.meta_page_id = self.alloc.allocate();
-        self.meta_seq = Seq(1);
-
-        // Erase all meta pages.
+    /// Pages with a meta page header, including old ones not erased yet.
+    #[cfg(test)]
+    pub async fn meta_page_ids(&mut self) -> Vec<PageID> {
+        let mut res = Vec::new();
         for page_id in 0..self.page_count() {
             let page_id = PageID::from_raw(page_id as _).unwrap();
             if self.read_header::<MetaHeader>(page_id).await.is_ok() {
-                self.flash.erase(page_id).await.map_err(FormatError::Flash)?;
+                res.push(page_id);
             }
         }
+        res
+    }
 
-        // Write initial meta page.
-        let mut w = self.write_page(self.meta_page_id).await;
-        let h = MetaHeader {
-            page_count: self.page_count() as u32,
-            seq: self.meta_seq,
-        };
-        w.write_header(&mut self.flash, h).await.map_err(FormatError::Flash)?;
+    /// Pages of a file, from the last one back.
+    #[cfg(test)]
+    pub async fn file_page_ids(&mut self, file_id: FileID) -> Vec<PageID> {
+        let f = self.files[file_id as usize];
+        let mut pages = Vec::new();
+        let mut p = f.last_page;
+        while let Some(pp) = p {
+            pages.push(pp.page_id);
+            p = pp.prev(self, f.first_seq).await.unwrap();
+        }
+        pages
+    }
 
-        self.dirty = false;
-        Ok(())
+    pub fn flash(&self) -> &F {
+        #[cfg(not(feature = "encryption"))]
+        return &self.flash;
+        #[cfg(feature = "encryption")]
+        return &self.flash.flash;
+    }
+
+    pub fn flash_mut(&mut self) -> &mut F {
+        #[cfg(not(feature = "encryption"))]
+        return &mut self.flash;
+        #[cfg(feature = "encryption")]
+        return &mut self.flash.flash;
+    }
+
+    /// Set whether to read back all page writes, to check they were written correctly.
+    ///
+    /// Pages failing the check are retired, and the data written to them is moved to another page.
+    pub fn set_verify_writes(&mut self, verify: bool) {
+        self.verify_writes = verify;
+    }
+
+    /// Set whether to write the meta page to two pages, so mount can use one if the other is corrupted.
+    ///
+    /// If the meta pages found on mount don't match this, they're rewritten at the next commit.
+    pub fn set_redundant_meta(&mut self, redundant: bool) {
+        self.redundant_meta = redundant;
+    }
+
+    pub fn redundant_meta(&self) -> bool {
+        self.redundant_meta
     }
 
-    pub async fn mount(&mut self, r: &mut PageReader) -> Result<(), Error<F::Error>> {
+    /// Set the cipher to encrypt pages with. This forces a remount.
+    #[cfg(feature = "encryption")]
+    pub fn set_cipher(&mut self, cipher: Option<&'static dyn Cipher>) {
+        self.flash.cipher = cipher;
         self.dirty = true;
-        self.files.fill(FileState::EMPTY);
+    }
+
+    /// Make sure new page writes use generations higher than all the ones on flash,
+    /// so nonces are never reused.
+    #[cfg(feature = "encryption")]
+    async fn scan_generation(&mut self) -> Result<(), F::Error> {
+        if self.flash.cipher.is_none() {
+            return Ok(());
+        }
 
-        let mut meta_page_id = None;
-        let mut meta_seq = Seq::ZERO;
-        let mut meta_page_count = 0;
+        let mut max = 0;
         for page_id in 0..self.flash.page_count() {
             let page_id = PageID::from_raw(page_id as _).unwrap();
-            if let Ok(h) = self.read_header::<MetaHeader>(page_id).await {
-                if h.seq > meta_seq {
-                    meta_page_id = Some(page_id);
-                    meta_seq = h.seq;
-                    meta_page_count = h.page_count;
-                }
-            }
+            // Pages with no valid header can still have chunks, laid out for either kind of header.
+            let meta = page::read_max_generation::<_, MetaHeader>(&mut self.flash, page_id).await?;
+            let data = page::read_max_generation::<_, DataHeader>(&mut self.flash, page_id).await?;
+            max = max.max(meta).max(data);
         }
+        self.flash.generation = max + 1;
+        Ok(())
+    }
 
-        let Some(meta_page_id) = meta_page_id else {
-            debug!("Meta page not found");
-            corrupted!()
-        };
-        if meta_page_count == 0 {
-            debug!("mount: flash page count zero",);
-            corrupted!()
-        }
-        if meta_page_count as usize > self.flash.page_count() {
-            debug!(
-                "mount: flash page count {} less than meta page {}. flash image truncated?",
-                self.flash.page_count(),
-                meta_page_count
-            );
-            corrupted!()
-        };
-        if meta_page_id.index() >= meta_page_count as usize {
-            debug!(
-                "mount: meta page id {} out of bounds for page count {}",
-                meta_page_id.index(),
-                meta_page_count
-            );
-            corrupted!()
-        };
+    pub fn is_empty(&self, file_id: FileID) -> bool {
+        self.files[file_id as usize].last_page.is_none()
+    }
 
-        self.meta_page_id = meta_page_id;
-        self.meta_seq = meta_seq;
+    /// Length of a file in bytes.
+    pub fn file_len(&self, file_id: FileID) -> usize {
+        let f = &self.files[file_id as usize];
+        f.last_seq.sub(f.first_seq)
+    }
 
-        let random_seed = self.random();
-        self.alloc.reset(meta_page_count as _, random_seed);
-        self.alloc.mark_used(meta_page_id)?;
+    /// Count of pages used by a file, and the erase count of the least erased one.
+    pub async fn file_wear(&mut self, file_id: FileID) -> Result<(usize, u32), Error<F::Error>> {
+        let f = self.files[file_id as usize];
+        let mut pages = 0;
+        let mut min_erase_count = u32::MAX;
+        let mut p = f.last_page;
+        while let Some(pp) = p {
+            pages += 1;
+            min_erase_count = min_erase_count.min(pp.header.erase_count);
+            p = pp.prev(self, f.first_seq).await?;
+        }
+        Ok((pages, min_erase_count))
+    }
 
-        r.open::<_, MetaHeader>(&mut self.flash, meta_page_id)
-            .await
-            .inspect_err(|_| {
-                debug!("failed read meta_page_id={:?}", meta_page_id);
-            })?;
+    /// Check the pages of a file: headers, chunks, seqs, skiplists, and that record boundaries
+    /// are within the pages.
+    ///
+    /// Problems are added to `report`. Returns whether none were found.
+    pub async fn check_pages(
+        &mut self,
+        r: &mut PageReader,
+        file_id: FileID,
+        report: &mut CheckReport,
+    ) -> Result<bool, Error<F::Error>> {
+        let f = self.files[file_id as usize];
+        let problem_count = report.problem_count;
+
+        // Walk the file backwards, checking each page against the newer one after it.
+        let mut end = f.last_seq;
+        let mut next: Option<PagePointer> = None;
+        let mut page = f.last_page.map(|pp| pp.page_id);
+        while let Some(page_id) = page {
+            let header = match self.read_header::<DataHeader>(page_id).await {
+                Ok(h) => h,
+                Err(Error::Flash(e)) => return Err(Error::Flash(e)),
+                Err(Error::Corrupted) => {
+                    report.add(file_id, Some(page_id), CheckProblemKind::PageHeader);
+                    break;
+                }
+            };
+            report.page_count += 1;
 
-        let mut files = [FileMeta {
-            file_id: 0,
-            flags: 0,
-            last_page_id: OptionPageID::none(),
-            first_seq: Seq::ZERO,
-        }; FILE_COUNT];
-        loop {
-            let mut meta = [0; FileMeta::SIZE];
-            let n = r.read(&mut self.flash, &mut meta).await?;
-            if n == 0 {
-                break;
+            if let Some(next) = next {
+                // Seqs must decrease when walking back, or we could loop forever.
+                if header.seq >= next.header.seq {
+                    report.add(file_id, Some(page_id), CheckProblemKind::Seq);
+                    break;
+                }
+
+                let mut skiplist = header.skiplist;
+                let top = skiplist_index(header.seq, next.header.seq) + 1;
+                skiplist[..top].fill(page_id.into());
+                if next.header.skiplist != skiplist {
+                    report.add(file_id, Some(next.page_id), CheckProblemKind::Skiplist);
+                }
             }
-            if n != FileMeta::SIZE {
-                debug!("meta page last entry incomplete, size {}", n);
-                corrupted!();
+
+            match self.page_len(r, page_id).await {
+                Ok(len) => {
+                    if header.seq.add(len).ok() != Some(end) {
+                        report.add(file_id, Some(page_id), CheckProblemKind::Seq);
+                    }
+                    if header.record_boundary != u16::MAX && header.record_boundary as usize >= len {
+                        report.add(file_id, Some(page_id), CheckProblemKind::RecordBoundary);
+                    }
+                }
+                Err(Error::Flash(e)) => return Err(Error::Flash(e)),
+                Err(Error::Corrupted) => report.add(file_id, Some(page_id), CheckProblemKind::Chunk),
             }
 
-            let meta = FileMeta::from_bytes(meta);
-            if meta.file_id >= FILE_COUNT as _ {
-                debug!("meta file_id out of range: {}", meta.file_id);
-                corrupted!();
+            // The first page can be only partially in the file, if it was truncated.
+            if header.seq <= f.first_seq {
+                break;
             }
 
-            if let Some(page_id) = meta.last_page_id.into_option() {
-                if page_id.index() >= self.page_count() {
-                    debug!("meta last_page_id out of range: {}", meta.file_id);
-                    corrupted!();
+            page = header.skiplist[0].into_option();
+            match page {
+                Some(p) if p.index() >= self.page_count() => {
+                    report.add(file_id, Some(page_id), CheckProblemKind::Skiplist);
+                    break;
                 }
-            } else if meta.first_seq.0 != 0 {
-                debug!("meta last_page_id invalid, but first seq nonzero: {}", meta.file_id);
-                corrupted!();
+                Some(_) => {}
+                None => report.add(file_id, Some(page_id), CheckProblemKind::Seq),
             }
-
-            files[meta.file_id as usize] = meta
+            end = header.seq;
+            next = Some(PagePointer { page_id, header });
         }
 
-        for file_id in 0..FILE_COUNT as FileID {
-            let meta = files[file_id as usize];
+        Ok(report.problem_count == problem_count)
+    }
 
-            let Some(last_page_id) = meta.last_page_id.into_option() else {
-                continue;
-            };
+    /// Read a whole data page, returning its length.
+    async fn page_len(&mut self, r: &mut PageReader, page_id: PageID) -> Result<usize, Error<F::Error>> {
+        r.open::<_, DataHeader>(&mut self.flash, page_id).await?;
+        r.skip(&mut self.flash, PAGE_SIZE).await
+    }
 
-            let h = r
-                .open::<_, DataHeader>(&mut self.flash, last_page_id)
-                .await
-                .inspect_err(|_| {
-                    debug!("read_page failed: last_page_id={:?} file_id={}", last_page_id, file_id);
-                })?;
+    /// Start salvaging a corrupted database, see [`Database::salvage`](crate::Database::salvage).
+    ///
+    /// This finds the chains of pages to salvage, and the file table in the newest meta page that can
+    /// be read, if any. Then it formats, keeping the pages of the chains. Load the chains one by one
+    /// with [`salvage_next`](Self::salvage_next).
+    pub async fn salvage_start(
+        &mut self,
+        r: &mut PageReader,
+        report: &mut SalvageReport,
+    ) -> Result<Salvage, Error<F::Error>> {
+        self.dirty = true;
+        self.pending = None;
 
-            let page_len = r.skip(&mut self.flash, PAGE_SIZE).await?;
-            let last_seq = h.seq.add(page_len)?;
+        let page_count = self.flash.page_count();
+        let random_seed = self.random();
+        self.alloc.reset(page_count, random_seed);
 
-            let mut p = Some(PagePointer {
-                page_id: last_page_id,
-                header: h,
-            });
+        let mut s = Salvage::new();
+        let mut complete = self.salvage_file_table(r, &mut s, report).await?;
 
-            // note: first_seq == last_seq is corruption too, because in that case what we do is delete the file.
-            if meta.first_seq >= last_seq {
-                debug!(
-                    "meta: file {} first_seq {:?} not smaller than last_seq {:?}",
-                    file_id, meta.first_seq, last_seq
-                );
-                corrupted!();
+        // Find all pages with a valid header and chunks.
+        for page_id in 0..page_count {
+            let page_id = PageID::from_raw(page_id as _).unwrap();
+            if self.alloc.is_bad(page_id) {
+                continue;
             }
-
-            self.files[file_id as usize] = FileState {
-                dirty: false,
-                last_page: p,
-                first_seq: meta.first_seq,
-                last_seq,
-                flags: meta.flags,
+            match self.read_header::<DataHeader>(page_id).await {
+                Ok(_) => {}
+                Err(Error::Flash(e)) => return Err(Error::Flash(e)),
+                Err(Error::Corrupted) => continue,
+            }
+            match self.page_len(r, page_id).await {
+                Ok(0) => {}
+                Ok(_) => {
+                    s.kept.insert(page_id);
+                    report.page_count += 1;
+                }
+                Err(Error::Flash(e)) => return Err(Error::Flash(e)),
+                Err(Error::Corrupted) => report.unreadable_page_count += 1,
+            }
+        }
+        // Link the pages into chains.
+        let mut continued = PageSet::new();
+        for page_id in s.kept.iter() {
+            let header = self.read_header::<DataHeader>(page_id).await?;
+            let Some(prev) = header.skiplist[0].into_option() else {
+                continue;
             };
-
+            if !s.kept.contains(prev) {
+                continue;
+            }
+            let prev_header = self.read_header::<DataHeader>(prev).await?;
+            let len = self.page_len(r, prev).await?;
+            if prev_header.seq.add(len).ok() != Some(header.seq) {
+                continue;
+            }
+            let mut skiplist = prev_header.skiplist;
+            let top = skiplist_index(prev_header.seq, header.seq) + 1;
+            skiplist[..top].fill(prev.into());
+            if header.skiplist != skiplist {
+                continue;
+            }
+            s.linked.insert(page_id);
+            continued.insert(prev);
+        }
+        for page_id in s.kept.iter() {
+            if !continued.contains(page_id) {
+                s.heads.insert(page_id);
+            }
+        }
+
+        // Find the chains of the files in the file table. If a file has an unreadable page, the chain
+        // with the pages before it is salvaged as part of the file too.
+        let mut seen = PageSet::new();
+        for file_id in 0..FILE_COUNT {
+            let Some((page_id, first_seq)) = s.files[file_id] else {
+                continue;
+            };
+            if !s.kept.contains(page_id) {
+                debug!("salvage: file {} last page {:?} unreadable", file_id, page_id);
+                report.lost_file_count += 1;
+                s.files[file_id] = None;
+                complete = false;
+                continue;
+            }
+            s.heads.remove(page_id);
+
+            let mut page_id = page_id;
+            loop {
+                let mut pp = PagePointer {
+                    page_id,
+                    header: self.read_header(page_id).await?,
+                };
+                loop {
+                    if !seen.insert(pp.page_id) {
+                        s.shared.insert(pp.page_id);
+                    }
+                    match self.salvage_prev(&s.linked, pp, first_seq).await? {
+                        Some(prev) => pp = prev,
+                        None => break,
+                    }
+                }
+                if pp.header.seq <= first_seq {
+                    break;
+                }
+                complete = false;
+                let Some(next) = self.salvage_continuation(&s.linked, &s.heads, &pp).await? else {
+                    break;
+                };
+                s.heads.remove(next);
+                s.continued.insert(next);
+                page_id = next;
+            }
+        }
+
+        // If the file table and all its files are whole, the rest of chains are stale: pages freed but
+        // not erased yet, or writes never committed. Free their pages, instead of salvaging them.
+        if complete {
+            debug!("salvage: file table complete, skipping the rest of chains");
+            s.heads = PageSet::new();
+            for page_id in 0..page_count {
+                let page_id = PageID::from_raw(page_id as _).unwrap();
+                if !seen.contains(page_id) {
+                    s.kept.remove(page_id);
+                }
+            }
+        }
+
+        // The rest of chains can have pages in common with them, or each other.
+        for page_id in s.heads.iter() {
+            let mut pp = PagePointer {
+                page_id,
+                header: self.read_header(page_id).await?,
+            };
+            loop {
+                if !seen.insert(pp.page_id) {
+                    s.shared.insert(pp.page_id);
+                }
+                match self.salvage_prev(&s.linked, pp, Seq::ZERO).await? {
+                    Some(prev) => pp = prev,
+                    None => break,
+                }
+            }
+        }
+
+        let meta_page_count = 1 + self.redundant_meta as usize;
+        if s.kept.iter().count() + self.alloc.bad_pages() + meta_page_count > page_count {
+            debug!("salvage: no free pages for the meta pages");
+            corrupted!();
+        }
+
+        self.format_keeping(Some(&s.kept)).await?;
+        Ok(s)
+    }
+
+    /// Read the file table in the newest meta page with a valid header, as much of it as can be read.
+    ///
+    /// Returns whether it was found, and read whole.
+    async fn salvage_file_table(
+        &mut self,
+        r: &mut PageReader,
+        s: &mut Salvage,
+        report: &mut SalvageReport,
+    ) -> Result<bool, Error<F::Error>> {
+        let page_count = self.page_count();
+
+        let mut meta: Option<(PageID, MetaHeader)> = None;
+        for page_id in 0..page_count {
+            let page_id = PageID::from_raw(page_id as _).unwrap();
+            match self.read_header::<MetaHeader>(page_id).await {
+                Ok(h) => {
+                    if h.format_version == FORMAT_VERSION
+                        && h.config_fingerprint == CONFIG_FINGERPRINT
+                        && meta.map_or(true, |(_, m)| h.seq > m.seq)
+                    {
+                        meta = Some((page_id, h));
+                    }
+                }
+                Err(Error::Flash(e)) => return Err(Error::Flash(e)),
+                Err(Error::Corrupted) => {}
+            }
+        }
+        let Some((meta_page_id, _)) = meta else {
+            debug!("salvage: meta page not found");
+            return Ok(false);
+        };
+        report.file_table_found = true;
+
+        let mut complete = true;
+        let res: Result<(), Error<F::Error>> = try {
+            r.open::<_, MetaHeader>(&mut self.flash, meta_page_id).await?;
+            loop {
+                let mut meta = [0; FileMeta::SIZE];
+                let n = r.read(&mut self.flash, &mut meta).await?;
+                if n != FileMeta::SIZE {
+                    complete &= n == 0;
+                    break;
+                }
+
+                let meta = FileMeta::from_bytes(meta);
+                let page_id = meta.last_page_id.into_option();
+                if page_id.is_some_and(|p| p.index() >= page_count) {
+                    complete = false;
+                    continue;
+                }
+                match (meta.file_id, page_id) {
+                    (BAD_PAGE_FILE_ID, Some(page_id)) => self.alloc.retire(page_id),
+                    (WEAR_LEVELING_FILE_ID, None) => {}
+                    (file_id, page_id) if (file_id as usize) < FILE_COUNT => {
+                        s.files[file_id as usize] = page_id.map(|p| (p, meta.first_seq));
+                    }
+                    _ => complete = false,
+                }
+            }
+        };
+        match res {
+            Ok(()) => Ok(complete),
+            Err(Error::Flash(e)) => Err(Error::Flash(e)),
+            Err(Error::Corrupted) => Ok(false),
+        }
+    }
+
+    /// Get the page before `pp` in its chain, down to the page containing `first_seq`.
+    async fn salvage_prev(
+        &mut self,
+        linked: &PageSet,
+        pp: PagePointer,
+        first_seq: Seq,
+    ) -> Result<Option<PagePointer>, Error<F::Error>> {
+        if pp.header.seq <= first_seq || !linked.contains(pp.page_id) {
+            return Ok(None);
+        }
+        // NOTE(unwrap): linked pages continue a valid page.
+        let page_id = pp.header.skiplist[0].into_option().unwrap();
+        let header = self.read_header(page_id).await?;
+        Ok(Some(PagePointer { page_id, header }))
+    }
+
+    /// Find the chain that continues back after the first page of another one, `first`, when the
+    /// page before it is unreadable.
+    ///
+    /// Its last page is one of `heads`, and the chain has one of the pages in the skiplist of `first`.
+    async fn salvage_continuation(
+        &mut self,
+        linked: &PageSet,
+        heads: &PageSet,
+        first: &PagePointer,
+    ) -> Result<Option<PageID>, Error<F::Error>> {
+        for target in first.header.skiplist[1..].iter().filter_map(|p| p.into_option()) {
+            let mut found: Option<PagePointer> = None;
+            for page_id in heads.iter() {
+                let head = PagePointer {
+                    page_id,
+                    header: self.read_header(page_id).await?,
+                };
+                if head.header.seq >= first.header.seq || found.is_some_and(|f| f.header.seq > head.header.seq) {
+                    continue;
+                }
+                let mut p = Some(head);
+                while let Some(pp) = p {
+                    if pp.page_id == target {
+                        found = Some(head);
+                        break;
+                    }
+                    p = self.salvage_prev(linked, pp, Seq::ZERO).await?;
+                }
+            }
+            if let Some(head) = found {
+                return Ok(Some(head.page_id));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Load the next chain to salvage, freeing the pages of the previous one that no other chain has.
+    /// Read it with [`read_salvage`](Self::read_salvage).
+    ///
+    /// The chains not in the file table go first, by the seq of their last page. Then the files in the
+    /// file table, oldest first, so records in later chains are newer, as far as we can tell.
+    ///
+    /// Returns the bytes lost at the chain start, before its first record, or `None` once all chains are
+    /// done, and all pages kept for them are freed.
+    pub async fn salvage_next(
+        &mut self,
+        r: &mut PageReader,
+        s: &mut Salvage,
+    ) -> Result<Option<usize>, Error<F::Error>> {
+        if let Some((page_id, first_seq)) = s.current.take() {
+            let mut p = Some(PagePointer {
+                page_id,
+                header: self.read_header(page_id).await?,
+            });
+            while let Some(pp) = p {
+                if s.kept.contains(pp.page_id) && !s.shared.contains(pp.page_id) {
+                    s.kept.remove(pp.page_id);
+                    self.free_page(pp.page_id)?;
+                }
+                p = self.salvage_prev(&s.linked, pp, first_seq).await?;
+            }
+            self.salvage = FileState::EMPTY;
+        }
+
+        let mut first_head: Option<PagePointer> = None;
+        for page_id in s.heads.iter() {
+            let header = self.read_header::<DataHeader>(page_id).await?;
+            if first_head.map_or(true, |h| header.seq < h.header.seq) {
+                first_head = Some(PagePointer { page_id, header });
+            }
+        }
+
+        let (page_id, file_first_seq) = match first_head {
+            Some(head) => {
+                s.heads.remove(head.page_id);
+                (head.page_id, None)
+            }
+            None => loop {
+                if let Some((page_id, first_seq)) = s.next_page.take() {
+                    break (page_id, Some(first_seq));
+                }
+                if s.next_file == FILE_COUNT {
+                    for page_id in s.kept.iter() {
+                        self.free_page(page_id)?;
+                    }
+                    s.kept = PageSet::new();
+                    return Ok(None);
+                }
+                s.next_page = s.files[s.next_file];
+                s.next_file += 1;
+            },
+        };
+        let walk_seq = file_first_seq.unwrap_or(Seq::ZERO);
+        s.current = Some((page_id, walk_seq));
+        s.current_in_file_table = file_first_seq.is_some();
+
+        // Walk back to the chain start, finding the first record boundary.
+        let last = PagePointer {
+            page_id,
+            header: self.read_header(page_id).await?,
+        };
+        let mut pp = last;
+        let mut boundary = None;
+        loop {
+            if pp.header.record_boundary != u16::MAX {
+                boundary = pp.header.seq.add(pp.header.record_boundary as usize).ok();
+            }
+            match self.salvage_prev(&s.linked, pp, walk_seq).await? {
+                Some(prev) => pp = prev,
+                None => break,
+            }
+        }
+        if let Some(first_seq) = file_first_seq {
+            if pp.header.seq <= first_seq {
+                boundary = Some(first_seq);
+            } else {
+                s.next_page = self
+                    .salvage_continuation(&s.linked, &s.continued, &pp)
+                    .await?
+                    .map(|page_id| (page_id, first_seq));
+                if let Some((page_id, _)) = s.next_page {
+                    s.continued.remove(page_id);
+                }
+            }
+        }
+
+        let last_seq = last.header.seq.add(self.page_len(r, last.page_id).await?)?;
+        let start_seq = pp.header.seq.max(walk_seq);
+        let first_seq = boundary.filter(|&b| b >= start_seq && b < last_seq);
+        self.salvage = match first_seq {
+            Some(first_seq) => FileState {
+                dirty: false,
+                last_page: Some(last),
+                first_seq,
+                last_seq,
+                flags: 0,
+            },
+            None => FileState::EMPTY,
+        };
+        Ok(Some(first_seq.unwrap_or(last_seq).sub(start_seq)))
+    }
+
+    /// Open the chain loaded by [`salvage_next`](Self::salvage_next) for reading.
+    pub fn read_salvage<'a>(&mut self, r: &'a mut PageReader) -> FileReader<'a> {
+        self.read(r, SALVAGE_FILE_ID)
+    }
+
+    pub fn read<'a>(&mut self, r: &'a mut PageReader, file_id: FileID) -> FileReader<'a> {
+        assert!(!self.dirty);
+        FileReader::new(self, r, file_id, false)
+    }
+
+    /// Open a file for reading, including the uncommitted data from the last [`FileWriter::snapshot`].
+    ///
+    /// If the file has no snapshot, this is the same as [`read`](Self::read).
+    pub fn read_pending<'a>(&mut self, r: &'a mut PageReader, file_id: FileID) -> FileReader<'a> {
+        assert!(!self.dirty);
+        let pending = self.pending.is_some_and(|p| p.file_id == file_id);
+        FileReader::new(self, r, file_id, pending)
+    }
+
+    fn file(&self, file_id: FileID, pending: bool) -> &FileState {
+        match &self.pending {
+            Some(p) if pending && p.file_id == file_id => &p.state,
+            _ if file_id == SALVAGE_FILE_ID => &self.salvage,
+            _ => &self.files[file_id as usize],
+        }
+    }
+
+    pub async fn write(&mut self, r: &mut PageReader, file_id: FileID) -> Result<FileWriter, Error<F::Error>> {
+        assert!(!self.dirty);
+        FileWriter::new(self, r, file_id).await
+    }
+
+    pub async fn read_rehydrated<'a>(
+        &mut self,
+        r: &'a mut PageReader,
+        dehydrated: &DehydratedFileReader,
+    ) -> Result<FileReader<'a>, Error<F::Error>> {
+        assert!(!self.dirty);
+
+        Ok(FileReader {
+            file_id: dehydrated.file_id,
+            pending: dehydrated.pending,
+            state: match &dehydrated.state {
+                DehydratedReaderState::Created => ReaderState::Created,
+                DehydratedReaderState::Finished => ReaderState::Finished,
+                DehydratedReaderState::Reading(s, rs) => {
+                    r.rehydrate_from(&mut self.flash, rs).await?;
+                    ReaderState::Reading(s.clone())
+                }
+            },
+            r,
+        })
+    }
+
+    /// Get the ID of a scratch file.
+    ///
+    /// Scratch files can be written, committed, read and deleted like the rest of files, but they're
+    /// only kept in RAM. They're never written to the meta page, so they're lost on remount.
+    #[allow(clippy::absurd_extreme_comparisons)] // there are no scratch files without unsorted writes.
+    pub fn scratch_file_id(index: usize) -> FileID {
+        assert!(index < SCRATCH_FILE_COUNT);
+        (FILE_COUNT + index) as _
+    }
+
+    fn is_scratch(file_id: FileID) -> bool {
+        file_id as usize >= FILE_COUNT
+    }
+
+    /// Commit a scratch file writer.
+    ///
+    /// This doesn't write anything to the meta page.
+    pub async fn commit_scratch(&mut self, w: &mut FileWriter) -> Result<(), Error<F::Error>> {
+        assert!(Self::is_scratch(w.file_id));
+        w.commit_state(self).await
+    }
+
+    /// Delete a scratch file, freeing all its pages.
+    pub async fn delete_scratch(&mut self, file_id: FileID) -> Result<(), Error<F::Error>> {
+        assert!(Self::is_scratch(file_id));
+        self.clear_pending(file_id);
+
+        let f = self.files[file_id as usize];
+        self.free_between(f.last_page, None, f.first_seq).await?;
+        self.files[file_id as usize] = FileState::EMPTY;
+        Ok(())
+    }
+
+    /// Rename a scratch file. The destination must be empty.
+    pub fn rename_scratch(&mut self, from: FileID, to: FileID) {
+        assert!(Self::is_scratch(from) && Self::is_scratch(to));
+        assert!(self.is_empty(to));
+        self.files.swap(from as usize, to as usize);
+    }
+
+    pub fn file_flags(&self, file_id: FileID) -> u8 {
+        self.files[file_id as usize].flags
+    }
+
+    pub fn files_with_flag(&self, flag: u8) -> impl Iterator<Item = FileID> + '_ {
+        (0..FILE_COUNT as FileID).filter(move |&i| self.file_flags(i) & flag != 0)
+    }
+
+    pub async fn format(&mut self) -> Result<(), FormatError<F::Error>> {
+        self.format_keeping(None).await
+    }
+
+    /// Format, keeping the pages in `keep` marked as used. They're not part of any file.
+    async fn format_keeping(&mut self, keep: Option<&PageSet>) -> Result<(), FormatError<F::Error>> {
+        self.dirty = true;
+
+        let page_count = self.flash.page_count();
+
+        #[cfg(feature = "encryption")]
+        self.scan_generation().await.map_err(FormatError::Flash)?;
+
+        // Pages retired since boot stay retired.
+        self.max_erase_count = None;
+        self.wear_leveling_erase_count = None;
+        self.wear_leveling_dirty = false;
+        let random_seed = self.random();
+        self.alloc.reset(page_count, random_seed);
+        for page_id in keep.iter().flat_map(|keep| keep.iter()) {
+            // NOTE(unwrap): all pages are free after the reset, except the retired ones, which are fine.
+            self.alloc.mark_used(page_id).unwrap();
+        }
+        self.files.fill(FileState::EMPTY);
+        self.meta_seq = Seq(1);
+
+        // Erase all meta pages.
+        for page_id in 0..self.page_count() {
+            let page_id = PageID::from_raw(page_id as _).unwrap();
+            if !self.alloc.is_bad(page_id) && self.read_header::<MetaHeader>(page_id).await.is_ok() {
+                self.flash.erase(page_id).await.map_err(FormatError::Flash)?;
+            }
+        }
+
+        // Write initial meta pages.
+        (self.meta_page_id, self.meta_copy_page_id) = self.write_meta_pages().await.map_err(|e| match e {
+            Error::Flash(e) => FormatError::Flash(e),
+            // Allocating can't fail, all pages are free except the retired ones, and the ones kept
+            // for salvaging, which always leave enough free.
+            Error::Corrupted => unreachable!(),
+        })?;
+
+        self.dirty = false;
+        Ok(())
+    }
+
+    pub async fn mount(&mut self, r: &mut PageReader) -> Result<(), MountError<F::Error>> {
+        self.dirty = true;
+        self.files.fill(FileState::EMPTY);
+        self.pending = None;
+        // Retired pages are loaded from the meta page, they're left out of the highest erase count.
+        self.max_erase_count = None;
+        self.wear_leveling_erase_count = None;
+        self.wear_leveling_dirty = false;
+
+        #[cfg(feature = "encryption")]
+        self.scan_generation().await.map_err(MountError::Flash)?;
+
+        // Find the newest meta page, and its copy if there's one.
+        let mut meta: Option<(PageID, MetaHeader)> = None;
+        let mut copy: Option<(PageID, MetaHeader)> = None;
+        for page_id in 0..self.flash.page_count() {
+            let page_id = PageID::from_raw(page_id as _).unwrap();
+            if let Ok(h) = self.read_header::<MetaHeader>(page_id).await {
+                match meta {
+                    Some((_, m)) if h.seq == m.seq => copy = copy.or(Some((page_id, h))),
+                    Some((_, m)) if h.seq < m.seq => {}
+                    _ => {
+                        meta = Some((page_id, h));
+                        copy = None;
+                    }
+                }
+            }
+        }
+
+        let Some((meta_page_id, meta)) = meta else {
+            debug!("Meta page not found");
+            corrupted!()
+        };
+        if meta.format_version != FORMAT_VERSION {
+            debug!(
+                "mount: unsupported format version {}, expected {}",
+                meta.format_version, FORMAT_VERSION
+            );
+            return Err(MountError::UnsupportedVersion);
+        }
+        if meta.config_fingerprint != CONFIG_FINGERPRINT {
+            debug!(
+                "mount: config fingerprint {:08x} doesn't match ours {:08x}. Formatted with a different config?",
+                meta.config_fingerprint, CONFIG_FINGERPRINT
+            );
+            return Err(MountError::ConfigMismatch);
+        }
+        let meta_seq = meta.seq;
+        let meta_page_count = meta.page_count;
+        if meta_page_count == 0 {
+            debug!("mount: flash page count zero",);
+            corrupted!()
+        }
+        if meta_page_count as usize > self.flash.page_count() {
+            debug!(
+                "mount: flash page count {} less than meta page {}. flash image truncated?",
+                self.flash.page_count(),
+                meta_page_count
+            );
+            corrupted!()
+        };
+        if meta_page_id.index() >= meta_page_count as usize {
+            debug!(
+                "mount: meta page id {} out of bounds for page count {}",
+                meta_page_id.index(),
+                meta_page_count
+            );
+            corrupted!()
+        };
+        let copy_page_id = copy
+            .filter(|(page_id, h)| {
+                page_id.index() < meta_page_count as usize
+                    && h.page_count == meta_page_count
+                    && h.format_version == meta.format_version
+                    && h.config_fingerprint == meta.config_fingerprint
+            })
+            .map(|(page_id, _)| page_id);
+
+        let random_seed = self.random();
+        self.alloc.reset(meta_page_count as _, random_seed);
+
+        // Use the copy with the most entries. The other one can be missing the last commit, if power was
+        // lost before writing it to both, or be unreadable.
+        let mut files = [FileMeta {
+            file_id: 0,
+            flags: 0,
+            last_page_id: OptionPageID::none(),
+            first_seq: Seq::ZERO,
+        }; FILE_COUNT];
+        let len = self.read_file_table(r, meta_page_id, &mut files, false).await;
+        let copy_len = match copy_page_id {
+            Some(page_id) => Some(self.read_file_table(r, page_id, &mut files, false).await),
+            None => None,
+        };
+        let (meta_page_id, copy_page_id, in_sync) = match (len, copy_len) {
+            (Err(Error::Flash(e)), _) | (_, Some(Err(Error::Flash(e)))) => return Err(MountError::Flash(e)),
+            (Ok(len), Some(Ok(copy_len))) if copy_len > len => (copy_page_id.unwrap(), Some(meta_page_id), false),
+            (Ok(len), Some(Ok(copy_len))) => (meta_page_id, copy_page_id, len == copy_len),
+            (Ok(_), _) => (meta_page_id, copy_page_id, false),
+            (Err(_), Some(Ok(_))) => (copy_page_id.unwrap(), Some(meta_page_id), false),
+            (Err(e), _) => return Err(e.into()),
+        };
+        debug!(
+            "mount: meta page {:?}, copy {:?}, in sync {}",
+            meta_page_id, copy_page_id, in_sync
+        );
+
+        self.meta_page_id = meta_page_id;
+        self.meta_copy_page_id = copy_page_id;
+        self.meta_seq = meta_seq;
+        // Rewrite the meta pages at the next commit if they don't match the configuration.
+        self.meta_rewrite = match self.redundant_meta {
+            true => !in_sync,
+            false => copy_page_id.is_some(),
+        };
+
+        self.alloc.mark_used(meta_page_id)?;
+        if let Some(page_id) = copy_page_id {
+            self.alloc.mark_used(page_id)?;
+        }
+        self.read_file_table(r, meta_page_id, &mut files, true).await?;
+
+        for file_id in 0..FILE_COUNT as FileID {
+            let meta = files[file_id as usize];
+
+            let Some(last_page_id) = meta.last_page_id.into_option() else {
+                continue;
+            };
+
+            let h = r
+                .open::<_, DataHeader>(&mut self.flash, last_page_id)
+                .await
+                .inspect_err(|_| {
+                    debug!("read_page failed: last_page_id={:?} file_id={}", last_page_id, file_id);
+                })?;
+
+            let page_len = r.skip(&mut self.flash, PAGE_SIZE).await?;
+            let last_seq = h.seq.add(page_len)?;
+
+            let mut p = Some(PagePointer {
+                page_id: last_page_id,
+                header: h,
+            });
+
+            // note: first_seq == last_seq is corruption too, because in that case what we do is delete the file.
+            if meta.first_seq >= last_seq {
+                debug!(
+                    "meta: file {} first_seq {:?} not smaller than last_seq {:?}",
+                    file_id, meta.first_seq, last_seq
+                );
+                corrupted!();
+            }
+
+            self.files[file_id as usize] = FileState {
+                dirty: false,
+                last_page: p,
+                first_seq: meta.first_seq,
+                last_seq,
+                flags: meta.flags,
+            };
+
             while let Some(pp) = p {
                 if self.alloc.mark_used(pp.page_id).is_err() {
                     info!("page used by multiple files at the same time. page_id={:?}", pp.page_id);
@@ -353,77 +1308,438 @@ impl<F: Flash> FileManager<F> {
                 p = pp.prev(self, meta.first_seq).await?;
             }
         }
-
-        self.dirty = false;
+
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Read the file table in a meta page into `files`. If `apply`, the pages listed as bad are retired,
+    /// and the wear leveling state is loaded.
+    ///
+    /// Returns the count of entries in it.
+    async fn read_file_table(
+        &mut self,
+        r: &mut PageReader,
+        page_id: PageID,
+        files: &mut [FileMeta; FILE_COUNT],
+        apply: bool,
+    ) -> Result<usize, Error<F::Error>> {
+        r.open::<_, MetaHeader>(&mut self.flash, page_id)
+            .await
+            .inspect_err(|_| {
+                debug!("failed read meta_page_id={:?}", page_id);
+            })?;
+
+        files.fill(FileMeta {
+            file_id: 0,
+            flags: 0,
+            last_page_id: OptionPageID::none(),
+            first_seq: Seq::ZERO,
+        });
+        let mut len = 0;
+        loop {
+            let mut meta = [0; FileMeta::SIZE];
+            let n = r.read(&mut self.flash, &mut meta).await?;
+            if n == 0 {
+                break;
+            }
+            if n != FileMeta::SIZE {
+                debug!("meta page last entry incomplete, size {}", n);
+                corrupted!();
+            }
+            len += 1;
+
+            let meta = FileMeta::from_bytes(meta);
+            if meta.file_id == BAD_PAGE_FILE_ID {
+                let Some(page_id) = meta.last_page_id.into_option() else {
+                    debug!("meta bad page id invalid");
+                    corrupted!();
+                };
+                if page_id.index() >= self.page_count() {
+                    debug!("meta bad page id out of range: {:?}", page_id);
+                    corrupted!();
+                }
+                if apply {
+                    self.alloc.retire(page_id);
+                }
+                continue;
+            }
+            if meta.file_id == WEAR_LEVELING_FILE_ID {
+                if apply {
+                    self.wear_leveling_erase_count = Some(meta.first_seq.0);
+                }
+                continue;
+            }
+            if meta.file_id >= FILE_COUNT as _ {
+                debug!("meta file_id out of range: {}", meta.file_id);
+                corrupted!();
+            }
+
+            if let Some(page_id) = meta.last_page_id.into_option() {
+                if page_id.index() >= self.page_count() {
+                    debug!("meta last_page_id out of range: {}", meta.file_id);
+                    corrupted!();
+                }
+            } else if meta.first_seq.0 != 0 {
+                debug!("meta last_page_id invalid, but first seq nonzero: {}", meta.file_id);
+                corrupted!();
+            }
+
+            files[meta.file_id as usize] = meta
+        }
+        Ok(len)
+    }
+
+    pub async fn remount_if_dirty(&mut self, r: &mut PageReader) -> Result<(), MountError<F::Error>> {
+        if self.dirty {
+            self.mount(r).await
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn transaction(&mut self) -> Transaction<'_, F> {
+        assert!(!self.dirty);
+        Transaction { m: self }
+    }
+
+    // convenience method
+    pub async fn commit(&mut self, w: &mut FileWriter) -> Result<(), Error<F::Error>> {
+        if w.commit_in_place(self).await? {
+            return Ok(());
+        }
+        let mut tx = self.transaction();
+        w.commit(&mut tx).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    // convenience method
+    #[allow(unused)]
+    pub async fn truncate(&mut self, file_id: FileID, bytes: usize) -> Result<(), Error<F::Error>> {
+        let mut tx = self.transaction();
+        tx.truncate(file_id, bytes).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn get_file_page(
+        &mut self,
+        file_id: FileID,
+        pending: bool,
+        seq: Seq,
+    ) -> Result<Option<PagePointer>, Error<F::Error>> {
+        let f = self.file(file_id, pending);
+        let Some(last) = f.last_page else { return Ok(None) };
+        if seq < f.first_seq || seq >= f.last_seq {
+            Ok(None)
+        } else {
+            Ok(Some(last.prev_seq(self, seq).await?))
+        }
+    }
+
+    fn clear_pending(&mut self, file_id: FileID) {
+        if self.pending.is_some_and(|p| p.file_id == file_id) {
+            self.pending = None;
+        }
+    }
+
+    /// Get the seq where the page of a pending file that is still being written starts. If there's none,
+    /// that's the end of the file.
+    fn tail_seq(&self, file_id: FileID, pending: bool) -> Seq {
+        match self.pending.as_ref().filter(|p| pending && p.file_id == file_id) {
+            Some(PendingFile {
+                tail: Some((_, seq, _)),
+                ..
+            }) => *seq,
+            _ => self.file(file_id, pending).last_seq,
+        }
+    }
+
+    /// Get the page of a pending file that is still being written, if it contains `seq`.
+    fn get_tail_page(&self, file_id: FileID, pending: bool, seq: Seq) -> Option<(PageID, Seq, PendingChunk)> {
+        let p = self.pending.as_ref().filter(|p| pending && p.file_id == file_id)?;
+        let (page_id, tail_seq, chunk) = p.tail?;
+        (seq >= tail_seq && seq < p.state.last_seq).then_some((page_id, tail_seq, chunk))
+    }
+
+    async fn free_between(
+        &mut self,
+        mut from: Option<PagePointer>,
+        to: Option<PagePointer>,
+        seq_limit: Seq,
+    ) -> Result<(), Error<F::Error>> {
+        while let Some(pp) = from {
+            if let Some(to) = to {
+                if pp.page_id == to.page_id {
+                    break;
+                }
+            }
+            self.free_page(pp.page_id)?;
+            from = pp.prev(self, seq_limit).await?;
+        }
+        Ok(())
+    }
+
+    async fn read_header<H: Header>(&mut self, page_id: PageID) -> Result<H, Error<F::Error>> {
+        page::read_header(&mut self.flash, page_id).await
+    }
+
+    /// Allocate a page, and erase it. Returns its ID, and the times it has been erased.
+    ///
+    /// If erasing fails, the page is retired, and another one is allocated instead.
+    async fn allocate_erased_page(&mut self) -> Result<(PageID, u32), Error<F::Error>> {
+        loop {
+            let page_id = self.alloc.try_allocate().ok_or(CorruptedError)?;
+            trace!("allocated page {:?}", page_id);
+            let erase_count = self.next_erase_count(page_id).await.map_err(Error::Flash)?;
+
+            match self.flash.erase(page_id).await {
+                Ok(()) => return Ok((page_id, erase_count)),
+                Err(e) => {
+                    if !self.retire_page(page_id) {
+                        return Err(Error::Flash(e));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Allocate a page to write to it.
+    async fn allocate_page<H: Header>(&mut self) -> Result<PageWriter<H>, Error<F::Error>> {
+        let (page_id, erase_count) = self.allocate_erased_page().await?;
+        let mut w = PageWriter::new();
+        w.set_verify(self.verify_writes);
+        w.set_erase_count(erase_count);
+        w.open_erased(&mut self.flash, page_id).await;
+        Ok(w)
+    }
+
+    /// Open the last page of a file to append to it, if it has `len` bytes of data and room for more.
+    async fn open_append_page(
+        &mut self,
+        pp: PagePointer,
+        len: usize,
+    ) -> Result<Option<PageWriter<DataHeader>>, Error<F::Error>> {
+        let mut w = PageWriter::new();
+        w.set_verify(self.verify_writes);
+        w.set_erase_count(pp.header.erase_count);
+        w.open_append(&mut self.flash, pp.page_id).await?;
+        Ok((w.len() == len && !w.is_full()).then_some(w))
+    }
+
+    /// Move the data written so far by `w` to a new page, after writing to its page failed with `err`.
+    ///
+    /// The failed page is retired. If the data can't be moved, `err` is returned.
+    async fn relocate_page<H: Header>(
+        &mut self,
+        w: &mut PageWriter<H>,
+        err: Error<F::Error>,
+    ) -> Result<(), Error<F::Error>> {
+        if !self.retire_page(w.page_id()) || !w.can_relocate() {
+            return Err(err);
+        }
+        self.move_page(w).await
+    }
+
+    /// Move the data written so far by `w` to a new page. The old one is left as is.
+    async fn move_page<H: Header>(&mut self, w: &mut PageWriter<H>) -> Result<(), Error<F::Error>> {
+        let (page_id, erase_count) = self.allocate_erased_page().await?;
+        w.set_erase_count(erase_count);
+        if let Err(e) = w.relocate(&mut self.flash, page_id).await {
+            self.retire_page(page_id);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Write data to the page of `w`, relocating it if writing fails.
+    ///
+    /// Returns the amount of bytes written, which is 0 if the page is full.
+    async fn write_page_data<H: Header>(
+        &mut self,
+        w: &mut PageWriter<H>,
+        data: &[u8],
+    ) -> Result<usize, Error<F::Error>> {
+        loop {
+            let len = w.len();
+            match w.write(&mut self.flash, data).await {
+                Ok(n) => return Ok(n),
+                Err(e) => {
+                    // Data written before the failure is kept, and relocated along with the rest.
+                    let n = w.len() - len;
+                    self.relocate_page(w, e).await?;
+                    if n != 0 {
+                        return Ok(n);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Commit the current chunk of `w`, relocating its page if writing fails.
+    #[cfg(feature = "encryption")]
+    async fn commit_page<H: Header>(&mut self, w: &mut PageWriter<H>) -> Result<(), Error<F::Error>> {
+        loop {
+            match w.commit(&mut self.flash).await {
+                Ok(()) => return Ok(()),
+                Err(e) => self.relocate_page(w, e).await?,
+            }
+        }
+    }
+
+    /// Retire a page that failed to erase or write, so it's not used again. It's persisted on the next
+    /// meta page write.
+    ///
+    /// Returns `false` if there are too many retired pages already.
+    fn retire_page(&mut self, page_id: PageID) -> bool {
+        if self.alloc.is_bad(page_id) {
+            return true;
+        }
+        if MAX_BAD_PAGE_COUNT == 0 || self.alloc.bad_pages() > MAX_BAD_PAGE_COUNT - 1 {
+            warn!(
+                "page {:?} failed, but there are too many bad pages to retire it",
+                page_id
+            );
+            return false;
+        }
+        warn!("page {:?} failed, retiring it", page_id);
+        self.alloc.retire(page_id);
+        self.bad_pages_dirty = true;
+        // It might have been the most erased one.
+        self.max_erase_count = None;
+        true
+    }
+
+    /// Write the metas of the dirty files, and the retired pages if they changed.
+    async fn write_dirty_metas(&mut self, w: &mut PageWriter<MetaHeader>) -> Result<(), WriteError<F::Error>> {
+        for file_id in 0..FILE_COUNT {
+            if self.files[file_id].dirty {
+                self.write_file_meta(w, file_id as FileID).await?;
+            }
+        }
+        if self.bad_pages_dirty {
+            self.write_bad_page_metas(w).await?;
+        }
+        if self.wear_leveling_dirty {
+            self.write_wear_leveling_meta(w).await?;
+        }
+        Ok(())
+    }
+
+    async fn write_wear_leveling_meta(&mut self, w: &mut PageWriter<MetaHeader>) -> Result<(), WriteError<F::Error>> {
+        let Some(erase_count) = self.wear_leveling_erase_count else {
+            return Ok(());
+        };
+        let meta = FileMeta {
+            file_id: WEAR_LEVELING_FILE_ID,
+            flags: 0,
+            first_seq: Seq(erase_count),
+            last_page_id: OptionPageID::none(),
+        };
+        self.write_meta(w, meta).await
+    }
+
+    async fn write_file_meta(
+        &mut self,
+        w: &mut PageWriter<MetaHeader>,
+        file_id: FileID,
+    ) -> Result<(), WriteError<F::Error>> {
+        let f = &self.files[file_id as usize];
+        let meta = FileMeta {
+            file_id,
+            flags: f.flags,
+            first_seq: f.first_seq,
+            last_page_id: f.last_page.as_ref().map(|pp| pp.page_id).into(),
+        };
+        self.write_meta(w, meta).await
+    }
+
+    async fn write_bad_page_metas(&mut self, w: &mut PageWriter<MetaHeader>) -> Result<(), WriteError<F::Error>> {
+        for page_id in 0..self.page_count() {
+            let page_id = PageID::from_raw(page_id as _).unwrap();
+            if self.alloc.is_bad(page_id) {
+                let meta = FileMeta {
+                    file_id: BAD_PAGE_FILE_ID,
+                    flags: 0,
+                    first_seq: Seq::ZERO,
+                    last_page_id: Some(page_id).into(),
+                };
+                self.write_meta(w, meta).await?;
+            }
+        }
         Ok(())
     }
 
-    pub async fn remount_if_dirty(&mut self, r: &mut PageReader) -> Result<(), Error<F::Error>> {
-        if self.dirty {
-            self.mount(r).await
-        } else {
-            Ok(())
+    async fn write_meta(&mut self, w: &mut PageWriter<MetaHeader>, meta: FileMeta) -> Result<(), WriteError<F::Error>> {
+        let n = w.write(&mut self.flash, &meta.to_bytes()).await?;
+        if n != FileMeta::SIZE {
+            return Err(WriteError::Full);
         }
-    }
-
-    pub fn transaction(&mut self) -> Transaction<'_, F> {
-        assert!(!self.dirty);
-        Transaction { m: self }
-    }
-
-    // convenience method
-    pub async fn commit(&mut self, w: &mut FileWriter) -> Result<(), Error<F::Error>> {
-        let mut tx = self.transaction();
-        w.commit(&mut tx).await?;
-        tx.commit().await?;
         Ok(())
     }
 
-    // convenience method
-    #[allow(unused)]
-    pub async fn truncate(&mut self, file_id: FileID, bytes: usize) -> Result<(), Error<F::Error>> {
-        let mut tx = self.transaction();
-        tx.truncate(file_id, bytes).await?;
-        tx.commit().await?;
-        Ok(())
-    }
+    /// Write a new meta page from scratch, with `meta_seq`. Returns its page ID.
+    ///
+    /// If writing fails, the page is retired, and it's written to another one instead.
+    async fn write_meta_page(&mut self) -> Result<PageID, Error<F::Error>> {
+        loop {
+            let mut w = self.allocate_page().await?;
+            let page_id = w.page_id();
+            let res: Result<(), WriteError<F::Error>> = try {
+                for file_id in 0..FILE_COUNT as FileID {
+                    // Since we're writing a new page from scratch, no need to
+                    // write metas for empty files.
+                    if self.files[file_id as usize].last_page.is_some() {
+                        self.write_file_meta(&mut w, file_id).await?;
+                    }
+                }
+                self.write_bad_page_metas(&mut w).await?;
+                self.write_wear_leveling_meta(&mut w).await?;
 
-    async fn get_file_page(&mut self, file_id: FileID, seq: Seq) -> Result<Option<PagePointer>, Error<F::Error>> {
-        let f = &self.files[file_id as usize];
-        let Some(last) = f.last_page else { return Ok(None) };
-        if seq < f.first_seq || seq >= f.last_seq {
-            Ok(None)
-        } else {
-            Ok(Some(last.prev_seq(self, seq).await?))
-        }
-    }
+                let h = MetaHeader {
+                    page_count: self.page_count() as _,
+                    seq: self.meta_seq,
+                    erase_count: w.erase_count(),
+                    format_version: FORMAT_VERSION,
+                    config_fingerprint: CONFIG_FINGERPRINT,
+                };
+                w.write_header(&mut self.flash, h).await?;
+                w.commit(&mut self.flash).await?;
+            };
 
-    async fn free_between(
-        &mut self,
-        mut from: Option<PagePointer>,
-        to: Option<PagePointer>,
-        seq_limit: Seq,
-    ) -> Result<(), Error<F::Error>> {
-        while let Some(pp) = from {
-            if let Some(to) = to {
-                if pp.page_id == to.page_id {
-                    break;
+            let err = match res {
+                Ok(()) => {
+                    self.bad_pages_dirty = false;
+                    self.wear_leveling_dirty = false;
+                    return Ok(page_id);
                 }
+                Err(WriteError::Flash(e)) => Error::Flash(e),
+                // Writing failed verification.
+                Err(WriteError::Corrupted) => Error::Corrupted,
+                // The metas always fit in a new page.
+                Err(WriteError::Full) => unreachable!(),
+            };
+            if !self.retire_page(page_id) {
+                return Err(err);
             }
-            self.free_page(pp.page_id)?;
-            from = pp.prev(self, seq_limit).await?;
         }
-        Ok(())
     }
 
-    async fn read_header<H: Header>(&mut self, page_id: PageID) -> Result<H, Error<F::Error>> {
-        page::read_header(&mut self.flash, page_id).await
-    }
+    /// Write new meta pages from scratch, with `meta_seq`: one, and its copy with redundant meta pages.
+    /// Returns their page IDs.
+    async fn write_meta_pages(&mut self) -> Result<(PageID, Option<PageID>), Error<F::Error>> {
+        let page_id = self.write_meta_page().await?;
+        if !self.redundant_meta {
+            self.meta_rewrite = false;
+            return Ok((page_id, None));
+        }
 
-    async fn write_page<H: Header>(&mut self, page_id: PageID) -> PageWriter<H> {
-        let mut w = PageWriter::new();
-        w.open(&mut self.flash, page_id).await;
-        w
+        let bad_pages = self.alloc.bad_pages();
+        let copy_page_id = self.write_meta_page().await?;
+        // A page retired while writing the copy is listed in it, but not in the first one.
+        self.meta_rewrite = self.alloc.bad_pages() != bad_pages;
+        Ok((page_id, Some(copy_page_id)))
     }
 
     fn free_page(&mut self, page_id: PageID) -> Result<(), CorruptedError> {
@@ -497,8 +1813,8 @@ impl<F: Flash> FileManager<F> {
     pub async fn dump_page(&mut self, r: &mut PageReader, page_id: PageID) {
         if let Ok(h) = r.open::<_, MetaHeader>(&mut self.flash, page_id).await {
             info!(
-                "  page {:?}: META seq {:?} page_count {:?}",
-                page_id, h.seq, h.page_count,
+                "  page {:?}: META seq {:?} page_count {:?} format_version {} config_fingerprint {:08x}",
+                page_id, h.seq, h.page_count, h.format_version, h.config_fingerprint,
             );
         } else if let Ok(h) = r.open::<_, DataHeader>(&mut self.flash, page_id).await {
             // Read all chunks
@@ -608,7 +1924,7 @@ impl<'a, F: Flash> Transaction<'a, F> {
             f.last_page
         } else {
             self.m
-                .get_file_page(file_id, seq)
+                .get_file_page(file_id, false, seq)
                 .await?
                 .unwrap()
                 .prev(self.m, old_seq)
@@ -640,78 +1956,67 @@ impl<'a, F: Flash> Transaction<'a, F> {
         Ok(())
     }
 
-    async fn write_file_meta(
-        &mut self,
-        w: &mut PageWriter<MetaHeader>,
-        file_id: FileID,
-    ) -> Result<(), WriteError<F::Error>> {
-        let f = &self.m.files[file_id as usize];
-        let meta = FileMeta {
-            file_id,
-            flags: f.flags,
-            first_seq: f.first_seq,
-            last_page_id: f.last_page.as_ref().map(|pp| pp.page_id).into(),
-        };
-        let n = w.write(&mut self.m.flash, &meta.to_bytes()).await?;
-        if n != FileMeta::SIZE {
-            return Err(WriteError::Full);
-        }
-
-        Ok(())
-    }
-
-    pub async fn commit(mut self) -> Result<(), Error<F::Error>> {
+    pub async fn commit(self) -> Result<(), Error<F::Error>> {
         self.m.dirty = true;
 
-        // Try appending to the existing meta page.
-        let res: Result<(), WriteError<F::Error>> = try {
+        if !self.m.meta_rewrite {
+            // Try appending to the existing meta page, then to its copy.
             let mut mw = PageWriter::new();
+            mw.set_verify(self.m.verify_writes);
             mw.open_append(&mut self.m.flash, self.m.meta_page_id).await?;
-            for file_id in 0..FILE_COUNT {
-                if self.m.files[file_id].dirty {
-                    self.write_file_meta(&mut mw, file_id as FileID).await?;
-                }
+            let mut page_id = self.m.meta_page_id;
+            let mut res: Result<(), WriteError<F::Error>> = try {
+                self.m.write_dirty_metas(&mut mw).await?;
+                mw.commit(&mut self.m.flash).await?;
+            };
+            if let (Ok(()), Some(copy_page_id)) = (&res, self.m.meta_copy_page_id) {
+                page_id = copy_page_id;
+                res = try {
+                    let mut mw = PageWriter::new();
+                    mw.set_verify(self.m.verify_writes);
+                    mw.open_append(&mut self.m.flash, copy_page_id).await?;
+                    self.m.write_dirty_metas(&mut mw).await?;
+                    mw.commit(&mut self.m.flash).await?;
+                };
             }
-            mw.commit(&mut self.m.flash).await?;
-        };
 
-        match res {
-            Ok(()) => {
-                self.m.dirty = false;
-                Ok(())
-            }
-            Err(WriteError::Flash(e)) => Err(Error::Flash(e)),
-            Err(WriteError::Corrupted) => corrupted!(),
-            Err(WriteError::Full) => {
-                // Existing meta page was full. Write a new one.
-
-                let page_id = self.m.alloc.allocate();
-                trace!("meta: allocated page {:?}", page_id);
-                let mut w = self.m.write_page(page_id).await;
-                for file_id in 0..FILE_COUNT as FileID {
-                    // Since we're writing a new page from scratch, no need to
-                    // write metas for empty files.
-                    if self.m.files[file_id as usize].last_page.is_some() {
-                        self.write_file_meta(&mut w, file_id).await.unwrap();
+            match res {
+                Ok(()) => {
+                    self.m.bad_pages_dirty = false;
+                    self.m.wear_leveling_dirty = false;
+                    self.m.dirty = false;
+                    return Ok(());
+                }
+                // The existing meta page failed. Retire it, and write new ones.
+                Err(WriteError::Flash(e)) => {
+                    if !self.m.retire_page(page_id) {
+                        return Err(Error::Flash(e));
                     }
                 }
+                // Writing it failed verification.
+                Err(WriteError::Corrupted) => {
+                    if !self.m.retire_page(page_id) {
+                        return Err(Error::Corrupted);
+                    }
+                }
+                // Existing meta page was full. Write new ones.
+                Err(WriteError::Full) => {}
+            }
+        }
 
-                self.m.meta_seq = self.m.meta_seq.add(1)?; // TODO handle wraparound
-                let h = MetaHeader {
-                    page_count: self.m.page_count() as _,
-                    seq: self.m.meta_seq,
-                };
-                w.write_header(&mut self.m.flash, h).await.map_err(Error::Flash)?;
-                w.commit(&mut self.m.flash).await?;
-
-                // free the old one.
-                self.m.free_page(self.m.meta_page_id)?;
-                self.m.meta_page_id = page_id;
+        self.m.meta_seq = self.m.meta_seq.add(1)?; // TODO handle wraparound
+        let (page_id, copy_page_id) = self.m.write_meta_pages().await?;
 
-                self.m.dirty = false;
-                Ok(())
-            }
+        // free the old ones.
+        self.m.free_page(self.m.meta_page_id)?;
+        if let Some(old_copy_page_id) = self.m.meta_copy_page_id {
+            self.m.free_page(old_copy_page_id)?;
         }
+        self.m.meta_page_id = page_id;
+        self.m.meta_copy_page_id = copy_page_id;
+
+        self.m.dirty = false;
+        Ok(())
     }
 }
 
@@ -791,6 +2096,8 @@ impl PagePointer {
 
 pub struct FileReader<'a> {
     file_id: FileID,
+    /// Read the pending snapshot of the file instead of the committed one.
+    pending: bool,
     r: &'a mut PageReader,
     state: ReaderState,
 }
@@ -809,6 +2116,7 @@ struct ReaderStateReading {
 #[derive(Clone)]
 pub struct DehydratedFileReader {
     file_id: FileID,
+    pending: bool,
     state: DehydratedReaderState,
 }
 
@@ -820,9 +2128,10 @@ enum DehydratedReaderState {
 }
 
 impl<'a> FileReader<'a> {
-    fn new<F: Flash>(_m: &mut FileManager<F>, r: &'a mut PageReader, file_id: FileID) -> Self {
+    fn new<F: Flash>(_m: &mut FileManager<F>, r: &'a mut PageReader, file_id: FileID, pending: bool) -> Self {
         Self {
             file_id,
+            pending,
             r,
             state: ReaderState::Created,
         }
@@ -831,6 +2140,7 @@ impl<'a> FileReader<'a> {
     pub fn dehydrate(&self) -> DehydratedFileReader {
         DehydratedFileReader {
             file_id: self.file_id,
+            pending: self.pending,
             state: match &self.state {
                 ReaderState::Created => DehydratedReaderState::Created,
                 ReaderState::Finished => DehydratedReaderState::Finished,
@@ -841,13 +2151,13 @@ impl<'a> FileReader<'a> {
 
     pub fn curr_seq<F: Flash>(&mut self, m: &FileManager<F>) -> Seq {
         match &self.state {
-            ReaderState::Created => m.files[self.file_id as usize].first_seq,
+            ReaderState::Created => m.file(self.file_id, self.pending).first_seq,
             ReaderState::Reading(s) => s.seq,
-            ReaderState::Finished => m.files[self.file_id as usize].last_seq,
+            ReaderState::Finished => m.file(self.file_id, self.pending).last_seq,
         }
     }
 
-    fn page_id(&self) -> Option<PageID> {
+    pub fn page_id(&self) -> Option<PageID> {
         match &self.state {
             ReaderState::Reading(_s) => Some(self.r.page_id()),
             _ => None,
@@ -870,22 +2180,37 @@ impl<'a> FileReader<'a> {
     }
 
     async fn seek_seq<F: Flash>(&mut self, m: &mut FileManager<F>, seq: Seq) -> Result<(), Error<F::Error>> {
-        self.state = match m.get_file_page(self.file_id, seq).await? {
-            Some(pp) => {
-                let h = self
-                    .r
-                    .open::<_, DataHeader>(&mut m.flash, pp.page_id)
-                    .await
-                    .inspect_err(|_| {
-                        debug!("failed read next page={:?}", pp.page_id);
-                    })?;
-                let n = seq.sub(h.seq);
+        let page = match m.get_tail_page(self.file_id, self.pending, seq) {
+            Some((page_id, page_seq, chunk)) => {
+                self.r
+                    .open_pending::<_, DataHeader>(&mut m.flash, page_id, chunk)
+                    .await?;
+                Some((page_id, page_seq))
+            }
+            None => match m.get_file_page(self.file_id, self.pending, seq).await? {
+                Some(pp) => {
+                    let h = self
+                        .r
+                        .open::<_, DataHeader>(&mut m.flash, pp.page_id)
+                        .await
+                        .inspect_err(|_| {
+                            debug!("failed read next page={:?}", pp.page_id);
+                        })?;
+                    Some((pp.page_id, h.seq))
+                }
+                None => None,
+            },
+        };
+
+        self.state = match page {
+            Some((page_id, page_seq)) => {
+                let n = seq.sub(page_seq);
                 let got_n = self.r.skip(&mut m.flash, n).await?;
                 let eof = self.r.is_at_eof(&mut m.flash).await?;
                 if n != got_n || eof {
                     debug!(
                         "found seq hole in file. page={:?} h.seq={:?} seq={:?} n={} got_n={} eof={}",
-                        pp.page_id, h.seq, seq, n, got_n, eof
+                        page_id, page_seq, seq, n, got_n, eof
                     );
                     corrupted!();
                 }
@@ -949,13 +2274,13 @@ impl<'a> FileReader<'a> {
         // If we got more to skip
         if len != 0 {
             let seq = match &self.state {
-                ReaderState::Created => m.files[self.file_id as usize].first_seq,
+                ReaderState::Created => m.file(self.file_id, self.pending).first_seq,
                 ReaderState::Reading(s) => s.seq,
                 ReaderState::Finished => unreachable!(),
             };
 
             let new_seq = seq.add(len).map_err(|_| ReadError::Eof)?;
-            if new_seq > m.files[self.file_id as usize].last_seq {
+            if new_seq > m.file(self.file_id, self.pending).last_seq {
                 return Err(ReadError::Eof);
             }
 
@@ -966,15 +2291,20 @@ impl<'a> FileReader<'a> {
     }
 
     pub fn offset<F: Flash>(&mut self, m: &FileManager<F>) -> usize {
-        let first_seq = m.files[self.file_id as usize].first_seq;
+        let first_seq = m.file(self.file_id, self.pending).first_seq;
         self.curr_seq(m).sub(first_seq)
     }
 
+    /// Bytes left until the end of the file.
+    pub fn bytes_left<F: Flash>(&mut self, m: &FileManager<F>) -> usize {
+        m.file(self.file_id, self.pending).last_seq.sub(self.curr_seq(m))
+    }
+
     #[allow(unused)]
     pub async fn seek<F: Flash>(&mut self, m: &mut FileManager<F>, offs: usize) -> Result<(), ReadError<F::Error>> {
-        let first_seq = m.files[self.file_id as usize].first_seq;
+        let first_seq = m.file(self.file_id, self.pending).first_seq;
         let new_seq = first_seq.add(offs).map_err(|_| ReadError::Eof)?;
-        if new_seq > m.files[self.file_id as usize].last_seq {
+        if new_seq > m.file(self.file_id, self.pending).last_seq {
             return Err(ReadError::Eof);
         }
 
@@ -983,6 +2313,115 @@ impl<'a> FileReader<'a> {
     }
 }
 
+/// Checks the `record_boundary` of the pages of a file against where its records start.
+///
+/// Pages with records starting in them must point to one of them, the others must have no boundary.
+/// Record starts are passed in order to [`record`](Self::record), then [`finish`](Self::finish)
+/// checks the pages after the last one.
+pub struct RecordBoundaryCheck {
+    file_id: FileID,
+    /// Page the last record starts in.
+    page: Option<BoundaryCheckPage>,
+}
+
+struct BoundaryCheckPage {
+    pp: PagePointer,
+    end: Seq,
+    /// Whether the page's `record_boundary` points to a record start seen so far.
+    found: bool,
+}
+
+impl BoundaryCheckPage {
+    fn is_boundary(&self, seq: Seq) -> bool {
+        let rb = self.pp.header.record_boundary;
+        rb != u16::MAX && self.pp.header.seq.add(rb as usize).ok() == Some(seq)
+    }
+}
+
+impl RecordBoundaryCheck {
+    pub fn new(file_id: FileID) -> Self {
+        Self { file_id, page: None }
+    }
+
+    /// Check a record starting at `seq`.
+    pub async fn record<F: Flash>(
+        &mut self,
+        m: &mut FileManager<F>,
+        r: &mut PageReader,
+        seq: Seq,
+        report: &mut CheckReport,
+    ) -> Result<(), Error<F::Error>> {
+        if let Some(p) = &mut self.page {
+            if seq < p.end {
+                p.found |= p.is_boundary(seq);
+                return Ok(());
+            }
+        }
+        self.finish_page(report);
+
+        let f = m.files[self.file_id as usize];
+        let Some(pp) = m.get_file_page(self.file_id, false, seq).await? else {
+            corrupted!()
+        };
+        let end = check_corrupted!(pp.header.seq.add(m.page_len(r, pp.page_id).await?));
+        let prev = pp.prev(m, f.first_seq).await?;
+        self.check_no_boundary(m, prev, report).await?;
+
+        let mut page = BoundaryCheckPage { pp, end, found: false };
+        // The records truncated off the start of the file are unknown, the boundary can point to one of them.
+        let rb = pp.header.record_boundary;
+        page.found = page.is_boundary(seq)
+            || (pp.header.seq < f.first_seq
+                && rb != u16::MAX
+                && pp.header.seq.add(rb as usize).is_ok_and(|b| b < f.first_seq));
+        self.page = Some(page);
+        Ok(())
+    }
+
+    /// Check the pages after the last record start.
+    pub async fn finish<F: Flash>(
+        &mut self,
+        m: &mut FileManager<F>,
+        report: &mut CheckReport,
+    ) -> Result<(), Error<F::Error>> {
+        self.finish_page(report);
+        let last_page = m.files[self.file_id as usize].last_page;
+        self.check_no_boundary(m, last_page, report).await
+    }
+
+    fn finish_page(&mut self, report: &mut CheckReport) {
+        if let Some(p) = &self.page {
+            if !p.found {
+                report.add(self.file_id, Some(p.pp.page_id), CheckProblemKind::RecordBoundary);
+            }
+        }
+    }
+
+    /// Check the pages from `p` back to the page of the last record start have no boundary.
+    async fn check_no_boundary<F: Flash>(
+        &self,
+        m: &mut FileManager<F>,
+        mut p: Option<PagePointer>,
+        report: &mut CheckReport,
+    ) -> Result<(), Error<F::Error>> {
+        let f = m.files[self.file_id as usize];
+        let start = match &self.page {
+            Some(page) => page.end,
+            None => f.first_seq,
+        };
+        while let Some(pp) = p {
+            if pp.header.seq < start {
+                break;
+            }
+            if pp.header.record_boundary != u16::MAX {
+                report.add(self.file_id, Some(pp.page_id), CheckProblemKind::RecordBoundary);
+            }
+            p = pp.prev(m, f.first_seq).await?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 #[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum SearchSeekError<E> {
@@ -1055,20 +2494,23 @@ impl<'a> FileSearcher<'a> {
     }
 
     pub async fn start<F: Flash>(&mut self, m: &mut FileManager<F>) -> Result<bool, Error<F::Error>> {
-        let f = &m.files[self.r.file_id as usize];
+        // The page of a pending file that's still being written has no header yet, so only the pages
+        // before it are binary searched. The caller's linear search goes on through it.
+        let f = *m.file(self.r.file_id, self.r.pending);
+        let end = m.tail_seq(self.r.file_id, self.r.pending);
         self.result = f.first_seq;
         self.left = f.first_seq;
-        self.right = f.last_seq;
+        self.right = end;
 
         match f.last_page {
             Some(pp) => {
-                if f.last_seq <= pp.header.seq {
+                if end <= pp.header.seq {
                     corrupted!();
                 }
 
                 // Create skiplist.
                 self.right_skiplist = pp.header.skiplist;
-                let top = skiplist_index(pp.header.seq, f.last_seq) + 1;
+                let top = skiplist_index(pp.header.seq, end) + 1;
                 self.right_skiplist[..top].fill(pp.page_id.into());
 
                 trace!(
@@ -1264,6 +2706,13 @@ pub struct FileWriter {
     // Previous last page of the file, that was copied to a new page because
     // it was not full. Must be freed on commit.
     rewritten_last_page_id: Option<PageID>,
+    // Last page of the file, that we're appending to in place because it was not full.
+    // Its header is already written. The data appended to it is kept in its current chunk,
+    // committing it is what makes it visible.
+    appended_page: Option<PagePointer>,
+    // Last page of the file we're appending to, not counting the rewritten one.
+    // Pages after it are written by us. Must be freed on discard.
+    initial_last_page: Option<PagePointer>,
 
     at_record_boundary: bool,
     record_boundary: Option<u16>,
@@ -1282,6 +2731,8 @@ impl FileWriter {
             last_page: f.last_page,
             seq: f.last_seq,
             rewritten_last_page_id: None,
+            appended_page: None,
+            initial_last_page: f.last_page,
             at_record_boundary: true,
             record_boundary: Some(0),
             writer: None,
@@ -1291,54 +2742,63 @@ impl FileWriter {
         // This is needed to ensure progressive compaction does not actually un-compact
         // the data, due to leaving pages not full in the middle of the file.
         if let Some(pp) = f.last_page {
-            r.open::<_, DataHeader>(&mut m.flash, pp.page_id).await?;
-
-            // Measure total page len.
-            let mut page_len = 0;
-            loop {
-                let n = r.skip(&mut m.flash, PAGE_MAX_PAYLOAD_SIZE).await?;
-                if n == 0 {
-                    break;
-                }
-                page_len += n;
-            }
+            let page_len = f.last_seq.sub(pp.header.seq);
 
             if page_len != PAGE_MAX_PAYLOAD_SIZE {
-                // Page is not full.
-                // Open a new page, copy all the data over, and make that the last file page.
-                // TODO: if possible, use PageManager::write_append to avoid the copy.
-
-                // seek again to start.
-                r.open::<_, DataHeader>(&mut m.flash, pp.page_id).await?;
-
-                // open new page
-                let page_id = m.alloc.try_allocate().ok_or(CorruptedError)?;
-                trace!("writer: last page is not full. Copying it to new page {:?}", page_id);
-                let mut w = m.write_page(page_id).await;
-
-                let mut buf = [0; 128];
-                loop {
-                    let n = r.read(&mut m.flash, &mut buf).await?;
-                    if n == 0 {
-                        break;
+                // Page is not full. Append to it in place if the rest of it is erased, and its header doesn't
+                // need changing, because a record starts in it already. Otherwise, open a new page, copy all
+                // the data over, and make that the last file page.
+                let w = match pp.header.record_boundary {
+                    u16::MAX => None,
+                    _ => m.open_append_page(pp, page_len).await?,
+                };
+                match w {
+                    Some(w) => {
+                        trace!("writer: last page is not full. Appending to it {:?}", pp.page_id);
+                        this.writer = Some(w);
+                        this.appended_page = Some(pp);
                     }
-                    let mut data = &buf[..n];
-                    while !data.is_empty() {
-                        let n = w.write(&mut m.flash, data).await?;
-                        data = &data[n..];
-
-                        // the new page can't get full, because we're not writing more
-                        // than 1 page worth of data to it.
-                        assert!(n != 0);
+                    None => {
+                        r.open::<_, DataHeader>(&mut m.flash, pp.page_id).await?;
+
+                        // open new page
+                        let mut w = m.allocate_page().await?;
+                        trace!(
+                            "writer: last page is not full. Copying it to new page {:?}",
+                            w.page_id()
+                        );
+
+                        let mut buf = [0; 128];
+                        loop {
+                            let n = r.read(&mut m.flash, &mut buf).await?;
+                            if n == 0 {
+                                break;
+                            }
+                            let mut data = &buf[..n];
+                            while !data.is_empty() {
+                                let n = m.write_page_data(&mut w, data).await?;
+                                data = &data[n..];
+
+                                // the new page can't get full, because we're not writing more
+                                // than 1 page worth of data to it.
+                                assert!(n != 0);
+                            }
+                        }
+
+                        this.writer = Some(w);
+                        this.rewritten_last_page_id = Some(pp.page_id);
                     }
                 }
 
-                this.writer = Some(w);
                 this.seq = pp.header.seq;
                 this.at_record_boundary = true;
-                this.record_boundary = (pp.header.record_boundary != u16::MAX).then_some(pp.header.record_boundary);
+                // The end of the data is a record boundary too, the next record written starts there.
+                this.record_boundary = match pp.header.record_boundary {
+                    u16::MAX => Some(page_len as u16),
+                    b => Some(b),
+                };
                 this.last_page = pp.prev(m, f.first_seq).await?;
-                this.rewritten_last_page_id = Some(pp.page_id);
+                this.initial_last_page = this.last_page;
             }
         }
 
@@ -1363,7 +2823,6 @@ impl FileWriter {
         mut w: PageWriter<DataHeader>,
     ) -> Result<(), Error<F::Error>> {
         let page_size = w.len();
-        let page_id = w.page_id();
         let mut skiplist = [OptionPageID::none(); SKIPLIST_LEN];
         if let Some(last_page) = &self.last_page {
             skiplist = last_page.header.skiplist;
@@ -1378,13 +2837,32 @@ impl FileWriter {
             Some(b) if (b as usize) < page_size => b,
             _ => u16::MAX,
         };
-        let header = DataHeader {
-            seq: self.seq,
-            skiplist,
-            record_boundary,
+        // The page we appended to in place has its header already, unless it was relocated.
+        let appended = self.appended_page.take();
+        let (page_id, header) = loop {
+            let page_id = w.page_id();
+            let in_place = appended.filter(|pp| pp.page_id == page_id);
+            let header = match in_place {
+                Some(pp) => pp.header,
+                None => DataHeader {
+                    seq: self.seq,
+                    erase_count: w.erase_count(),
+                    skiplist,
+                    record_boundary,
+                },
+            };
+            let res: Result<(), Error<F::Error>> = try {
+                if in_place.is_none() {
+                    w.write_header(&mut m.flash, header).await?;
+                }
+                w.commit(&mut m.flash).await?;
+            };
+            match res {
+                Ok(()) => break (page_id, header),
+                // The header is written again to the new page.
+                Err(e) => m.relocate_page(&mut w, e).await?,
+            }
         };
-        w.write_header(&mut m.flash, header).await.map_err(Error::Flash)?;
-        w.commit(&mut m.flash).await?;
 
         trace!(
             "flush_header: page={:?} h={:?} record_boundary={:?}",
@@ -1395,6 +2873,13 @@ impl FileWriter {
 
         self.seq = next_seq;
         self.last_page = Some(PagePointer { page_id, header });
+        match appended {
+            // The page is still part of the file, only the pages after it are written by us.
+            Some(pp) if pp.page_id == page_id => self.initial_last_page = self.last_page,
+            // Its data was moved to a new page, it's no longer part of the file on commit.
+            Some(pp) => self.rewritten_last_page_id = Some(pp.page_id),
+            None => {}
+        }
 
         self.record_boundary = None;
         if self.at_record_boundary {
@@ -1409,21 +2894,24 @@ impl FileWriter {
             self.flush_header(m, w).await?;
         }
 
-        let page_id = m.alloc.allocate();
-        trace!("writer: allocated page {:?}", page_id);
-        self.writer = Some(m.write_page(page_id).await);
+        self.writer = Some(m.allocate_page().await?);
         Ok(())
     }
 
     pub async fn write<F: Flash>(&mut self, m: &mut FileManager<F>, mut data: &[u8]) -> Result<(), Error<F::Error>> {
         while !data.is_empty() {
+            if let (Some(_), Some(w)) = (self.appended_page, &self.writer) {
+                if data.len() >= w.chunk_space_left() {
+                    self.move_appended_page(m).await?;
+                }
+            }
             match &mut self.writer {
                 None => {
                     self.next_page(m).await?;
                     continue;
                 }
                 Some(w) => {
-                    let n = w.write(&mut m.flash, data).await?;
+                    let n = m.write_page_data(w, data).await?;
                     data = &data[n..];
                     if n == 0 {
                         self.next_page(m).await?;
@@ -1438,11 +2926,23 @@ impl FileWriter {
     }
 
     pub async fn commit<F: Flash>(&mut self, tx: &mut Transaction<'_, F>) -> Result<(), Error<F::Error>> {
+        // Data appended in place is visible as soon as its chunk is committed, it can't wait
+        // for the meta page write that commits the rest of the transaction.
+        self.move_appended_page(tx.m).await?;
+        if self.writer.is_some() {
+            tx.m.dirty = true;
+        }
+        self.commit_state(tx.m).await
+    }
+
+    /// Flush the last page, and update the in-memory file state with the written data.
+    async fn commit_state<F: Flash>(&mut self, m: &mut FileManager<F>) -> Result<(), Error<F::Error>> {
+        m.clear_pending(self.file_id);
+
         if let Some(w) = self.writer.take() {
-            self.flush_header(tx.m, w).await?;
+            self.flush_header(m, w).await?;
 
-            tx.m.dirty = true;
-            let f = &mut tx.m.files[self.file_id as usize];
+            let f = &mut m.files[self.file_id as usize];
             let old_f = *f;
             f.last_page = self.last_page;
             f.last_seq = self.seq;
@@ -1459,25 +2959,109 @@ impl FileWriter {
 
             if let Some(rewritten_page_id) = self.rewritten_last_page_id {
                 trace!("freeing rewritten page {:?}", rewritten_page_id);
-                tx.m.alloc.free(rewritten_page_id)?;
+                m.alloc.free(rewritten_page_id)?;
             }
         }
         Ok(())
     }
 
+    /// Commit the data appended in place to the last page of the file, if that's all that was written.
+    ///
+    /// The file meta doesn't change, so committing the chunk is all that's needed, no meta page write.
+    /// Returns `false` if not appending in place, then it has to be committed with a transaction.
+    async fn commit_in_place<F: Flash>(&mut self, m: &mut FileManager<F>) -> Result<bool, Error<F::Error>> {
+        let (Some(pp), Some(w)) = (self.appended_page, &mut self.writer) else {
+            return Ok(false);
+        };
+        m.clear_pending(self.file_id);
+
+        if let Err(e) = w.commit(&mut m.flash).await {
+            // The data is moved to a new page, that replaces the failed one on commit.
+            m.relocate_page(w, e).await?;
+            self.appended_page = None;
+            self.rewritten_last_page_id = Some(pp.page_id);
+            return Ok(false);
+        }
+
+        let last_seq = self.seq.add(w.len())?;
+        trace!(
+            "commit in place file_id={:?} page={:?} last_seq={:?}",
+            self.file_id,
+            pp.page_id,
+            last_seq
+        );
+        m.files[self.file_id as usize].last_seq = last_seq;
+        self.writer = None;
+        self.appended_page = None;
+        Ok(true)
+    }
+
+    /// Move the last page of the file we're appending to in place to a new page, along with the data
+    /// appended to it, so it can be committed in more than one chunk.
+    async fn move_appended_page<F: Flash>(&mut self, m: &mut FileManager<F>) -> Result<(), Error<F::Error>> {
+        let (Some(pp), Some(w)) = (self.appended_page, &mut self.writer) else {
+            return Ok(());
+        };
+        m.move_page(w).await?;
+        trace!("writer: moved appended page {:?} to {:?}", pp.page_id, w.page_id());
+        self.appended_page = None;
+        self.rewritten_last_page_id = Some(pp.page_id);
+        Ok(())
+    }
+
     pub async fn discard<F: Flash>(&mut self, m: &mut FileManager<F>) -> Result<(), Error<F::Error>> {
+        m.clear_pending(self.file_id);
+
         if let Some(w) = &self.writer {
-            // Free the page we're writing now (not yet committed)
+            // Free the page we're writing now (not yet committed), unless it's the last page of the file
+            // we're appending to in place. The data appended to it is not committed, so it's not visible.
             let page_id = w.page_id();
-            m.free_page(page_id)?;
+            if self.appended_page.is_none() {
+                m.free_page(page_id)?;
+            }
 
             // Free previous pages, if any
-            let f = &m.files[self.file_id as usize];
-            m.free_between(self.last_page, f.last_page, Seq::ZERO).await?;
+            m.free_between(self.last_page, self.initial_last_page, Seq::ZERO)
+                .await?;
         };
         Ok(())
     }
 
+    /// Make the data written so far visible to [`FileManager::read_pending`], without committing it.
+    ///
+    /// The data in the page being written is read from its committed chunks, and from the flash and the
+    /// writer's state for the current one, so this doesn't write to flash. With encryption, the current
+    /// chunk is only encrypted on commit, so it's committed. The snapshot is dropped when the writer is
+    /// committed or discarded.
+    pub async fn snapshot<F: Flash>(&mut self, m: &mut FileManager<F>) -> Result<(), Error<F::Error>> {
+        // Committing the chunk would make data appended in place visible.
+        #[cfg(feature = "encryption")]
+        self.move_appended_page(m).await?;
+
+        let mut last_seq = self.seq;
+        let mut tail = None;
+        if let Some(w) = &mut self.writer {
+            if w.len() != 0 {
+                #[cfg(feature = "encryption")]
+                m.commit_page(w).await?;
+                tail = Some((w.page_id(), self.seq, w.pending_chunk()));
+                last_seq = self.seq.add(w.len())?;
+            }
+        }
+
+        let f = m.files[self.file_id as usize];
+        m.pending = Some(PendingFile {
+            file_id: self.file_id,
+            state: FileState {
+                last_page: self.last_page,
+                last_seq,
+                ..f
+            },
+            tail,
+        });
+        Ok(())
+    }
+
     pub fn record_end(&mut self) {
         if self.record_boundary.is_none() {
             self.record_boundary = Some(self.writer.as_mut().unwrap().len().try_into().unwrap());
@@ -1580,11 +3164,13 @@ fn skiplist_seq(curr: Seq, index: usize) -> Seq {
 }
 
 #[cfg(test)]
+#[allow(clippy::unnecessary_mut_passed)]
 mod tests {
     use rand::Rng;
 
     use super::*;
-    use crate::flash::MemFlash;
+    use crate::flash::{BadPageFlash, MemFlash};
+    use crate::record::CheckProblem;
     use crate::types::RawPageID;
 
     /// Big amount of data that it's reasonable to write to a file.
@@ -1972,19 +3558,67 @@ mod tests {
         let mut r = m.read(&mut pr, 0);
         let mut buf = [0; 5];
         r.read(&mut m, &mut buf).await.unwrap();
-        assert_eq!(buf, [1, 2, 3, 4, 5]);
+        assert_eq!(buf, [1, 2, 3, 4, 5]);
+        let mut buf = [0; 1];
+        let res = r.read(&mut m, &mut buf).await;
+        assert!(matches!(res, Err(ReadError::Eof)));
+
+        let mut w = m.write(&mut pr, 0).await.unwrap();
+        w.write(&mut m, &[10, 11]).await.unwrap();
+        m.commit(&mut w).await.unwrap();
+
+        let mut r = m.read(&mut pr, 0);
+        let mut buf = [0; 7];
+        r.read(&mut m, &mut buf).await.unwrap();
+        assert_eq!(buf, [1, 2, 3, 4, 5, 10, 11]);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_append_in_place() {
+        let mut f = MemFlash::new();
+        let mut m = FileManager::new(&mut f, 0);
+        let mut pr = PageReader::new();
+        m.format().await.unwrap();
+        m.mount(&mut pr).await.unwrap();
+
+        let mut w = m.write(&mut pr, 0).await.unwrap();
+        w.write(&mut m, &[1, 2, 3, 4, 5]).await.unwrap();
+        m.commit(&mut w).await.unwrap();
+        let last_page = m.files[0].last_page.unwrap().page_id;
+
+        // Data appended in place is committed by committing its chunk, it doesn't need a new page.
+        let mut w = m.write(&mut pr, 0).await.unwrap();
+        w.write(&mut m, &[6, 7, 8]).await.unwrap();
+        m.commit(&mut w).await.unwrap();
+        assert_eq!(m.files[0].last_page.unwrap().page_id, last_page);
+
+        // Data appended but not committed is not visible, even after remounting. Without encryption,
+        // it's written to flash already, because it's a whole `ALIGN` block.
+        let mut w = m.write(&mut pr, 0).await.unwrap();
+        w.write(&mut m, &[9; ALIGN]).await.unwrap();
+        w.discard(&mut m).await.unwrap();
+        m.mount(&mut pr).await.unwrap();
+
+        let mut r = m.read(&mut pr, 0);
+        let mut buf = [0; 8];
+        r.read(&mut m, &mut buf).await.unwrap();
+        assert_eq!(buf, [1, 2, 3, 4, 5, 6, 7, 8]);
         let mut buf = [0; 1];
         let res = r.read(&mut m, &mut buf).await;
         assert!(matches!(res, Err(ReadError::Eof)));
 
+        // Then the rest of the page is not erased anymore, so the next append copies it to a new page.
         let mut w = m.write(&mut pr, 0).await.unwrap();
-        w.write(&mut m, &[10, 11]).await.unwrap();
+        w.write(&mut m, &[11]).await.unwrap();
         m.commit(&mut w).await.unwrap();
+        #[cfg(not(feature = "encryption"))]
+        assert_ne!(m.files[0].last_page.unwrap().page_id, last_page);
 
+        m.mount(&mut pr).await.unwrap();
         let mut r = m.read(&mut pr, 0);
-        let mut buf = [0; 7];
+        let mut buf = [0; 9];
         r.read(&mut m, &mut buf).await.unwrap();
-        assert_eq!(buf, [1, 2, 3, 4, 5, 10, 11]);
+        assert_eq!(buf, [1, 2, 3, 4, 5, 6, 7, 8, 11]);
     }
 
     #[test_log::test(tokio::test)]
@@ -2193,8 +3827,8 @@ mod tests {
         let mut w = m.write(&mut pr, 0).await.unwrap();
         w.write(&mut m, &data).await.unwrap();
         assert_eq!(m.alloc.is_used(page(0)), true);
-        assert_eq!(m.alloc.is_used(page(1)), true);
-        assert_eq!(m.alloc.is_used(page(2)), true);
+        assert_eq!(m.alloc.is_used(page(1)), true); // appended in-place.
+        assert_eq!(m.alloc.is_used(page(2)), false);
         assert_eq!(m.alloc.is_used(page(3)), false);
 
         w.discard(&mut m).await.unwrap();
@@ -2436,6 +4070,36 @@ mod tests {
         assert_eq!(h.record_boundary, u16::MAX);
     }
 
+    #[test_log::test(tokio::test)]
+    async fn test_record_boundary_append() {
+        let mut f = MemFlash::new();
+        let mut m = FileManager::new(&mut f, 0);
+        let mut pr = PageReader::new();
+        m.format().await.unwrap();
+        m.mount(&mut pr).await.unwrap();
+
+        // The last page has only the end of a record, so no boundary.
+        let mut w = m.write(&mut pr, 1).await.unwrap();
+        w.write(&mut m, &[0x00; PAGE_MAX_PAYLOAD_SIZE + PAGE_MAX_PAYLOAD_SIZE / 2])
+            .await
+            .unwrap();
+        w.record_end();
+        m.commit(&mut w).await.unwrap();
+        assert_eq!(m.files[1].last_page.unwrap().header.record_boundary, u16::MAX);
+
+        // Appending copies it to a new page, where the new record starts after the copied data.
+        let mut w = m.write(&mut pr, 1).await.unwrap();
+        w.write(&mut m, &[0x00; PAGE_MAX_PAYLOAD_SIZE]).await.unwrap();
+        w.record_end();
+        m.commit(&mut w).await.unwrap();
+
+        let last = m.files[1].last_page.unwrap();
+        assert_eq!(last.header.record_boundary, u16::MAX);
+        let prev = last.prev(&mut m, Seq::ZERO).await.unwrap().unwrap();
+        assert_eq!(prev.header.seq, Seq(PAGE_MAX_PAYLOAD_SIZE as u32));
+        assert_eq!(prev.header.record_boundary, (PAGE_MAX_PAYLOAD_SIZE / 2) as u16);
+    }
+
     #[test_log::test(tokio::test)]
     async fn test_record_boundary_overlong_3() {
         let mut f = MemFlash::new();
@@ -2626,6 +4290,47 @@ mod tests {
         assert_eq!(s.reader().offset(&mut m), PAGE_MAX_PAYLOAD_SIZE);
     }
 
+    #[test_log::test(tokio::test)]
+    async fn test_search_pending() {
+        let mut f = MemFlash::new();
+        let mut m = FileManager::new(&mut f, 0);
+        let mut pr = PageReader::new();
+        m.format().await.unwrap();
+        m.mount(&mut pr).await.unwrap();
+
+        let mut w = m.write(&mut pr, 1).await.unwrap();
+        for i in 1..=2 {
+            w.write(&mut m, &[i; PAGE_MAX_PAYLOAD_SIZE]).await.unwrap();
+            w.record_end();
+        }
+        w.write(&mut m, &[3; 10]).await.unwrap();
+        w.record_end();
+
+        // Taking the snapshot doesn't write to flash.
+        #[cfg(not(feature = "encryption"))]
+        let write_count = m.flash.write_count;
+        w.snapshot(&mut m).await.unwrap();
+        #[cfg(not(feature = "encryption"))]
+        assert_eq!(m.flash.write_count, write_count);
+
+        // The pages before the one being written are binary searched.
+        let mut s = FileSearcher::new(m.read_pending(&mut pr, 1));
+        assert_eq!(s.start(&mut m).await.unwrap(), true);
+        assert_eq!(s.reader().offset(&m), PAGE_MAX_PAYLOAD_SIZE);
+        assert_eq!(s.seek(&mut m, SeekDirection::Right).await.unwrap(), false);
+        assert_eq!(s.reader().offset(&m), PAGE_MAX_PAYLOAD_SIZE);
+
+        // Reading goes on through it.
+        let mut buf = [0; PAGE_MAX_PAYLOAD_SIZE];
+        s.reader().read(&mut m, &mut buf).await.unwrap();
+        assert_eq!(buf, [2; PAGE_MAX_PAYLOAD_SIZE]);
+        let mut buf = [0; 10];
+        s.reader().read(&mut m, &mut buf).await.unwrap();
+        assert_eq!(buf, [3; 10]);
+        let res = s.reader().read(&mut m, &mut buf[..1]).await;
+        assert!(matches!(res, Err(ReadError::Eof)));
+    }
+
     #[test_log::test(tokio::test)]
     async fn test_search_no_boundary_on_second_page() {
         let mut f = MemFlash::new();
@@ -2881,6 +4586,528 @@ mod tests {
         rand::thread_rng().gen_range(from..=to)
     }
 
+    /// Write a newer meta page with the given header fields, as if written by another ekv build.
+    async fn write_foreign_meta(m: &mut FileManager<&mut MemFlash>, format_version: u32, config_fingerprint: u32) {
+        let mut w = m.allocate_page().await.unwrap();
+        let h = MetaHeader {
+            page_count: m.page_count() as u32,
+            seq: m.meta_seq.add(1).unwrap(),
+            erase_count: w.erase_count(),
+            format_version,
+            config_fingerprint,
+        };
+        w.write_header(&mut m.flash, h).await.unwrap();
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test_log::test(tokio::test)]
+    async fn test_encryption_generation_remount() {
+        let mut f = MemFlash::new();
+
+        let generation = {
+            let mut m = FileManager::new(&mut f, 0);
+            m.set_cipher(Some(&crate::cipher::test::CIPHER));
+            let mut pr = PageReader::new();
+            m.format().await.unwrap();
+            m.mount(&mut pr).await.unwrap();
+
+            for _ in 0..10 {
+                let mut w = m.write(&mut pr, 0).await.unwrap();
+                w.write(&mut m, &dummy_data(PAGE_SIZE)).await.unwrap();
+                m.commit(&mut w).await.unwrap();
+            }
+            m.flash.generation
+        };
+
+        // Mounting again continues after the last generation used.
+        let mut m = FileManager::new(&mut f, 0);
+        m.set_cipher(Some(&crate::cipher::test::CIPHER));
+        let mut pr = PageReader::new();
+        m.mount(&mut pr).await.unwrap();
+        assert_eq!(m.flash.generation, generation);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_erase_counts() {
+        let mut f = MemFlash::new();
+
+        let counts = {
+            let mut m = FileManager::new(&mut f, 0);
+            let mut pr = PageReader::new();
+            m.format().await.unwrap();
+            m.mount(&mut pr).await.unwrap();
+            let base = m.erase_counts().await.unwrap();
+            assert_eq!(base.page_count, MAX_PAGE_COUNT);
+            let base_erases = m.flash().erase_count;
+
+            for _ in 0..10 {
+                let mut w = m.write(&mut pr, 0).await.unwrap();
+                w.write(&mut m, &dummy_data(PAGE_SIZE)).await.unwrap();
+                m.commit(&mut w).await.unwrap();
+            }
+
+            // All erases are counted.
+            let counts = m.erase_counts().await.unwrap();
+            let erases = m.flash().erase_count - base_erases;
+            assert!(erases > 0);
+            assert_eq!(counts.total - base.total, erases as u64);
+            assert_eq!(m.max_erase_count().await.unwrap(), counts.max);
+            counts
+        };
+
+        // Remounting reads the counts from the page headers. Pages without a
+        // header get the highest count, so counts never go down.
+        let mut m = FileManager::new(&mut f, 0);
+        let mut pr = PageReader::new();
+        m.mount(&mut pr).await.unwrap();
+        let new_counts = m.erase_counts().await.unwrap();
+        assert_eq!(new_counts.max, counts.max);
+        assert!(new_counts.min >= counts.min);
+        assert!(new_counts.total >= counts.total);
+        let counts = new_counts;
+        m.mount(&mut pr).await.unwrap();
+        assert_eq!(m.erase_counts().await.unwrap(), counts);
+
+        m.format().await.unwrap();
+        m.mount(&mut pr).await.unwrap();
+        let new_counts = m.erase_counts().await.unwrap();
+        assert!(new_counts.min >= counts.min);
+        assert!(new_counts.total > counts.total);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_bad_page_erase() {
+        let mut f = MemFlash::new();
+        let mut f = BadPageFlash::new(&mut f);
+        let mut m = FileManager::new(&mut f, 0);
+        let mut pr = PageReader::new();
+        m.format().await.unwrap();
+        m.mount(&mut pr).await.unwrap();
+
+        // Small pages don't have room to list many retired pages in the meta page.
+        let count = MAX_BAD_PAGE_COUNT.min(7);
+        if count == 0 {
+            return;
+        }
+        for page_id in 0..MAX_PAGE_COUNT {
+            if page_id != m.meta_page_id.index() {
+                m.flash_mut().bad_erase[page_id] = true;
+            }
+            if m.flash().bad_erase.iter().filter(|&&b| b).count() == count {
+                break;
+            }
+        }
+
+        // Pages failing to erase are retired, and others are used instead.
+        let data = dummy_data(PAGE_SIZE * 4);
+        let mut w = m.write(&mut pr, 1).await.unwrap();
+        w.write(&mut m, &data).await.unwrap();
+        m.commit(&mut w).await.unwrap();
+        let bad_pages = m.bad_pages();
+        assert!(bad_pages > 0);
+        assert!(bad_pages <= count);
+        for page_id in 0..MAX_PAGE_COUNT {
+            if m.alloc.is_bad(page(page_id as _)) {
+                assert!(m.flash().bad_erase[page_id]);
+            }
+        }
+
+        let mut r = m.read(&mut pr, 1);
+        let mut buf = vec![0; data.len()];
+        r.read(&mut m, &mut buf).await.unwrap();
+        assert_eq!(data, buf);
+
+        // Retired pages are persisted in the meta page.
+        let mut f = BadPageFlash::new(f.flash);
+        let mut m = FileManager::new(&mut f, 0);
+        m.mount(&mut pr).await.unwrap();
+        assert_eq!(m.bad_pages(), bad_pages);
+        let mut r = m.read(&mut pr, 1);
+        let mut buf = vec![0; data.len()];
+        r.read(&mut m, &mut buf).await.unwrap();
+        assert_eq!(data, buf);
+
+        // Formatting keeps them retired.
+        m.format().await.unwrap();
+        m.mount(&mut pr).await.unwrap();
+        assert_eq!(m.bad_pages(), bad_pages);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_bad_page_write() {
+        // Small pages don't have room to list many retired pages in the meta page.
+        if MAX_BAD_PAGE_COUNT < 2 {
+            return;
+        }
+
+        let mut f = MemFlash::new();
+        let mut f = BadPageFlash::new(&mut f);
+        let mut m = FileManager::new(&mut f, 0);
+        let mut pr = PageReader::new();
+        m.format().await.unwrap();
+        m.mount(&mut pr).await.unwrap();
+
+        // Failing to append to the meta page writes a new one.
+        let old_meta_page_id = m.meta_page_id;
+        m.flash_mut().bad_write[old_meta_page_id.index()] = true;
+        let data = dummy_data(PAGE_SIZE / 4);
+        let mut w = m.write(&mut pr, 1).await.unwrap();
+        w.write(&mut m, &data).await.unwrap();
+        m.commit(&mut w).await.unwrap();
+        assert!(m.alloc.is_bad(old_meta_page_id));
+        assert_ne!(m.meta_page_id, old_meta_page_id);
+
+        let bad_pages = m.bad_pages();
+        m.mount(&mut pr).await.unwrap();
+        assert_eq!(m.bad_pages(), bad_pages);
+        let mut r = m.read(&mut pr, 1);
+        let mut buf = vec![0; data.len()];
+        r.read(&mut m, &mut buf).await.unwrap();
+        assert_eq!(data, buf);
+
+        // Writes to empty pages are retried on other pages.
+        let next = m.meta_page_id.index() + 1;
+        for page_id in (next..MAX_PAGE_COUNT).take(MAX_BAD_PAGE_COUNT.min(MAX_PAGE_COUNT / 4).min(8) - 2) {
+            m.flash_mut().bad_write[page_id] = true;
+        }
+        let data = dummy_data(PAGE_SIZE * 4);
+        let mut w = m.write(&mut pr, 2).await.unwrap();
+        w.write(&mut m, &data).await.unwrap();
+        m.commit(&mut w).await.unwrap();
+        let mut r = m.read(&mut pr, 2);
+        let mut buf = vec![0; data.len()];
+        r.read(&mut m, &mut buf).await.unwrap();
+        assert_eq!(data, buf);
+
+        // Failing in the middle of a page relocates the data written to it to another page.
+        let data = dummy_data(PAGE_SIZE / 4 + PAGE_SIZE);
+        let mut w = m.write(&mut pr, 3).await.unwrap();
+        w.write(&mut m, &data[..PAGE_SIZE / 4]).await.unwrap();
+        let page_id = w.writer.as_ref().unwrap().page_id();
+        m.flash_mut().bad_write[page_id.index()] = true;
+        w.write(&mut m, &data[PAGE_SIZE / 4..]).await.unwrap();
+        m.commit(&mut w).await.unwrap();
+        assert!(m.alloc.is_bad(page_id));
+        let mut r = m.read(&mut pr, 3);
+        let mut buf = vec![0; data.len()];
+        r.read(&mut m, &mut buf).await.unwrap();
+        assert_eq!(data, buf);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_verify_writes() {
+        // Small pages don't have room to list many retired pages in the meta page.
+        let count = MAX_BAD_PAGE_COUNT.min(4);
+        if count == 0 {
+            return;
+        }
+
+        let mut f = MemFlash::new();
+        let mut f = BadPageFlash::new(&mut f);
+        let mut m = FileManager::new(&mut f, 0);
+        let mut pr = PageReader::new();
+        m.set_verify_writes(true);
+        m.format().await.unwrap();
+        m.mount(&mut pr).await.unwrap();
+
+        // Pages where data is written wrong are detected, retired, and their data moved to other pages.
+        let next = m.meta_page_id.index() + 1;
+        for page_id in (next..MAX_PAGE_COUNT).take(count) {
+            m.flash_mut().flip_write[page_id] = true;
+        }
+        let data = dummy_data(PAGE_SIZE * 4);
+        let mut w = m.write(&mut pr, 1).await.unwrap();
+        w.write(&mut m, &data[..PAGE_SIZE / 2]).await.unwrap();
+        w.write(&mut m, &data[PAGE_SIZE / 2..]).await.unwrap();
+        m.commit(&mut w).await.unwrap();
+        assert_eq!(m.bad_pages(), count);
+
+        m.mount(&mut pr).await.unwrap();
+        assert_eq!(m.bad_pages(), count);
+        let mut r = m.read(&mut pr, 1);
+        let mut buf = vec![0; data.len()];
+        r.read(&mut m, &mut buf).await.unwrap();
+        assert_eq!(data, buf);
+    }
+
+    async fn check_file(m: &mut FileManager<&mut MemFlash>, pr: &mut PageReader, file_id: FileID, data: &[u8]) {
+        let mut r = m.read(pr, file_id);
+        let mut buf = vec![0; data.len()];
+        r.read(m, &mut buf).await.unwrap();
+        assert_eq!(data, buf);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_redundant_meta() {
+        let mut f = MemFlash::new();
+        let data = dummy_data(PAGE_SIZE * 2);
+        let (meta_page_id, copy_page_id) = {
+            let mut m = FileManager::new(&mut f, 0);
+            let mut pr = PageReader::new();
+            m.set_redundant_meta(true);
+            m.format().await.unwrap();
+            m.mount(&mut pr).await.unwrap();
+
+            let mut w = m.write(&mut pr, 1).await.unwrap();
+            w.write(&mut m, &data).await.unwrap();
+            m.commit(&mut w).await.unwrap();
+            (m.meta_page_id, m.meta_copy_page_id.unwrap())
+        };
+        assert_ne!(meta_page_id, copy_page_id);
+        let image = f.data.clone();
+
+        // Mounting works with either copy erased, and the next commit writes both again.
+        for page_id in [meta_page_id, copy_page_id] {
+            f.data.copy_from_slice(&image);
+            f.data[page_id.index() * PAGE_SIZE..][..PAGE_SIZE].fill(ERASE_VALUE);
+
+            let mut m = FileManager::new(&mut f, 0);
+            let mut pr = PageReader::new();
+            m.set_redundant_meta(true);
+            m.mount(&mut pr).await.unwrap();
+            check_file(&mut m, &mut pr, 1, &data).await;
+            assert!(m.meta_rewrite);
+
+            let mut w = m.write(&mut pr, 2).await.unwrap();
+            w.write(&mut m, &data).await.unwrap();
+            m.commit(&mut w).await.unwrap();
+            assert!(m.meta_copy_page_id.is_some());
+
+            m.mount(&mut pr).await.unwrap();
+            assert!(!m.meta_rewrite);
+            check_file(&mut m, &mut pr, 1, &data).await;
+            check_file(&mut m, &mut pr, 2, &data).await;
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_redundant_meta_torn_commit() {
+        let mut f = MemFlash::new();
+        let data = dummy_data(PAGE_SIZE / 4);
+        let (meta_page_id, copy_page_id, before) = {
+            let mut m = FileManager::new(&mut f, 0);
+            let mut pr = PageReader::new();
+            m.set_redundant_meta(true);
+            m.format().await.unwrap();
+            m.mount(&mut pr).await.unwrap();
+            let before = m.flash_mut().data.clone();
+
+            let mut w = m.write(&mut pr, 1).await.unwrap();
+            w.write(&mut m, &data).await.unwrap();
+            m.commit(&mut w).await.unwrap();
+            (m.meta_page_id, m.meta_copy_page_id.unwrap(), before)
+        };
+        let after = f.data.clone();
+
+        // If power is lost before the commit is written to one of the copies, the other one is used.
+        for page_id in [meta_page_id, copy_page_id] {
+            f.data.copy_from_slice(&after);
+            let page = page_id.index() * PAGE_SIZE..(page_id.index() + 1) * PAGE_SIZE;
+            f.data[page.clone()].copy_from_slice(&before[page]);
+
+            let mut m = FileManager::new(&mut f, 0);
+            let mut pr = PageReader::new();
+            m.set_redundant_meta(true);
+            m.mount(&mut pr).await.unwrap();
+            assert_ne!(m.meta_page_id, page_id);
+            assert!(m.meta_rewrite);
+            check_file(&mut m, &mut pr, 1, &data).await;
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_redundant_meta_switch() {
+        let mut f = MemFlash::new();
+        let mut m = FileManager::new(&mut f, 0);
+        let mut pr = PageReader::new();
+        m.format().await.unwrap();
+        m.mount(&mut pr).await.unwrap();
+        let data = dummy_data(PAGE_SIZE / 4);
+        let used_pages = m.used_pages();
+
+        // Meta pages are rewritten as configured at the next commit.
+        for (file_id, redundant) in [(1, true), (2, false)] {
+            m.set_redundant_meta(redundant);
+            m.mount(&mut pr).await.unwrap();
+            assert!(m.meta_rewrite);
+
+            let mut w = m.write(&mut pr, file_id).await.unwrap();
+            w.write(&mut m, &data).await.unwrap();
+            m.commit(&mut w).await.unwrap();
+            assert_eq!(m.meta_copy_page_id.is_some(), redundant);
+            assert_eq!(m.used_pages(), used_pages + file_id as usize + redundant as usize);
+
+            m.mount(&mut pr).await.unwrap();
+            assert!(!m.meta_rewrite);
+            assert_eq!(m.meta_copy_page_id.is_some(), redundant);
+            check_file(&mut m, &mut pr, 1, &data).await;
+        }
+        check_file(&mut m, &mut pr, 2, &data).await;
+    }
+
+    async fn rewrite_header(m: &mut FileManager<&mut MemFlash>, page_id: PageID, header: DataHeader) {
+        let offset = page_id.index() * PAGE_SIZE;
+        m.flash_mut().data[offset..][..page::page_header_size::<DataHeader>()].fill(ERASE_VALUE);
+        page::write_header(&mut m.flash, page_id, header, false).await.unwrap();
+    }
+
+    async fn check_pages(
+        m: &mut FileManager<&mut MemFlash>,
+        pr: &mut PageReader,
+        file_id: FileID,
+    ) -> Vec<CheckProblem> {
+        let mut report = CheckReport::new();
+        m.check_pages(pr, file_id, &mut report).await.unwrap();
+        report.problems().to_vec()
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_check_pages() {
+        let mut f = MemFlash::new();
+        let mut m = FileManager::new(&mut f, 0);
+        let mut pr = PageReader::new();
+        m.format().await.unwrap();
+        m.mount(&mut pr).await.unwrap();
+
+        let data = dummy_data(PAGE_MAX_PAYLOAD_SIZE * 3 + PAGE_MAX_PAYLOAD_SIZE / 2);
+        let mut w = m.write(&mut pr, 1).await.unwrap();
+        w.write(&mut m, &data).await.unwrap();
+        w.record_end();
+        m.commit(&mut w).await.unwrap();
+
+        let mut report = CheckReport::new();
+        assert!(m.check_pages(&mut pr, 1, &mut report).await.unwrap());
+        assert_eq!(report.page_count, 4);
+
+        let last = m.files[1].last_page.unwrap();
+        let prev = last.prev(&mut m, Seq::ZERO).await.unwrap().unwrap();
+        let first = m.get_file_page(1, false, Seq::ZERO).await.unwrap().unwrap();
+        let problem = |page_id: PageID, kind| CheckProblem {
+            file_id: 1,
+            page_id: Some(page_id),
+            kind,
+        };
+
+        // Wrong skiplist pointer.
+        let mut h = last.header;
+        h.skiplist[1] = match h.skiplist[1].into_option() {
+            Some(_) => OptionPageID::none(),
+            None => last.page_id.into(),
+        };
+        rewrite_header(&mut m, last.page_id, h).await;
+        let problems = check_pages(&mut m, &mut pr, 1).await;
+        assert_eq!(problems, [problem(last.page_id, CheckProblemKind::Skiplist)]);
+        rewrite_header(&mut m, last.page_id, last.header).await;
+
+        // Record boundary past the end of the page.
+        let mut h = last.header;
+        h.record_boundary = (PAGE_MAX_PAYLOAD_SIZE / 2) as u16;
+        rewrite_header(&mut m, last.page_id, h).await;
+        let problems = check_pages(&mut m, &mut pr, 1).await;
+        assert_eq!(problems, [problem(last.page_id, CheckProblemKind::RecordBoundary)]);
+        rewrite_header(&mut m, last.page_id, last.header).await;
+
+        // Page data not lining up with the next page.
+        let mut h = prev.header;
+        h.seq = Seq(h.seq.0 + 1);
+        rewrite_header(&mut m, prev.page_id, h).await;
+        let problems = check_pages(&mut m, &mut pr, 1).await;
+        assert!(problems.contains(&problem(prev.page_id, CheckProblemKind::Seq)));
+        rewrite_header(&mut m, prev.page_id, prev.header).await;
+
+        assert_eq!(check_pages(&mut m, &mut pr, 1).await, []);
+
+        // Corrupted chunk data.
+        #[cfg(feature = "crc")]
+        {
+            let offset =
+                prev.page_id.index() * PAGE_SIZE + page::page_header_size::<DataHeader>() + page::CHUNK_HEADER_SIZE;
+            m.flash_mut().data[offset] ^= 0x01;
+            let problems = check_pages(&mut m, &mut pr, 1).await;
+            assert_eq!(problems, [problem(prev.page_id, CheckProblemKind::Chunk)]);
+            m.flash_mut().data[offset] ^= 0x01;
+        }
+
+        // Corrupted page header. The pages before it can't be found.
+        m.flash_mut().data[first.page_id.index() * PAGE_SIZE] ^= 0x01;
+        let problems = check_pages(&mut m, &mut pr, 1).await;
+        assert_eq!(problems, [problem(first.page_id, CheckProblemKind::PageHeader)]);
+    }
+
+    async fn check_record_boundaries(
+        m: &mut FileManager<&mut MemFlash>,
+        pr: &mut PageReader,
+        seqs: &[u32],
+    ) -> Vec<PageID> {
+        let mut report = CheckReport::new();
+        let mut c = RecordBoundaryCheck::new(1);
+        for &seq in seqs {
+            c.record(m, pr, Seq(seq), &mut report).await.unwrap();
+        }
+        c.finish(m, &mut report).await.unwrap();
+        report.problems().iter().map(|p| p.page_id.unwrap()).collect()
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_check_record_boundaries() {
+        let mut f = MemFlash::new();
+        let mut m = FileManager::new(&mut f, 0);
+        let mut pr = PageReader::new();
+        m.format().await.unwrap();
+        m.mount(&mut pr).await.unwrap();
+
+        // Records starting at 0 and 10 in the first page, none in the second one, and one at 10 in the third one.
+        const P: u32 = PAGE_MAX_PAYLOAD_SIZE as u32;
+        let mut w = m.write(&mut pr, 1).await.unwrap();
+        for len in [10, PAGE_MAX_PAYLOAD_SIZE * 2, 10] {
+            w.write(&mut m, &dummy_data(len)).await.unwrap();
+            w.record_end();
+        }
+        m.commit(&mut w).await.unwrap();
+        let pages = [page(1), page(2), page(3)];
+
+        assert_eq!(check_record_boundaries(&mut m, &mut pr, &[0, 10, 2 * P + 10]).await, []);
+        // Page pointing to a record that isn't there.
+        assert_eq!(check_record_boundaries(&mut m, &mut pr, &[0, 10]).await, [pages[2]]);
+        assert_eq!(
+            check_record_boundaries(&mut m, &mut pr, &[0, 10, 2 * P + 11]).await,
+            [pages[2]]
+        );
+        // Page with no boundary, but a record starting in it.
+        assert_eq!(
+            check_record_boundaries(&mut m, &mut pr, &[0, 10, P + 5, 2 * P + 10]).await,
+            [pages[1]]
+        );
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_mount_config_mismatch() {
+        let mut f = MemFlash::new();
+        let mut m = FileManager::new(&mut f, 0);
+        let mut pr = PageReader::new();
+        m.format().await.unwrap();
+        m.mount(&mut pr).await.unwrap();
+
+        write_foreign_meta(&mut m, FORMAT_VERSION, CONFIG_FINGERPRINT ^ 1).await;
+        assert_eq!(m.mount(&mut pr).await, Err(MountError::ConfigMismatch));
+
+        // Formatting again fixes it.
+        m.format().await.unwrap();
+        m.mount(&mut pr).await.unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_mount_unsupported_version() {
+        let mut f = MemFlash::new();
+        let mut m = FileManager::new(&mut f, 0);
+        let mut pr = PageReader::new();
+        m.format().await.unwrap();
+        m.mount(&mut pr).await.unwrap();
+
+        write_foreign_meta(&mut m, FORMAT_VERSION + 1, CONFIG_FINGERPRINT).await;
+        assert_eq!(m.mount(&mut pr).await, Err(MountError::UnsupportedVersion));
+    }
+
     #[test_log::test(tokio::test)]
     async fn test_smoke() {
         let mut f = MemFlash::new();
@@ -1,16 +1,16 @@
 //! Flash storage trait.
 //!
 //! You must implement this trait for your flash storage to use it with `ekv`.
+//!
+//! If your flash driver implements the `embedded-storage` traits, you can use
+//! [`NorFlashAdapter`] or [`BlockingNorFlashAdapter`] instead, with the `embedded-storage` Cargo feature.
 
 use core::fmt::Debug;
 
-#[cfg(feature = "std")]
+#[cfg(any(feature = "std", feature = "embedded-storage"))]
 use crate::config::*;
 pub use crate::types::PageID;
 
-// TODO: use embedded-storage instead
-// or make an adapter instead?
-
 /// Flash storage trait
 pub trait Flash {
     /// Error type for the flash operations.
@@ -63,6 +63,187 @@ impl<T: Flash> Flash for &mut T {
     }
 }
 
+/// Location of the database within a NOR flash, shared by the `embedded-storage` adapters.
+#[cfg(feature = "embedded-storage")]
+struct NorFlashRegion {
+    base: u32,
+    page_count: usize,
+}
+
+#[cfg(feature = "embedded-storage")]
+impl NorFlashRegion {
+    fn new(
+        base: u32,
+        page_count: usize,
+        capacity: usize,
+        read_size: usize,
+        write_size: usize,
+        erase_size: usize,
+    ) -> Self {
+        assert!(
+            PAGE_SIZE % erase_size == 0,
+            "PAGE_SIZE must be a multiple of the flash erase size"
+        );
+        assert!(
+            ALIGN % write_size == 0,
+            "ALIGN must be a multiple of the flash write size"
+        );
+        assert!(
+            ALIGN % read_size == 0,
+            "ALIGN must be a multiple of the flash read size"
+        );
+        assert!(
+            base as usize % erase_size == 0,
+            "base must be aligned to the flash erase size"
+        );
+        assert!(
+            page_count <= MAX_PAGE_COUNT,
+            "page_count must not be higher than MAX_PAGE_COUNT"
+        );
+        assert!(
+            base as usize + page_count * PAGE_SIZE <= capacity,
+            "pages must be within the flash capacity"
+        );
+        Self { base, page_count }
+    }
+
+    fn address(&self, page_id: PageID, offset: usize) -> u32 {
+        self.base + (page_id.index() * PAGE_SIZE + offset) as u32
+    }
+}
+
+/// Adapter implementing [`Flash`] for a [`NorFlash`](embedded_storage_async::nor_flash::NorFlash).
+///
+/// The database uses `page_count` pages of `PAGE_SIZE` bytes, starting at byte address `base`
+/// of the flash.
+#[cfg(feature = "embedded-storage")]
+pub struct NorFlashAdapter<T> {
+    flash: T,
+    region: NorFlashRegion,
+}
+
+#[cfg(feature = "embedded-storage")]
+impl<T: embedded_storage_async::nor_flash::NorFlash> NorFlashAdapter<T> {
+    /// Create a new adapter.
+    ///
+    /// Panics if the flash can't be used with the current configuration: [`PAGE_SIZE`] must be a
+    /// multiple of `T::ERASE_SIZE`, and [`ALIGN`] a multiple of `T::WRITE_SIZE` and `T::READ_SIZE`.
+    /// Also panics if `base` is not aligned to `T::ERASE_SIZE`, `page_count` is higher than
+    /// [`MAX_PAGE_COUNT`], or the pages don't fit within the flash capacity.
+    pub fn new(flash: T, base: u32, page_count: usize) -> Self {
+        let region = NorFlashRegion::new(
+            base,
+            page_count,
+            flash.capacity(),
+            T::READ_SIZE,
+            T::WRITE_SIZE,
+            T::ERASE_SIZE,
+        );
+        Self { flash, region }
+    }
+
+    /// Get a reference to the underlying flash.
+    pub fn flash(&self) -> &T {
+        &self.flash
+    }
+
+    /// Get a mutable reference to the underlying flash.
+    pub fn flash_mut(&mut self) -> &mut T {
+        &mut self.flash
+    }
+
+    /// Consume the adapter, returning the underlying flash.
+    pub fn into_inner(self) -> T {
+        self.flash
+    }
+}
+
+#[cfg(feature = "embedded-storage")]
+impl<T: embedded_storage_async::nor_flash::NorFlash> Flash for NorFlashAdapter<T> {
+    type Error = T::Error;
+
+    fn page_count(&self) -> usize {
+        self.region.page_count
+    }
+
+    async fn erase(&mut self, page_id: PageID) -> Result<(), Self::Error> {
+        let from = self.region.address(page_id, 0);
+        self.flash.erase(from, from + PAGE_SIZE as u32).await
+    }
+
+    async fn read(&mut self, page_id: PageID, offset: usize, data: &mut [u8]) -> Result<(), Self::Error> {
+        self.flash.read(self.region.address(page_id, offset), data).await
+    }
+
+    async fn write(&mut self, page_id: PageID, offset: usize, data: &[u8]) -> Result<(), Self::Error> {
+        self.flash.write(self.region.address(page_id, offset), data).await
+    }
+}
+
+/// Adapter implementing [`Flash`] for a blocking [`NorFlash`](embedded_storage::nor_flash::NorFlash).
+///
+/// Same as [`NorFlashAdapter`], except flash operations block until done.
+#[cfg(feature = "embedded-storage")]
+pub struct BlockingNorFlashAdapter<T> {
+    flash: T,
+    region: NorFlashRegion,
+}
+
+#[cfg(feature = "embedded-storage")]
+impl<T: embedded_storage::nor_flash::NorFlash> BlockingNorFlashAdapter<T> {
+    /// Create a new adapter.
+    ///
+    /// Panics under the same conditions as [`NorFlashAdapter::new`].
+    pub fn new(flash: T, base: u32, page_count: usize) -> Self {
+        let region = NorFlashRegion::new(
+            base,
+            page_count,
+            flash.capacity(),
+            T::READ_SIZE,
+            T::WRITE_SIZE,
+            T::ERASE_SIZE,
+        );
+        Self { flash, region }
+    }
+
+    /// Get a reference to the underlying flash.
+    pub fn flash(&self) -> &T {
+        &self.flash
+    }
+
+    /// Get a mutable reference to the underlying flash.
+    pub fn flash_mut(&mut self) -> &mut T {
+        &mut self.flash
+    }
+
+    /// Consume the adapter, returning the underlying flash.
+    pub fn into_inner(self) -> T {
+        self.flash
+    }
+}
+
+#[cfg(feature = "embedded-storage")]
+impl<T: embedded_storage::nor_flash::NorFlash> Flash for BlockingNorFlashAdapter<T> {
+    type Error = T::Error;
+
+    fn page_count(&self) -> usize {
+        self.region.page_count
+    }
+
+    async fn erase(&mut self, page_id: PageID) -> Result<(), Self::Error> {
+        let from = self.region.address(page_id, 0);
+        self.flash.erase(from, from + PAGE_SIZE as u32)
+    }
+
+    async fn read(&mut self, page_id: PageID, offset: usize, data: &mut [u8]) -> Result<(), Self::Error> {
+        self.flash.read(self.region.address(page_id, offset), data)
+    }
+
+    async fn write(&mut self, page_id: PageID, offset: usize, data: &[u8]) -> Result<(), Self::Error> {
+        self.flash.write(self.region.address(page_id, offset), data)
+    }
+}
+
 /// Fake in-memory flash
 #[cfg(feature = "std")]
 pub struct MemFlash {
@@ -162,3 +343,195 @@ impl Flash for MemFlash {
         Ok(())
     }
 }
+
+/// In-memory flash where some pages fail to erase or write, for testing.
+#[cfg(test)]
+pub(crate) struct BadPageFlash<'a> {
+    pub flash: &'a mut MemFlash,
+    /// Pages that fail to erase. Their data is left as is.
+    pub bad_erase: [bool; MAX_PAGE_COUNT],
+    /// Pages that fail to write. Their data is left as is.
+    pub bad_write: [bool; MAX_PAGE_COUNT],
+    /// Pages where writes report success, but flip a bit of the written data.
+    pub flip_write: [bool; MAX_PAGE_COUNT],
+}
+
+#[cfg(test)]
+impl<'a> BadPageFlash<'a> {
+    pub fn new(flash: &'a mut MemFlash) -> Self {
+        Self {
+            flash,
+            bad_erase: [false; MAX_PAGE_COUNT],
+            bad_write: [false; MAX_PAGE_COUNT],
+            flip_write: [false; MAX_PAGE_COUNT],
+        }
+    }
+}
+
+#[cfg(test)]
+impl<'a> Flash for BadPageFlash<'a> {
+    type Error = ();
+
+    fn page_count(&self) -> usize {
+        self.flash.page_count()
+    }
+
+    async fn erase(&mut self, page_id: PageID) -> Result<(), Self::Error> {
+        if self.bad_erase[page_id.index()] {
+            return Err(());
+        }
+        unwrap!(self.flash.erase(page_id).await);
+        Ok(())
+    }
+
+    async fn read(&mut self, page_id: PageID, offset: usize, data: &mut [u8]) -> Result<(), Self::Error> {
+        unwrap!(self.flash.read(page_id, offset, data).await);
+        Ok(())
+    }
+
+    async fn write(&mut self, page_id: PageID, offset: usize, data: &[u8]) -> Result<(), Self::Error> {
+        if self.bad_write[page_id.index()] {
+            return Err(());
+        }
+        unwrap!(self.flash.write(page_id, offset, data).await);
+        if self.flip_write[page_id.index()] {
+            self.flash.data[page_id.index() * PAGE_SIZE + offset] ^= 0x01;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "embedded-storage"))]
+mod tests {
+    use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+    use embedded_storage::nor_flash::{ErrorType, NorFlashErrorKind};
+
+    use super::*;
+    use crate::{Config, Database};
+
+    /// NOR flash with some space before and after the database pages.
+    struct TestNorFlash {
+        data: Vec<u8>,
+    }
+
+    const BASE: u32 = PAGE_SIZE as u32;
+
+    impl TestNorFlash {
+        fn new() -> Self {
+            Self {
+                data: vec![0x55; PAGE_SIZE * (MAX_PAGE_COUNT + 2)],
+            }
+        }
+
+        fn check(&self, offset: u32, len: usize, align: usize) -> Result<(), NorFlashErrorKind> {
+            if offset as usize % align != 0 || len % align != 0 {
+                return Err(NorFlashErrorKind::NotAligned);
+            }
+            if offset as usize + len > self.data.len() {
+                return Err(NorFlashErrorKind::OutOfBounds);
+            }
+            Ok(())
+        }
+    }
+
+    impl ErrorType for TestNorFlash {
+        type Error = NorFlashErrorKind;
+    }
+
+    impl embedded_storage::nor_flash::ReadNorFlash for TestNorFlash {
+        const READ_SIZE: usize = 1;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            self.check(offset, bytes.len(), Self::READ_SIZE)?;
+            bytes.copy_from_slice(&self.data[offset as usize..][..bytes.len()]);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.data.len()
+        }
+    }
+
+    impl embedded_storage::nor_flash::NorFlash for TestNorFlash {
+        const WRITE_SIZE: usize = ALIGN;
+        const ERASE_SIZE: usize = PAGE_SIZE;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+            self.check(from, (to - from) as usize, Self::ERASE_SIZE)?;
+            self.data[from as usize..to as usize].fill(ERASE_VALUE);
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+            self.check(offset, bytes.len(), Self::WRITE_SIZE)?;
+            self.data[offset as usize..][..bytes.len()].copy_from_slice(bytes);
+            Ok(())
+        }
+    }
+
+    impl embedded_storage_async::nor_flash::ReadNorFlash for TestNorFlash {
+        const READ_SIZE: usize = 1;
+
+        async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            embedded_storage::nor_flash::ReadNorFlash::read(self, offset, bytes)
+        }
+
+        fn capacity(&self) -> usize {
+            self.data.len()
+        }
+    }
+
+    impl embedded_storage_async::nor_flash::NorFlash for TestNorFlash {
+        const WRITE_SIZE: usize = ALIGN;
+        const ERASE_SIZE: usize = PAGE_SIZE;
+
+        async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+            embedded_storage::nor_flash::NorFlash::erase(self, from, to)
+        }
+
+        async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+            embedded_storage::nor_flash::NorFlash::write(self, offset, bytes)
+        }
+    }
+
+    async fn check_db(flash: impl Flash) {
+        let db = Database::<_, NoopRawMutex>::new(flash, Config::default());
+        db.format().await.unwrap();
+
+        let mut wtx = db.write_transaction().await;
+        wtx.write(b"foo", b"1234").await.unwrap();
+        wtx.commit().await.unwrap();
+
+        db.mount().await.unwrap();
+        let rtx = db.read_transaction().await;
+        let mut buf = [0; 16];
+        let n = rtx.read(b"foo", &mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"1234");
+    }
+
+    fn check_outside_untouched(f: &TestNorFlash) {
+        let end = BASE as usize + MAX_PAGE_COUNT * PAGE_SIZE;
+        assert!(f.data[..BASE as usize].iter().all(|&x| x == 0x55));
+        assert!(f.data[end..].iter().all(|&x| x == 0x55));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_nor_flash_adapter() {
+        let mut f = TestNorFlash::new();
+        check_db(NorFlashAdapter::new(&mut f, BASE, MAX_PAGE_COUNT)).await;
+        check_outside_untouched(&f);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_blocking_nor_flash_adapter() {
+        let mut f = TestNorFlash::new();
+        check_db(BlockingNorFlashAdapter::new(&mut f, BASE, MAX_PAGE_COUNT)).await;
+        check_outside_untouched(&f);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_nor_flash_adapter_out_of_bounds() {
+        BlockingNorFlashAdapter::new(TestNorFlash::new(), BASE * 3, MAX_PAGE_COUNT);
+    }
+}
@@ -14,6 +14,9 @@ mod fmt;
 mod macros;
 
 mod alloc;
+pub mod blob;
+#[cfg(feature = "encryption")]
+pub mod cipher;
 pub mod config;
 mod cursor;
 mod errors;
@@ -22,7 +25,10 @@ mod types;
 
 pub use cursor::Cursor;
 pub use errors::*;
-pub use record::{Config, Database, ReadTransaction, WriteTransaction};
+pub use record::{
+    CheckProblem, CheckProblemKind, CheckReport, CompactorPolicy, Config, Database, ReadTransaction, SalvageReport,
+    Stats, ValueWriter, WearStats, WriteTransaction,
+};
 
 #[cfg(feature = "_test")]
 pub mod file;
@@ -1,6 +1,9 @@
 use core::marker::PhantomData;
 use core::mem::size_of;
+use core::ops::Range;
 
+#[cfg(feature = "encryption")]
+use crate::cipher::{self, Cipher, MAX_GENERATION, TAG_SIZE};
 use crate::config::{self, ALIGN, ERASE_VALUE, MAX_HEADER_SIZE, PAGE_SIZE};
 use crate::errors::Error;
 use crate::flash::Flash;
@@ -15,6 +18,10 @@ pub struct PageHeader {
     page_id: u32,
     #[cfg(feature = "crc")]
     crc: u32,
+    #[cfg(feature = "encryption")]
+    generation: u32,
+    #[cfg(feature = "encryption")]
+    tag: [u8; TAG_SIZE],
 }
 impl_bytes!(PageHeader);
 
@@ -25,6 +32,10 @@ pub struct ChunkHeader {
     len: u16,
     #[cfg(feature = "crc")]
     crc: u32,
+    #[cfg(feature = "encryption")]
+    generation: u32,
+    #[cfg(feature = "encryption")]
+    tag: [u8; TAG_SIZE],
 }
 impl_bytes!(ChunkHeader);
 
@@ -44,6 +55,23 @@ impl<E> From<Error<E>> for ReadError<E> {
     }
 }
 
+/// [`Flash`] as seen by the page layer.
+///
+/// With the `encryption` feature, this also gives access to the cipher and the
+/// generation counter used to build nonces. Otherwise it's just [`Flash`].
+pub trait PageFlash: Flash {
+    /// Cipher to encrypt pages with, or `None` to store them unencrypted.
+    #[cfg(feature = "encryption")]
+    fn cipher(&self) -> Option<&'static dyn Cipher>;
+
+    /// Get a generation never used before, for a page write.
+    #[cfg(feature = "encryption")]
+    fn next_generation(&mut self) -> u32;
+}
+
+#[cfg(not(feature = "encryption"))]
+impl<F: Flash> PageFlash for F {}
+
 /// Higher-layer header.
 ///
 /// # Safety
@@ -53,17 +81,87 @@ pub unsafe trait Header: Sized {
     const MAGIC: u32;
 }
 
-pub(crate) const MAX_CHUNK_SIZE: usize = if config::MAX_CHUNK_SIZE > (PAGE_SIZE - PageHeader::SIZE - ChunkHeader::SIZE)
-{
-    PAGE_SIZE - PageHeader::SIZE - ChunkHeader::SIZE
-} else {
-    config::MAX_CHUNK_SIZE
-};
+/// Size of the chunk header on flash, padded to `ALIGN`.
+pub(crate) const CHUNK_HEADER_SIZE: usize = align_up(ChunkHeader::SIZE);
+
+/// Size of the page header plus the higher-layer header on flash, padded to `ALIGN`.
+/// The first chunk starts right after it.
+pub(crate) const fn page_header_size<H: Header>() -> usize {
+    align_up(PageHeader::SIZE + size_of::<H>())
+}
+
+const MAX_PAGE_HEADER_SIZE: usize = align_up(PageHeader::SIZE + MAX_HEADER_SIZE);
+
+pub(crate) const MAX_CHUNK_SIZE: usize =
+    if config::MAX_CHUNK_SIZE > align_down(PAGE_SIZE - PageHeader::SIZE - CHUNK_HEADER_SIZE) {
+        align_down(PAGE_SIZE - PageHeader::SIZE - CHUNK_HEADER_SIZE)
+    } else {
+        config::MAX_CHUNK_SIZE
+    };
+
+/// Size of the buffers used to verify and copy data, in the stack.
+const BUF_SIZE: usize = align_up(128);
+
+/// Write data to flash.
+///
+/// If `verify` is set, the data is read back, and if it doesn't match what was written,
+/// fails with [`Error::Corrupted`].
+async fn write_flash<F: PageFlash>(
+    flash: &mut F,
+    page_id: PageID,
+    offset: usize,
+    data: &[u8],
+    verify: bool,
+) -> Result<(), Error<F::Error>> {
+    flash.write(page_id, offset, data).await.map_err(Error::Flash)?;
+
+    if verify {
+        let mut buf = [0u8; BUF_SIZE];
+        for (i, data) in data.chunks(BUF_SIZE).enumerate() {
+            let buf = &mut buf[..data.len()];
+            flash
+                .read(page_id, offset + i * BUF_SIZE, buf)
+                .await
+                .map_err(Error::Flash)?;
+            if buf != data {
+                // don't use `corrupted!()` here, this is a flash failure, and it's
+                // handled by writing to another page.
+                warn!("write verify failed: page={:?} offset={}", page_id, offset);
+                return Err(Error::Corrupted);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Copy the bytes in `range` from page `from` to the same offsets in page `to`.
+async fn copy_flash<F: PageFlash>(
+    flash: &mut F,
+    from: PageID,
+    to: PageID,
+    range: Range<usize>,
+    verify: bool,
+) -> Result<(), Error<F::Error>> {
+    let mut buf = [0u8; BUF_SIZE];
+    for offset in range.clone().step_by(BUF_SIZE) {
+        let buf = &mut buf[..(range.end - offset).min(BUF_SIZE)];
+        flash.read(from, offset, buf).await.map_err(Error::Flash)?;
+        write_flash(flash, to, offset, buf, verify).await?;
+    }
+    Ok(())
+}
 
-async fn write_header<F: Flash, H: Header>(flash: &mut F, page_id: PageID, header: H) -> Result<(), F::Error> {
+pub async fn write_header<F: PageFlash, H: Header>(
+    flash: &mut F,
+    page_id: PageID,
+    header: H,
+    verify: bool,
+) -> Result<(), Error<F::Error>> {
     assert!(size_of::<H>() <= MAX_HEADER_SIZE);
-    let mut buf = [0u8; PageHeader::SIZE + MAX_HEADER_SIZE];
-    let buf = &mut buf[..PageHeader::SIZE + size_of::<H>()];
+    // Padding bytes after the header are written as zero.
+    let mut buf = [0u8; MAX_PAGE_HEADER_SIZE];
+    let buf = &mut buf[..page_header_size::<H>()];
 
     unsafe {
         buf.as_mut_ptr()
@@ -72,22 +170,34 @@ async fn write_header<F: Flash, H: Header>(flash: &mut F, page_id: PageID, heade
             .write_unaligned(header)
     };
 
-    let page_header = PageHeader {
+    #[allow(unused_mut)]
+    let mut page_header = PageHeader {
         magic: H::MAGIC,
         page_id: page_id.index() as u32,
         #[cfg(feature = "crc")]
-        crc: crc32(&buf[PageHeader::SIZE..]),
+        crc: crc32(&buf[PageHeader::SIZE..][..size_of::<H>()]),
+        #[cfg(feature = "encryption")]
+        generation: flash.next_generation(),
+        #[cfg(feature = "encryption")]
+        tag: [0; TAG_SIZE],
     };
     buf[..PageHeader::SIZE].copy_from_slice(&page_header.to_bytes());
 
-    flash.write(page_id, 0, buf).await?;
-    Ok(())
+    // Authenticate the whole header, with the tag zeroed.
+    #[cfg(feature = "encryption")]
+    if let Some(cipher) = flash.cipher() {
+        let nonce = cipher::nonce(page_id, page_header.generation, 0);
+        page_header.tag = cipher.encrypt(&nonce, &buf[..PageHeader::SIZE + size_of::<H>()], &mut []);
+        buf[..PageHeader::SIZE].copy_from_slice(&page_header.to_bytes());
+    }
+
+    write_flash(flash, page_id, 0, buf, verify).await
 }
 
-pub async fn read_header<F: Flash, H: Header>(flash: &mut F, page_id: PageID) -> Result<H, Error<F::Error>> {
+pub async fn read_header<F: PageFlash, H: Header>(flash: &mut F, page_id: PageID) -> Result<H, Error<F::Error>> {
     assert!(size_of::<H>() <= MAX_HEADER_SIZE);
-    let mut buf = [0u8; PageHeader::SIZE + MAX_HEADER_SIZE];
-    let buf = &mut buf[..PageHeader::SIZE + size_of::<H>()];
+    let mut buf = [0u8; MAX_PAGE_HEADER_SIZE];
+    let buf = &mut buf[..page_header_size::<H>()];
 
     flash.read(page_id as _, 0, buf).await.map_err(Error::Flash)?;
 
@@ -100,16 +210,96 @@ pub async fn read_header<F: Flash, H: Header>(flash: &mut F, page_id: PageID) ->
 
     #[cfg(feature = "crc")]
     {
-        let got_crc = crc32(&buf[PageHeader::SIZE..]);
+        let got_crc = crc32(&buf[PageHeader::SIZE..][..size_of::<H>()]);
         if got_crc != page_header.crc {
             return Err(Error::Corrupted);
         }
     }
 
+    #[cfg(feature = "encryption")]
+    if let Some(cipher) = flash.cipher() {
+        let tag = page_header.tag;
+        let page_header = PageHeader {
+            tag: [0; TAG_SIZE],
+            ..page_header
+        };
+        buf[..PageHeader::SIZE].copy_from_slice(&page_header.to_bytes());
+        let nonce = cipher::nonce(page_id, page_header.generation, 0);
+        if cipher
+            .decrypt(&nonce, &buf[..PageHeader::SIZE + size_of::<H>()], &mut [], &tag)
+            .is_err()
+        {
+            return Err(Error::Corrupted);
+        }
+    }
+
     let header = unsafe { buf.as_ptr().add(PageHeader::SIZE).cast::<H>().read_unaligned() };
     Ok(header)
 }
 
+/// Get the highest generation in a page with header `H`, from the page header and all chunk headers.
+///
+/// Nothing is authenticated, this is only used to ensure new generations are higher than
+/// all the ones on flash. Also works for pages without a header, or partially written.
+#[cfg(feature = "encryption")]
+pub async fn read_max_generation<F: PageFlash, H: Header>(flash: &mut F, page_id: PageID) -> Result<u32, F::Error> {
+    let mut max = 0;
+    let mut consider = |generation: u32| {
+        if generation <= MAX_GENERATION {
+            max = max.max(generation);
+        }
+    };
+
+    let mut buf = [0u8; align_up(PageHeader::SIZE)];
+    flash.read(page_id, 0, &mut buf).await?;
+    let page_header = PageHeader::from_bytes(buf[..PageHeader::SIZE].try_into().unwrap());
+    if page_header.magic == H::MAGIC {
+        consider(page_header.generation);
+    }
+
+    let mut offset = page_header_size::<H>();
+    while offset + CHUNK_HEADER_SIZE <= PAGE_SIZE {
+        let mut buf = [0u8; CHUNK_HEADER_SIZE];
+        flash.read(page_id, offset, &mut buf).await?;
+        let header = ChunkHeader::from_bytes(buf[..ChunkHeader::SIZE].try_into().unwrap());
+        if header.magic != CHUNK_MAGIC || header.len as usize > MAX_CHUNK_SIZE {
+            break;
+        }
+        consider(header.generation);
+        offset += CHUNK_HEADER_SIZE + align_up(header.len as usize);
+    }
+
+    Ok(max)
+}
+
+/// Associated data authenticated together with a chunk: its header, with the CRC and tag zeroed.
+#[cfg(feature = "encryption")]
+fn chunk_aad(len: usize, generation: u32) -> [u8; ChunkHeader::SIZE] {
+    ChunkHeader {
+        magic: CHUNK_MAGIC,
+        len: len as u16,
+        #[cfg(feature = "crc")]
+        crc: 0,
+        generation,
+        tag: [0; TAG_SIZE],
+    }
+    .to_bytes()
+}
+
+/// Chunk of a page that a [`PageWriter`] is writing, and hasn't committed yet.
+///
+/// See [`PageWriter::pending_chunk`] and [`PageReader::open_pending`].
+#[derive(Debug, Clone, Copy)]
+pub struct PendingChunk {
+    /// Offset where the chunk starts.
+    offset: usize,
+    /// Data bytes in the chunk.
+    len: usize,
+    /// Data after the last whole `ALIGN` block, which is not written to flash yet.
+    #[cfg(not(feature = "encryption"))]
+    align_buf: [u8; ALIGN],
+}
+
 #[derive(Clone)]
 struct ChunkIter {
     page_id: PageID,
@@ -125,27 +315,48 @@ struct ChunkIter {
     /// CRC
     #[cfg(feature = "crc")]
     chunk_crc: u32,
+    /// Generation the current chunk was encrypted with.
+    #[cfg(feature = "encryption")]
+    chunk_generation: u32,
+    /// Tag of the current chunk.
+    #[cfg(feature = "encryption")]
+    chunk_tag: [u8; TAG_SIZE],
+    /// Chunk not committed yet, read after the committed ones.
+    pending: Option<PendingChunk>,
 }
 
 impl ChunkIter {
-    async fn next_chunk<F: Flash>(&mut self, flash: &mut F) -> Result<bool, Error<F::Error>> {
-        self.chunk_offset += ChunkHeader::SIZE + align_up(self.chunk_len);
+    /// Whether the current chunk is the pending one.
+    #[cfg(not(feature = "encryption"))]
+    fn in_pending(&self) -> bool {
+        !self.at_end && self.pending.is_some_and(|p| p.offset == self.chunk_offset)
+    }
+
+    async fn next_chunk<F: PageFlash>(&mut self, flash: &mut F) -> Result<bool, Error<F::Error>> {
+        self.chunk_offset += CHUNK_HEADER_SIZE + align_up(self.chunk_len);
         self.open_chunk(flash).await
     }
 
-    async fn open_chunk<F: Flash>(&mut self, flash: &mut F) -> Result<bool, Error<F::Error>> {
-        let data_start = self.chunk_offset + ChunkHeader::SIZE;
+    async fn open_chunk<F: PageFlash>(&mut self, flash: &mut F) -> Result<bool, Error<F::Error>> {
+        let data_start = self.chunk_offset + CHUNK_HEADER_SIZE;
         if data_start > PAGE_SIZE {
             self.at_end = true;
             return Ok(false);
         }
 
-        let mut header = [0u8; ChunkHeader::SIZE];
+        // The pending chunk has no header yet.
+        if let Some(p) = self.pending.filter(|p| p.offset == self.chunk_offset && p.len != 0) {
+            trace!("open pending chunk at offs={} len={}", self.chunk_offset, p.len);
+            self.chunk_len = p.len;
+            return Ok(true);
+        }
+
+        let mut header = [0u8; CHUNK_HEADER_SIZE];
         flash
             .read(self.page_id as _, self.chunk_offset, &mut header)
             .await
             .map_err(Error::Flash)?;
-        let header = ChunkHeader::from_bytes(header);
+        let header = ChunkHeader::from_bytes(header[..ChunkHeader::SIZE].try_into().unwrap());
 
         if header.magic != CHUNK_MAGIC {
             self.at_end = true;
@@ -171,6 +382,11 @@ impl ChunkIter {
         {
             self.chunk_crc = header.crc;
         }
+        #[cfg(feature = "encryption")]
+        {
+            self.chunk_generation = header.generation;
+            self.chunk_tag = header.tag;
+        }
 
         Ok(true)
     }
@@ -202,6 +418,11 @@ impl PageReader {
                 chunk_len: 0,
                 #[cfg(feature = "crc")]
                 chunk_crc: 0,
+                #[cfg(feature = "encryption")]
+                chunk_generation: 0,
+                #[cfg(feature = "encryption")]
+                chunk_tag: [0; TAG_SIZE],
+                pending: None,
             },
             chunk_pos: 0,
             buf: [0u8; MAX_CHUNK_SIZE],
@@ -215,7 +436,7 @@ impl PageReader {
         }
     }
 
-    pub async fn rehydrate_from<F: Flash>(
+    pub async fn rehydrate_from<F: PageFlash>(
         &mut self,
         flash: &mut F,
         dehydrated: &DehydratedPageReader,
@@ -225,46 +446,103 @@ impl PageReader {
         self.load_chunk(flash).await
     }
 
-    pub async fn open<F: Flash, H: Header>(&mut self, flash: &mut F, page_id: PageID) -> Result<H, Error<F::Error>> {
+    pub async fn open<F: PageFlash, H: Header>(
+        &mut self,
+        flash: &mut F,
+        page_id: PageID,
+    ) -> Result<H, Error<F::Error>> {
         trace!("page: read {:?}", page_id);
         let header = read_header(flash, page_id).await?;
+        self.open_without_header::<F, H>(flash, page_id).await?;
+        Ok(header)
+    }
+
+    /// Open a page that's still being written, so its header is not written yet.
+    ///
+    /// Only the data in chunks already committed by the [`PageWriter`] can be read.
+    pub async fn open_without_header<F: PageFlash, H: Header>(
+        &mut self,
+        flash: &mut F,
+        page_id: PageID,
+    ) -> Result<(), Error<F::Error>> {
+        self.ch.pending = None;
+        self.rewind::<F, H>(flash, page_id).await
+    }
 
+    /// Like [`open_without_header`](Self::open_without_header), reading the data in the chunk the
+    /// [`PageWriter`] hasn't committed yet too, after the committed ones.
+    ///
+    /// The writer must not have written more to the page since it was taken with
+    /// [`PageWriter::pending_chunk`], other than appending to the chunk.
+    pub async fn open_pending<F: PageFlash, H: Header>(
+        &mut self,
+        flash: &mut F,
+        page_id: PageID,
+        chunk: PendingChunk,
+    ) -> Result<(), Error<F::Error>> {
+        self.ch.pending = Some(chunk);
+        self.rewind::<F, H>(flash, page_id).await
+    }
+
+    async fn rewind<F: PageFlash, H: Header>(&mut self, flash: &mut F, page_id: PageID) -> Result<(), Error<F::Error>> {
         self.ch.page_id = page_id;
         self.ch.prev_chunks_len = 0;
         self.ch.at_end = false;
-        self.ch.chunk_offset = PageHeader::SIZE + size_of::<H>();
+        self.ch.chunk_offset = page_header_size::<H>();
         self.ch.chunk_len = 0;
         self.ch.open_chunk(flash).await?;
         self.chunk_pos = 0;
         self.load_chunk(flash).await?;
-        Ok(header)
+        Ok(())
     }
 
-    async fn load_chunk<F: Flash>(&mut self, flash: &mut F) -> Result<(), Error<F::Error>> {
+    async fn load_chunk<F: PageFlash>(&mut self, flash: &mut F) -> Result<(), Error<F::Error>> {
         let n = align_up(self.ch.chunk_len);
         assert!(n <= MAX_CHUNK_SIZE);
 
         flash
             .read(
                 self.ch.page_id as _,
-                self.ch.chunk_offset + ChunkHeader::SIZE,
+                self.ch.chunk_offset + CHUNK_HEADER_SIZE,
                 &mut self.buf[..n],
             )
             .await
             .map_err(Error::Flash)?;
 
+        // The end of the pending chunk is only in the writer's buffer, not on flash yet.
+        #[cfg(not(feature = "encryption"))]
+        if let Some(p) = self.ch.pending.filter(|_| self.ch.in_pending()) {
+            let n = align_down(p.len);
+            self.buf[n..p.len].copy_from_slice(&p.align_buf[..p.len - n]);
+            return Ok(());
+        }
+
+        // Nothing to check if there's no chunk. `chunk_crc` is stale then.
         #[cfg(feature = "crc")]
-        {
+        if !self.ch.at_end {
             let got_crc = crc32(&self.buf[..self.ch.chunk_len]);
             if got_crc != self.ch.chunk_crc {
                 return Err(Error::Corrupted);
             }
         }
 
+        // Nothing to decrypt if there's no chunk.
+        #[cfg(feature = "encryption")]
+        if let (Some(cipher), false) = (flash.cipher(), self.ch.at_end) {
+            let aad = chunk_aad(self.ch.chunk_len, self.ch.chunk_generation);
+            let nonce = cipher::nonce(self.ch.page_id, self.ch.chunk_generation, self.ch.chunk_offset);
+            if cipher
+                .decrypt(&nonce, &aad, &mut self.buf[..self.ch.chunk_len], &self.ch.chunk_tag)
+                .is_err()
+            {
+                return Err(Error::Corrupted);
+            }
+        }
+
         Ok(())
     }
 
-    async fn next_chunk<F: Flash>(&mut self, flash: &mut F) -> Result<bool, Error<F::Error>> {
+    async fn next_chunk<F: PageFlash>(&mut self, flash: &mut F) -> Result<bool, Error<F::Error>> {
         if !self.ch.next_chunk(flash).await? {
             return Ok(false);
         }
@@ -280,7 +558,7 @@ impl PageReader {
     /// Read up to data.len() bytes of data or until the end of the current chunk.
     ///
     /// May return less bytes than the buffer if not available.
-    pub async fn read<F: Flash>(&mut self, flash: &mut F, data: &mut [u8]) -> Result<usize, Error<F::Error>> {
+    pub async fn read<F: PageFlash>(&mut self, flash: &mut F, data: &mut [u8]) -> Result<usize, Error<F::Error>> {
         trace!("PageReader({:?}): read({})", self.ch.page_id, data.len());
         if self.is_at_eof(flash).await? || data.is_empty() {
             trace!("read: at end or zero len");
@@ -297,7 +575,7 @@ impl PageReader {
     /// Skip up to len bytes in the reader or until the end of the last chunk
     ///
     /// Skips across chunks within the page.
-    pub async fn skip<F: Flash>(&mut self, flash: &mut F, mut len: usize) -> Result<usize, Error<F::Error>> {
+    pub async fn skip<F: PageFlash>(&mut self, flash: &mut F, mut len: usize) -> Result<usize, Error<F::Error>> {
         trace!("PageReader({:?}): skip({})", self.ch.page_id, len);
         if self.ch.at_end || len == 0 {
             trace!("skip: at end or zero len");
@@ -322,7 +600,7 @@ impl PageReader {
         }
     }
 
-    pub async fn is_at_eof<F: Flash>(&mut self, flash: &mut F) -> Result<bool, Error<F::Error>> {
+    pub async fn is_at_eof<F: PageFlash>(&mut self, flash: &mut F) -> Result<bool, Error<F::Error>> {
         if self.ch.at_end {
             return Ok(true);
         }
@@ -338,11 +616,26 @@ pub struct PageWriter<H: Header> {
 
     page_id: PageID,
     needs_erase: bool,
+    /// Read back all writes, to check they were written correctly.
+    verify: bool,
+    /// Times the page has been erased, for the caller to write in the page header.
+    erase_count: u32,
 
     #[cfg(feature = "crc")]
     crc: Crc32,
+    #[cfg(not(feature = "encryption"))]
     align_buf: [u8; ALIGN],
 
+    /// Generation chunks are encrypted with.
+    #[cfg(feature = "encryption")]
+    generation: u32,
+    /// Data in the current chunk. It can only be encrypted once complete.
+    #[cfg(feature = "encryption")]
+    buf: [u8; MAX_CHUNK_SIZE],
+    /// Length of the longest chunk committed in the page, padded to `ALIGN`.
+    #[cfg(feature = "encryption")]
+    max_chunk_len: usize,
+
     /// Total data bytes in page (all chunks)
     total_pos: usize,
 
@@ -358,41 +651,95 @@ impl<H: Header> PageWriter<H> {
             _phantom: PhantomData,
             page_id: PageID::from_raw(0).unwrap(),
             needs_erase: true,
+            verify: false,
+            erase_count: 0,
+            #[cfg(not(feature = "encryption"))]
             align_buf: [0; ALIGN],
+            #[cfg(feature = "encryption")]
+            generation: 0,
+            #[cfg(feature = "encryption")]
+            buf: [0; MAX_CHUNK_SIZE],
+            #[cfg(feature = "encryption")]
+            max_chunk_len: 0,
             total_pos: 0,
-            chunk_offset: PageHeader::SIZE + size_of::<H>(),
+            chunk_offset: page_header_size::<H>(),
             chunk_pos: 0,
             #[cfg(feature = "crc")]
             crc: Crc32::new(),
         }
     }
 
-    pub async fn open<F: Flash>(&mut self, _flash: &mut F, page_id: PageID) {
+    #[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+    pub async fn open<F: PageFlash>(&mut self, flash: &mut F, page_id: PageID) {
         trace!("page: write {:?}", page_id);
         self.page_id = page_id;
         self.needs_erase = true;
-        self.align_buf = [0; ALIGN];
+        #[cfg(not(feature = "encryption"))]
+        {
+            self.align_buf = [0; ALIGN];
+        }
+        #[cfg(feature = "encryption")]
+        {
+            self.generation = flash.next_generation();
+            self.max_chunk_len = 0;
+        }
         self.total_pos = 0;
-        self.chunk_offset = PageHeader::SIZE + size_of::<H>();
+        self.chunk_offset = page_header_size::<H>();
         self.chunk_pos = 0;
         #[cfg(feature = "crc")]
         self.crc.reset();
     }
 
-    pub async fn open_append<F: Flash>(&mut self, flash: &mut F, page_id: PageID) -> Result<(), Error<F::Error>> {
+    /// Like [`open`](Self::open), for a page that's already erased.
+    pub async fn open_erased<F: PageFlash>(&mut self, flash: &mut F, page_id: PageID) {
+        self.open(flash, page_id).await;
+        self.needs_erase = false;
+    }
+
+    /// Set whether to read back all writes, to check they were written correctly.
+    ///
+    /// If they weren't, writing fails with [`Error::Corrupted`].
+    pub fn set_verify(&mut self, verify: bool) {
+        self.verify = verify;
+    }
+
+    /// Times the page has been erased, as set with [`set_erase_count`](Self::set_erase_count).
+    pub fn erase_count(&self) -> u32 {
+        self.erase_count
+    }
+
+    /// Set the times the page has been erased. It's not used by the writer, it's only kept along with the
+    /// page so it can be written in the page header. It must be set again after relocating.
+    pub fn set_erase_count(&mut self, erase_count: u32) {
+        self.erase_count = erase_count;
+    }
+
+    pub async fn open_append<F: PageFlash>(&mut self, flash: &mut F, page_id: PageID) -> Result<(), Error<F::Error>> {
         trace!("page: write_append {:?}", page_id);
 
         let mut r = ChunkIter {
             page_id,
             prev_chunks_len: 0,
             at_end: false,
-            chunk_offset: PageHeader::SIZE + size_of::<H>(),
+            chunk_offset: page_header_size::<H>(),
             chunk_len: 0,
             #[cfg(feature = "crc")]
             chunk_crc: 0,
+            #[cfg(feature = "encryption")]
+            chunk_generation: 0,
+            #[cfg(feature = "encryption")]
+            chunk_tag: [0; TAG_SIZE],
+            pending: None,
         };
+        #[cfg(feature = "encryption")]
+        let mut max_chunk_len = 0;
         r.open_chunk(flash).await?;
         while !r.at_end {
+            #[cfg(feature = "encryption")]
+            {
+                max_chunk_len = max_chunk_len.max(align_up(r.chunk_len));
+            }
+            r.prev_chunks_len += r.chunk_len;
             r.next_chunk(flash).await?;
         }
 
@@ -423,7 +770,15 @@ impl<H: Header> PageWriter<H> {
 
         self.page_id = page_id;
         self.needs_erase = false;
-        self.align_buf = [0; ALIGN];
+        #[cfg(not(feature = "encryption"))]
+        {
+            self.align_buf = [0; ALIGN];
+        }
+        #[cfg(feature = "encryption")]
+        {
+            self.generation = flash.next_generation();
+            self.max_chunk_len = max_chunk_len;
+        }
         self.total_pos = r.prev_chunks_len;
         self.chunk_offset = r.chunk_offset;
         self.chunk_pos = 0;
@@ -442,6 +797,37 @@ impl<H: Header> PageWriter<H> {
         self.page_id
     }
 
+    /// The chunk being written, for a [`PageReader`] to read the data in it before it's committed.
+    ///
+    /// With encryption, the data is only encrypted and written to flash on commit, so the chunk must
+    /// be committed first.
+    pub fn pending_chunk(&self) -> PendingChunk {
+        #[cfg(feature = "encryption")]
+        assert!(self.chunk_pos == 0);
+        PendingChunk {
+            offset: self.chunk_offset,
+            len: self.chunk_pos,
+            #[cfg(not(feature = "encryption"))]
+            align_buf: self.align_buf,
+        }
+    }
+
+    /// Whether no more data fits in the page.
+    pub fn is_full(&self) -> bool {
+        self.chunk_offset + CHUNK_HEADER_SIZE + self.chunk_pos >= PAGE_SIZE
+    }
+
+    /// Bytes that can be written to the current chunk while it stays uncommitted and
+    /// [relocatable](Self::can_relocate). Writing all of them can commit it, if it or the page gets full.
+    pub fn chunk_space_left(&self) -> usize {
+        let n = PAGE_SIZE
+            .saturating_sub(self.chunk_offset + CHUNK_HEADER_SIZE + self.chunk_pos)
+            .min(MAX_CHUNK_SIZE - self.chunk_pos);
+        #[cfg(feature = "encryption")]
+        let n = n.min(MAX_CHUNK_SIZE.saturating_sub(self.chunk_pos + self.max_chunk_len));
+        n
+    }
+
     fn is_chunk_full(&self) -> bool {
         self.chunk_pos >= MAX_CHUNK_SIZE
     }
@@ -449,63 +835,68 @@ impl<H: Header> PageWriter<H> {
     /// Write n bytes of data to the page.
     ///
     /// If the current chunk is full, it will commit it.
-    pub async fn write<F: Flash>(&mut self, flash: &mut F, data: &[u8]) -> Result<usize, Error<F::Error>> {
+    pub async fn write<F: PageFlash>(&mut self, flash: &mut F, data: &[u8]) -> Result<usize, Error<F::Error>> {
         let max_write = PAGE_SIZE
-            .saturating_sub(self.chunk_offset + ChunkHeader::SIZE + self.chunk_pos)
+            .saturating_sub(self.chunk_offset + CHUNK_HEADER_SIZE + self.chunk_pos)
             .min(MAX_CHUNK_SIZE.saturating_sub(self.chunk_pos));
         let total_n = data.len().min(max_write);
         if total_n == 0 {
             return Ok(0);
         }
-        let mut data = &data[..total_n];
 
-        #[cfg(feature = "crc")]
-        self.crc.update(data);
+        // Just buffer the data, it's encrypted and written on commit.
+        #[cfg(feature = "encryption")]
+        {
+            self.buf[self.chunk_pos..][..total_n].copy_from_slice(&data[..total_n]);
+            self.total_pos += total_n;
+            self.chunk_pos += total_n;
+
+            if self.is_chunk_full() {
+                self.commit(flash).await?;
+            }
+
+            Ok(total_n)
+        }
+
+        #[cfg(not(feature = "encryption"))]
+        self.write_unencrypted(flash, &data[..total_n]).await
+    }
+
+    #[cfg(not(feature = "encryption"))]
+    async fn write_unencrypted<F: PageFlash>(&mut self, flash: &mut F, data: &[u8]) -> Result<usize, Error<F::Error>> {
+        let total_n = data.len();
+        let mut data = data;
 
         self.erase_if_needed(flash).await.map_err(Error::Flash)?;
 
+        // The position is only advanced after writing succeeds, so that if it fails, everything
+        // before it is still accounted for, and can be relocated.
         let align_offs = self.chunk_pos % ALIGN;
         if align_offs != 0 {
             let left = ALIGN - align_offs;
             let n = left.min(data.len());
 
             self.align_buf[align_offs..][..n].copy_from_slice(&data[..n]);
-            data = &data[n..];
-            self.total_pos += n;
-            self.chunk_pos += n;
-
             if n == left {
-                flash
-                    .write(
-                        self.page_id as _,
-                        self.chunk_offset + ChunkHeader::SIZE + self.chunk_pos - ALIGN,
-                        &self.align_buf,
-                    )
-                    .await
-                    .map_err(Error::Flash)?;
+                let offset = self.chunk_offset + CHUNK_HEADER_SIZE + self.chunk_pos - align_offs;
+                write_flash(flash, self.page_id, offset, &self.align_buf, self.verify).await?;
             }
+            self.advance(&data[..n]);
+            data = &data[n..];
         }
 
         let n = data.len() - (data.len() % ALIGN);
         if n != 0 {
-            flash
-                .write(
-                    self.page_id as _,
-                    self.chunk_offset + ChunkHeader::SIZE + self.chunk_pos,
-                    &data[..n],
-                )
-                .await
-                .map_err(Error::Flash)?;
+            let offset = self.chunk_offset + CHUNK_HEADER_SIZE + self.chunk_pos;
+            write_flash(flash, self.page_id, offset, &data[..n], self.verify).await?;
+            self.advance(&data[..n]);
             data = &data[n..];
-            self.total_pos += n;
-            self.chunk_pos += n;
         }
 
         let n = data.len();
         assert!(n < ALIGN);
         self.align_buf[..n].copy_from_slice(data);
-        self.total_pos += n;
-        self.chunk_pos += n;
+        self.advance(data);
 
         if self.is_chunk_full() {
             self.commit(flash).await?;
@@ -514,7 +905,16 @@ impl<H: Header> PageWriter<H> {
         Ok(total_n)
     }
 
-    async fn erase_if_needed<F: Flash>(&mut self, flash: &mut F) -> Result<(), F::Error> {
+    /// Account for data written to the current chunk.
+    #[cfg(not(feature = "encryption"))]
+    fn advance(&mut self, data: &[u8]) {
+        #[cfg(feature = "crc")]
+        self.crc.update(data);
+        self.total_pos += data.len();
+        self.chunk_pos += data.len();
+    }
+
+    async fn erase_if_needed<F: PageFlash>(&mut self, flash: &mut F) -> Result<(), F::Error> {
         if self.needs_erase {
             flash.erase(self.page_id as _).await?;
             self.needs_erase = false;
@@ -522,57 +922,188 @@ impl<H: Header> PageWriter<H> {
         Ok(())
     }
 
-    pub async fn write_header<F: Flash>(&mut self, flash: &mut F, header: H) -> Result<(), F::Error> {
-        self.erase_if_needed(flash).await?;
-
-        write_header(flash, self.page_id, header).await?;
+    pub async fn write_header<F: PageFlash>(&mut self, flash: &mut F, header: H) -> Result<(), Error<F::Error>> {
+        self.erase_if_needed(flash).await.map_err(Error::Flash)?;
 
-        Ok(())
+        write_header(flash, self.page_id, header, self.verify).await
     }
 
-    pub async fn commit<F: Flash>(&mut self, flash: &mut F) -> Result<(), Error<F::Error>> {
+    pub async fn commit<F: PageFlash>(&mut self, flash: &mut F) -> Result<(), Error<F::Error>> {
         if self.chunk_pos == 0 {
             // nothing to commit.
             return Ok(());
         }
         self.erase_if_needed(flash).await.map_err(Error::Flash)?;
 
-        // flush align buf.
-        let align_offs = self.chunk_pos % ALIGN;
-        if align_offs != 0 {
-            flash
-                .write(
-                    self.page_id as _,
-                    self.chunk_offset + ChunkHeader::SIZE + self.chunk_pos - align_offs,
-                    &self.align_buf,
-                )
-                .await
-                .map_err(Error::Flash)?;
-        }
+        // encrypt the whole chunk.
+        #[cfg(feature = "encryption")]
+        let tag = match flash.cipher() {
+            Some(cipher) => {
+                let aad = chunk_aad(self.chunk_pos, self.generation);
+                let nonce = cipher::nonce(self.page_id, self.generation, self.chunk_offset);
+                cipher.encrypt(&nonce, &aad, &mut self.buf[..self.chunk_pos])
+            }
+            None => [0; TAG_SIZE],
+        };
 
-        let h = ChunkHeader {
-            magic: CHUNK_MAGIC,
-            len: self.chunk_pos as u16,
-            #[cfg(feature = "crc")]
-            crc: self.crc.finish(),
+        let res: Result<(), Error<F::Error>> = try {
+            // flush align buf.
+            #[cfg(not(feature = "encryption"))]
+            {
+                let align_offs = self.chunk_pos % ALIGN;
+                if align_offs != 0 {
+                    let offset = self.chunk_offset + CHUNK_HEADER_SIZE + self.chunk_pos - align_offs;
+                    write_flash(flash, self.page_id, offset, &self.align_buf, self.verify).await?;
+                }
+            }
+
+            // write the whole encrypted chunk.
+            #[cfg(feature = "encryption")]
+            {
+                // CRC is calculated over the encrypted data.
+                #[cfg(feature = "crc")]
+                self.crc.update(&self.buf[..self.chunk_pos]);
+
+                // Padding bytes after the data are written as zero.
+                let len = align_up(self.chunk_pos);
+                self.buf[self.chunk_pos..len].fill(0);
+                let offset = self.chunk_offset + CHUNK_HEADER_SIZE;
+                write_flash(flash, self.page_id, offset, &self.buf[..len], self.verify).await?;
+            }
+
+            let h = ChunkHeader {
+                magic: CHUNK_MAGIC,
+                len: self.chunk_pos as u16,
+                #[cfg(feature = "crc")]
+                crc: self.crc.finish(),
+                #[cfg(feature = "encryption")]
+                generation: self.generation,
+                #[cfg(feature = "encryption")]
+                tag,
+            };
+
+            // Padding bytes after the header are written as zero.
+            let mut buf = [0u8; CHUNK_HEADER_SIZE];
+            buf[..ChunkHeader::SIZE].copy_from_slice(&h.to_bytes());
+            write_flash(flash, self.page_id, self.chunk_offset, &buf, self.verify).await?;
         };
 
-        flash
-            .write(self.page_id as _, self.chunk_offset, &h.to_bytes())
-            .await
-            .map_err(Error::Flash)?;
+        // Undo the encryption, so the chunk can be written again after relocating.
+        #[cfg(feature = "encryption")]
+        if res.is_err() {
+            if let Some(cipher) = flash.cipher() {
+                let aad = chunk_aad(self.chunk_pos, self.generation);
+                let nonce = cipher::nonce(self.page_id, self.generation, self.chunk_offset);
+                unwrap!(cipher.decrypt(&nonce, &aad, &mut self.buf[..self.chunk_pos], &tag));
+            }
+            #[cfg(feature = "crc")]
+            self.crc.reset();
+        }
+        res?;
 
         // Prepare for next chunk.
-        self.chunk_offset += ChunkHeader::SIZE + align_up(self.chunk_pos);
+        #[cfg(feature = "encryption")]
+        {
+            self.max_chunk_len = self.max_chunk_len.max(align_up(self.chunk_pos));
+        }
+        self.chunk_offset += CHUNK_HEADER_SIZE + align_up(self.chunk_pos);
         self.chunk_pos = 0;
         #[cfg(feature = "crc")]
         self.crc.reset();
 
         Ok(())
     }
+
+    /// Whether [`relocate`](Self::relocate) can move the data written so far.
+    ///
+    /// With encryption, committed chunks are decrypted and encrypted again for the new page in the
+    /// free space of the chunk buffer, so this fails if they don't fit next to the current chunk.
+    pub fn can_relocate(&self) -> bool {
+        #[cfg(feature = "encryption")]
+        return self.chunk_pos + self.max_chunk_len <= MAX_CHUNK_SIZE;
+        #[cfg(not(feature = "encryption"))]
+        return true;
+    }
+
+    /// Move the data written so far to the erased page `page_id`, for example after writing to the current
+    /// one failed.
+    ///
+    /// Writing can then continue on the new page as if nothing happened. Committed chunks are moved, along
+    /// with the data of the current chunk that's not committed yet. The page header is not, if it was
+    /// written already it has to be written again.
+    ///
+    /// Requires [`can_relocate`](Self::can_relocate).
+    pub async fn relocate<F: PageFlash>(&mut self, flash: &mut F, page_id: PageID) -> Result<(), Error<F::Error>> {
+        assert!(self.can_relocate());
+        let from = self.page_id;
+        trace!("page: relocate {:?} to {:?}", from, page_id);
+
+        #[cfg(not(feature = "encryption"))]
+        {
+            let data_offset = self.chunk_offset + CHUNK_HEADER_SIZE;
+            let committed = page_header_size::<H>()..self.chunk_offset;
+            copy_flash(flash, from, page_id, committed, self.verify).await?;
+            let current = data_offset..data_offset + align_down(self.chunk_pos);
+            copy_flash(flash, from, page_id, current, self.verify).await?;
+        }
+
+        // Data of the current chunk is in the buffer, only the committed chunks are on flash.
+        #[cfg(feature = "encryption")]
+        match flash.cipher() {
+            None => {
+                let committed = page_header_size::<H>()..self.chunk_offset;
+                copy_flash(flash, from, page_id, committed, self.verify).await?;
+            }
+            Some(cipher) => {
+                let generation = flash.next_generation();
+                let mut offset = page_header_size::<H>();
+                while offset < self.chunk_offset {
+                    let mut buf = [0u8; CHUNK_HEADER_SIZE];
+                    flash.read(from, offset, &mut buf).await.map_err(Error::Flash)?;
+                    let h = ChunkHeader::from_bytes(buf[..ChunkHeader::SIZE].try_into().unwrap());
+                    let len = h.len as usize;
+                    if h.magic != CHUNK_MAGIC || align_up(len) > self.max_chunk_len {
+                        corrupted!();
+                    }
+
+                    let data = &mut self.buf[self.chunk_pos..][..align_up(len)];
+                    let data_offset = offset + CHUNK_HEADER_SIZE;
+                    flash.read(from, data_offset, data).await.map_err(Error::Flash)?;
+                    let nonce = cipher::nonce(from, h.generation, offset);
+                    if cipher
+                        .decrypt(&nonce, &chunk_aad(len, h.generation), &mut data[..len], &h.tag)
+                        .is_err()
+                    {
+                        corrupted!();
+                    }
+
+                    let nonce = cipher::nonce(page_id, generation, offset);
+                    let tag = cipher.encrypt(&nonce, &chunk_aad(len, generation), &mut data[..len]);
+                    write_flash(flash, page_id, data_offset, data, self.verify).await?;
+
+                    let h = ChunkHeader {
+                        #[cfg(feature = "crc")]
+                        crc: crc32(&data[..len]),
+                        generation,
+                        tag,
+                        ..h
+                    };
+                    buf[..ChunkHeader::SIZE].copy_from_slice(&h.to_bytes());
+                    write_flash(flash, page_id, offset, &buf, self.verify).await?;
+
+                    offset = data_offset + align_up(len);
+                }
+                self.generation = generation;
+            }
+        }
+
+        self.page_id = page_id;
+        self.needs_erase = false;
+        Ok(())
+    }
 }
 
-fn align_up(n: usize) -> usize {
+const fn align_up(n: usize) -> usize {
     if n % ALIGN != 0 {
         n + ALIGN - n % ALIGN
     } else {
@@ -580,6 +1111,10 @@ fn align_up(n: usize) -> usize {
     }
 }
 
+const fn align_down(n: usize) -> usize {
+    n - n % ALIGN
+}
+
 #[allow(unused)]
 #[derive(Clone, Copy)]
 struct Crc32 {
@@ -629,7 +1164,9 @@ fn crc32(data: &[u8]) -> u32 {
 mod tests {
 
     use super::*;
-    use crate::flash::MemFlash;
+    #[cfg(feature = "encryption")]
+    use crate::cipher::{test, CipherFlash};
+    use crate::flash::{BadPageFlash, MemFlash};
 
     const PAGE: PageID = match PageID::from_raw(0) {
         Some(x) => x,
@@ -646,8 +1183,18 @@ mod tests {
         const MAGIC: u32 = 0x470b635c;
     }
 
+    #[cfg(not(feature = "encryption"))]
+    fn new_flash() -> MemFlash {
+        MemFlash::new()
+    }
+
+    #[cfg(feature = "encryption")]
+    fn new_flash() -> CipherFlash<MemFlash> {
+        CipherFlash::new(MemFlash::new(), Some(&test::CIPHER))
+    }
+
     const HEADER: TestHeader = TestHeader { foo: 123456 };
-    const MAX_PAYLOAD: usize = PAGE_SIZE - PageHeader::SIZE - size_of::<TestHeader>() - ChunkHeader::SIZE;
+    const MAX_PAYLOAD: usize = PAGE_SIZE - page_header_size::<TestHeader>() - CHUNK_HEADER_SIZE;
 
     #[test_log::test]
     fn test_crc32() {
@@ -658,16 +1205,16 @@ mod tests {
 
     #[test_log::test(tokio::test)]
     async fn test_header() {
-        let f = &mut MemFlash::new();
+        let f = &mut new_flash();
 
-        write_header(f, PAGE, HEADER).await.unwrap();
+        write_header(f, PAGE, HEADER, false).await.unwrap();
         let h = read_header::<_, TestHeader>(f, PAGE).await.unwrap();
         assert_eq!(h, HEADER)
     }
 
     #[test_log::test(tokio::test)]
     async fn test_header_read_unwritten() {
-        let f = &mut MemFlash::new();
+        let f = &mut new_flash();
 
         let res = read_header::<_, TestHeader>(f, PAGE).await;
         assert!(matches!(res, Err(Error::Corrupted)))
@@ -675,7 +1222,7 @@ mod tests {
 
     #[test_log::test(tokio::test)]
     async fn test_read_unwritten() {
-        let f = &mut MemFlash::new();
+        let f = &mut new_flash();
 
         // Read
         let mut r = PageReader::new();
@@ -685,7 +1232,7 @@ mod tests {
 
     #[test_log::test(tokio::test)]
     async fn test_read_uncommitted() {
-        let f = &mut MemFlash::new();
+        let f = &mut new_flash();
 
         let data = dummy_data(13);
 
@@ -703,7 +1250,7 @@ mod tests {
 
     #[test_log::test(tokio::test)]
     async fn test_write_short() {
-        let f = &mut MemFlash::new();
+        let f = &mut new_flash();
 
         let data = dummy_data(13);
 
@@ -738,7 +1285,7 @@ mod tests {
 
     #[test_log::test(tokio::test)]
     async fn test_overread() {
-        let f = &mut MemFlash::new();
+        let f = &mut new_flash();
 
         let data = dummy_data(13);
 
@@ -761,7 +1308,7 @@ mod tests {
 
     #[test_log::test(tokio::test)]
     async fn test_overwrite() {
-        let f = &mut MemFlash::new();
+        let f = &mut new_flash();
 
         let data = dummy_data(65536);
 
@@ -785,7 +1332,7 @@ mod tests {
 
     #[test_log::test(tokio::test)]
     async fn test_write_many() {
-        let f = &mut MemFlash::new();
+        let f = &mut new_flash();
 
         // Write
         let mut w = PageWriter::new();
@@ -809,7 +1356,7 @@ mod tests {
 
     #[test_log::test(tokio::test)]
     async fn test_read_many() {
-        let f = &mut MemFlash::new();
+        let f = &mut new_flash();
 
         // Write
         let mut w = PageWriter::new();
@@ -837,9 +1384,34 @@ mod tests {
         assert_eq!(n, 0);
     }
 
+    #[test_log::test(tokio::test)]
+    async fn test_read_empty_after_other_page() {
+        let f = &mut new_flash();
+        let page2 = PageID::from_raw(1).unwrap();
+
+        let mut w = PageWriter::new();
+        w.open(f, PAGE).await;
+        w.write(f, &[1, 2, 3]).await.unwrap();
+        w.write_header(f, HEADER).await.unwrap();
+        w.commit(f).await.unwrap();
+
+        // A page with a header, but no chunks.
+        let mut w = PageWriter::new();
+        w.open(f, page2).await;
+        w.write_header(f, HEADER).await.unwrap();
+
+        // Reusing a reader for it doesn't check the last chunk it read.
+        let mut r = PageReader::new();
+        r.open::<_, TestHeader>(f, PAGE).await.unwrap();
+        let mut buf = [0; 3];
+        assert_eq!(r.read(f, &mut buf).await.unwrap(), 3);
+        r.open::<_, TestHeader>(f, page2).await.unwrap();
+        assert_eq!(r.read(f, &mut buf).await.unwrap(), 0);
+    }
+
     #[test_log::test(tokio::test)]
     async fn test_multichunk() {
-        let f = &mut MemFlash::new();
+        let f = &mut new_flash();
 
         // Write
         let mut w = PageWriter::new();
@@ -874,9 +1446,138 @@ mod tests {
         assert_eq!(n, 0);
     }
 
+    #[test_log::test(tokio::test)]
+    async fn test_align_padding() {
+        let f = &mut new_flash();
+
+        assert_eq!(CHUNK_HEADER_SIZE % ALIGN, 0);
+        assert!(CHUNK_HEADER_SIZE >= ChunkHeader::SIZE);
+        assert_eq!(page_header_size::<TestHeader>() % ALIGN, 0);
+        assert!(page_header_size::<TestHeader>() >= PageHeader::SIZE + size_of::<TestHeader>());
+
+        // Write chunks with lengths that aren't a multiple of ALIGN, in pieces that aren't either,
+        // appending each one after reopening the page.
+        let lens = [1, ALIGN + 1, 2 * ALIGN - 1, 3, 2 * ALIGN + 3];
+        let mut chunks = Vec::new();
+        let mut offset = page_header_size::<TestHeader>();
+        let mut w = PageWriter::new();
+        w.open(f, PAGE).await;
+        w.write_header(f, HEADER).await.unwrap();
+        for (i, &len) in lens.iter().enumerate() {
+            if len >= MAX_CHUNK_SIZE || offset + CHUNK_HEADER_SIZE + align_up(len) > PAGE_SIZE {
+                continue;
+            }
+            if !chunks.is_empty() {
+                w.open_append(f, PAGE).await.unwrap();
+            }
+            assert_eq!(w.pending_chunk().offset, offset);
+
+            let data = vec![i as u8 + 1; len];
+            let (a, b) = data.split_at(len / 2);
+            assert_eq!(w.write(f, a).await.unwrap(), a.len());
+            assert_eq!(w.write(f, b).await.unwrap(), b.len());
+            w.commit(f).await.unwrap();
+
+            chunks.push((offset, len, i as u8 + 1));
+            offset += CHUNK_HEADER_SIZE + align_up(len);
+            assert_eq!(offset % ALIGN, 0);
+            assert_eq!(w.pending_chunk().offset, offset);
+        }
+        assert!(!chunks.is_empty());
+
+        // Padding after the headers is written as zero.
+        let mut buf = [0xFF; MAX_PAGE_HEADER_SIZE];
+        let buf = &mut buf[..page_header_size::<TestHeader>()];
+        f.read(PAGE, 0, buf).await.unwrap();
+        assert!(buf[PageHeader::SIZE + size_of::<TestHeader>()..]
+            .iter()
+            .all(|&b| b == 0));
+        for &(offset, _, _) in &chunks {
+            let mut buf = [0xFF; CHUNK_HEADER_SIZE];
+            f.read(PAGE, offset, &mut buf).await.unwrap();
+            assert!(buf[ChunkHeader::SIZE..].iter().all(|&b| b == 0));
+        }
+
+        // Read
+        let mut r = PageReader::new();
+        let h = r.open::<_, TestHeader>(f, PAGE).await.unwrap();
+        assert_eq!(h, HEADER);
+        let mut buf = [0u8; MAX_PAYLOAD];
+        for &(offset, len, value) in &chunks {
+            let n = r.read(f, &mut buf).await.unwrap();
+            assert_eq!(n, len);
+            assert!(buf[..n].iter().all(|&b| b == value));
+            assert_eq!(r.ch.chunk_offset, offset);
+        }
+        let n = r.read(f, &mut buf).await.unwrap();
+        assert_eq!(n, 0);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_relocate() {
+        let f = &mut new_flash();
+        let to = PageID::from_raw(1).unwrap();
+
+        let mut w = PageWriter::new();
+        w.open(f, PAGE).await;
+        w.write(f, &[1, 2, 3, 4, 5, 6, 7, 8, 9]).await.unwrap();
+        w.commit(f).await.unwrap();
+        w.write(f, &[10, 11, 12]).await.unwrap();
+
+        // Move the committed and uncommitted data to another page, and keep writing there.
+        assert!(w.can_relocate());
+        w.relocate(f, to).await.unwrap();
+        assert_eq!(w.page_id(), to);
+        w.write(f, &[13, 14]).await.unwrap();
+        w.write_header(f, HEADER).await.unwrap();
+        w.commit(f).await.unwrap();
+
+        // Read
+        let mut r = PageReader::new();
+        let h = r.open::<_, TestHeader>(f, to).await.unwrap();
+        assert_eq!(h, HEADER);
+        let mut buf = [0u8; MAX_PAYLOAD];
+        let mut len = 0;
+        loop {
+            let n = r.read(f, &mut buf[len..]).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            len += n;
+        }
+        assert_eq!(buf[..len], [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14]);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_write_verify() {
+        let mut f = MemFlash::new();
+        let mut f = BadPageFlash::new(&mut f);
+        f.flip_write[PAGE.index()] = true;
+        #[cfg(feature = "encryption")]
+        let mut f = CipherFlash::new(f, Some(&test::CIPHER));
+        let f = &mut f;
+
+        let data = dummy_data(13);
+
+        // Without verifying, the flipped bit goes unnoticed.
+        let mut w: PageWriter<TestHeader> = PageWriter::new();
+        w.open(f, PAGE).await;
+        w.write(f, &data).await.unwrap();
+        w.commit(f).await.unwrap();
+
+        // With verifying, writing fails.
+        w.set_verify(true);
+        w.open(f, PAGE).await;
+        let res: Result<(), Error<()>> = try {
+            w.write(f, &data).await?;
+            w.commit(f).await?;
+        };
+        assert!(matches!(res, Err(Error::Corrupted)));
+    }
+
     #[test_log::test(tokio::test)]
     async fn test_multichunk_no_commit() {
-        let f = &mut MemFlash::new();
+        let f = &mut new_flash();
 
         // Write
         let mut w = PageWriter::new();
@@ -905,7 +1606,7 @@ mod tests {
 
     #[test_log::test(tokio::test)]
     async fn test_multichunk_append() {
-        let f = &mut MemFlash::new();
+        let f = &mut new_flash();
 
         // Write
         let mut w = PageWriter::new();
@@ -940,7 +1641,7 @@ mod tests {
 
     #[test_log::test(tokio::test)]
     async fn test_multichunk_append_no_commit() {
-        let f = &mut MemFlash::new();
+        let f = &mut new_flash();
 
         // Write
         let mut w = PageWriter::new();
@@ -971,7 +1672,7 @@ mod tests {
 
     #[test_log::test(tokio::test)]
     async fn test_multichunk_append_no_commit_then_retry() {
-        let f = &mut MemFlash::new();
+        let f = &mut new_flash();
 
         // Write
         let mut w = PageWriter::new();
@@ -981,10 +1682,12 @@ mod tests {
         w.write_header(f, HEADER).await.unwrap();
         w.commit(f).await.unwrap();
 
-        // Append but don't commit
+        // Append but don't commit. Write more than one ALIGN word, so that some of
+        // it actually reaches flash instead of staying buffered.
         w.open_append(f, PAGE).await.unwrap();
-        let n = w.write(f, &[10, 11, 12, 13, 14, 15]).await.unwrap();
-        assert_eq!(n, 6);
+        let data = [10; 6 + ALIGN];
+        let n = w.write(f, &data).await.unwrap();
+        assert_eq!(n, data.len());
         // no commit!
 
         // Even though we didn't commit the appended stuff, it did get written to flash.
@@ -992,7 +1695,11 @@ mod tests {
         // behave as if it was full.
         w.open_append(f, PAGE).await.unwrap();
         let n = w.write(f, &[13, 14, 15]).await.unwrap();
+        #[cfg(not(feature = "encryption"))]
         assert_eq!(n, 0);
+        // With encryption, uncommitted data is only buffered in RAM, so the page is still clean.
+        #[cfg(feature = "encryption")]
+        assert_eq!(n, 3);
 
         // Read
         let mut r = PageReader::new();
@@ -1010,7 +1717,7 @@ mod tests {
 
     #[test_log::test(tokio::test)]
     async fn test_hydration() {
-        let f = &mut MemFlash::new();
+        let f = &mut new_flash();
 
         // Write
         let mut w = PageWriter::new();
@@ -1065,7 +1772,7 @@ mod tests {
 
     #[test_log::test(tokio::test)]
     async fn test_hydration_multichunk() {
-        let f = &mut MemFlash::new();
+        let f = &mut new_flash();
 
         // Write
         let mut w = PageWriter::new();
@@ -1154,6 +1861,63 @@ mod tests {
         assert_eq!(n, 0);
     }
 
+    #[cfg(feature = "encryption")]
+    #[test_log::test(tokio::test)]
+    async fn test_encryption_tampered() {
+        let f = &mut new_flash();
+
+        let data = dummy_data(13);
+
+        let mut w = PageWriter::new();
+        w.open(f, PAGE).await;
+        w.write(f, &data).await.unwrap();
+        w.write_header(f, HEADER).await.unwrap();
+        w.commit(f).await.unwrap();
+
+        // Data is not stored in plaintext.
+        let data_offset = page_header_size::<TestHeader>() + CHUNK_HEADER_SIZE;
+        assert_ne!(f.flash.data[data_offset..][..data.len()], data);
+
+        // Flip a bit in the header.
+        f.flash.data[PageHeader::SIZE] ^= 0x01;
+        let res = read_header::<_, TestHeader>(f, PAGE).await;
+        assert!(matches!(res, Err(Error::Corrupted)));
+        f.flash.data[PageHeader::SIZE] ^= 0x01;
+
+        // Flip a bit in the data.
+        f.flash.data[data_offset] ^= 0x01;
+        let mut r = PageReader::new();
+        let res = r.open::<_, TestHeader>(f, PAGE).await;
+        assert!(matches!(res, Err(Error::Corrupted)));
+        f.flash.data[data_offset] ^= 0x01;
+
+        let mut r = PageReader::new();
+        r.open::<_, TestHeader>(f, PAGE).await.unwrap();
+        let mut buf = vec![0; data.len()];
+        r.read(f, &mut buf).await.unwrap();
+        assert_eq!(data, buf);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test_log::test(tokio::test)]
+    async fn test_encryption_max_generation() {
+        let f = &mut new_flash();
+
+        let mut w = PageWriter::new();
+        w.open(f, PAGE).await;
+        w.write(f, &dummy_data(13)).await.unwrap();
+        w.commit(f).await.unwrap();
+        w.write_header(f, HEADER).await.unwrap();
+
+        // Appended chunks use a newer generation.
+        w.open_append(f, PAGE).await.unwrap();
+        w.write(f, &dummy_data(13)).await.unwrap();
+        w.commit(f).await.unwrap();
+
+        let max = read_max_generation::<_, TestHeader>(f, PAGE).await.unwrap();
+        assert_eq!(max + 1, f.generation);
+    }
+
     fn dummy_data(len: usize) -> Vec<u8> {
         let mut res = vec![0; len];
         for (i, v) in res.iter_mut().enumerate() {
@@ -1,8 +1,10 @@
 use core::cell::RefCell;
 use core::cmp::Ordering;
+use core::convert::Infallible;
 use core::future::poll_fn;
-use core::ops::{Deref, DerefMut, RangeBounds};
+use core::ops::{Bound, Deref, DerefMut, RangeBounds};
 use core::task::Poll;
+use core::time::Duration;
 
 use embassy_sync::blocking_mutex::raw::RawMutex;
 use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
@@ -10,12 +12,18 @@ use embassy_sync::mutex::Mutex;
 use embassy_sync::waitqueue::WakerRegistration;
 use heapless::Vec;
 
+#[cfg(feature = "encryption")]
+use crate::cipher::Cipher;
 use crate::config::*;
-use crate::errors::{no_eof, CorruptedError, Error, MountError, ReadError, WriteError};
-use crate::file::{FileID, FileManager, FileReader, FileSearcher, FileWriter, SeekDirection, PAGE_MAX_PAYLOAD_SIZE};
-use crate::flash::Flash;
+use crate::cursor::ScratchReaders;
+use crate::errors::{no_eof, CorruptedError, Error, MountError, ReadError, SalvageError, WriteError};
+use crate::file::{
+    DehydratedFileReader, FileID, FileManager, FileReader, FileSearcher, FileWriter, RecordBoundaryCheck,
+    SeekDirection, PAGE_MAX_PAYLOAD_SIZE,
+};
+use crate::flash::{Flash, PageID};
 use crate::page::{PageReader, ReadError as PageReadError};
-use crate::{CommitError, Cursor, FormatError};
+use crate::{blob, CommitError, Cursor, CursorError, FormatError};
 
 const FILE_FLAG_COMPACT_DEST: u8 = 0x01;
 const FILE_FLAG_COMPACT_SRC: u8 = 0x02;
@@ -30,6 +38,81 @@ pub struct Config {
     /// This should be different every boot, and "random enough". It does not
     /// need to be cryptographically secure.
     pub random_seed: u32,
+
+    /// Allow writing keys in any order within a write transaction.
+    ///
+    /// If disabled, keys written in a write transaction must be in lexicographically ascending
+    /// order, otherwise writes fail with [`WriteError::NotSorted`].
+    ///
+    /// If enabled, keys can be written in any order, and the same key can be written multiple times.
+    /// The last write to a key wins. Sorted stretches of writes are stored as separate "runs", which
+    /// are merged as they accumulate, and at commit. This has some costs:
+    /// - Each out-of-order write costs some extra flash writes for the merging. Mostly sorted writes
+    ///   are cheap, fully sorted writes cost nothing extra.
+    /// - A write transaction can only use up to half the free space, because merging needs
+    ///   enough space to hold a second copy of the data written in the transaction.
+    ///
+    /// Needs the `unsorted-writes` Cargo feature.
+    #[cfg(feature = "unsorted-writes")]
+    pub unsorted_writes: bool,
+
+    /// Enable blobs, values bigger than [`MAX_VALUE_SIZE`] stored in parts. See the [`blob`](crate::blob) module.
+    ///
+    /// The parts are stored as regular records, with keys made from the blob key. With this enabled, keys
+    /// shaped like them are reserved: writing them fails with [`WriteError::ReservedKey`]. If disabled, no
+    /// keys are reserved, and the blob methods panic.
+    ///
+    /// Keep it enabled for databases with blobs, otherwise their parts can be overwritten as regular keys.
+    /// Needs `MAX_VALUE_SIZE` to be at least 4. Defaults to `false`.
+    pub blobs: bool,
+
+    /// Erase count spread that triggers static wear leveling.
+    ///
+    /// Data that's rarely compacted, like the oldest file in the tree, can stay on the same pages
+    /// for the whole life of the flash, while the other pages wear out. When the most erased page
+    /// has been erased this many more times than the least erased page of the oldest file, the file
+    /// is relocated to other pages, so the ones it was on get used too.
+    ///
+    /// Relocating is done by [`compact_step`](Database::compact_step), [`compact_full`](Database::compact_full)
+    /// and [`run_compactor`](Database::run_compactor), when there's no compaction work to do. It has the
+    /// same power-fail guarantees as compaction. To bound the extra wear, it happens at most once every
+    /// this many erases of the most erased page. The erase count when it last happened is stored in the
+    /// meta page, so this holds across reboots.
+    ///
+    /// If `None`, the default, there's no static wear leveling.
+    pub wear_leveling_threshold: Option<u32>,
+
+    /// Read back all data after writing it to flash, to check it was written correctly.
+    ///
+    /// Some flash chips can report success writing data, while leaving some bits wrong. With this
+    /// enabled, when the data read back doesn't match, the page is retired like pages failing to
+    /// write, and the data written to it so far is moved to another page, so the database never
+    /// points to the bad data.
+    ///
+    /// With encryption, moving the data needs room in a chunk-sized buffer. If chunks are smaller
+    /// than pages, it can fail, and then the write fails with [`Error::Corrupted`].
+    ///
+    /// This roughly doubles the flash traffic of writes. Defaults to `false`.
+    pub verify_writes: bool,
+
+    /// Keep two copies of the meta page, which has the file table.
+    ///
+    /// The whole database is found through the meta page. If it gets corrupted, for example by a
+    /// disturbed bit or retention loss, mounting fails, or finds an older meta page not erased yet,
+    /// with stale data. With this enabled, the meta page is written to two pages, and mounting uses the
+    /// copy that can be read. If power is lost between writing both, it uses the one written last.
+    ///
+    /// This doubles the flash writes of the meta page at each commit, and uses one more page. Databases
+    /// can be mounted with this enabled or not, no matter how they were written: the meta pages are
+    /// rewritten as configured at the next commit. Defaults to `false`.
+    pub redundant_meta: bool,
+
+    /// Cipher to encrypt and authenticate all the data stored in flash.
+    ///
+    /// If `None`, data is stored unencrypted and unauthenticated. See the [`cipher`](crate::cipher)
+    /// module for details.
+    #[cfg(feature = "encryption")]
+    pub cipher: Option<&'static dyn Cipher>,
 }
 
 impl Default for Config {
@@ -40,7 +123,300 @@ impl Default for Config {
 
 impl Config {
     const fn default() -> Self {
-        Self { random_seed: 0 }
+        Self {
+            random_seed: 0,
+            #[cfg(feature = "unsorted-writes")]
+            unsorted_writes: false,
+            blobs: false,
+            wear_leveling_threshold: None,
+            verify_writes: false,
+            redundant_meta: false,
+            #[cfg(feature = "encryption")]
+            cipher: None,
+        }
+    }
+}
+
+/// Configuration of the background compactor, see [`Database::run_compactor`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct CompactorPolicy {
+    /// Free page count to keep.
+    ///
+    /// The compactor runs while there are this many free pages or less, and there's compaction
+    /// work to do. Writes only have to compact by themselves if they use up all the free pages above
+    /// the minimum they keep, before the compactor gets to run.
+    ///
+    /// If `None`, the default, it's a quarter of the pages of the flash, on top of that minimum.
+    pub free_page_watermark: Option<usize>,
+
+    /// Pages written in each compaction step.
+    ///
+    /// Between steps, the compactor lets other tasks run, and waits for write transactions.
+    /// Smaller steps make them wait less, but make compaction slower overall.
+    pub step_page_count: usize,
+}
+
+impl Default for CompactorPolicy {
+    fn default() -> Self {
+        Self::default()
+    }
+}
+
+impl CompactorPolicy {
+    const fn default() -> Self {
+        Self {
+            free_page_watermark: None,
+            step_page_count: COMPACT_STEP_PAGE_COUNT,
+        }
+    }
+}
+
+/// Space usage statistics, returned by [`Database::stats`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct Stats {
+    /// Total amount of pages in the flash.
+    pub page_count: usize,
+    /// Pages in use.
+    pub used_pages: usize,
+    /// Free pages.
+    pub free_pages: usize,
+    /// Free pages that writes always leave for compaction, so it can't get stuck.
+    ///
+    /// Writes fail with [`WriteError::Full`] if they'd need any of them, and compaction can't free
+    /// more pages. The storage is full when the free pages are down to this, and the garbage bytes
+    /// to zero.
+    pub reserved_pages: usize,
+    /// Pages reserved for scratch files. These are included in `reserved_pages`.
+    pub scratch_pages: usize,
+    /// Pages retired because erasing or writing them failed. These are included in `used_pages`.
+    pub bad_pages: usize,
+    /// Bytes of the records of the keys in the database.
+    pub live_bytes: usize,
+    /// Bytes of the records that aren't live: overwritten and deleted keys, and tombstones.
+    ///
+    /// Compaction removes them, except tombstones can be kept until they reach the oldest file.
+    pub garbage_bytes: usize,
+    /// Bytes in each file.
+    file_bytes: [usize; FILE_COUNT],
+}
+
+impl Stats {
+    /// Number of levels in the file tree.
+    pub fn level_count(&self) -> usize {
+        LEVEL_COUNT
+    }
+
+    /// Bytes in the files of a level.
+    ///
+    /// New data is written to the last level, and compaction moves it towards level 0, merging files.
+    /// The single file holding the result of the compaction of level 0 is counted as part of it.
+    ///
+    /// Returns 0 if `level` is not less than [`level_count`](Self::level_count).
+    pub fn level_bytes(&self, level: usize) -> usize {
+        if level >= LEVEL_COUNT {
+            return 0;
+        }
+        let files = 1 + level * BRANCHING_FACTOR..1 + (level + 1) * BRANCHING_FACTOR;
+        let mut bytes = self.file_bytes[files].iter().sum();
+        if level == 0 {
+            bytes += self.file_bytes[0];
+        }
+        bytes
+    }
+
+    /// Number of files in the file tree.
+    pub fn file_count(&self) -> usize {
+        FILE_COUNT
+    }
+
+    /// Bytes in a file.
+    ///
+    /// File 0 holds the result of the compaction of level 0, and level `l` is made of files
+    /// `1 + l * n..1 + (l + 1) * n`, with `n` files per level. Returns 0 if `file` is not less than
+    /// [`file_count`](Self::file_count).
+    pub fn file_bytes(&self, file: usize) -> usize {
+        self.file_bytes.get(file).copied().unwrap_or(0)
+    }
+
+    /// Bytes in all the files.
+    pub fn total_file_bytes(&self) -> usize {
+        self.file_bytes.iter().sum()
+    }
+}
+
+/// Flash wear statistics, returned by [`Database::wear_stats`].
+///
+/// Erase counts are stored in the page headers, so they're kept across reboots and formats.
+/// Retired pages are left out, they're never erased again.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct WearStats {
+    /// Amount of pages in the flash, except the retired ones.
+    pub page_count: usize,
+    /// Erase count of the least erased page.
+    pub min_erase_count: u32,
+    /// Erase count of the most erased page.
+    pub max_erase_count: u32,
+    /// Sum of the erase counts of all pages.
+    pub total_erase_count: u64,
+}
+
+impl WearStats {
+    /// Mean erase count of all pages.
+    pub fn mean_erase_count(&self) -> f32 {
+        self.total_erase_count as f32 / self.page_count as f32
+    }
+
+    /// Project the lifetime of the flash, if the write pattern stays the same.
+    ///
+    /// `endurance` is the amount of erase cycles each page is rated for, and `elapsed` is the time
+    /// the flash has been in use so far, since it was new. The flash is worn out when the most erased
+    /// page reaches the endurance, so the projection is `elapsed * endurance / max_erase_count`.
+    ///
+    /// Returns `None` if no page has been erased yet, or the result doesn't fit a `Duration`.
+    pub fn projected_lifetime(&self, endurance: u32, elapsed: Duration) -> Option<Duration> {
+        if self.max_erase_count == 0 {
+            return None;
+        }
+        let secs = elapsed.as_secs_f64() * endurance as f64 / self.max_erase_count as f64;
+        Duration::try_from_secs_f64(secs).ok()
+    }
+}
+
+/// Maximum amount of problems listed in a [`CheckReport`].
+const MAX_CHECK_PROBLEMS: usize = 16;
+
+/// Integrity check results, returned by [`Database::check`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct CheckReport {
+    /// Pages checked.
+    pub page_count: usize,
+    /// Records checked.
+    pub record_count: usize,
+    /// Problems found. Only the first ones are listed in [`problems`](Self::problems) if there are many.
+    pub problem_count: usize,
+    problems: Vec<CheckProblem, MAX_CHECK_PROBLEMS>,
+}
+
+impl CheckReport {
+    pub(crate) const fn new() -> Self {
+        Self {
+            page_count: 0,
+            record_count: 0,
+            problem_count: 0,
+            problems: Vec::new(),
+        }
+    }
+
+    /// Whether no problems were found.
+    pub fn is_ok(&self) -> bool {
+        self.problem_count == 0
+    }
+
+    /// Problems found, in the order they were found.
+    pub fn problems(&self) -> &[CheckProblem] {
+        &self.problems
+    }
+
+    pub(crate) fn add(&mut self, file_id: FileID, page_id: Option<PageID>, kind: CheckProblemKind) {
+        warn!("check: file {} page {:?}: {:?}", file_id, page_id, kind);
+        self.problem_count += 1;
+        let _ = self.problems.push(CheckProblem { file_id, page_id, kind });
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for CheckReport {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(
+            fmt,
+            "CheckReport {{ page_count: {}, record_count: {}, problem_count: {}, problems: {} }}",
+            self.page_count,
+            self.record_count,
+            self.problem_count,
+            self.problems(),
+        )
+    }
+}
+
+/// A problem found by [`Database::check`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct CheckProblem {
+    /// File the problem was found in.
+    pub file_id: u8,
+    /// Page the problem was found in, if it's known.
+    pub page_id: Option<PageID>,
+    /// What's wrong.
+    pub kind: CheckProblemKind,
+}
+
+/// Kind of a [`CheckProblem`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub enum CheckProblemKind {
+    /// The page header is unreadable, or fails its integrity check. The pages before it in the file
+    /// can't be found, so they aren't checked.
+    PageHeader,
+    /// A chunk of the page is malformed, or fails its CRC or authentication check.
+    Chunk,
+    /// The page's data doesn't line up with the page after it in the file, or the pages before it
+    /// are missing.
+    Seq,
+    /// The page's skiplist doesn't point to the right pages.
+    Skiplist,
+    /// The page's `record_boundary` doesn't point to the start of a record in the page.
+    RecordBoundary,
+    /// A record header is invalid, or a record is cut short by the end of the file. The records
+    /// after it in the file can't be found, so they aren't checked.
+    Record,
+    /// A record's key is not greater than the key of the record before it.
+    KeyOrder,
+}
+
+/// Salvage results, returned by [`Database::salvage`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct SalvageReport {
+    /// Whether a meta page with the file table was found. Without it, which records are the newest
+    /// is a guess.
+    pub file_table_found: bool,
+    /// Files in the file table whose last page is unreadable. Their records are salvaged from the
+    /// pages before it, but they're treated as older than all the files.
+    pub lost_file_count: usize,
+    /// Pages with a valid header and chunks found.
+    pub page_count: usize,
+    /// Pages with a valid header, but chunks that can't be read. Their data is lost.
+    pub unreadable_page_count: usize,
+    /// Chains of pages salvaged.
+    pub chain_count: usize,
+    /// Records salvaged.
+    pub record_count: usize,
+    /// Bytes of the chains that couldn't be salvaged: the partial record at the start of the chains
+    /// that start in the middle of one, and the rest of a chain after a record that can't be read.
+    pub lost_bytes: usize,
+}
+
+impl SalvageReport {
+    pub(crate) const fn new() -> Self {
+        Self {
+            file_table_found: false,
+            lost_file_count: 0,
+            page_count: 0,
+            unreadable_page_count: 0,
+            chain_count: 0,
+            record_count: 0,
+            lost_bytes: 0,
+        }
     }
 }
 
@@ -57,6 +433,9 @@ struct State {
     read_tx_count: usize,
     write_tx: WriteTxState,
     waker: WakerRegistration,
+    // Set when a write transaction commits, for the background compactor to check the free pages.
+    committed: bool,
+    compactor_waker: WakerRegistration,
 }
 
 /// The main database struct.
@@ -64,6 +443,9 @@ pub struct Database<F: Flash, M: RawMutex> {
     state: BlockingMutex<M, RefCell<State>>,
 
     pub(crate) inner: Mutex<M, Inner<F>>,
+
+    /// Whether blobs are enabled, see [`Config::blobs`].
+    pub(crate) blobs: bool,
 }
 
 impl<F: Flash, M: RawMutex> Database<F, M> {
@@ -78,13 +460,19 @@ impl<F: Flash, M: RawMutex> Database<F, M> {
     /// useful to detect whether the storage is formatted or not, so that you can format it if it isn't
     /// before first use.
     pub fn new(flash: F, config: Config) -> Self {
+        // Blob headers are stored as a regular record, so they must fit in one.
+        assert!(!config.blobs || MAX_VALUE_SIZE >= blob::HEADER_SIZE);
+
         Self {
-            inner: Mutex::new(Inner::new(flash, config.random_seed)),
+            inner: Mutex::new(Inner::new(flash, &config)),
             state: BlockingMutex::new(RefCell::new(State {
                 read_tx_count: 0,
                 write_tx: WriteTxState::Idle,
                 waker: WakerRegistration::new(),
+                committed: false,
+                compactor_waker: WakerRegistration::new(),
             })),
+            blobs: config.blobs,
         }
     }
 
@@ -127,6 +515,189 @@ impl<F: Flash, M: RawMutex> Database<F, M> {
         self.inner.lock().await.dump().await
     }
 
+    /// Get space usage statistics.
+    ///
+    /// This reads all the keys in the database, to count the bytes of the live records, so it takes
+    /// a while for big databases. It waits for write transactions being committed, like a read transaction.
+    pub async fn stats(&self) -> Result<Stats, Error<F::Error>> {
+        let rtx = self.read_transaction().await;
+
+        let mut live_bytes = 0;
+        let mut cursor = rtx.read_all().await?;
+        let mut key = [0; MAX_KEY_SIZE];
+        loop {
+            let res = cursor.next_key(&mut key).await.map_err(|e| match e {
+                CursorError::Flash(e) => Error::Flash(e),
+                _ => Error::Corrupted,
+            })?;
+            let Some((key_len, value_len)) = res else {
+                break;
+            };
+            live_bytes += RECORD_HEADER_SIZE + key_len + value_len;
+        }
+
+        let inner = self.inner.lock().await;
+        let file_bytes = core::array::from_fn(|i| inner.files.file_len(i as FileID));
+        let used_pages = inner.files.used_pages();
+        let free_pages = inner.files.free_pages();
+        let bad_pages = inner.files.bad_pages();
+        Ok(Stats {
+            page_count: used_pages + free_pages,
+            used_pages,
+            free_pages,
+            reserved_pages: inner.min_free_page_count(),
+            scratch_pages: SCRATCH_PAGE_COUNT,
+            bad_pages,
+            live_bytes,
+            garbage_bytes: file_bytes.iter().sum::<usize>().saturating_sub(live_bytes),
+            file_bytes,
+        })
+    }
+
+    /// Get flash wear statistics.
+    ///
+    /// The erase counts are not kept in RAM, this reads the header of every page.
+    pub async fn wear_stats(&self) -> Result<WearStats, Error<F::Error>> {
+        let inner = &mut *self.inner.lock().await;
+        inner.files.remount_if_dirty(&mut inner.readers[0]).await?;
+
+        let counts = inner.files.erase_counts().await.map_err(Error::Flash)?;
+        Ok(WearStats {
+            page_count: counts.page_count,
+            min_erase_count: counts.min,
+            max_erase_count: counts.max,
+            total_erase_count: counts.total,
+        })
+    }
+
+    /// Check the integrity of the database.
+    ///
+    /// This reads every page of every file, checking the page headers, the chunks (their CRCs,
+    /// with the `crc` feature), the page sequence numbers, skiplists and record boundaries, the
+    /// record headers, and that keys are sorted within each file. Problems found are returned in
+    /// the report, errors are only returned if mounting fails, or for flash errors.
+    ///
+    /// This reads the whole database, so it takes a while for big ones. All other operations wait
+    /// for it to finish.
+    pub async fn check(&self) -> Result<CheckReport, Error<F::Error>> {
+        let inner = &mut *self.inner.lock().await;
+        inner.files.remount_if_dirty(&mut inner.readers[0]).await?;
+        inner.check().await
+    }
+
+    /// Salvage as many records as possible from a corrupted database, into a freshly formatted one.
+    ///
+    /// Use this instead of [`format`](Self::format) when [`mount`](Self::mount) fails with
+    /// [`MountError::Corrupted`], or [`check`](Self::check) finds problems. It scans all pages for
+    /// data pages, and follows the chains they make up, like the pages of a file. The records of each
+    /// chain are written in a write transaction of their own, so the ones in later chains win.
+    ///
+    /// The file table in the newest meta page that can be read, if any, tells which chains are files,
+    /// and their age. Files are written oldest first, so the newest data wins. If the file table and all
+    /// its files are read whole, the rest of chains are stale, and they're discarded. Otherwise, they're
+    /// written before the files, by the seq of their last page. They can be pages freed by compaction but
+    /// not erased yet, so records that were deleted or overwritten can come back. If a page of a file
+    /// is unreadable, the chain with the pages before it is found through its skiplist, and written
+    /// along with the file.
+    ///
+    /// Pages are kept until their records are salvaged, so this needs enough free space to write them.
+    /// The records of chains not in the file table that don't fit are lost, they're likely stale. If it
+    /// fails, calling it again before writing anything else salvages the records not salvaged yet.
+    /// This reads the whole flash, so it takes a while.
+    pub async fn salvage(&self) -> Result<SalvageReport, SalvageError<F::Error>> {
+        // Wait for the write transaction to end, and keep new ones from starting until we're done.
+        let _wtx = self.write_transaction().await;
+        self.inner.lock().await.salvage().await
+    }
+
+    /// Do one step of compaction.
+    ///
+    /// Compaction normally runs when needed, in the middle of a write, which can take a long
+    /// time if there's a lot of work to do. Calling this when the database is idle does the work
+    /// ahead of time, in pieces of bounded size, so that later writes are faster.
+    ///
+    /// Returns `true` if there's more compaction work left to do.
+    ///
+    /// This will wait if there's a write transaction open.
+    pub async fn compact_step(&self) -> Result<bool, Error<F::Error>> {
+        self.compact(COMPACT_STEP_PAGE_COUNT, Some(usize::MAX)).await
+    }
+
+    /// Do all the compaction work there is to do.
+    ///
+    /// This can take a long time, and erase many pages. Other operations can run between
+    /// compactions of different levels. See [`compact_step`](Self::compact_step) to do the work
+    /// in smaller pieces.
+    ///
+    /// This will wait if there's a write transaction open.
+    pub async fn compact_full(&self) -> Result<(), Error<F::Error>> {
+        while self.compact(usize::MAX, Some(usize::MAX)).await? {}
+        Ok(())
+    }
+
+    /// Run compaction in the background.
+    ///
+    /// This keeps the free page count above the [watermark](CompactorPolicy::free_page_watermark),
+    /// compacting in small steps, so that writes almost never have to wait for compaction. Run it
+    /// in its own task.
+    ///
+    /// Between steps, it lets other tasks run, and waits while a write transaction is open, so they
+    /// aren't delayed by more than a step. When there's nothing to do, it waits for the next commit.
+    ///
+    /// This never returns, unless there's an error.
+    pub async fn run_compactor(&self, policy: CompactorPolicy) -> Result<Infallible, Error<F::Error>> {
+        loop {
+            if !self.compact(policy.step_page_count, policy.free_page_watermark).await? {
+                poll_fn(|cx| {
+                    self.state.lock(|s| {
+                        let s = &mut s.borrow_mut();
+                        if !s.committed {
+                            s.compactor_waker.register(cx.waker());
+                            return Poll::Pending;
+                        }
+                        s.committed = false;
+                        Poll::Ready(())
+                    })
+                })
+                .await;
+            }
+
+            yield_now().await;
+        }
+    }
+
+    /// Do a compaction step, if there are `free_page_watermark` free pages or less. Otherwise, or if
+    /// there's no compaction work, start relocating a file for wear leveling, if needed.
+    ///
+    /// If `free_page_watermark` is `None`, it's a quarter of the pages above the minimum free pages.
+    ///
+    /// Returns whether there's more work to do.
+    async fn compact(&self, max_pages: usize, free_page_watermark: Option<usize>) -> Result<bool, Error<F::Error>> {
+        // Compaction must not run in the middle of a write transaction. Wait for it to end, and keep
+        // new ones from starting until we're done.
+        let _wtx = self.write_transaction().await;
+
+        let inner = &mut *self.inner.lock().await;
+        inner.files.remount_if_dirty(&mut inner.readers[0]).await?;
+        // Discard the writes of a transaction dropped without committing, if any.
+        inner.rollback_if_any().await?;
+
+        let free_page_watermark =
+            free_page_watermark.unwrap_or_else(|| inner.min_free_page_count() + inner.files.page_count() / 4);
+
+        // Finish in-progress compactions even above the watermark, they can be relocations for wear leveling.
+        let did_work = match inner.files.free_pages() <= free_page_watermark || inner.is_compacting() {
+            true => inner.compact_step(max_pages).await?,
+            false => false,
+        };
+        if !did_work {
+            inner.wear_level(max_pages).await?;
+        }
+
+        Ok(inner.is_compacting()
+            || (inner.files.free_pages() <= free_page_watermark && inner.compact_find_work()?.is_some()))
+    }
+
     /// Open a read transaction.
     ///
     /// This will wait if there's a write transaction either being currently committed, or
@@ -185,10 +756,25 @@ impl<F: Flash, M: RawMutex> Database<F, M> {
         WriteTransaction {
             db: self,
             state: WriteTransactionState::Created,
+            scratch: ScratchReaders::new(),
         }
     }
 }
 
+/// Let other tasks run before continuing.
+async fn yield_now() {
+    let mut yielded = false;
+    poll_fn(|cx| {
+        if yielded {
+            return Poll::Ready(());
+        }
+        yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    })
+    .await
+}
+
 struct FlashLockGuard<G, F>(G)
 where
     G: Deref<Target = Inner<F>> + DerefMut,
@@ -217,7 +803,7 @@ where
 
 /// In-progress read transaction.
 pub struct ReadTransaction<'a, F: Flash + 'a, M: RawMutex + 'a> {
-    db: &'a Database<F, M>,
+    pub(crate) db: &'a Database<F, M>,
 }
 
 impl<'a, F: Flash + 'a, M: RawMutex + 'a> Drop for ReadTransaction<'a, F, M> {
@@ -244,7 +830,31 @@ impl<'a, F: Flash + 'a, M: RawMutex + 'a> ReadTransaction<'a, F, M> {
             return Err(ReadError::KeyTooBig);
         }
 
-        self.db.inner.lock().await.read(key, value).await
+        self.db.inner.lock().await.read(key, value, false).await
+    }
+
+    /// Read part of the value of a key from the database, starting at `offset` bytes into it.
+    ///
+    /// Reads as many bytes as fit in the `value` buffer, stopping at the end of the value.
+    /// The amount of bytes read is returned, which is zero if `offset` is at or past the end of the value.
+    ///
+    /// This allows reading values bigger than the buffer in pieces, or reading only a part of them.
+    /// Use [`value_len`](Self::value_len) to get the full length of the value.
+    pub async fn read_at(&self, key: &[u8], offset: usize, value: &mut [u8]) -> Result<usize, ReadError<F::Error>> {
+        if key.len() > MAX_KEY_SIZE {
+            return Err(ReadError::KeyTooBig);
+        }
+
+        self.db.inner.lock().await.read_at(key, offset, value, false).await
+    }
+
+    /// Get the length of the value of a key in the database.
+    pub async fn value_len(&self, key: &[u8]) -> Result<usize, ReadError<F::Error>> {
+        if key.len() > MAX_KEY_SIZE {
+            return Err(ReadError::KeyTooBig);
+        }
+
+        self.db.inner.lock().await.value_len(key, false).await
     }
 
     /// Get a cursor for reading all the keys in the database.
@@ -263,7 +873,49 @@ impl<'a, F: Flash + 'a, M: RawMutex + 'a> ReadTransaction<'a, F, M> {
         &'b self,
         range: impl RangeBounds<&'b [u8]>,
     ) -> Result<Cursor<'b, F, M>, Error<F::Error>> {
-        Cursor::new(self.db, range.start_bound().map(|x| *x), range.end_bound().map(|x| *x)).await
+        self.cursor(range, false).await
+    }
+
+    /// Get a cursor for reading all the keys in the database, in reverse order.
+    ///
+    /// This is equivalent to calling `read_range_rev(..)`.
+    ///
+    /// The cursor returns the keys in lexicographically descending order.
+    pub async fn read_all_rev<'b>(&'b self) -> Result<Cursor<'b, F, M>, Error<F::Error>> {
+        self.read_range_rev(..).await
+    }
+
+    /// Get a cursor for reading keys in the database that are in the given range, in reverse order.
+    ///
+    /// The cursor returns the keys in lexicographically descending order, starting from the end of the range.
+    pub async fn read_range_rev<'b>(
+        &'b self,
+        range: impl RangeBounds<&'b [u8]>,
+    ) -> Result<Cursor<'b, F, M>, Error<F::Error>> {
+        self.cursor(range, true).await
+    }
+
+    /// Get a cursor for reading all the keys in the database that start with `prefix`.
+    ///
+    /// The cursor returns the keys in lexicographically ascending order. An empty prefix matches all keys.
+    pub async fn read_prefix<'b>(&'b self, prefix: &'b [u8]) -> Result<Cursor<'b, F, M>, Error<F::Error>> {
+        Cursor::new(self.db, Bound::Included(prefix), Bound::Unbounded, prefix, None, false).await
+    }
+
+    async fn cursor<'b>(
+        &'b self,
+        range: impl RangeBounds<&'b [u8]>,
+        rev: bool,
+    ) -> Result<Cursor<'b, F, M>, Error<F::Error>> {
+        Cursor::new(
+            self.db,
+            range.start_bound().map(|x| *x),
+            range.end_bound().map(|x| *x),
+            &[],
+            None,
+            rev,
+        )
+        .await
     }
 }
 
@@ -286,8 +938,10 @@ enum WriteTransactionState {
 /// To recover, you must drop the entire `WriteTransaction`, and (if desired) open a new one to retry
 /// the writes.
 pub struct WriteTransaction<'a, F: Flash + 'a, M: RawMutex + 'a> {
-    db: &'a Database<F, M>,
+    pub(crate) db: &'a Database<F, M>,
     state: WriteTransactionState,
+    /// Scratch file state of the cursor reading the pending writes, if any.
+    scratch: ScratchReaders,
 }
 
 impl<'a, F: Flash + 'a, M: RawMutex + 'a> Drop for WriteTransaction<'a, F, M> {
@@ -303,61 +957,408 @@ impl<'a, F: Flash + 'a, M: RawMutex + 'a> Drop for WriteTransaction<'a, F, M> {
 }
 
 impl<'a, F: Flash + 'a, M: RawMutex + 'a> WriteTransaction<'a, F, M> {
-    /// Write a key to the database.
+    /// Read a key from the database.
     ///
-    /// If the key was already present, the previous value is overwritten.
-    pub async fn write(&mut self, key: &[u8], value: &[u8]) -> Result<(), WriteError<F::Error>> {
-        self.write_inner(key, value, false).await
-    }
-
-    /// Delete a key from the database.
+    /// Reads see the writes and deletes done so far in this transaction, even
+    /// though they're not committed yet.
     ///
-    /// If the key was not present, this is a no-op.
-    pub async fn delete(&mut self, key: &[u8]) -> Result<(), WriteError<F::Error>> {
-        self.write_inner(key, &[], true).await
-    }
+    /// The value is stored in the `value` buffer, and the length is returned.
+    pub async fn read(&mut self, key: &[u8], value: &mut [u8]) -> Result<usize, ReadError<F::Error>> {
+        if key.len() > MAX_KEY_SIZE {
+            return Err(ReadError::KeyTooBig);
+        }
 
-    async fn write_inner(&mut self, key: &[u8], value: &[u8], is_delete: bool) -> Result<(), WriteError<F::Error>> {
-        let is_first_write = match self.state {
-            WriteTransactionState::Canceled => return Err(WriteError::TransactionCanceled),
-            WriteTransactionState::Created => true,
-            WriteTransactionState::InProgress => false,
+        let Some(pending) = self.read_snapshot().await? else {
+            return Err(ReadError::TransactionCanceled);
         };
 
+        self.db.inner.lock().await.read(key, value, pending).await
+    }
+
+    /// Read part of the value of a key from the database, starting at `offset` bytes into it.
+    ///
+    /// Reads see the writes and deletes done so far in this transaction, even
+    /// though they're not committed yet.
+    ///
+    /// Reads as many bytes as fit in the `value` buffer, stopping at the end of the value.
+    /// The amount of bytes read is returned, which is zero if `offset` is at or past the end of the value.
+    /// See [`ReadTransaction::read_at`].
+    pub async fn read_at(&mut self, key: &[u8], offset: usize, value: &mut [u8]) -> Result<usize, ReadError<F::Error>> {
         if key.len() > MAX_KEY_SIZE {
-            return Err(WriteError::KeyTooBig);
-        }
-        if value.len() > MAX_VALUE_SIZE {
-            return Err(WriteError::ValueTooBig);
+            return Err(ReadError::KeyTooBig);
         }
 
-        // Canceling a call to `write()` (dropping its `Future` before it's done) can leave
-        // the transaction in an undefined state, so we cancel it entirely.
-        self.state = WriteTransactionState::Canceled;
+        let Some(pending) = self.read_snapshot().await? else {
+            return Err(ReadError::TransactionCanceled);
+        };
 
-        // If inner `write` fails, we also cancel the transaction.
-        let db = &mut *self.db.inner.lock().await;
-        db.files.remount_if_dirty(&mut db.readers[0]).await?;
+        self.db.inner.lock().await.read_at(key, offset, value, pending).await
+    }
 
-        if is_first_write {
-            db.rollback_if_any().await?;
+    /// Get the length of the value of a key in the database.
+    ///
+    /// Reads see the writes and deletes done so far in this transaction, even
+    /// though they're not committed yet.
+    pub async fn value_len(&mut self, key: &[u8]) -> Result<usize, ReadError<F::Error>> {
+        if key.len() > MAX_KEY_SIZE {
+            return Err(ReadError::KeyTooBig);
         }
-        db.write(key, value, is_delete).await?;
 
-        self.state = WriteTransactionState::InProgress;
+        let Some(pending) = self.read_snapshot().await? else {
+            return Err(ReadError::TransactionCanceled);
+        };
 
-        Ok(())
+        self.db.inner.lock().await.value_len(key, pending).await
     }
 
-    /// Commit the transaction.
+    /// Get a cursor for reading all the keys in the database.
     ///
-    /// This will wait until no read transaction is open. While waiting, opening new read transactions
-    /// is blocked, to prevent readers from starving writers.
+    /// This is equivalent to calling `read_range(..)`.
     ///
-    /// This makes all the writes permanent on the underlyling database. When it returns, the writes are
-    /// guaranteed to be fully safely written to flash, so they can't get lost by a crash or a power failure anymore.
+    /// The cursor returns the keys in lexicographically ascending order. It sees the writes and deletes
+    /// done so far in this transaction, even though they're not committed yet.
+    pub async fn read_all<'b>(&'b mut self) -> Result<Cursor<'b, F, M>, CursorError<F::Error>> {
+        self.read_range(..).await
+    }
+
+    /// Get a cursor for reading keys in the database that are in the given range.
     ///
-    /// Committing is atomic: if commit is interrupted (due to power loss, crash, or canceling the future), it is
+    /// The cursor returns the keys in lexicographically ascending order. It sees the writes and deletes
+    /// done so far in this transaction, even though they're not committed yet.
+    pub async fn read_range<'b>(
+        &'b mut self,
+        range: impl RangeBounds<&'b [u8]>,
+    ) -> Result<Cursor<'b, F, M>, CursorError<F::Error>> {
+        self.cursor(range, false).await
+    }
+
+    /// Get a cursor for reading all the keys in the database, in reverse order.
+    ///
+    /// This is equivalent to calling `read_range_rev(..)`.
+    ///
+    /// The cursor returns the keys in lexicographically descending order. It sees the writes and deletes
+    /// done so far in this transaction, even though they're not committed yet.
+    pub async fn read_all_rev<'b>(&'b mut self) -> Result<Cursor<'b, F, M>, CursorError<F::Error>> {
+        self.read_range_rev(..).await
+    }
+
+    /// Get a cursor for reading keys in the database that are in the given range, in reverse order.
+    ///
+    /// The cursor returns the keys in lexicographically descending order, starting from the end of the range.
+    /// It sees the writes and deletes done so far in this transaction, even though they're not committed yet.
+    pub async fn read_range_rev<'b>(
+        &'b mut self,
+        range: impl RangeBounds<&'b [u8]>,
+    ) -> Result<Cursor<'b, F, M>, CursorError<F::Error>> {
+        self.cursor(range, true).await
+    }
+
+    /// Get a cursor for reading all the keys in the database that start with `prefix`.
+    ///
+    /// The cursor returns the keys in lexicographically ascending order. An empty prefix matches all keys.
+    /// It sees the writes and deletes done so far in this transaction, even though they're not committed yet.
+    pub async fn read_prefix<'b>(&'b mut self, prefix: &'b [u8]) -> Result<Cursor<'b, F, M>, CursorError<F::Error>> {
+        self.cursor_prefix((Bound::Included(prefix), Bound::Unbounded), prefix, false)
+            .await
+    }
+
+    async fn cursor<'b>(
+        &'b mut self,
+        range: impl RangeBounds<&'b [u8]>,
+        rev: bool,
+    ) -> Result<Cursor<'b, F, M>, CursorError<F::Error>> {
+        self.cursor_prefix(range, &[], rev).await
+    }
+
+    async fn cursor_prefix<'b>(
+        &'b mut self,
+        range: impl RangeBounds<&'b [u8]>,
+        prefix: &'b [u8],
+        rev: bool,
+    ) -> Result<Cursor<'b, F, M>, CursorError<F::Error>> {
+        let Some(pending) = self.read_snapshot().await? else {
+            return Err(CursorError::TransactionCanceled);
+        };
+
+        let cursor = Cursor::new(
+            self.db,
+            range.start_bound().map(|x| *x),
+            range.end_bound().map(|x| *x),
+            prefix,
+            pending.then_some(&mut self.scratch),
+            rev,
+        )
+        .await?;
+        Ok(cursor)
+    }
+
+    /// Get ready to read within this transaction. Returns whether the reads have to see the writes done so
+    /// far, or `None` if the transaction is canceled.
+    async fn read_snapshot(&mut self) -> Result<Option<bool>, Error<F::Error>> {
+        match self.state {
+            WriteTransactionState::Canceled => Ok(None),
+            WriteTransactionState::Created => Ok(Some(false)),
+            WriteTransactionState::InProgress => {
+                self.snapshot().await?;
+                Ok(Some(true))
+            }
+        }
+    }
+
+    /// Make the writes done so far visible to reads within this transaction.
+    async fn snapshot(&mut self) -> Result<(), Error<F::Error>> {
+        // Taking the snapshot can write to flash. If it fails or is canceled, the
+        // transaction is left in an undefined state, so we cancel it entirely.
+        self.state = WriteTransactionState::Canceled;
+
+        let db = &mut *self.db.inner.lock().await;
+        db.files.remount_if_dirty(&mut db.readers[0]).await?;
+        db.snapshot_write_transaction().await?;
+
+        self.state = WriteTransactionState::InProgress;
+
+        Ok(())
+    }
+
+    /// Make sure the writes that follow will fit.
+    ///
+    /// This reserves space for `records` more records, with keys and values adding up to `bytes`,
+    /// compacting now if needed. After it succeeds, writes within the reservation never fail with
+    /// [`WriteError::Full`], and never have to wait for compaction.
+    ///
+    /// A delete counts as a record with an empty value, and a range or prefix delete counts as two:
+    /// one for each bound. With [`Config::unsorted_writes`], the reservation includes the space
+    /// needed to merge the runs, assuming there could be range deletes.
+    ///
+    /// If the records can't fit even after compacting, this fails with [`WriteError::Full`], without
+    /// canceling the transaction. This can be used before the first write, to check whether the whole
+    /// transaction will fit.
+    pub async fn reserve(&mut self, records: usize, bytes: usize) -> Result<(), WriteError<F::Error>> {
+        let state = self.state;
+        if state == WriteTransactionState::Canceled {
+            return Err(WriteError::TransactionCanceled);
+        }
+
+        // Canceling the compaction can leave the transaction in an undefined state, so we cancel it entirely.
+        self.state = WriteTransactionState::Canceled;
+
+        let db = &mut *self.db.inner.lock().await;
+        db.files.remount_if_dirty(&mut db.readers[0]).await?;
+
+        if state == WriteTransactionState::Created {
+            db.rollback_if_any().await?;
+        }
+
+        // Each record has a header, and tombstones have a small value. A range start written later
+        // needs a range end after it, which can have a key of any length.
+        let record_size = RecordHeader::tombstone(RecordKind::RangeEnd, 0).record_size();
+        let size = records
+            .saturating_mul(record_size)
+            .saturating_add(bytes)
+            .saturating_add(MAX_RANGE_TOMBSTONE_SIZE);
+
+        let res = db.reserve(size).await;
+        if let Ok(()) | Err(WriteError::Full) = res {
+            self.state = state;
+        }
+        res
+    }
+
+    /// Write a key to the database.
+    ///
+    /// If the key was already present, the previous value is overwritten.
+    ///
+    /// Keys must be written in lexicographically ascending order, unless
+    /// [`Config::unsorted_writes`] is enabled.
+    ///
+    /// With [`Config::blobs`], keys reserved for the records of blobs can't be written, see [`WriteError::ReservedKey`].
+    pub async fn write(&mut self, key: &[u8], value: &[u8]) -> Result<(), WriteError<F::Error>> {
+        self.check_reserved_key(key)?;
+        self.write_inner(key, value, RecordKind::Value).await
+    }
+
+    /// Delete a key from the database.
+    ///
+    /// If the key was not present, this is a no-op. With [`Config::blobs`], keys reserved for the records
+    /// of blobs can't be deleted, use [`delete_blob`](Self::delete_blob) instead.
+    pub async fn delete(&mut self, key: &[u8]) -> Result<(), WriteError<F::Error>> {
+        self.check_reserved_key(key)?;
+        self.write_inner(key, &[], RecordKind::Delete).await
+    }
+
+    /// Delete all the keys in `range` from the database.
+    ///
+    /// Instead of deleting the keys one by one, this writes a single range tombstone, no matter how many
+    /// keys are in the range. The space used by the deleted keys is freed by compaction.
+    ///
+    /// Unless [`Config::unsorted_writes`] is enabled, the start of the range must be after the keys written
+    /// before in this transaction, and the keys written after must not be lower than its end. If the range
+    /// has no end, no more keys can be written after it.
+    ///
+    /// With [`Config::blobs`], ranges that would delete only some of the records of a blob fail with
+    /// [`WriteError::ReservedKey`], see the [`blob`](crate::blob) module.
+    pub async fn delete_range(&mut self, range: impl RangeBounds<&[u8]>) -> Result<(), WriteError<F::Error>> {
+        self.check_reserved_range(&range)?;
+        self.delete_range_inner(range).await
+    }
+
+    pub(crate) async fn delete_range_inner(
+        &mut self,
+        range: impl RangeBounds<&[u8]>,
+    ) -> Result<(), WriteError<F::Error>> {
+        if self.state == WriteTransactionState::Canceled {
+            return Err(WriteError::TransactionCanceled);
+        }
+        for bound in [range.start_bound(), range.end_bound()] {
+            if let Bound::Included(k) | Bound::Excluded(k) = bound {
+                if k.len() > MAX_KEY_SIZE {
+                    return Err(WriteError::KeyTooBig);
+                }
+            }
+        }
+
+        let start = match range.start_bound() {
+            Bound::Included(k) => Vec::from_slice(k).unwrap(),
+            Bound::Excluded(k) => match key_successor(k) {
+                Some(k) => k,
+                None => return Ok(()), // nothing after the highest key.
+            },
+            Bound::Unbounded => Vec::new(),
+        };
+        let end = match range.end_bound() {
+            Bound::Included(k) => key_successor(k),
+            Bound::Excluded(k) => Some(Vec::from_slice(k).unwrap()),
+            Bound::Unbounded => None,
+        };
+        if end.as_ref().is_some_and(|end| start >= *end) {
+            return Ok(());
+        }
+        // A range with just one key is a delete. Snapshots for reads rely on ranges having a key between
+        // their start and end, see `Inner::close_range_before_end`.
+        if end.is_some() && end == key_successor(&start) {
+            return self.write_inner(&start, &[], RecordKind::Delete).await;
+        }
+
+        let value_len = RecordKind::RangeStart.tombstone_value_len();
+        self.write_begin(&start, value_len, RecordKind::RangeStart).await?;
+
+        let db = &mut *self.db.inner.lock().await;
+        db.write_value(&TOMBSTONE_VALUE[..value_len]).await?;
+        db.write_end();
+        db.write_tx.as_mut().unwrap().range_end = Some(end);
+
+        self.state = WriteTransactionState::InProgress;
+
+        Ok(())
+    }
+
+    /// Delete all the keys starting with `prefix` from the database.
+    ///
+    /// An empty prefix deletes all keys. This is the same as [`delete_range`](Self::delete_range) with the range
+    /// of the keys starting with `prefix`, so the same ordering rules apply.
+    pub async fn delete_prefix(&mut self, prefix: &[u8]) -> Result<(), WriteError<F::Error>> {
+        if prefix.len() > MAX_KEY_SIZE {
+            return Err(WriteError::KeyTooBig);
+        }
+
+        match prefix_successor(prefix) {
+            Some(end) => self.delete_range(prefix..&end[..]).await,
+            None => self.delete_range(prefix..).await,
+        }
+    }
+
+    /// Start writing a key to the database, with a value of `len` bytes written in pieces.
+    ///
+    /// The value is written with the returned [`ValueWriter`]. The pieces written to it must add
+    /// up to exactly `len` bytes, and then [`ValueWriter::finish`] must be called. Otherwise, the
+    /// entire transaction is canceled.
+    ///
+    /// This allows writing values that aren't available in a single buffer, for example
+    /// because they're received in fragments, without assembling them in RAM first.
+    ///
+    /// Keys must be written in lexicographically ascending order, unless
+    /// [`Config::unsorted_writes`] is enabled. With [`Config::blobs`], keys reserved for the records of
+    /// blobs can't be written, see [`WriteError::ReservedKey`].
+    pub async fn begin_value(
+        &mut self,
+        key: &[u8],
+        len: usize,
+    ) -> Result<ValueWriter<'_, 'a, F, M>, WriteError<F::Error>> {
+        self.check_reserved_key(key)?;
+        self.write_begin(key, len, RecordKind::Value).await?;
+
+        // The transaction stays canceled until the value is finished, so dropping the
+        // `ValueWriter` before that cancels it.
+        Ok(ValueWriter {
+            tx: self,
+            remaining: len,
+            canceled: false,
+        })
+    }
+
+    pub(crate) async fn write_inner(
+        &mut self,
+        key: &[u8],
+        value: &[u8],
+        kind: RecordKind,
+    ) -> Result<(), WriteError<F::Error>> {
+        self.write_begin(key, value.len(), kind).await?;
+
+        let db = &mut *self.db.inner.lock().await;
+        db.write_value(value).await?;
+        db.write_end();
+
+        self.state = WriteTransactionState::InProgress;
+
+        Ok(())
+    }
+
+    /// Write the header and key of a record. Leaves the transaction canceled, the caller must
+    /// set it back to in progress after writing the value.
+    async fn write_begin(
+        &mut self,
+        key: &[u8],
+        value_len: usize,
+        kind: RecordKind,
+    ) -> Result<(), WriteError<F::Error>> {
+        let is_first_write = match self.state {
+            WriteTransactionState::Canceled => return Err(WriteError::TransactionCanceled),
+            WriteTransactionState::Created => true,
+            WriteTransactionState::InProgress => false,
+        };
+
+        if key.len() > MAX_KEY_SIZE {
+            return Err(WriteError::KeyTooBig);
+        }
+        if value_len > MAX_VALUE_SIZE {
+            return Err(WriteError::ValueTooBig);
+        }
+
+        // Canceling a call to `write()` (dropping its `Future` before it's done) can leave
+        // the transaction in an undefined state, so we cancel it entirely.
+        self.state = WriteTransactionState::Canceled;
+
+        // If inner `write` fails, we also cancel the transaction.
+        let db = &mut *self.db.inner.lock().await;
+        db.files.remount_if_dirty(&mut db.readers[0]).await?;
+
+        if is_first_write {
+            db.rollback_if_any().await?;
+        }
+        db.write_begin(key, value_len, kind).await?;
+
+        Ok(())
+    }
+
+    /// Commit the transaction.
+    ///
+    /// This will wait until no read transaction is open. While waiting, opening new read transactions
+    /// is blocked, to prevent readers from starving writers.
+    ///
+    /// This makes all the writes permanent on the underlyling database. When it returns, the writes are
+    /// guaranteed to be fully safely written to flash, so they can't get lost by a crash or a power failure anymore.
+    ///
+    /// Committing is atomic: if commit is interrupted (due to power loss, crash, or canceling the future), it is
     /// guaranteed that either all or none of the writes in the transaction are committed.
     pub async fn commit(self) -> Result<(), CommitError<F::Error>> {
         match self.state {
@@ -389,6 +1390,12 @@ impl<'a, F: Flash + 'a, M: RawMutex + 'a> WriteTransaction<'a, F, M> {
         // do commit
         self.db.inner.lock().await.commit().await?;
 
+        self.db.state.lock(|s| {
+            let s = &mut s.borrow_mut();
+            s.committed = true;
+            s.compactor_waker.wake();
+        });
+
         // Here self gets dropped, which unlocks the write in `Database`, to let
         // read transactions proceed again.
 
@@ -396,19 +1403,85 @@ impl<'a, F: Flash + 'a, M: RawMutex + 'a> WriteTransaction<'a, F, M> {
     }
 }
 
+/// Writer for a value written in pieces, returned by [`WriteTransaction::begin_value`].
+///
+/// If it's dropped before calling [`finish`](Self::finish), the write transaction is canceled.
+pub struct ValueWriter<'b, 'a, F: Flash + 'a, M: RawMutex + 'a> {
+    tx: &'b mut WriteTransaction<'a, F, M>,
+    /// Bytes of the value left to write.
+    remaining: usize,
+    canceled: bool,
+}
+
+impl<'b, 'a, F: Flash + 'a, M: RawMutex + 'a> ValueWriter<'b, 'a, F, M> {
+    /// Write the next piece of the value.
+    ///
+    /// If the pieces written so far add up to more than the length passed to
+    /// [`begin_value`](WriteTransaction::begin_value), this fails with [`WriteError::ValueLengthMismatch`].
+    pub async fn write(&mut self, data: &[u8]) -> Result<(), WriteError<F::Error>> {
+        if self.canceled {
+            return Err(WriteError::TransactionCanceled);
+        }
+
+        // Same as for `WriteTransaction::write`, failing or canceling the write cancels the transaction.
+        self.canceled = true;
+
+        if data.len() > self.remaining {
+            return Err(WriteError::ValueLengthMismatch);
+        }
+
+        self.tx.db.inner.lock().await.write_value(data).await?;
+        self.remaining -= data.len();
+
+        self.canceled = false;
+
+        Ok(())
+    }
+
+    /// Finish writing the value.
+    ///
+    /// If the pieces written add up to less than the length passed to
+    /// [`begin_value`](WriteTransaction::begin_value), this fails with [`WriteError::ValueLengthMismatch`].
+    pub async fn finish(self) -> Result<(), WriteError<F::Error>> {
+        if self.canceled {
+            return Err(WriteError::TransactionCanceled);
+        }
+        if self.remaining != 0 {
+            return Err(WriteError::ValueLengthMismatch);
+        }
+
+        self.tx.db.inner.lock().await.write_end();
+        self.tx.state = WriteTransactionState::InProgress;
+
+        Ok(())
+    }
+}
+
 pub(crate) struct Inner<F: Flash> {
     pub(crate) files: FileManager<F>,
     pub(crate) readers: [PageReader; BRANCHING_FACTOR],
     write_tx: Option<WriteTransactionInner>,
+    unsorted_writes: bool,
+    wear_leveling_threshold: Option<u32>,
 }
 
 impl<F: Flash> Inner<F> {
-    fn new(flash: F, random_seed: u32) -> Self {
+    fn new(flash: F, config: &Config) -> Self {
         const NEW_PR: PageReader = PageReader::new();
+        let mut files = FileManager::new(flash, config.random_seed);
+        files.set_verify_writes(config.verify_writes);
+        files.set_redundant_meta(config.redundant_meta);
+        #[cfg(feature = "encryption")]
+        files.set_cipher(config.cipher);
         Self {
-            files: FileManager::new(flash, random_seed),
+            files,
             readers: [NEW_PR; BRANCHING_FACTOR],
             write_tx: None,
+            #[cfg(feature = "unsorted-writes")]
+            unsorted_writes: config.unsorted_writes,
+            #[cfg(not(feature = "unsorted-writes"))]
+            unsorted_writes: false,
+            wear_leveling_threshold: config.wear_leveling_threshold,
         }
     }
 
@@ -423,12 +1496,56 @@ impl<F: Flash> Inner<F> {
         Ok(())
     }
 
-    async fn read(&mut self, key: &[u8], value: &mut [u8]) -> Result<usize, ReadError<F::Error>> {
+    /// Read a key.
+    ///
+    /// If `pending` is set, the snapshot of the in-progress write transaction is read too.
+    async fn read(&mut self, key: &[u8], value: &mut [u8], pending: bool) -> Result<usize, ReadError<F::Error>> {
+        let len = self.read_value(key, 0, value, pending).await?;
+        if len > value.len() {
+            return Err(ReadError::BufferTooSmall);
+        }
+        Ok(len)
+    }
+
+    /// Read part of the value of a key, starting at `offset`. Returns the amount of bytes read.
+    async fn read_at(
+        &mut self,
+        key: &[u8],
+        offset: usize,
+        value: &mut [u8],
+        pending: bool,
+    ) -> Result<usize, ReadError<F::Error>> {
+        let len = self.read_value(key, offset, value, pending).await?;
+        Ok(value.len().min(len.saturating_sub(offset)))
+    }
+
+    /// Get the value length of a key.
+    async fn value_len(&mut self, key: &[u8], pending: bool) -> Result<usize, ReadError<F::Error>> {
+        self.read_value(key, 0, &mut [], pending).await
+    }
+
+    /// Find a key, and read as much of its value as fits in `value`, starting at `offset`.
+    ///
+    /// Returns the full length of the value.
+    async fn read_value(
+        &mut self,
+        key: &[u8],
+        offset: usize,
+        value: &mut [u8],
+        pending: bool,
+    ) -> Result<usize, ReadError<F::Error>> {
         self.files.remount_if_dirty(&mut self.readers[0]).await?;
 
-        for file_id in (0..FILE_COUNT).rev() {
+        // The sorted runs of a write transaction with unsorted writes live in scratch files.
+        // They're newer than all the files in the tree.
+        let file_count = match pending {
+            true => ALL_FILE_COUNT,
+            false => FILE_COUNT,
+        };
+
+        for file_id in (0..file_count).rev() {
             trace!("read: checking file {}", file_id);
-            if let Some(res) = self.read_in_file(file_id as _, key, value).await? {
+            if let Some(res) = self.read_in_file(file_id as _, pending, key, offset, value).await? {
                 return Ok(res);
             }
         }
@@ -438,10 +1555,15 @@ impl<F: Flash> Inner<F> {
     async fn read_in_file(
         &mut self,
         file_id: FileID,
+        pending: bool,
         key: &[u8],
+        offset: usize,
         value: &mut [u8],
     ) -> Result<Option<usize>, ReadError<F::Error>> {
-        let r = self.files.read(&mut self.readers[0], file_id);
+        let r = match pending {
+            true => self.files.read_pending(&mut self.readers[0], file_id),
+            false => self.files.read(&mut self.readers[0], file_id),
+        };
         let m = &mut self.files;
         let mut s = FileSearcher::new(r);
 
@@ -464,19 +1586,15 @@ impl<F: Flash> Inner<F> {
 
             // Found?
             let dir = match got_key[..].cmp(key) {
-                Ordering::Equal => {
-                    if header.is_delete {
-                        return Err(ReadError::KeyNotFound);
-                    }
-                    if header.value_len > value.len() {
-                        return Err(ReadError::BufferTooSmall);
+                Ordering::Equal => match header.kind {
+                    RecordKind::Value => {
+                        read_value_at(m, s.reader(), header.value_len, offset, value).await?;
+                        return Ok(Some(header.value_len));
                     }
-                    s.reader()
-                        .read(m, &mut value[..header.value_len])
-                        .await
-                        .map_err(no_eof)?;
-                    return Ok(Some(header.value_len));
-                }
+                    RecordKind::Delete => return Err(ReadError::KeyNotFound),
+                    RecordKind::RangeStart => return self.range_deleted(file_id, key).await,
+                    RecordKind::RangeEnd => return Ok(None),
+                },
                 Ordering::Less => SeekDirection::Right,
                 Ordering::Greater => SeekDirection::Left,
             };
@@ -487,10 +1605,13 @@ impl<F: Flash> Inner<F> {
 
         let r = s.reader();
 
-        // Linear search
+        // Linear search. The binary search leaves the reader at a record before the key or at the file
+        // start, so this goes through the record just before the key, which can be a range deleting it.
+        let mut in_range = false;
         loop {
             match r.read(m, &mut header).await {
                 Ok(()) => {}
+                Err(PageReadError::Eof) if in_range => return self.range_deleted(file_id, key).await,
                 Err(PageReadError::Eof) => return Ok(None), // key not present.
                 Err(e) => return Err(no_eof(e).into()),
             };
@@ -502,17 +1623,17 @@ impl<F: Flash> Inner<F> {
 
             // Found?
             match got_key[..].cmp(key) {
-                Ordering::Equal => {
-                    if header.is_delete {
-                        return Err(ReadError::KeyNotFound);
+                Ordering::Equal => match header.kind {
+                    RecordKind::Value => {
+                        read_value_at(m, r, header.value_len, offset, value).await?;
+                        return Ok(Some(header.value_len));
                     }
-                    if header.value_len > value.len() {
-                        return Err(ReadError::BufferTooSmall);
-                    }
-                    r.read(m, &mut value[..header.value_len]).await.map_err(no_eof)?;
-                    return Ok(Some(header.value_len));
-                }
-                Ordering::Less => {}                  // keep going
+                    RecordKind::Delete => return Err(ReadError::KeyNotFound),
+                    RecordKind::RangeStart => return self.range_deleted(file_id, key).await,
+                    RecordKind::RangeEnd => return Ok(None),
+                },
+                Ordering::Less => in_range = header.kind == RecordKind::RangeStart, // keep going
+                Ordering::Greater if in_range => return self.range_deleted(file_id, key).await,
                 Ordering::Greater => return Ok(None), // not present.
             }
 
@@ -520,13 +1641,36 @@ impl<F: Flash> Inner<F> {
         }
     }
 
-    async fn ensure_write_transaction_started(&mut self) -> Result<(), Error<F::Error>> {
+    /// Start the write transaction, if not started yet. `key` is the first key to be written.
+    async fn ensure_write_transaction_started(&mut self, key: &[u8]) -> Result<(), Error<F::Error>> {
         if self.write_tx.is_some() {
             return Ok(());
         }
 
         debug!("write_transaction: start");
 
+        // If all the keys are going to go after the ones in the newest file, append to it
+        // instead of starting a new one. The records are written in place after the ones in
+        // its last page, so this saves erasing a new page until it's full. It also makes the
+        // last level fill up slower, so compaction is needed less often.
+        if !self.unsorted_writes {
+            if let Some(file_id) = self.append_file(key).await? {
+                debug!("write_transaction: appending to file {}", file_id);
+                let w = self.files.write(&mut self.readers[0], file_id).await?;
+                self.write_tx = Some(WriteTransactionInner {
+                    file_id,
+                    w,
+                    last_key: None,
+                    runs: Vec::new(),
+                    len: 0,
+                    append: true,
+                    range_end: None,
+                    has_ranges: false,
+                });
+                return Ok(());
+            }
+        }
+
         let file_id = loop {
             match self.new_file_in_level(LEVEL_COUNT - 1) {
                 Some(f) => break f,
@@ -541,63 +1685,677 @@ impl<F: Flash> Inner<F> {
             }
         };
 
-        debug!("write_transaction: writing file {}", file_id);
-        let w = self.files.write(&mut self.readers[0], file_id).await?;
+        let mut runs = Vec::new();
+        let w = match self.unsorted_writes {
+            false => {
+                debug!("write_transaction: writing file {}", file_id);
+                self.files.write(&mut self.readers[0], file_id).await?
+            }
+            true => {
+                // Write to scratch files, they're moved to `file_id` on commit.
+                debug!("write_transaction: writing runs, for file {}", file_id);
+                runs.push(0).unwrap();
+                let run_file_id = FileManager::<F>::scratch_file_id(0);
+                self.files.write(&mut self.readers[0], run_file_id).await?
+            }
+        };
 
-        self.write_tx = Some(WriteTransactionInner { w, last_key: None });
+        self.write_tx = Some(WriteTransactionInner {
+            file_id,
+            w,
+            last_key: None,
+            runs,
+            len: 0,
+            append: false,
+            range_end: None,
+            has_ranges: false,
+        });
 
         Ok(())
     }
 
-    async fn write(&mut self, key: &[u8], value: &[u8], is_delete: bool) -> Result<(), WriteError<F::Error>> {
-        self.ensure_write_transaction_started().await?;
-        let tx = self.write_tx.as_mut().unwrap();
+    /// Get the file a write transaction starting with `key` can append to, if any.
+    ///
+    /// This is the newest file in the tree, if it's in the last level, it's not being
+    /// compacted, all its keys are lower than `key`, and it doesn't end with a range
+    /// deleting all the keys after it.
+    async fn append_file(&mut self, key: &[u8]) -> Result<Option<FileID>, Error<F::Error>> {
+        let Some(file_id) = (0..FILE_COUNT as FileID).rev().find(|&f| !self.files.is_empty(f)) else {
+            return Ok(None);
+        };
+        if file_id < Self::file_id(LEVEL_COUNT - 1, 0) || self.files.file_flags(file_id) != 0 {
+            return Ok(None);
+        }
+
+        match self.last_record(file_id).await? {
+            Some((kind, last_key)) if *last_key < *key && kind != RecordKind::RangeStart => Ok(Some(file_id)),
+            _ => Ok(None),
+        }
+    }
 
-        if let Some(last_key) = &tx.last_key {
-            if key <= last_key {
-                return Err(WriteError::NotSorted);
+    /// Check the integrity of all files, see [`Database::check`].
+    async fn check(&mut self) -> Result<CheckReport, Error<F::Error>> {
+        let mut report = CheckReport::new();
+        for file_id in 0..FILE_COUNT as FileID {
+            // Records can only be found if the pages are fine.
+            if self
+                .files
+                .check_pages(&mut self.readers[0], file_id, &mut report)
+                .await?
+            {
+                self.check_records(file_id, &mut report).await?;
             }
         }
-        tx.last_key = Some(Vec::from_slice(key).unwrap());
+        Ok(report)
+    }
 
-        let header = RecordHeader {
-            is_delete,
-            key_len: key.len(),
-            value_len: value.len(),
-        };
+    /// Check the records of a file: headers, key order, and the pages' record boundaries.
+    async fn check_records(&mut self, file_id: FileID, report: &mut CheckReport) -> Result<(), Error<F::Error>> {
+        let [pr, boundary_pr, ..] = &mut self.readers;
+        let m = &mut self.files;
+        let mut r = m.read(pr, file_id);
+        let mut boundaries = RecordBoundaryCheck::new(file_id);
 
+        let mut header = [0; RECORD_HEADER_SIZE];
+        let mut key = [0; MAX_KEY_SIZE];
+        let mut prev_key: Option<Vec<u8, MAX_KEY_SIZE>> = None;
         loop {
-            let tx = self.write_tx.as_mut().unwrap();
+            let seq = r.curr_seq(m);
+            let res: Result<(), Error<F::Error>> = try {
+                match r.read(m, &mut header).await {
+                    Ok(()) => {}
+                    Err(PageReadError::Eof) => break,
+                    Err(e) => Err(no_eof(e))?,
+                }
+                boundaries.record(m, boundary_pr, seq, report).await?;
 
-            let need_size = header.record_size() + MIN_FREE_PAGE_COUNT * PAGE_MAX_PAYLOAD_SIZE;
-            let available_size = tx.w.space_left_on_current_page() + self.files.free_pages() * PAGE_MAX_PAYLOAD_SIZE;
-            if need_size <= available_size {
-                break;
+                let header = RecordHeader::decode(header)?;
+                let key = &mut key[..header.key_len];
+                r.read(m, key).await.map_err(no_eof)?;
+                if prev_key.as_ref().is_some_and(|prev| prev[..] >= key[..]) {
+                    report.add(file_id, r.page_id(), CheckProblemKind::KeyOrder);
+                }
+                prev_key = Some(Vec::from_slice(key).unwrap());
+
+                r.skip(m, header.value_len).await.map_err(no_eof)?;
+                report.record_count += 1;
+            };
+            match res {
+                Ok(()) => {}
+                Err(Error::Flash(e)) => return Err(Error::Flash(e)),
+                Err(Error::Corrupted) => {
+                    report.add(file_id, r.page_id(), CheckProblemKind::Record);
+                    return Ok(());
+                }
             }
+        }
 
-            debug!("free pages less than buffer, compacting.");
-            let did_something = self.compact().await?;
-            if !did_something {
-                debug!("storage full");
-                return Err(WriteError::Full);
+        match boundaries.finish(m, report).await {
+            Ok(()) => Ok(()),
+            Err(Error::Flash(e)) => Err(Error::Flash(e)),
+            Err(Error::Corrupted) => {
+                report.add(file_id, None, CheckProblemKind::RecordBoundary);
+                Ok(())
             }
         }
+    }
 
-        let tx = self.write_tx.as_mut().unwrap();
+    /// Salvage the records of all the chains of pages found, see [`Database::salvage`].
+    async fn salvage(&mut self) -> Result<SalvageReport, SalvageError<F::Error>> {
+        // The writes of a transaction dropped without committing are discarded with the file table.
+        self.write_tx = None;
 
-        tx.w.write(&mut self.files, &header.encode()).await?;
-        tx.w.write(&mut self.files, key).await?;
-        tx.w.write(&mut self.files, value).await?;
-        tx.w.record_end();
+        let mut report = SalvageReport::new();
+        let mut s = self.files.salvage_start(&mut self.readers[0], &mut report).await?;
 
-        Ok(())
-    }
+        // The records of each chain are sorted, so they're written as they are.
+        let unsorted_writes = core::mem::replace(&mut self.unsorted_writes, false);
+        let res: Result<(), SalvageError<F::Error>> = try {
+            while let Some(lost_bytes) = self.files.salvage_next(&mut self.readers[0], &mut s).await? {
+                report.chain_count += 1;
+                report.lost_bytes += lost_bytes;
+                self.salvage_chain(&mut report, s.in_file_table()).await?;
+            }
+        };
+        self.unsorted_writes = unsorted_writes;
+        res?;
+
+        info!(
+            "salvage: {} records from {} chains, {} bytes lost",
+            report.record_count, report.chain_count, report.lost_bytes
+        );
+        Ok(report)
+    }
+
+    /// Write the records of the chain being salvaged in a write transaction, and commit it.
+    ///
+    /// This stops at the first record that can't be read, or is out of order. The bytes from it to the
+    /// end of the chain are lost. So are the ones that don't fit in the storage, if the chain isn't part
+    /// of a file in the file table, instead of failing with [`SalvageError::Full`].
+    async fn salvage_chain(
+        &mut self,
+        report: &mut SalvageReport,
+        in_file_table: bool,
+    ) -> Result<(), SalvageError<F::Error>> {
+        let mut r = self.files.read_salvage(&mut self.readers[0]).dehydrate();
+        let mut key_buf = [0; MAX_KEY_SIZE];
+        // A range is written once its end is known, it's the key of the record after it.
+        let mut range_start: Option<Vec<u8, MAX_KEY_SIZE>> = None;
+
+        let lost = loop {
+            let record = r.clone();
+            let (header, value) = match self.salvage_read_record(&mut r, &mut key_buf).await {
+                Ok(Some(x)) => x,
+                Ok(None) => break None,
+                Err(Error::Flash(e)) => return Err(SalvageError::Flash(e)),
+                Err(Error::Corrupted) => break Some(record),
+            };
+            let key = &key_buf[..header.key_len];
+
+            if let Some(start) = range_start.take() {
+                if *key <= *start {
+                    break Some(record);
+                }
+                match self.salvage_range(&start, Some(key)).await {
+                    Ok(()) => report.record_count += 1,
+                    Err(WriteError::NotSorted) => break Some(record),
+                    Err(WriteError::Full) if !in_file_table => break Some(record),
+                    Err(e) => return Err(e.into()),
+                }
+            }
+
+            match header.kind {
+                RecordKind::RangeStart => range_start = Some(Vec::from_slice(key).unwrap()),
+                RecordKind::RangeEnd => {
+                    // It must end a range started by the record before.
+                    let tx = self.write_tx.as_ref();
+                    if !tx.is_some_and(|tx| tx.range_end.as_ref().is_some_and(|end| end.as_deref() == Some(key))) {
+                        break Some(record);
+                    }
+                    self.close_range().await?;
+                    report.record_count += 1;
+                }
+                kind => {
+                    match self.write_begin(key, header.value_len, kind).await {
+                        Ok(()) => {}
+                        Err(WriteError::NotSorted) => break Some(record),
+                        Err(WriteError::Full) if !in_file_table => break Some(record),
+                        Err(e) => return Err(e.into()),
+                    }
+                    self.salvage_copy_value(value, header.value_len).await?;
+                    self.write_end();
+                    report.record_count += 1;
+                }
+            }
+        };
+
+        if let Some(record) = lost {
+            let mut r = self.files.read_rehydrated(&mut self.readers[0], &record).await?;
+            report.lost_bytes += r.bytes_left(&self.files);
+        } else if let Some(start) = range_start {
+            // A range with no end deletes all the keys after it.
+            match self.salvage_range(&start, None).await {
+                Ok(()) => report.record_count += 1,
+                Err(WriteError::NotSorted) => {}
+                Err(WriteError::Full) if !in_file_table => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        if self.write_tx.is_some() {
+            self.commit().await?;
+        }
+        Ok(())
+    }
+
+    /// Read the header and key of the next record of the chain being salvaged, and skip its value.
+    ///
+    /// Returns the header, and a reader at the start of the value, or `None` at the end of the chain.
+    async fn salvage_read_record(
+        &mut self,
+        d: &mut DehydratedFileReader,
+        key: &mut [u8; MAX_KEY_SIZE],
+    ) -> Result<Option<(RecordHeader, DehydratedFileReader)>, Error<F::Error>> {
+        let m = &mut self.files;
+        let mut r = m.read_rehydrated(&mut self.readers[0], d).await?;
+
+        let mut header = [0; RECORD_HEADER_SIZE];
+        match r.read(m, &mut header).await {
+            Ok(()) => {}
+            Err(PageReadError::Eof) => return Ok(None),
+            Err(e) => return Err(no_eof(e)),
+        }
+        let header = RecordHeader::decode(header)?;
+        r.read(m, &mut key[..header.key_len]).await.map_err(no_eof)?;
+
+        let value = r.dehydrate();
+        r.skip(m, header.value_len).await.map_err(no_eof)?;
+        *d = r.dehydrate();
+        Ok(Some((header, value)))
+    }
+
+    /// Copy a value of `len` bytes from the chain being salvaged to the write transaction.
+    async fn salvage_copy_value(&mut self, mut d: DehydratedFileReader, mut len: usize) -> Result<(), Error<F::Error>> {
+        let mut buf = [0; 64];
+        while len != 0 {
+            let n = len.min(buf.len());
+            let mut r = self.files.read_rehydrated(&mut self.readers[0], &d).await?;
+            r.read(&mut self.files, &mut buf[..n]).await.map_err(no_eof)?;
+            d = r.dehydrate();
+            self.write_value(&buf[..n]).await?;
+            len -= n;
+        }
+        Ok(())
+    }
+
+    /// Write a range deleting the keys from `start` to `end` to the write transaction.
+    async fn salvage_range(&mut self, start: &[u8], end: Option<&[u8]>) -> Result<(), WriteError<F::Error>> {
+        let value_len = RecordKind::RangeStart.tombstone_value_len();
+        self.write_begin(start, value_len, RecordKind::RangeStart).await?;
+        self.write_value(&TOMBSTONE_VALUE[..value_len]).await?;
+        self.write_end();
+        self.write_tx.as_mut().unwrap().range_end = Some(end.map(|end| Vec::from_slice(end).unwrap()));
+        Ok(())
+    }
+
+    /// Get the kind and key of the last record in a file, or `None` if it's empty.
+    async fn last_record(
+        &mut self,
+        file_id: FileID,
+    ) -> Result<Option<(RecordKind, Vec<u8, MAX_KEY_SIZE>)>, Error<F::Error>> {
+        let r = self.files.read(&mut self.readers[0], file_id);
+        let m = &mut self.files;
+        let mut s = FileSearcher::new(r);
+
+        let mut header = [0; RECORD_HEADER_SIZE];
+
+        // Binary search towards the end of the file.
+        let mut ok = s.start(m).await?;
+        while ok {
+            s.reader().read(m, &mut header).await.map_err(no_eof)?;
+            RecordHeader::decode(header)?;
+            ok = s.seek(m, SeekDirection::Right).await?;
+        }
+
+        let r = s.reader();
+
+        // Linear search the rest of the file.
+        let mut last = None;
+        loop {
+            match r.read(m, &mut header).await {
+                Ok(()) => {}
+                Err(PageReadError::Eof) => return Ok(last),
+                Err(e) => return Err(no_eof(e)),
+            };
+            let header = RecordHeader::decode(header)?;
+
+            // Read key
+            let mut key = Vec::new();
+            key.resize_default(header.key_len).unwrap();
+            r.read(m, &mut key).await.map_err(no_eof)?;
+            last = Some((header.kind, key));
+
+            r.skip(m, header.value_len).await.map_err(no_eof)?;
+        }
+    }
+
+    /// Get the keys the sources of the in-progress compaction don't apply to, if there's one.
+    pub(crate) async fn compact_clip(&mut self) -> Result<Option<Clip>, Error<F::Error>> {
+        let Ok(dst) = self.files.files_with_flag(FILE_FLAG_COMPACT_DEST).single() else {
+            return Ok(None);
+        };
+        let Some((_, last_key)) = self.last_record(dst).await? else {
+            return Ok(None);
+        };
+        let src = core::array::from_fn(|i| self.files.file_flags(i as FileID) & FILE_FLAG_COMPACT_SRC != 0);
+        Ok(Some(Clip { src, last_key }))
+    }
+
+    /// Result of reading a key deleted by a range start in a file.
+    async fn range_deleted(&mut self, file_id: FileID, key: &[u8]) -> Result<Option<usize>, ReadError<F::Error>> {
+        if self.files.file_flags(file_id) & FILE_FLAG_COMPACT_SRC != 0 {
+            if let Some(clip) = self.compact_clip().await? {
+                if clip.hides(file_id, key) {
+                    return Ok(None);
+                }
+            }
+        }
+        Err(ReadError::KeyNotFound)
+    }
+
+    /// Whether a file can be compacted. The file the write transaction is appending to can't,
+    /// since it's being written.
+    fn is_compactable(&self, file_id: FileID) -> bool {
+        let appending = self
+            .write_tx
+            .as_ref()
+            .is_some_and(|tx| tx.append && tx.file_id == file_id);
+        !self.files.is_empty(file_id) && !appending
+    }
+
+    /// Compact until there's space for `size` more bytes of records in the write transaction,
+    /// so that writing them doesn't need compacting.
+    async fn reserve(&mut self, size: usize) -> Result<(), WriteError<F::Error>> {
+        // Make sure there's a free file for the transaction, if it has to start a new one.
+        // Compaction never writes to the last level, so it can't take it afterwards.
+        if self.write_tx.is_none() {
+            while self.new_file_in_level(LEVEL_COUNT - 1).is_none() {
+                debug!("reserve: no free file, compacting.");
+                let did_something = self.compact().await?;
+
+                // if last level is full, compact should always
+                // find something to do.
+                assert!(did_something);
+            }
+        }
+
+        loop {
+            let (space_left, len) = match &self.write_tx {
+                Some(tx) => (tx.w.space_left_on_current_page(), tx.len),
+                None => (0, 0),
+            };
+
+            // Same as in `write_begin`, for all the records at once. Assume the worst case for merging.
+            let mut need_size = size.saturating_add(self.min_free_page_count() * PAGE_MAX_PAYLOAD_SIZE);
+            if self.write_tx.is_none() && !self.unsorted_writes {
+                // The transaction may append to the newest file, which copies its last page to a new one.
+                need_size = need_size.saturating_add(PAGE_MAX_PAYLOAD_SIZE);
+            }
+            if self.unsorted_writes {
+                need_size = need_size.saturating_add(
+                    len.saturating_add(size)
+                        .saturating_mul(3)
+                        .saturating_add(PAGE_MAX_PAYLOAD_SIZE),
+                );
+            }
+            let available_size = space_left + self.files.free_pages() * PAGE_MAX_PAYLOAD_SIZE;
+            if need_size <= available_size {
+                return Ok(());
+            }
+
+            debug!("reserve: free pages less than needed, compacting.");
+            let did_something = self.compact().await?;
+            if !did_something {
+                debug!("storage full");
+                return Err(WriteError::Full);
+            }
+        }
+    }
+
+    /// Start writing a record, by writing its header and key.
+    ///
+    /// Its value of `value_len` bytes must be written next with `write_value`, followed by `write_end`.
+    async fn write_begin(
+        &mut self,
+        key: &[u8],
+        value_len: usize,
+        kind: RecordKind,
+    ) -> Result<(), WriteError<F::Error>> {
+        self.ensure_write_transaction_started(key).await?;
+        let tx = self.write_tx.as_mut().unwrap();
+
+        let sorted = match (&tx.range_end, &tx.last_key) {
+            // A range is open, keys can go at its end or after it.
+            (Some(end), _) => end.as_ref().is_some_and(|end| key >= end),
+            (None, Some(last_key)) => key > last_key,
+            (None, None) => true,
+        };
+        if !sorted && !self.unsorted_writes {
+            return Err(WriteError::NotSorted);
+        }
+
+        let header = RecordHeader {
+            kind,
+            key_len: key.len(),
+            value_len,
+        };
+
+        // Space for the record, plus the range end written before or after it, if any.
+        let mut size = header.record_size();
+        if tx.range_end.is_some() || kind == RecordKind::RangeStart {
+            size += MAX_RANGE_TOMBSTONE_SIZE;
+        }
+
+        loop {
+            let mut need_size = size + self.min_free_page_count() * PAGE_MAX_PAYLOAD_SIZE;
+            let tx = self.write_tx.as_mut().unwrap();
+            if self.unsorted_writes {
+                // Keep enough space to merge all runs into a new file. The merged file can't be bigger
+                // than the sum of all the records written, plus one page for rounding up. If there are
+                // range tombstones, merging can add one after each record, at most 3 bytes bigger than it.
+                let len = match tx.has_ranges || kind == RecordKind::RangeStart {
+                    false => tx.len + size,
+                    true => 3 * (tx.len + size),
+                };
+                need_size += len + PAGE_MAX_PAYLOAD_SIZE;
+            }
+            let available_size = tx.w.space_left_on_current_page() + self.files.free_pages() * PAGE_MAX_PAYLOAD_SIZE;
+            if need_size <= available_size {
+                break;
+            }
+
+            debug!("free pages less than buffer, compacting.");
+            let did_something = self.compact().await?;
+            if !did_something {
+                debug!("storage full");
+                return Err(WriteError::Full);
+            }
+        }
+
+        if !sorted {
+            self.start_run().await?;
+        } else {
+            // If this key is the end of the open range, it closes it already.
+            let tx = self.write_tx.as_mut().unwrap();
+            if tx.range_end.as_ref().is_some_and(|end| end.as_deref() == Some(key)) {
+                tx.range_end = None;
+            }
+            self.close_range().await?;
+        }
+
+        let tx = self.write_tx.as_mut().unwrap();
+        tx.last_key = Some(Vec::from_slice(key).unwrap());
+        tx.len += header.record_size();
+        tx.has_ranges |= kind == RecordKind::RangeStart;
+
+        tx.w.write(&mut self.files, &header.encode()).await?;
+        tx.w.write(&mut self.files, key).await?;
+
+        Ok(())
+    }
+
+    /// Write (part of) the value of the record started with `write_begin`.
+    async fn write_value(&mut self, data: &[u8]) -> Result<(), Error<F::Error>> {
+        let tx = self.write_tx.as_mut().unwrap();
+        tx.w.write(&mut self.files, data).await
+    }
+
+    /// Finish the record started with `write_begin`.
+    fn write_end(&mut self) {
+        let tx = self.write_tx.as_mut().unwrap();
+        tx.w.record_end();
+    }
+
+    /// Write the end of the open range, if it has one.
+    async fn close_range(&mut self) -> Result<(), Error<F::Error>> {
+        let tx = self.write_tx.as_mut().unwrap();
+        let Some(Some(end)) = &tx.range_end else {
+            return Ok(());
+        };
+
+        let header = RecordHeader::tombstone(RecordKind::RangeEnd, end.len());
+        tx.w.write(&mut self.files, &header.encode()).await?;
+        tx.w.write(&mut self.files, end).await?;
+        tx.w.write(&mut self.files, &TOMBSTONE_VALUE[..header.value_len])
+            .await?;
+        tx.w.record_end();
+
+        tx.len += header.record_size();
+        tx.last_key = tx.range_end.take().unwrap();
+        Ok(())
+    }
+
+    /// End the open range, if it has an end, without writing its end.
+    ///
+    /// The range is ended by deleting the highest key before its end, so the keys that can be written
+    /// next are still the ones from its end on. Used by snapshots, which can't leave the range open.
+    async fn close_range_before_end(&mut self) -> Result<(), Error<F::Error>> {
+        let tx = self.write_tx.as_mut().unwrap();
+        let Some(Some(end)) = &tx.range_end else {
+            return Ok(());
+        };
+        // The end is after the start, so it has a predecessor.
+        let last = key_predecessor(end).unwrap();
+        if tx.last_key.as_ref() == Some(&last) {
+            // The range has no key other than its start, only salvage writes these.
+            return self.close_range().await;
+        }
+
+        let header = RecordHeader::tombstone(RecordKind::Delete, last.len());
+        tx.w.write(&mut self.files, &header.encode()).await?;
+        tx.w.write(&mut self.files, &last).await?;
+        tx.w.record_end();
+
+        tx.len += header.record_size();
+        tx.last_key = Some(last);
+        tx.range_end = None;
+        Ok(())
+    }
+
+    /// Finish the current sorted run, and start a new one.
+    async fn start_run(&mut self) -> Result<(), Error<F::Error>> {
+        self.finish_run().await?;
+
+        if self.write_tx.as_ref().unwrap().runs.len() == RUN_COUNT {
+            debug!("write_transaction: too many runs, merging.");
+            self.merge_runs(BRANCHING_FACTOR).await?;
+        }
+
+        let tx = self.write_tx.as_mut().unwrap();
+        let file_id = FileManager::<F>::scratch_file_id(tx.runs.len());
+        debug!("write_transaction: starting run in file {}", file_id);
+        tx.w = self.files.write(&mut self.readers[0], file_id).await?;
+        tx.runs.push(0).unwrap();
+        Ok(())
+    }
+
+    /// Commit the sorted run currently being written to its scratch file.
+    ///
+    /// Then, merge the newest runs while there's `BRANCHING_FACTOR` of them in the same level.
+    async fn finish_run(&mut self) -> Result<(), Error<F::Error>> {
+        self.close_range().await?;
+        let tx = self.write_tx.as_mut().unwrap();
+        self.files.commit_scratch(&mut tx.w).await?;
+        // A range with no end ends with the run.
+        tx.range_end = None;
+
+        loop {
+            let runs = &self.write_tx.as_ref().unwrap().runs;
+            let n = runs.len();
+            if n < BRANCHING_FACTOR || runs[n - BRANCHING_FACTOR..].iter().any(|&lv| lv != runs[n - 1]) {
+                return Ok(());
+            }
+            self.merge_runs(BRANCHING_FACTOR).await?;
+        }
+    }
+
+    /// Merge the newest `count` sorted runs of the current write transaction into a single one.
+    ///
+    /// The runs must be finished already. If a key is in multiple runs, the newest one wins.
+    async fn merge_runs(&mut self, count: usize) -> Result<(), Error<F::Error>> {
+        let tx = self.write_tx.as_mut().unwrap();
+        let n = tx.runs.len();
+        assert!((2..=BRANCHING_FACTOR).contains(&count) && count <= n);
+        let first = n - count;
+        let level = tx.runs[first..].iter().max().unwrap() + 1;
+        let dst = FileManager::<F>::scratch_file_id(n);
+
+        debug!("merge_runs: runs {}..{} -> level {}", first, n, level);
+
+        let m = &mut self.files;
+        let mut w = m.write(&mut self.readers[0], dst).await?;
+
+        // Open all runs for reading.
+        let mut r: Vec<FileReader, BRANCHING_FACTOR> = Vec::from_iter(
+            core::iter::zip(first..n, &mut self.readers[..])
+                .map(|(i, reader)| m.read(reader, FileManager::<F>::scratch_file_id(i))),
+        );
+
+        let mut k = [KeySlot::NEW; BRANCHING_FACTOR];
+        for i in 0..count {
+            read_key_slot(m, &mut r[i], &mut k[i]).await?;
+        }
+
+        let mut merge = Merge::new(true);
+        loop {
+            let bits = lowest_key_bits(&k[..count]);
+            if bits == 0 {
+                // All runs finished.
+                break;
+            }
+
+            let copied = merge.step(m, &mut r, &mut w, &k[..count], bits).await?;
+
+            // Advance all readers
+            for j in 0..count {
+                if (bits & 1 << j) != 0 {
+                    if copied != Some(j) {
+                        r[j].skip(m, k[j].header.value_len).await.map_err(no_eof)?;
+                    }
+                    merge.in_range[j] = k[j].header.kind == RecordKind::RangeStart;
+                    read_key_slot(m, &mut r[j], &mut k[j]).await?;
+                }
+            }
+        }
+        merge.finish(m, &mut w).await?;
+
+        m.commit_scratch(&mut w).await?;
+        for i in first..n {
+            m.delete_scratch(FileManager::<F>::scratch_file_id(i)).await?;
+        }
+        m.rename_scratch(dst, FileManager::<F>::scratch_file_id(first));
+
+        tx.runs.truncate(first);
+        tx.runs.push(level).unwrap();
+
+        Ok(())
+    }
+
+    /// Snapshot the in-progress write transaction, if any, so that its writes
+    /// can be read by opening files with `read_pending`.
+    pub(crate) async fn snapshot_write_transaction(&mut self) -> Result<(), Error<F::Error>> {
+        if self.write_tx.is_some() {
+            trace!("write_transaction: snapshot");
+            self.close_range_before_end().await?;
+            let tx = self.write_tx.as_mut().unwrap();
+            tx.w.snapshot(&mut self.files).await?;
+        }
+        Ok(())
+    }
 
     async fn commit(&mut self) -> Result<(), Error<F::Error>> {
         debug!("write_transaction: commit");
 
-        let tx = self.write_tx.as_mut().unwrap();
-        self.files.commit(&mut tx.w).await?;
+        if self.unsorted_writes {
+            self.finish_run().await?;
+            loop {
+                let n = self.write_tx.as_ref().unwrap().runs.len();
+                if n == 1 {
+                    break;
+                }
+                self.merge_runs(n.min(BRANCHING_FACTOR)).await?;
+            }
+
+            // Only one run left, with all the writes sorted. Move it to the final file.
+            let tx = self.write_tx.as_mut().unwrap();
+            let mut t = self.files.transaction();
+            t.rename(FileManager::<F>::scratch_file_id(0), tx.file_id).await?;
+            t.commit().await?;
+        } else {
+            self.close_range().await?;
+            let tx = self.write_tx.as_mut().unwrap();
+            self.files.commit(&mut tx.w).await?;
+        }
 
         self.write_tx = None;
 
@@ -610,6 +2368,12 @@ impl<F: Flash> Inner<F> {
         let tx = self.write_tx.as_mut().unwrap();
         tx.w.discard(&mut self.files).await.unwrap();
 
+        // Delete the finished runs, if any.
+        #[allow(clippy::reversed_empty_ranges)] // there are no scratch files without unsorted writes.
+        for i in 0..SCRATCH_FILE_COUNT {
+            self.files.delete_scratch(FileManager::<F>::scratch_file_id(i)).await?;
+        }
+
         self.write_tx = None;
 
         Ok(())
@@ -622,6 +2386,11 @@ impl<F: Flash> Inner<F> {
         Ok(())
     }
 
+    /// Free pages writes always leave. With redundant meta pages, one more is needed to write them.
+    fn min_free_page_count(&self) -> usize {
+        MIN_FREE_PAGE_COUNT + self.files.redundant_meta() as usize
+    }
+
     fn file_id(level: usize, index: usize) -> FileID {
         (1 + level * BRANCHING_FACTOR + index) as _
     }
@@ -651,7 +2420,7 @@ impl<F: Flash> Inner<F> {
 
     fn level_file_count(&self, level: usize) -> usize {
         (0..BRANCHING_FACTOR)
-            .filter(|&i| !self.files.is_empty(Self::file_id(level, i)))
+            .filter(|&i| self.is_compactable(Self::file_id(level, i)))
             .count()
     }
 
@@ -715,7 +2484,7 @@ impl<F: Flash> Inner<F> {
         let mut src = Vec::new();
         for i in 0..BRANCHING_FACTOR {
             let src_file = Self::file_id(lv, i);
-            if !self.files.is_empty(src_file) {
+            if self.is_compactable(src_file) {
                 src.push(src_file).unwrap();
             }
         }
@@ -730,14 +2499,23 @@ impl<F: Flash> Inner<F> {
         Ok(Some((src, dst)))
     }
 
-    async fn do_compact(&mut self, src: Vec<FileID, BRANCHING_FACTOR>, dst: FileID) -> Result<(), Error<F::Error>> {
+    /// Merge the `src` files into `dst`.
+    ///
+    /// Stops partway if the flash is getting full, or after writing `max_pages` pages.
+    async fn do_compact(
+        &mut self,
+        src: Vec<FileID, BRANCHING_FACTOR>,
+        dst: FileID,
+        max_pages: usize,
+    ) -> Result<(), Error<F::Error>> {
         debug!("do_compact {:?} -> {}", &src[..], dst);
 
         let topmost = dst == 0;
 
         assert!(!src.is_empty());
 
-        if self.files.is_empty(dst) && src.len() == 1 {
+        // Compacting a single file into file 0 is relocating it for wear leveling, so it must be copied.
+        if !topmost && self.files.is_empty(dst) && src.len() == 1 {
             debug!("do_compact: short-circuit rename");
             let mut tx = self.files.transaction();
             tx.rename(src[0], dst).await?;
@@ -745,152 +2523,114 @@ impl<F: Flash> Inner<F> {
             return Ok(());
         }
 
+        let mut merge = Merge::new(!topmost);
+
+        // If continuing a partial compaction, the sources can still have keys already in the destination.
+        if let Some((_, last_key)) = self.last_record(dst).await? {
+            merge.resume(last_key);
+        }
+
         let m = &mut self.files;
         let mut w = m.write(&mut self.readers[0], dst).await?;
+        let start_free_pages = m.free_pages();
 
         // Open all files in level for reading.
         let mut r: Vec<FileReader, BRANCHING_FACTOR> = Vec::from_iter(
             core::iter::zip(&src, &mut self.readers[..]).map(|(&file_id, reader)| m.read(reader, file_id)),
         );
 
-        struct KeySlot {
-            valid: bool,
-            header: RecordHeader,
-            key_buf: [u8; MAX_KEY_SIZE],
-        }
+        let mut k = [KeySlot::NEW; BRANCHING_FACTOR];
+        let mut trunc = [0; BRANCHING_FACTOR];
+        // Where the last record merged from each file starts. Used if it's a range start.
+        let mut range_trunc = [0; BRANCHING_FACTOR];
+        let k = &mut k[..src.len()];
 
-        impl KeySlot {
-            fn key(&self) -> &[u8] {
-                &self.key_buf[..self.header.key_len]
-            }
-        }
-
-        async fn read_key_slot<F: Flash>(
-            m: &mut FileManager<F>,
-            r: &mut FileReader<'_>,
-            buf: &mut KeySlot,
-        ) -> Result<(), Error<F::Error>> {
-            let mut header = [0; RECORD_HEADER_SIZE];
-            match r.read(m, &mut header).await {
-                Ok(()) => {}
-                Err(PageReadError::Flash(e)) => return Err(Error::Flash(e)),
-                Err(PageReadError::Eof) => {
-                    buf.valid = false;
-                    return Ok(());
-                }
-                Err(PageReadError::Corrupted) => corrupted!(),
-            }
-
-            buf.valid = true;
-            buf.header = RecordHeader::decode(header)?;
-
-            // Read key
-            match r.read(m, &mut buf.key_buf[..buf.header.key_len]).await {
-                Ok(()) => Ok(()),
-                Err(PageReadError::Flash(e)) => Err(Error::Flash(e)),
-                Err(PageReadError::Eof) => corrupted!(),
-                Err(PageReadError::Corrupted) => corrupted!(),
-            }
-        }
-
-        const NEW_SLOT: KeySlot = KeySlot {
-            valid: false,
-            header: RecordHeader {
-                key_len: 0,
-                value_len: 0,
-                is_delete: false,
-            },
-            key_buf: [0; MAX_KEY_SIZE],
-        };
-        let mut k = [NEW_SLOT; BRANCHING_FACTOR];
-        let mut trunc = [0; BRANCHING_FACTOR];
-
-        for i in 0..src.len() {
-            read_key_slot(m, &mut r[i], &mut k[i]).await?;
+        for i in 0..src.len() {
+            read_key_slot(m, &mut r[i], &mut k[i]).await?;
         }
 
         let mut progress = false;
+        // When stopping, the range open in the output must be ended, which can need merging some more keys.
+        let mut stopping = false;
         let done = loop {
-            fn highest_bit(x: u32) -> Option<usize> {
-                match x {
-                    0 => None,
-                    _ => Some(31 - x.leading_zeros() as usize),
-                }
-            }
+            let bits = lowest_key_bits(k);
+
+            trace!("do_compact: bits {:02x}", bits);
+            // All keys empty, means we've finished
+            let Some(i) = highest_bit(bits) else {
+                break true;
+            };
 
-            let mut bits: u32 = 0;
-            for i in 0..src.len() {
-                // Ignore files that have already reached the end.
-                if !k[i].valid {
+            if stopping {
+                if !merge.must_step_to_close(k, bits) {
+                    break false;
+                }
+            } else if !merge.skipping(k, bits) {
+                let need_size = merge.step_need_size(k, bits) + MIN_FREE_PAGE_COUNT_COMPACT * PAGE_MAX_PAYLOAD_SIZE;
+                let available_size = w.space_left_on_current_page() + m.free_pages() * PAGE_MAX_PAYLOAD_SIZE;
+
+                trace!(
+                    "do_compact: key_len={} val_len={} space_left={} free_pages={} size={} available_size={}",
+                    k[i].header.key_len,
+                    k[i].header.value_len,
+                    w.space_left_on_current_page(),
+                    m.free_pages(),
+                    need_size,
+                    available_size
+                );
+
+                if need_size > available_size {
+                    // it will not fit, so stop.
+                    stopping = true;
                     continue;
                 }
 
-                match highest_bit(bits) {
-                    // If we haven't found any nonempty key yet, take the current one.
-                    None => bits = 1 << i,
-                    Some(j) => match k[j].key().cmp(k[i].key()) {
-                        Ordering::Greater => bits = 1 << i,
-                        Ordering::Equal => bits |= 1 << i,
-                        Ordering::Less => {}
-                    },
+                if start_free_pages - m.free_pages() >= max_pages {
+                    debug!("do_compact: wrote {} pages, stopping.", max_pages);
+                    stopping = true;
+                    continue;
                 }
+
+                progress = true;
             }
 
-            trace!("do_compact: bits {:02x}", bits);
-            match highest_bit(bits) {
-                // All keys empty, means we've finished
-                None => break true,
-                // Copy value from the highest bit (so newest file)
-                Some(i) => {
-                    let need_size = k[i].header.record_size() + MIN_FREE_PAGE_COUNT_COMPACT * PAGE_MAX_PAYLOAD_SIZE;
-                    let available_size = w.space_left_on_current_page() + m.free_pages() * PAGE_MAX_PAYLOAD_SIZE;
-
-                    trace!(
-                        "do_compact: key_len={} val_len={} space_left={} free_pages={} size={} available_size={}",
-                        k[i].header.key_len,
-                        k[i].header.value_len,
-                        w.space_left_on_current_page(),
-                        m.free_pages(),
-                        need_size,
-                        available_size
-                    );
-
-                    if need_size > available_size {
-                        // it will not fit, so stop.
-                        break false;
-                    }
+            #[cfg(feature = "defmt")]
+            trace!("do_compact: merging key from file {:?}: {:02x}", src[i], &k[i].key());
+            #[cfg(not(feature = "defmt"))]
+            trace!("do_compact: merging key from file {:?}: {:02x?}", src[i], &k[i].key());
 
-                    #[cfg(feature = "defmt")]
-                    trace!("do_compact: copying key from file {:?}: {:02x}", src[i], &k[i].key());
-                    #[cfg(not(feature = "defmt"))]
-                    trace!("do_compact: copying key from file {:?}: {:02x?}", src[i], &k[i].key());
-
-                    progress = true;
-
-                    // if we're compacting to the topmost level, do not write tombstone records
-                    if topmost && k[i].header.is_delete {
-                        trace!("do_compact: skipping tombstone.");
-                    } else {
-                        w.write(m, &k[i].header.encode()).await?;
-                        w.write(m, k[i].key()).await?;
-                        copy(m, &mut r[i], &mut w, k[i].header.value_len).await?;
-                        w.record_end();
-                    }
+            let copied = merge.step(m, &mut r, &mut w, k, bits).await?;
 
-                    // Advance all readers
-                    for j in 0..BRANCHING_FACTOR {
-                        if (bits & 1 << j) != 0 {
-                            if j != i {
-                                r[j].skip(m, k[j].header.value_len).await.map_err(no_eof)?;
-                            }
-                            trunc[j] = r[j].offset(m);
-                            read_key_slot(m, &mut r[j], &mut k[j]).await?;
-                        }
+            // Advance all readers
+            for j in 0..src.len() {
+                if (bits & 1 << j) != 0 {
+                    if copied != Some(j) {
+                        r[j].skip(m, k[j].header.value_len).await.map_err(no_eof)?;
                     }
+                    range_trunc[j] = trunc[j];
+                    trunc[j] = r[j].offset(m);
+                    merge.in_range[j] = k[j].header.kind == RecordKind::RangeStart;
+                    read_key_slot(m, &mut r[j], &mut k[j]).await?;
                 }
             }
         };
 
+        match done {
+            true => merge.finish(m, &mut w).await?,
+            false => {
+                merge.close(m, &mut w).await?;
+
+                // A range start merged from a file also deletes keys after the stopping point, in the files
+                // older than the destination, so it must stay in the file. It doesn't apply to the keys
+                // already in the destination, see `Clip`.
+                for j in 0..src.len() {
+                    if merge.in_range[j] {
+                        trunc[j] = range_trunc[j];
+                    }
+                }
+            }
+        }
+
         debug!("do_compact: stopped. done={:?} progress={:?}", done, progress);
 
         // We should've made some progress, as long as the free page margins were respected.
@@ -922,11 +2662,69 @@ impl<F: Flash> Inner<F> {
     }
 
     async fn compact(&mut self) -> Result<bool, Error<F::Error>> {
+        self.compact_step(usize::MAX).await
+    }
+
+    /// Do one compaction, stopping after writing `max_pages` pages. Returns whether there was work to do.
+    async fn compact_step(&mut self, max_pages: usize) -> Result<bool, Error<F::Error>> {
         let Some((src, dst)) = self.compact_find_work()? else {
             return Ok(false);
         };
 
-        self.do_compact(src, dst).await?;
+        self.do_compact(src, dst, max_pages).await?;
+        Ok(true)
+    }
+
+    /// Whether there's a compaction in progress, stopped partway.
+    fn is_compacting(&self) -> bool {
+        self.files.files_with_flag(FILE_FLAG_COMPACT_DEST).next().is_some()
+    }
+
+    /// Start relocating the oldest file to other pages, if its pages are worn much less than the most
+    /// worn page. Returns whether there was work to do.
+    ///
+    /// The relocation is a compaction of the file alone into file 0, so if it stops after writing
+    /// `max_pages` pages, the next compaction continues it.
+    async fn wear_level(&mut self, max_pages: usize) -> Result<bool, Error<F::Error>> {
+        let Some(threshold) = self.wear_leveling_threshold else {
+            return Ok(false);
+        };
+        if self.is_compacting() {
+            return Ok(false);
+        }
+
+        let max_erase_count = self.files.max_erase_count().await.map_err(Error::Flash)?;
+        if self
+            .files
+            .wear_leveling_erase_count()
+            .is_some_and(|c| max_erase_count < c.saturating_add(threshold))
+        {
+            return Ok(false);
+        }
+
+        // Only the oldest file can be moved to file 0, which is older than all the others.
+        let Some(src) = (1..FILE_COUNT as FileID).find(|&f| !self.files.is_empty(f)) else {
+            return Ok(false);
+        };
+        if !self.is_compactable(src) {
+            return Ok(false);
+        }
+
+        let (pages, min_erase_count) = self.files.file_wear(src).await?;
+        if max_erase_count.saturating_sub(min_erase_count) < threshold {
+            return Ok(false);
+        }
+        // Make sure the relocation can finish without running out of space.
+        if self.files.free_pages() < pages + self.min_free_page_count() {
+            return Ok(false);
+        }
+
+        debug!(
+            "wear_level: relocating file {}, erase counts min={} max={}",
+            src, min_erase_count, max_erase_count
+        );
+        self.files.set_wear_leveling_erase_count(max_erase_count);
+        self.do_compact(Vec::from_slice(&[src]).unwrap(), 0, max_pages).await?;
         Ok(true)
     }
 
@@ -967,7 +2765,7 @@ impl<F: Flash> Inner<F> {
         let mut key = [0u8; MAX_KEY_SIZE];
         let mut value = [0u8; MAX_VALUE_SIZE];
         loop {
-            let seq = r.curr_seq(&mut self.files);
+            let seq = r.curr_seq(&self.files);
 
             let mut header = [0; RECORD_HEADER_SIZE];
             match r.read(&mut self.files, &mut header).await {
@@ -1001,8 +2799,445 @@ impl<F: Flash> Inner<F> {
 }
 
 pub struct WriteTransactionInner {
+    /// File the transaction is committed to.
+    file_id: FileID,
+    /// Writer for the file being written. With unsorted writes, this is the newest sorted run.
     w: FileWriter,
     last_key: Option<Vec<u8, MAX_KEY_SIZE>>,
+    /// With unsorted writes, levels of the sorted runs, oldest first.
+    /// Run `i` is stored in scratch file `i`.
+    runs: Vec<u8, RUN_COUNT>,
+    /// Total size of the records written.
+    len: usize,
+    /// Whether the transaction is appending to an existing file, instead of writing a new one.
+    append: bool,
+    /// If the last record written is a range start, the end of its range, which is written when the next
+    /// key is. `None` inside if the range has no end.
+    range_end: Option<Option<Vec<u8, MAX_KEY_SIZE>>>,
+    /// Whether any range tombstones have been written.
+    has_ranges: bool,
+}
+
+/// Read as much as fits in `value` of a record value of length `value_len`, starting at `offset`.
+///
+/// `r` must be at the start of the value.
+async fn read_value_at<F: Flash>(
+    m: &mut FileManager<F>,
+    r: &mut FileReader<'_>,
+    value_len: usize,
+    offset: usize,
+    value: &mut [u8],
+) -> Result<(), Error<F::Error>> {
+    if offset >= value_len {
+        return Ok(());
+    }
+    let n = value.len().min(value_len - offset);
+    r.skip(m, offset).await.map_err(no_eof)?;
+    r.read(m, &mut value[..n]).await.map_err(no_eof)?;
+    Ok(())
+}
+
+/// Get the lowest key greater than `key`, or `None` if there's none.
+fn key_successor(key: &[u8]) -> Option<Vec<u8, MAX_KEY_SIZE>> {
+    if key.len() < MAX_KEY_SIZE {
+        let mut res = Vec::from_slice(key).unwrap();
+        res.push(0).unwrap();
+        return Some(res);
+    }
+    // No key starts with `key` other than itself, since it's as long as possible.
+    prefix_successor(key)
+}
+
+/// Get the highest key lower than `key`, or `None` if there's none.
+fn key_predecessor(key: &[u8]) -> Option<Vec<u8, MAX_KEY_SIZE>> {
+    let (&last, rest) = key.split_last()?;
+    let mut res = Vec::from_slice(rest).unwrap();
+    if last != 0 {
+        // The key before it, followed by as many of the highest byte as fit.
+        res.push(last - 1).unwrap();
+        res.resize(MAX_KEY_SIZE, 0xFF).unwrap();
+    }
+    Some(res)
+}
+
+/// Get the lowest key greater than all the keys starting with `prefix`, or `None` if there's none.
+fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8, MAX_KEY_SIZE>> {
+    let n = prefix.iter().rposition(|&b| b != 0xFF)?;
+    let mut res = Vec::from_slice(&prefix[..=n]).unwrap();
+    res[n] += 1;
+    Some(res)
+}
+
+/// Keys already merged into the destination of an in-progress compaction.
+///
+/// A partial compaction can stop in the middle of a range start merged from a source. The range start then
+/// stays in the source, since it still deletes the keys after the stopping point. The keys up to the last one
+/// in the destination already have their merged state there, so the records in the sources don't apply to them.
+/// The only records left in the sources for these keys are such range starts.
+pub(crate) struct Clip {
+    /// For each file, whether it's a source.
+    src: [bool; FILE_COUNT],
+    /// Last key in the destination.
+    last_key: Vec<u8, MAX_KEY_SIZE>,
+}
+
+impl Clip {
+    /// Whether the records in a file don't apply to `key`.
+    pub(crate) fn hides(&self, file_id: FileID, key: &[u8]) -> bool {
+        self.src.get(file_id as usize) == Some(&true) && key <= &self.last_key[..]
+    }
+}
+
+/// What a merge of files has for a key.
+enum Merged {
+    /// The value in the file with this index.
+    Value(usize),
+    /// The key is deleted.
+    Deleted,
+    /// The files say nothing about the key.
+    Unknown,
+}
+
+/// Record written by a merge for a key.
+enum Action {
+    /// Copy the record from the file with this index.
+    Copy(usize),
+    /// Write a tombstone of this kind.
+    Tombstone(RecordKind),
+    Nothing,
+}
+
+/// State of a merge of sorted files, shared by `merge_runs` and `do_compact`.
+///
+/// The newest file with a record for a key wins, taking into account that a range start deletes the keys up
+/// to the next record in its file. Tombstones are written to the output as needed to delete the same keys
+/// from the files older than it, so a range start from an older file is split around the newer keys inside it.
+struct Merge {
+    /// For each file, whether the last record merged from it is a range start, deleting the keys up to its next record.
+    in_range: [bool; BRANCHING_FACTOR],
+    /// Whether the last record written is a range start, so it deletes the keys after `last_key` up to the next one.
+    open: bool,
+    /// Last key merged.
+    last_key: Option<Vec<u8, MAX_KEY_SIZE>>,
+    /// Whether to write tombstones. They're not needed when merging to the topmost file, since there's nothing older.
+    tombstones: bool,
+    /// Keys up to this one are already in the output, from a previous partial compaction.
+    skip_until: Option<Vec<u8, MAX_KEY_SIZE>>,
+}
+
+impl Merge {
+    fn new(tombstones: bool) -> Self {
+        Self {
+            in_range: [false; BRANCHING_FACTOR],
+            open: false,
+            last_key: None,
+            tombstones,
+            skip_until: None,
+        }
+    }
+
+    /// Continue writing to an output whose last key is `last_key`. The keys up to it are skipped.
+    fn resume(&mut self, last_key: Vec<u8, MAX_KEY_SIZE>) {
+        self.skip_until = Some(last_key.clone());
+        self.last_key = Some(last_key);
+    }
+
+    /// Whether the keys after the last one merged, up to the next record in any file, are deleted.
+    fn covered(&self) -> bool {
+        self.in_range.iter().any(|&x| x)
+    }
+
+    /// Whether the keys after the lowest key, up to the next record in any file, will be deleted once it's merged.
+    fn covered_after(&self, k: &[KeySlot], bits: u32) -> bool {
+        (0..k.len()).any(|j| match (bits & 1 << j) != 0 {
+            true => k[j].header.kind == RecordKind::RangeStart,
+            false => self.in_range[j],
+        })
+    }
+
+    fn merged(&self, k: &[KeySlot], bits: u32) -> Merged {
+        for j in (0..k.len()).rev() {
+            if (bits & 1 << j) != 0 {
+                match k[j].header.kind {
+                    RecordKind::Value => return Merged::Value(j),
+                    RecordKind::Delete | RecordKind::RangeStart => return Merged::Deleted,
+                    RecordKind::RangeEnd => {}
+                }
+            } else if self.in_range[j] {
+                return Merged::Deleted;
+            }
+        }
+        Merged::Unknown
+    }
+
+    /// Whether the lowest key is already in the output, so it must be merged without writing it.
+    fn skipping(&self, k: &[KeySlot], bits: u32) -> bool {
+        let key = k[highest_bit(bits).unwrap()].key();
+        self.skip_until.as_ref().is_some_and(|s| key <= &s[..])
+    }
+
+    /// Get the range start or end to write after the last key, if the keys after it are deleted and the output
+    /// doesn't delete them yet, or the other way around. `next_key` is the next key merged, `None` if there's none.
+    fn boundary(&self, next_key: Option<&[u8]>) -> Option<(RecordKind, Vec<u8, MAX_KEY_SIZE>)> {
+        let covered = self.covered();
+        if !self.tombstones || covered == self.open {
+            return None;
+        }
+        let key = self.last_key.as_deref().and_then(key_successor)?;
+        if next_key.is_some_and(|next_key| *key >= *next_key) {
+            // No keys in between.
+            return None;
+        }
+        let kind = match covered {
+            true => RecordKind::RangeStart,
+            false => RecordKind::RangeEnd,
+        };
+        Some((kind, key))
+    }
+
+    /// Get the records to write for the lowest key: the boundary before it, and its own record.
+    fn plan(&self, k: &[KeySlot], bits: u32) -> (Option<(RecordKind, Vec<u8, MAX_KEY_SIZE>)>, Action) {
+        let key = k[highest_bit(bits).unwrap()].key();
+        let boundary = self.boundary(Some(key));
+        let open = match &boundary {
+            Some((kind, _)) => *kind == RecordKind::RangeStart,
+            None => self.open,
+        };
+        let action = match self.merged(k, bits) {
+            Merged::Value(i) => Action::Copy(i),
+            // Already deleted by the open range.
+            Merged::Deleted if !self.tombstones || open => Action::Nothing,
+            Merged::Deleted if self.covered_after(k, bits) => Action::Tombstone(RecordKind::RangeStart),
+            Merged::Deleted => Action::Tombstone(RecordKind::Delete),
+            Merged::Unknown if open => Action::Tombstone(RecordKind::RangeEnd),
+            Merged::Unknown => Action::Nothing,
+        };
+        (boundary, action)
+    }
+
+    fn apply(&mut self, boundary: &Option<(RecordKind, Vec<u8, MAX_KEY_SIZE>)>, action: &Action, key: &[u8]) {
+        self.open = self.open_after(boundary, action);
+        self.last_key = Some(Vec::from_slice(key).unwrap());
+    }
+
+    /// Whether the output has a range open after writing the records planned for a key.
+    fn open_after(&self, boundary: &Option<(RecordKind, Vec<u8, MAX_KEY_SIZE>)>, action: &Action) -> bool {
+        match (action, boundary) {
+            (Action::Copy(_), _) => false,
+            (Action::Tombstone(kind), _) => *kind == RecordKind::RangeStart,
+            (Action::Nothing, Some((kind, _))) => *kind == RecordKind::RangeStart,
+            (Action::Nothing, None) => self.open,
+        }
+    }
+
+    /// Get the size of the records `step` writes for the lowest key.
+    fn step_size(&self, k: &[KeySlot], bits: u32) -> usize {
+        if self.skipping(k, bits) {
+            return 0;
+        }
+        let key_len = k[highest_bit(bits).unwrap()].header.key_len;
+        let (boundary, action) = self.plan(k, bits);
+        let boundary_size = match boundary {
+            Some((kind, key)) => RecordHeader::tombstone(kind, key.len()).record_size(),
+            None => 0,
+        };
+        let action_size = match action {
+            Action::Copy(i) => k[i].header.record_size(),
+            Action::Tombstone(kind) => RecordHeader::tombstone(kind, key_len).record_size(),
+            Action::Nothing => 0,
+        };
+        boundary_size + action_size
+    }
+
+    /// Get the space needed to merge the lowest key: the size of its records, plus the size needed to
+    /// end the range it leaves open, if any, in case the compaction stops after it.
+    ///
+    /// Records leaving a range open are range tombstones. Ending the range is a tombstone too, unless the
+    /// next key comes right after this one: then it's merged to end it, which can copy its record.
+    fn step_need_size(&self, k: &[KeySlot], bits: u32) -> usize {
+        let open = match self.skipping(k, bits) {
+            true => self.open,
+            false => {
+                let (boundary, action) = self.plan(k, bits);
+                self.open_after(&boundary, &action)
+            }
+        };
+        let mut size = self.step_size(k, bits);
+        if open {
+            size += MAX_RECORD_SIZE;
+        }
+        size
+    }
+
+    /// Merge the lowest key, writing the records needed for it to `w`.
+    ///
+    /// If the value of the record from file `i` is copied, `Some(i)` is returned. The caller must then
+    /// advance the readers of all the files with the lowest key, updating `in_range`.
+    async fn step<F: Flash>(
+        &mut self,
+        m: &mut FileManager<F>,
+        r: &mut [FileReader<'_>],
+        w: &mut FileWriter,
+        k: &[KeySlot],
+        bits: u32,
+    ) -> Result<Option<usize>, Error<F::Error>> {
+        if self.skipping(k, bits) {
+            return Ok(None);
+        }
+        let key = k[highest_bit(bits).unwrap()].key();
+        let (boundary, action) = self.plan(k, bits);
+
+        if let Some((kind, key)) = &boundary {
+            write_tombstone(m, w, *kind, key).await?;
+        }
+        let mut copied = None;
+        match action {
+            Action::Copy(i) => {
+                w.write(m, &k[i].header.encode()).await?;
+                w.write(m, key).await?;
+                copy(m, &mut r[i], w, k[i].header.value_len).await?;
+                w.record_end();
+                copied = Some(i);
+            }
+            Action::Tombstone(kind) => write_tombstone(m, w, kind, key).await?,
+            Action::Nothing => {}
+        }
+
+        self.apply(&boundary, &action, key);
+        Ok(copied)
+    }
+
+    /// Finish the merge, once all the files have been merged.
+    async fn finish<F: Flash>(&mut self, m: &mut FileManager<F>, w: &mut FileWriter) -> Result<(), Error<F::Error>> {
+        if let Some((kind, key)) = self.boundary(None) {
+            write_tombstone(m, w, kind, &key).await?;
+            self.open = kind == RecordKind::RangeStart;
+        }
+        Ok(())
+    }
+
+    /// Whether the lowest key must be merged before stopping a partial compaction, because the output
+    /// has a range open right before it, and there's no room to end it.
+    fn must_step_to_close(&self, k: &[KeySlot], bits: u32) -> bool {
+        let Some(i) = highest_bit(bits) else {
+            return false;
+        };
+        let next = self.last_key.as_deref().and_then(key_successor);
+        self.open && next.is_some_and(|next| *next >= *k[i].key())
+    }
+
+    /// Stop a partial compaction before the lowest key, ending the range open in the output, if any.
+    ///
+    /// The range is ended with a record for the key after the last one, which then counts as merged too.
+    /// The files still delete it if they're in a range.
+    async fn close<F: Flash>(&mut self, m: &mut FileManager<F>, w: &mut FileWriter) -> Result<(), Error<F::Error>> {
+        if !self.open {
+            return Ok(());
+        }
+        if let Some(key) = self.last_key.as_deref().and_then(key_successor) {
+            let kind = match self.covered() {
+                true => RecordKind::Delete,
+                false => RecordKind::RangeEnd,
+            };
+            write_tombstone(m, w, kind, &key).await?;
+        }
+        self.open = false;
+        Ok(())
+    }
+}
+
+async fn write_tombstone<F: Flash>(
+    m: &mut FileManager<F>,
+    w: &mut FileWriter,
+    kind: RecordKind,
+    key: &[u8],
+) -> Result<(), Error<F::Error>> {
+    let header = RecordHeader::tombstone(kind, key.len());
+    w.write(m, &header.encode()).await?;
+    w.write(m, key).await?;
+    w.write(m, &TOMBSTONE_VALUE[..header.value_len]).await?;
+    w.record_end();
+    Ok(())
+}
+
+/// Key of the record a `FileReader` is currently at, while merging files.
+struct KeySlot {
+    valid: bool,
+    header: RecordHeader,
+    key_buf: [u8; MAX_KEY_SIZE],
+}
+
+impl KeySlot {
+    const NEW: KeySlot = KeySlot {
+        valid: false,
+        header: RecordHeader {
+            key_len: 0,
+            value_len: 0,
+            kind: RecordKind::Value,
+        },
+        key_buf: [0; MAX_KEY_SIZE],
+    };
+
+    fn key(&self) -> &[u8] {
+        &self.key_buf[..self.header.key_len]
+    }
+}
+
+async fn read_key_slot<F: Flash>(
+    m: &mut FileManager<F>,
+    r: &mut FileReader<'_>,
+    buf: &mut KeySlot,
+) -> Result<(), Error<F::Error>> {
+    let mut header = [0; RECORD_HEADER_SIZE];
+    match r.read(m, &mut header).await {
+        Ok(()) => {}
+        Err(PageReadError::Flash(e)) => return Err(Error::Flash(e)),
+        Err(PageReadError::Eof) => {
+            buf.valid = false;
+            return Ok(());
+        }
+        Err(PageReadError::Corrupted) => corrupted!(),
+    }
+
+    buf.valid = true;
+    buf.header = RecordHeader::decode(header)?;
+
+    // Read key
+    match r.read(m, &mut buf.key_buf[..buf.header.key_len]).await {
+        Ok(()) => Ok(()),
+        Err(PageReadError::Flash(e)) => Err(Error::Flash(e)),
+        Err(PageReadError::Eof) => corrupted!(),
+        Err(PageReadError::Corrupted) => corrupted!(),
+    }
+}
+
+fn highest_bit(x: u32) -> Option<usize> {
+    match x {
+        0 => None,
+        _ => Some(31 - x.leading_zeros() as usize),
+    }
+}
+
+/// Get a bitmask of the slots that have the lowest key. Empty if all slots are at the end.
+fn lowest_key_bits(k: &[KeySlot]) -> u32 {
+    let mut bits: u32 = 0;
+    for i in 0..k.len() {
+        // Ignore files that have already reached the end.
+        if !k[i].valid {
+            continue;
+        }
+
+        match highest_bit(bits) {
+            // If we haven't found any nonempty key yet, take the current one.
+            None => bits = 1 << i,
+            Some(j) => match k[j].key().cmp(k[i].key()) {
+                Ordering::Greater => bits = 1 << i,
+                Ordering::Equal => bits |= 1 << i,
+                Ordering::Less => {}
+            },
+        }
+    }
+    bits
 }
 
 async fn copy<F: Flash>(
@@ -1022,14 +3257,54 @@ async fn copy<F: Flash>(
     Ok(())
 }
 
+/// Kind of a record.
+///
+/// Tombstones are stored with the delete bit set, and a value length telling their kind. Their value is that
+/// many zero bytes, so code that skips values doesn't need to know about them.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub(crate) enum RecordKind {
+    /// Key with a value.
+    Value,
+    /// Deleted key.
+    Delete,
+    /// Start of a deleted range. Deletes its key, and all the keys up to the next record in the same file,
+    /// or all the keys after it if it's the last record in the file.
+    RangeStart,
+    /// End of a deleted range. It only marks where the range before it ends, it doesn't delete its key.
+    RangeEnd,
+}
+
+impl RecordKind {
+    /// Value length of tombstones of this kind.
+    const fn tombstone_value_len(self) -> usize {
+        match self {
+            RecordKind::Value | RecordKind::Delete => 0,
+            RecordKind::RangeStart => 1,
+            RecordKind::RangeEnd => 2,
+        }
+    }
+}
+
+/// Value of tombstone records, long enough for all kinds.
+const TOMBSTONE_VALUE: [u8; 2] = [0; 2];
+
 #[derive(Debug, Copy, Clone)]
 pub(crate) struct RecordHeader {
     pub key_len: usize,
     pub value_len: usize,
-    pub is_delete: bool,
+    pub kind: RecordKind,
 }
 
 impl RecordHeader {
+    pub const fn tombstone(kind: RecordKind, key_len: usize) -> Self {
+        Self {
+            key_len,
+            value_len: kind.tombstone_value_len(),
+            kind,
+        }
+    }
+
     pub fn decode(raw: [u8; RECORD_HEADER_SIZE]) -> Result<Self, CorruptedError> {
         let mut raw2 = [0u8; 4];
         raw2[..RECORD_HEADER_SIZE].copy_from_slice(&raw);
@@ -1037,8 +3312,15 @@ impl RecordHeader {
         let key_len = raw & ((1 << KEY_SIZE_BITS) - 1);
         let value_len = (raw >> KEY_SIZE_BITS) & ((1 << VALUE_SIZE_BITS) - 1);
         let is_delete = (raw >> (KEY_SIZE_BITS + VALUE_SIZE_BITS)) & 1 != 0;
+        let kind = match (is_delete, value_len) {
+            (false, _) => RecordKind::Value,
+            (true, 0) => RecordKind::Delete,
+            (true, 1) => RecordKind::RangeStart,
+            (true, 2) => RecordKind::RangeEnd,
+            (true, _) => corrupted!(),
+        };
         let this = Self {
-            is_delete,
+            kind,
             key_len: key_len as usize,
             value_len: value_len as usize,
         };
@@ -1053,9 +3335,10 @@ impl RecordHeader {
     pub fn encode(self) -> [u8; RECORD_HEADER_SIZE] {
         assert!(self.valid());
 
+        let is_delete = self.kind != RecordKind::Value;
         let res = (self.key_len as u32)
             | ((self.value_len as u32) << KEY_SIZE_BITS)
-            | ((self.is_delete as u32) << (KEY_SIZE_BITS + VALUE_SIZE_BITS));
+            | ((is_delete as u32) << (KEY_SIZE_BITS + VALUE_SIZE_BITS));
         res.to_le_bytes()[..RECORD_HEADER_SIZE].try_into().unwrap()
     }
 
@@ -1064,7 +3347,11 @@ impl RecordHeader {
     }
 
     fn valid(self) -> bool {
-        self.key_len <= MAX_KEY_SIZE && self.value_len <= MAX_VALUE_SIZE && !(self.is_delete && self.value_len != 0)
+        let value_len_ok = match self.kind {
+            RecordKind::Value => self.value_len <= MAX_VALUE_SIZE,
+            kind => self.value_len == kind.tombstone_value_len(),
+        };
+        self.key_len <= MAX_KEY_SIZE && value_len_ok
     }
 }
 
@@ -1107,7 +3394,10 @@ mod tests {
     use tokio::task::yield_now;
 
     use super::*;
-    use crate::flash::MemFlash;
+    #[cfg(feature = "encryption")]
+    use crate::cipher::test::{TestCipher, CIPHER};
+    use crate::file::MAX_BAD_PAGE_COUNT;
+    use crate::flash::{BadPageFlash, MemFlash};
 
     async fn check_read(db: &Database<impl Flash, NoopRawMutex>, key: &[u8], value: &[u8]) {
         let rtx = db.read_transaction().await;
@@ -1266,6 +3556,93 @@ mod tests {
         check_read(&db, b"bar", b"1234").await;
     }
 
+    #[test_log::test(tokio::test)]
+    async fn test_transaction_read() {
+        let mut f = MemFlash::new();
+        let db = Database::new(&mut f, Config::default());
+        db.format().await.unwrap();
+
+        let mut wtx = db.write_transaction().await;
+        wtx.write(b"bar", b"1234").await.unwrap();
+        wtx.write(b"baz", b"5678").await.unwrap();
+        wtx.commit().await.unwrap();
+
+        let mut buf = [0; 1024];
+        let mut wtx = db.write_transaction().await;
+
+        // Reading before writing anything sees the committed data.
+        let n = wtx.read(b"bar", &mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"1234");
+        assert_eq!(wtx.read(b"foo", &mut buf).await, Err(ReadError::KeyNotFound));
+
+        wtx.write(b"bar", b"4321").await.unwrap();
+        wtx.delete(b"baz").await.unwrap();
+        wtx.write(b"foo", b"aaaa").await.unwrap();
+
+        // The transaction sees its own writes and deletes...
+        let n = wtx.read(b"bar", &mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"4321");
+        assert_eq!(wtx.read(b"baz", &mut buf).await, Err(ReadError::KeyNotFound));
+        let n = wtx.read(b"foo", &mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"aaaa");
+        assert_eq!(wtx.read(b"foo", &mut buf[..3]).await, Err(ReadError::BufferTooSmall));
+
+        // ... but read transactions don't.
+        check_read(&db, b"bar", b"1234").await;
+        check_read(&db, b"baz", b"5678").await;
+        check_not_found(&db, b"foo").await;
+
+        // Writing after reading works fine.
+        wtx.write(b"lol", b"bbbb").await.unwrap();
+        let n = wtx.read(b"lol", &mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"bbbb");
+        wtx.commit().await.unwrap();
+
+        check_read(&db, b"bar", b"4321").await;
+        check_not_found(&db, b"baz").await;
+        check_read(&db, b"foo", b"aaaa").await;
+        check_read(&db, b"lol", b"bbbb").await;
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_transaction_read_many_pages() {
+        let mut f = MemFlash::new();
+        let db = Database::new(&mut f, Config::default());
+        db.format().await.unwrap();
+
+        let value = vec![0x42; MAX_VALUE_SIZE.min(300)];
+        // enough records to fill about 3 pages.
+        let count = 3 * PAGE_SIZE / (value.len() + 12);
+
+        // Read back every key after writing it, so the written data spans
+        // both finished pages and the page currently being written.
+        let mut buf = [0; MAX_VALUE_SIZE];
+        let mut wtx = db.write_transaction().await;
+        for i in 0..count {
+            let key = format!("key{:05}", i);
+            wtx.write(key.as_bytes(), &value).await.unwrap();
+            let n = wtx.read(key.as_bytes(), &mut buf).await.unwrap();
+            assert_eq!(&buf[..n], &value);
+        }
+
+        // Reading doesn't write to flash, the data that's not committed yet is read as it is.
+        #[cfg(not(feature = "encryption"))]
+        let write_count = db.lock_flash().await.write_count;
+        for i in 0..count {
+            let key = format!("key{:05}", i);
+            let n = wtx.read(key.as_bytes(), &mut buf).await.unwrap();
+            assert_eq!(&buf[..n], &value);
+        }
+        #[cfg(not(feature = "encryption"))]
+        assert_eq!(db.lock_flash().await.write_count, write_count);
+        wtx.commit().await.unwrap();
+
+        for i in 0..count {
+            let key = format!("key{:05}", i);
+            check_read(&db, key.as_bytes(), &value).await;
+        }
+    }
+
     #[test_log::test(tokio::test)]
     async fn test_transaction_drop() {
         let mut f = MemFlash::new();
@@ -1470,21 +3847,209 @@ mod tests {
         assert_eq!(prev_free, now_free);
     }
 
+    async fn file_count(db: &Database<impl Flash, NoopRawMutex>) -> usize {
+        let inner = db.inner.lock().await;
+        (0..FILE_COUNT as FileID).filter(|&f| !inner.files.is_empty(f)).count()
+    }
+
     #[test_log::test(tokio::test)]
-    async fn test_buf_too_small() {
+    async fn test_append_transaction() {
         let mut f = MemFlash::new();
         let db = Database::<_, NoopRawMutex>::new(&mut f, Config::default());
         db.format().await.unwrap();
 
         let mut wtx = db.write_transaction().await;
-        wtx.write(b"foo", b"1234").await.unwrap();
+        wtx.write(b"aaa", b"1111").await.unwrap();
         wtx.commit().await.unwrap();
+        assert_eq!(file_count(&db).await, 1);
 
-        let rtx = db.read_transaction().await;
-        let mut buf = [0u8; 1];
-        let r = rtx.read(b"foo", &mut buf).await;
-        assert!(matches!(r, Err(ReadError::BufferTooSmall)));
-    }
+        // Keys after the ones in the newest file get appended to it.
+        let mut wtx = db.write_transaction().await;
+        wtx.write(b"bbb", b"2222").await.unwrap();
+        wtx.write(b"bbc", b"3333").await.unwrap();
+        wtx.commit().await.unwrap();
+        assert_eq!(file_count(&db).await, 1);
+
+        // Keys before them need a new file.
+        let mut wtx = db.write_transaction().await;
+        wtx.write(b"abc", b"4444").await.unwrap();
+        wtx.commit().await.unwrap();
+        assert_eq!(file_count(&db).await, 2);
+
+        // Appending to the newest file works even if older files have higher keys.
+        let mut wtx = db.write_transaction().await;
+        wtx.write(b"abd", b"5555").await.unwrap();
+        wtx.commit().await.unwrap();
+        assert_eq!(file_count(&db).await, 2);
+
+        // Dropping an appending transaction leaves the file as it was.
+        let prev_free = db.inner.lock().await.files.free_pages();
+        let mut wtx = db.write_transaction().await;
+        wtx.write(b"xxx", b"6666").await.unwrap();
+        drop(wtx);
+        db.inner.lock().await.rollback().await.unwrap();
+        assert_eq!(db.inner.lock().await.files.free_pages(), prev_free);
+
+        db.mount().await.unwrap();
+        check_read(&db, b"aaa", b"1111").await;
+        check_read(&db, b"bbb", b"2222").await;
+        check_read(&db, b"bbc", b"3333").await;
+        check_read(&db, b"abc", b"4444").await;
+        check_read(&db, b"abd", b"5555").await;
+        check_not_found(&db, b"xxx").await;
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_append_transaction_many() {
+        let mut f = MemFlash::new();
+        let db = Database::<_, NoopRawMutex>::new(&mut f, Config::default());
+        db.format().await.unwrap();
+
+        // Many tiny transactions with ascending keys all end up in the same file,
+        // so they don't need any compaction.
+        let count = 4 * PAGE_SIZE / 16;
+        let erase_count = db.lock_flash().await.erase_count;
+        for i in 0..count as u32 {
+            let mut wtx = db.write_transaction().await;
+            wtx.write(&i.to_be_bytes(), &i.to_le_bytes()).await.unwrap();
+            wtx.commit().await.unwrap();
+        }
+        assert_eq!(file_count(&db).await, 1);
+
+        // They're appended in place to the last page of the file, so pages are only erased as they
+        // fill up, not on every transaction. Small pages with big chunk overhead fill up quicker.
+        let erases = db.lock_flash().await.erase_count - erase_count;
+        assert!(erases < count * 3 / 4, "{} erases for {} transactions", erases, count);
+
+        db.mount().await.unwrap();
+        for i in 0..count as u32 {
+            check_read(&db, &i.to_be_bytes(), &i.to_le_bytes()).await;
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_buf_too_small() {
+        let mut f = MemFlash::new();
+        let db = Database::<_, NoopRawMutex>::new(&mut f, Config::default());
+        db.format().await.unwrap();
+
+        let mut wtx = db.write_transaction().await;
+        wtx.write(b"foo", b"1234").await.unwrap();
+        wtx.commit().await.unwrap();
+
+        let rtx = db.read_transaction().await;
+        let mut buf = [0u8; 1];
+        let r = rtx.read(b"foo", &mut buf).await;
+        assert!(matches!(r, Err(ReadError::BufferTooSmall)));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_read_at() {
+        let mut f = MemFlash::new();
+        let db = Database::<_, NoopRawMutex>::new(&mut f, Config::default());
+        db.format().await.unwrap();
+
+        let mut wtx = db.write_transaction().await;
+        wtx.write(b"bar", b"").await.unwrap();
+        wtx.write(b"foo", b"0123456789").await.unwrap();
+        wtx.commit().await.unwrap();
+
+        let rtx = db.read_transaction().await;
+        assert_eq!(rtx.value_len(b"foo").await, Ok(10));
+        assert_eq!(rtx.value_len(b"bar").await, Ok(0));
+        assert_eq!(rtx.value_len(b"baz").await, Err(ReadError::KeyNotFound));
+
+        // Stream the value in pieces.
+        let mut buf = [0u8; 4];
+        let mut value = [0u8; 10];
+        let mut offset = 0;
+        loop {
+            let n = rtx.read_at(b"foo", offset, &mut buf).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            value[offset..][..n].copy_from_slice(&buf[..n]);
+            offset += n;
+        }
+        assert_eq!(offset, 10);
+        assert_eq!(&value, b"0123456789");
+
+        let n = rtx.read_at(b"foo", 7, &mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"789");
+        assert_eq!(rtx.read_at(b"foo", 100, &mut buf).await, Ok(0));
+        assert_eq!(rtx.read_at(b"bar", 0, &mut buf).await, Ok(0));
+        assert_eq!(rtx.read_at(b"baz", 0, &mut buf).await, Err(ReadError::KeyNotFound));
+        drop(rtx);
+
+        // Write transactions see their own writes and deletes.
+        let mut wtx = db.write_transaction().await;
+        wtx.delete(b"bar").await.unwrap();
+        wtx.write(b"foo", b"abcdef").await.unwrap();
+        assert_eq!(wtx.value_len(b"foo").await, Ok(6));
+        assert_eq!(wtx.value_len(b"bar").await, Err(ReadError::KeyNotFound));
+        let n = wtx.read_at(b"foo", 2, &mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"cdef");
+        assert_eq!(wtx.read_at(b"bar", 0, &mut buf).await, Err(ReadError::KeyNotFound));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_begin_value() {
+        let mut f = MemFlash::new();
+        let db = Database::<_, NoopRawMutex>::new(&mut f, Config::default());
+        db.format().await.unwrap();
+
+        let mut wtx = db.write_transaction().await;
+        wtx.write(b"bar", b"1234").await.unwrap();
+        let mut w = wtx.begin_value(b"baz", 10).await.unwrap();
+        for piece in b"0123456789".chunks(3) {
+            w.write(piece).await.unwrap();
+        }
+        w.finish().await.unwrap();
+        wtx.begin_value(b"empty", 0).await.unwrap().finish().await.unwrap();
+        wtx.write(b"foo", b"5678").await.unwrap();
+        wtx.commit().await.unwrap();
+
+        check_read(&db, b"bar", b"1234").await;
+        check_read(&db, b"baz", b"0123456789").await;
+        check_read(&db, b"empty", b"").await;
+        check_read(&db, b"foo", b"5678").await;
+
+        // Too much data cancels the transaction.
+        let mut wtx = db.write_transaction().await;
+        let mut w = wtx.begin_value(b"baz", 4).await.unwrap();
+        w.write(b"abc").await.unwrap();
+        assert_eq!(w.write(b"de").await, Err(WriteError::ValueLengthMismatch));
+        assert_eq!(w.write(b"d").await, Err(WriteError::TransactionCanceled));
+        assert_eq!(w.finish().await, Err(WriteError::TransactionCanceled));
+        assert_eq!(wtx.commit().await, Err(CommitError::TransactionCanceled));
+
+        // Too little data cancels the transaction.
+        let mut wtx = db.write_transaction().await;
+        let mut w = wtx.begin_value(b"baz", 4).await.unwrap();
+        w.write(b"abc").await.unwrap();
+        assert_eq!(w.finish().await, Err(WriteError::ValueLengthMismatch));
+        assert_eq!(wtx.write(b"foo", b"").await, Err(WriteError::TransactionCanceled));
+        drop(wtx);
+
+        // Dropping the writer before finishing cancels the transaction.
+        let mut wtx = db.write_transaction().await;
+        {
+            let mut w = wtx.begin_value(b"baz", 4).await.unwrap();
+            w.write(b"abcd").await.unwrap();
+        }
+        assert_eq!(wtx.commit().await, Err(CommitError::TransactionCanceled));
+
+        assert_eq!(
+            db.write_transaction()
+                .await
+                .begin_value(b"baz", MAX_VALUE_SIZE + 1)
+                .await
+                .err(),
+            Some(WriteError::ValueTooBig)
+        );
+
+        check_read(&db, b"baz", b"0123456789").await;
+    }
 
     #[test_log::test(tokio::test)]
     async fn test_unformatted_read() {
@@ -1561,6 +4126,60 @@ mod tests {
         }
     }
 
+    #[cfg(feature = "encryption")]
+    fn encrypted_config(cipher: &'static dyn Cipher) -> Config {
+        let mut config = Config::default();
+        config.cipher = Some(cipher);
+        config
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test_log::test(tokio::test)]
+    async fn test_encryption() {
+        static OTHER_CIPHER: TestCipher = TestCipher { key: 0xdeadbeef };
+
+        let mut f = MemFlash::new();
+
+        {
+            let db = Database::<_, NoopRawMutex>::new(&mut f, encrypted_config(&CIPHER));
+            db.format().await.unwrap();
+
+            // Overwrite many times, so it compacts.
+            for _ in 0..100 {
+                let mut wtx = db.write_transaction().await;
+                wtx.write(b"secret", b"hunter2").await.unwrap();
+                wtx.commit().await.unwrap();
+            }
+            check_read(&db, b"secret", b"hunter2").await;
+        }
+
+        // Neither keys nor values are stored in plaintext.
+        assert!(!f.data.windows(6).any(|w| w == b"secret"));
+        assert!(!f.data.windows(7).any(|w| w == b"hunter2"));
+
+        {
+            // remount
+            let db = Database::<_, NoopRawMutex>::new(&mut f, encrypted_config(&CIPHER));
+            check_read(&db, b"secret", b"hunter2").await;
+
+            let mut wtx = db.write_transaction().await;
+            wtx.write(b"secret", b"1234").await.unwrap();
+            wtx.commit().await.unwrap();
+        }
+
+        {
+            // remount with the wrong key
+            let db = Database::<_, NoopRawMutex>::new(&mut f, encrypted_config(&OTHER_CIPHER));
+            assert_eq!(db.mount().await, Err(MountError::Corrupted));
+        }
+
+        {
+            // remount
+            let db = Database::<_, NoopRawMutex>::new(&mut f, encrypted_config(&CIPHER));
+            check_read(&db, b"secret", b"1234").await;
+        }
+    }
+
     #[test_log::test(tokio::test)]
     async fn test_compact() {
         let mut f = MemFlash::new();
@@ -1588,41 +4207,1263 @@ mod tests {
         check_read(&db, b"bar", b"6666").await;
     }
 
+    /// Value used by `write_files`. Several of them fit in a page, so each file spans a few pages.
+    const FILES_VALUE: [u8; FILES_VALUE_LEN] = [0x42; FILES_VALUE_LEN];
+    const FILES_VALUE_LEN: usize = match PAGE_MAX_PAYLOAD_SIZE / 4 {
+        n if n < MAX_VALUE_SIZE => n,
+        _ => MAX_VALUE_SIZE,
+    };
+    const FILES_KEY_COUNT: u8 = match MAX_PAGE_COUNT * PAGE_MAX_PAYLOAD_SIZE / 4 / FILES_VALUE_LEN / 8 {
+        0 => 1,
+        n if n > 255 => 255,
+        n => n as u8,
+    };
+
+    /// Write some transactions, each to a new file, with about a quarter of the flash in total.
+    async fn write_files(db: &Database<impl Flash, NoopRawMutex>) {
+        for t in 0..8 {
+            // Starting each transaction with a smaller key than the last one makes it go to a new file.
+            let mut wtx = db.write_transaction().await;
+            for i in 0..FILES_KEY_COUNT {
+                wtx.write(&[i, t], &FILES_VALUE).await.unwrap();
+            }
+            wtx.commit().await.unwrap();
+        }
+    }
+
+    async fn check_files(db: &Database<&mut MemFlash, NoopRawMutex>) {
+        for i in 0..FILES_KEY_COUNT {
+            for t in 0..8 {
+                check_read(db, &[i, t], &FILES_VALUE).await;
+            }
+        }
+    }
+
     #[test_log::test(tokio::test)]
-    async fn test_compact_removes_tombstones() {
+    async fn test_compact_step() {
         let mut f = MemFlash::new();
         let db = Database::<_, NoopRawMutex>::new(&mut f, Config::default());
         db.format().await.unwrap();
+        write_files(&db).await;
 
-        // Write the key
+        let mut steps = 0;
+        loop {
+            let more = db.compact_step().await.unwrap();
+            steps += 1;
+            check_files(&db).await;
+            if !more {
+                break;
+            }
+        }
+        assert!(!compact(&db).await);
+
+        // Compacting the same files without a page limit takes less steps.
+        let mut f = MemFlash::new();
+        let db = Database::<_, NoopRawMutex>::new(&mut f, Config::default());
+        db.format().await.unwrap();
+        write_files(&db).await;
+
+        let mut full_steps = 0;
+        while db.inner.lock().await.compact().await.unwrap() {
+            full_steps += 1;
+        }
+        assert!(steps > full_steps);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_compact_full() {
+        let mut f = MemFlash::new();
+        let db = Database::<_, NoopRawMutex>::new(&mut f, Config::default());
+        db.format().await.unwrap();
+        write_files(&db).await;
+
+        db.compact_full().await.unwrap();
+        assert!(!db.compact_step().await.unwrap());
+        check_files(&db).await;
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_compact_step_drops_transaction() {
+        let mut f = MemFlash::new();
+        let db = Database::<_, NoopRawMutex>::new(&mut f, Config::default());
+        db.format().await.unwrap();
+
+        let prev_free = db.inner.lock().await.files.free_pages();
+
+        // A transaction dropped without committing is discarded before compacting.
         let mut wtx = db.write_transaction().await;
-        wtx.write(b"foo", b"4321").await.unwrap();
+        wtx.write(b"foo", b"1234").await.unwrap();
+        drop(wtx);
+
+        assert!(!db.compact_step().await.unwrap());
+        assert_eq!(db.inner.lock().await.files.free_pages(), prev_free);
+        check_not_found(&db, b"foo").await;
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_compactor() {
+        let mut f = MemFlash::new();
+        let db = Database::<_, NoopRawMutex>::new(&mut f, Config::default());
+        db.format().await.unwrap();
+
+        let mut policy = CompactorPolicy::default();
+        policy.free_page_watermark = Some(MAX_PAGE_COUNT / 2);
+
+        // Each transaction overwrites the same keys, with about a sixteenth of the flash.
+        let value = [0x42; MAX_VALUE_SIZE];
+        let count = (MAX_PAGE_COUNT * PAGE_MAX_PAYLOAD_SIZE / 16 / MAX_VALUE_SIZE).clamp(1, 255) as u8;
+
+        let writes = async {
+            for n in 0..100u8 {
+                // Give the compactor time to catch up.
+                for _ in 0..1000 {
+                    yield_now().await;
+                }
+                assert!(db.inner.lock().await.files.free_pages() > MAX_PAGE_COUNT / 2);
+
+                let mut wtx = db.write_transaction().await;
+                for i in 0..count {
+                    wtx.write(&[i], &value[..MAX_VALUE_SIZE - n as usize % 2])
+                        .await
+                        .unwrap();
+                }
+                wtx.commit().await.unwrap();
+            }
+        };
+
+        tokio::select! {
+            res = db.run_compactor(policy.clone()) => panic!("compactor stopped: {:?}", res),
+            _ = writes => {}
+        }
+
+        for i in 0..count {
+            check_read(&db, &[i], &value[..MAX_VALUE_SIZE - 1]).await;
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_stats() {
+        let mut f = MemFlash::new();
+        let db = Database::<_, NoopRawMutex>::new(&mut f, Config::default());
+        db.format().await.unwrap();
+
+        let stats = db.stats().await.unwrap();
+        assert_eq!(stats.page_count, MAX_PAGE_COUNT);
+        assert_eq!(stats.used_pages + stats.free_pages, MAX_PAGE_COUNT);
+        assert_eq!(stats.reserved_pages, MIN_FREE_PAGE_COUNT);
+        assert_eq!(stats.scratch_pages, SCRATCH_PAGE_COUNT);
+        assert_eq!(stats.live_bytes, 0);
+        assert_eq!(stats.garbage_bytes, 0);
+        assert_eq!(stats.total_file_bytes(), 0);
+
+        let record_size = |key: &[u8], value: &[u8]| RECORD_HEADER_SIZE + key.len() + value.len();
+
+        let mut wtx = db.write_transaction().await;
+        wtx.write(b"bar", b"1").await.unwrap();
+        wtx.write(b"foo", b"22").await.unwrap();
         wtx.commit().await.unwrap();
 
-        // force compact all the way.
-        compact(&db).await;
+        let stats = db.stats().await.unwrap();
+        let live_bytes = record_size(b"bar", b"1") + record_size(b"foo", b"22");
+        assert_eq!(stats.live_bytes, live_bytes);
+        assert_eq!(stats.garbage_bytes, 0);
+        assert_eq!(stats.total_file_bytes(), live_bytes);
+        assert_eq!(stats.level_bytes(stats.level_count() - 1), live_bytes);
+        assert!(stats.used_pages > 0);
+        assert_eq!(stats.used_pages + stats.free_pages, MAX_PAGE_COUNT);
 
-        // Then erase it.
+        // Overwritten and deleted keys are garbage, until compacted.
         let mut wtx = db.write_transaction().await;
-        wtx.delete(b"foo").await.unwrap();
+        wtx.delete(b"bar").await.unwrap();
+        wtx.write(b"foo", b"333").await.unwrap();
         wtx.commit().await.unwrap();
 
-        // force compact all the way.
-        compact(&db).await;
+        let stats = db.stats().await.unwrap();
+        let live_bytes = record_size(b"foo", b"333");
+        assert_eq!(stats.live_bytes, live_bytes);
+        assert!(stats.garbage_bytes > 0);
+        assert_eq!(stats.total_file_bytes(), live_bytes + stats.garbage_bytes);
+
+        // Compaction merges all files into one. The overwritten value is removed, but tombstones
+        // can be kept, if the file isn't the topmost one.
+        let garbage_bytes = stats.garbage_bytes;
+        db.compact_full().await.unwrap();
+
+        let stats = db.stats().await.unwrap();
+        assert_eq!(stats.live_bytes, live_bytes);
+        assert!(stats.garbage_bytes < garbage_bytes);
+        assert_eq!(stats.level_bytes(0), stats.total_file_bytes());
+        let file_bytes = (0..stats.file_count()).map(|i| stats.file_bytes(i)).sum::<usize>();
+        assert_eq!(file_bytes, stats.total_file_bytes());
+        assert_eq!(stats.level_bytes(stats.level_count()), 0);
+        assert_eq!(stats.file_bytes(stats.file_count()), 0);
+    }
 
-        let dbi = db.inner.lock().await;
-        assert!((0..FILE_COUNT).all(|i| dbi.files.is_empty(i as _)));
+    #[test_log::test(tokio::test)]
+    async fn test_stats_bad_pages() {
+        // Small pages don't have room to list many retired pages in the meta page.
+        if MAX_BAD_PAGE_COUNT == 0 {
+            return;
+        }
+
+        // With random seed 0, pages are allocated starting from page 0.
+        let mut f = MemFlash::new();
+        let mut bf = BadPageFlash::new(&mut f);
+        for page_id in 0..MAX_BAD_PAGE_COUNT.min(4) {
+            bf.bad_erase[page_id] = true;
+        }
+        let db = Database::<_, NoopRawMutex>::new(&mut bf, Config::default());
+        db.format().await.unwrap();
+
+        let mut wtx = db.write_transaction().await;
+        wtx.write(b"foo", b"1").await.unwrap();
+        wtx.commit().await.unwrap();
+
+        // Pages failing to erase are retired, and counted as used.
+        let stats = db.stats().await.unwrap();
+        assert!(stats.bad_pages > 0);
+        assert!(stats.used_pages > stats.bad_pages);
+        assert_eq!(stats.used_pages + stats.free_pages, MAX_PAGE_COUNT);
+
+        let rtx = db.read_transaction().await;
+        let mut buf = [0; 1];
+        assert_eq!(rtx.read(b"foo", &mut buf).await.unwrap(), 1);
+        drop(rtx);
+
+        // They stay retired after remounting.
+        let bad_pages = stats.bad_pages;
+        drop(db);
+        let db = Database::<_, NoopRawMutex>::new(&mut f, Config::default());
+        assert_eq!(db.stats().await.unwrap().bad_pages, bad_pages);
     }
 
     #[test_log::test(tokio::test)]
-    async fn test_write_not_sorted() {
+    async fn test_check() {
         let mut f = MemFlash::new();
         let db = Database::<_, NoopRawMutex>::new(&mut f, Config::default());
         db.format().await.unwrap();
 
-        // Write the key
+        let report = db.check().await.unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.record_count, 0);
+
+        write_files(&db).await;
         let mut wtx = db.write_transaction().await;
-        wtx.write(b"foo", b"4321").await.unwrap();
-        assert_eq!(wtx.write(b"bar", b"4321").await, Err(WriteError::NotSorted));
+        wtx.delete_range(&[1u8][..]..&[3u8][..]).await.unwrap();
+        wtx.commit().await.unwrap();
+
+        let report = db.check().await.unwrap();
+        assert!(report.is_ok(), "{:?}", report);
+        assert!(report.page_count > 0);
+        assert_eq!(report.record_count, 8 * FILES_KEY_COUNT as usize + 2);
+
+        // Partial compaction leaves files truncated at the start.
+        db.compact_step().await.unwrap();
+        assert!(db.check().await.unwrap().is_ok());
+        db.compact_full().await.unwrap();
+        assert!(db.check().await.unwrap().is_ok());
+
+        // Write records out of order, and one cut short by the end of the file, behind the database's back.
+        let file_id = FILE_COUNT as FileID - 1;
+        {
+            let inner = &mut *db.inner.lock().await;
+            let m = &mut inner.files;
+            assert!(m.is_empty(file_id));
+            let mut w = m.write(&mut inner.readers[0], file_id).await.unwrap();
+            for (key, value_len) in [(b"b", 0), (b"a", 0), (b"c", 4)] {
+                let header = RecordHeader {
+                    key_len: key.len(),
+                    value_len,
+                    kind: RecordKind::Value,
+                };
+                w.write(m, &header.encode()).await.unwrap();
+                w.write(m, key).await.unwrap();
+                w.write(m, &[0x42][..value_len.min(1)]).await.unwrap();
+                w.record_end();
+            }
+            m.commit(&mut w).await.unwrap();
+        }
+
+        let report = db.check().await.unwrap();
+        assert_eq!(report.problem_count, 2);
+        assert!(report.problems().iter().map(|p| (p.file_id, p.kind)).eq([
+            (file_id, CheckProblemKind::KeyOrder),
+            (file_id, CheckProblemKind::Record)
+        ]));
+    }
+
+    fn erase_page(f: &mut MemFlash, page_id: PageID) {
+        f.data[page_id.index() * PAGE_SIZE..][..PAGE_SIZE].fill(ERASE_VALUE);
+    }
+
+    /// Write a few small files, with keys `[t, i]` in file `t`. Salvaging needs room for a copy of them.
+    async fn write_salvage_files(db: &Database<&mut MemFlash, NoopRawMutex>) {
+        for t in 0..4 {
+            let mut wtx = db.write_transaction().await;
+            for i in 0..4 {
+                wtx.write(&[t, i], &[t; 4]).await.unwrap();
+            }
+            wtx.commit().await.unwrap();
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_salvage() {
+        let mut f = MemFlash::new();
+        let db = Database::<_, NoopRawMutex>::new(&mut f, Config::default());
+        db.format().await.unwrap();
+
+        write_salvage_files(&db).await;
+        let mut wtx = db.write_transaction().await;
+        wtx.write(&[0, 0], b"new").await.unwrap();
+        wtx.delete_range(&[1u8][..]..&[3u8][..]).await.unwrap();
+        wtx.commit().await.unwrap();
+
+        async fn check(db: &Database<&mut MemFlash, NoopRawMutex>) {
+            for t in 0..4 {
+                for i in 0..4 {
+                    match (t, i) {
+                        (0, 0) => check_read(db, &[t, i], b"new").await,
+                        (1..=2, _) => check_not_found(db, &[t, i]).await,
+                        _ => check_read(db, &[t, i], &[t; 4]).await,
+                    }
+                }
+            }
+        }
+
+        // Salvaging a database that isn't corrupted keeps it as it is, even if done twice.
+        for _ in 0..2 {
+            let report = db.salvage().await.unwrap();
+            assert!(report.file_table_found);
+            assert_eq!(report.lost_file_count, 0);
+            assert_eq!(report.unreadable_page_count, 0);
+            assert_eq!(report.lost_bytes, 0);
+            check(&db).await;
+            assert!(db.check().await.unwrap().is_ok());
+        }
+
+        drop(db);
+        let db = Database::<_, NoopRawMutex>::new(&mut f, Config::default());
+        db.mount().await.unwrap();
+        check(&db).await;
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_salvage_meta_page_lost() {
+        let mut f = MemFlash::new();
+        let db = Database::<_, NoopRawMutex>::new(&mut f, Config::default());
+        db.format().await.unwrap();
+        write_salvage_files(&db).await;
+        let meta_page_ids = db.inner.lock().await.files.meta_page_ids().await;
+        drop(db);
+
+        for page_id in meta_page_ids {
+            erase_page(&mut f, page_id);
+        }
+
+        let db = Database::<_, NoopRawMutex>::new(&mut f, Config::default());
+        assert_eq!(db.mount().await, Err(MountError::Corrupted));
+        let report = db.salvage().await.unwrap();
+        assert!(!report.file_table_found);
+        assert_eq!(report.unreadable_page_count, 0);
+        assert_eq!(report.lost_bytes, 0);
+        for t in 0..4 {
+            for i in 0..4 {
+                check_read(&db, &[t, i], &[t; 4]).await;
+            }
+        }
+        assert!(db.check().await.unwrap().is_ok());
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_salvage_unreadable_page() {
+        const RECORD_SIZE: usize = RECORD_HEADER_SIZE + 2 + 4;
+        const KEY_COUNT: u16 = (3 * PAGE_MAX_PAYLOAD_SIZE / RECORD_SIZE) as u16;
+
+        let mut f = MemFlash::new();
+        let db = Database::<_, NoopRawMutex>::new(&mut f, Config::default());
+        db.format().await.unwrap();
+
+        // Write the same keys to two files, so the newer one hides the older one.
+        for value in [b"old!", b"new!"] {
+            let mut wtx = db.write_transaction().await;
+            for i in 0..KEY_COUNT {
+                wtx.write(&i.to_be_bytes(), value).await.unwrap();
+            }
+            wtx.commit().await.unwrap();
+        }
+
+        let pages = {
+            let inner = &mut *db.inner.lock().await;
+            let file_id = (0..FILE_COUNT as FileID)
+                .rev()
+                .find(|&f| !inner.files.is_empty(f))
+                .unwrap();
+            inner.files.file_page_ids(file_id).await
+        };
+        assert!(pages.len() >= 3);
+        drop(db);
+
+        // The pages of the newer file before the erased one must still hide the older file.
+        erase_page(&mut f, pages[1]);
+
+        let db = Database::<_, NoopRawMutex>::new(&mut f, Config::default());
+        assert_eq!(db.mount().await, Err(MountError::Corrupted));
+        let report = db.salvage().await.unwrap();
+        assert!(report.file_table_found);
+        assert_eq!(report.lost_file_count, 0);
+
+        let rtx = db.read_transaction().await;
+        let mut old = 0;
+        for i in 0..KEY_COUNT {
+            let mut buf = [0; 4];
+            rtx.read(&i.to_be_bytes(), &mut buf).await.unwrap();
+            if buf == *b"old!" {
+                old += 1;
+            } else {
+                assert_eq!(buf, *b"new!");
+            }
+        }
+        assert!(old > 0);
+        assert!(old <= PAGE_MAX_PAYLOAD_SIZE / RECORD_SIZE + 2, "{} old keys", old);
+        drop(rtx);
+        assert!(db.check().await.unwrap().is_ok());
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_wear_stats() {
+        let mut f = MemFlash::new();
+        let stats = {
+            let db = Database::<_, NoopRawMutex>::new(&mut f, Config::default());
+            db.format().await.unwrap();
+
+            let stats = db.wear_stats().await.unwrap();
+            assert_eq!(stats.page_count, MAX_PAGE_COUNT);
+            assert_eq!(stats.min_erase_count, 0);
+            assert_eq!(stats.max_erase_count, 1);
+
+            // Write until pages get reused a few times.
+            let mut i = 0u32;
+            let stats = loop {
+                let mut wtx = db.write_transaction().await;
+                wtx.write(b"foo", &[0xAA; 8]).await.unwrap();
+                wtx.commit().await.unwrap();
+
+                let stats = db.wear_stats().await.unwrap();
+                if stats.max_erase_count >= 3 {
+                    break stats;
+                }
+                i += 1;
+                assert!(i < 100_000);
+            };
+            assert!(stats.min_erase_count <= stats.max_erase_count);
+            assert!(stats.total_erase_count >= stats.max_erase_count as u64);
+            let mean = stats.mean_erase_count();
+            assert!(mean >= stats.min_erase_count as f32 && mean <= stats.max_erase_count as f32);
+            stats
+        };
+
+        // Counts are kept across reboots.
+        let db = Database::<_, NoopRawMutex>::new(&mut f, Config::default());
+        assert_eq!(db.wear_stats().await.unwrap(), stats);
+    }
+
+    #[test]
+    fn test_projected_lifetime() {
+        let mut stats = WearStats {
+            page_count: 4,
+            min_erase_count: 0,
+            max_erase_count: 0,
+            total_erase_count: 0,
+        };
+        let day = Duration::from_secs(24 * 60 * 60);
+        assert_eq!(stats.projected_lifetime(100_000, day), None);
+
+        stats.min_erase_count = 10;
+        stats.max_erase_count = 20;
+        stats.total_erase_count = 60;
+        assert_eq!(stats.mean_erase_count(), 15.0);
+        assert_eq!(stats.projected_lifetime(100_000, day), Some(day * 5_000));
+        assert_eq!(stats.projected_lifetime(u32::MAX, Duration::MAX), None);
+    }
+
+    /// Erase count spread between the most erased page, and the least erased page of the oldest file.
+    async fn oldest_file_spread(db: &Database<impl Flash, NoopRawMutex>) -> u32 {
+        let inner = &mut *db.inner.lock().await;
+        let file_id = (1..FILE_COUNT as FileID).find(|&f| !inner.files.is_empty(f)).unwrap();
+        let (_, min_erase_count) = inner.files.file_wear(file_id).await.unwrap();
+        inner.files.max_erase_count().await.unwrap() - min_erase_count
+    }
+
+    /// Write cold data to the oldest file, then make the free pages look `spread` more worn than the
+    /// ones it's on. Then compact, and return the resulting spread.
+    async fn test_wear_leveling_inner(db: &Database<impl Flash, NoopRawMutex>, spread: u32) -> u32 {
+        db.format().await.unwrap();
+
+        let mut wtx = db.write_transaction().await;
+        for i in 0..8u8 {
+            wtx.write(&[i], &[i; 16]).await.unwrap();
+        }
+        wtx.commit().await.unwrap();
+        db.compact_full().await.unwrap();
+
+        db.inner.lock().await.files.wear_free_pages(spread, 1).await;
+        assert!(oldest_file_spread(db).await >= spread);
+
+        db.compact_full().await.unwrap();
+        for i in 0..8u8 {
+            check_read(db, &[i], &[i; 16]).await;
+        }
+        oldest_file_spread(db).await
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_wear_leveling() {
+        let mut f = MemFlash::new();
+        let mut config = Config::default();
+        config.wear_leveling_threshold = Some(4);
+        let db = Database::<_, NoopRawMutex>::new(&mut f, config);
+        // The cold data is relocated to more worn pages.
+        assert!(test_wear_leveling_inner(&db, 8).await < 4);
+
+        // Relocating once is enough, until pages get erased more.
+        let erase_count = db.lock_flash().await.erase_count;
+        db.compact_full().await.unwrap();
+        assert!(!db.compact_step().await.unwrap());
+        assert_eq!(db.lock_flash().await.erase_count, erase_count);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_wear_leveling_step() {
+        let mut f = MemFlash::new();
+        let mut config = Config::default();
+        config.wear_leveling_threshold = Some(4);
+        let db = Database::<_, NoopRawMutex>::new(&mut f, config);
+        db.format().await.unwrap();
+        write_files(&db).await;
+        db.compact_full().await.unwrap();
+
+        db.inner.lock().await.files.wear_free_pages(8, 1).await;
+
+        // The relocation is done in steps, like compaction.
+        let mut steps = 1;
+        while db.compact_step().await.unwrap() {
+            check_files(&db).await;
+            steps += 1;
+        }
+        assert!(steps > 1);
+        check_files(&db).await;
+        assert!(oldest_file_spread(&db).await < 4);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_wear_leveling_disabled() {
+        let mut f = MemFlash::new();
+        let mut config = Config::default();
+        config.wear_leveling_threshold = None;
+        let db = Database::<_, NoopRawMutex>::new(&mut f, config);
+        // The cold data stays where it is.
+        assert!(test_wear_leveling_inner(&db, 8).await >= 8);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_wear_leveling_limit() {
+        let mut f = MemFlash::new();
+        let mut config = Config::default();
+        config.wear_leveling_threshold = Some(4);
+        let db = Database::<_, NoopRawMutex>::new(&mut f, config.clone());
+        db.format().await.unwrap();
+
+        // Cold data spanning a few pages.
+        let value = [0x42; 16];
+        let value = &value[..MAX_VALUE_SIZE.min(16)];
+        let count = 2 * PAGE_MAX_PAYLOAD_SIZE / (RECORD_HEADER_SIZE + 2 + value.len()) + 2;
+        let mut wtx = db.write_transaction().await;
+        for i in 0..count as u16 {
+            wtx.write(&i.to_be_bytes(), value).await.unwrap();
+        }
+        wtx.commit().await.unwrap();
+        db.compact_full().await.unwrap();
+
+        // Only half the free pages are more worn, so the relocated file lands on less worn ones too.
+        db.inner.lock().await.files.wear_free_pages(8, 2).await;
+        db.compact_full().await.unwrap();
+        assert!(oldest_file_spread(&db).await >= 4);
+
+        // Relocating again has to wait for more erases, even after remounting.
+        let erase_count = db.lock_flash().await.erase_count;
+        db.compact_full().await.unwrap();
+        assert_eq!(db.lock_flash().await.erase_count, erase_count);
+        drop(db);
+        let db = Database::<_, NoopRawMutex>::new(&mut f, config);
+        db.compact_full().await.unwrap();
+        assert!(!db.compact_step().await.unwrap());
+        assert_eq!(db.lock_flash().await.erase_count, erase_count);
+        for i in 0..count as u16 {
+            check_read(&db, &i.to_be_bytes(), value).await;
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_wear_leveling_bad_pages() {
+        // Small pages don't have room to list many retired pages in the meta page.
+        if MAX_BAD_PAGE_COUNT == 0 {
+            return;
+        }
+
+        let mut f = MemFlash::new();
+        let mut bf = BadPageFlash::new(&mut f);
+        let mut config = Config::default();
+        config.wear_leveling_threshold = Some(4);
+        let db = Database::<_, NoopRawMutex>::new(&mut bf, config.clone());
+        db.format().await.unwrap();
+
+        // The most worn page wears out, and is retired.
+        let page_id = PageID::from_raw((MAX_PAGE_COUNT - 1) as _).unwrap();
+        db.inner.lock().await.files.wear_page(page_id, 100).await;
+        db.lock_flash().await.bad_erase[page_id.index()] = true;
+        let mut i = 0u8;
+        while db.stats().await.unwrap().bad_pages == 0 {
+            let mut wtx = db.write_transaction().await;
+            wtx.write(&[i % 8], &[i; 16]).await.unwrap();
+            wtx.commit().await.unwrap();
+            i = i.wrapping_add(1);
+        }
+
+        // It's left out of the wear stats, and doesn't trigger wear leveling, even after remounting.
+        assert!(db.wear_stats().await.unwrap().max_erase_count < 100);
+        db.compact_full().await.unwrap();
+        drop(db);
+        let db = Database::<_, NoopRawMutex>::new(&mut bf, config);
+        let erase_count = db.lock_flash().await.flash.erase_count;
+        db.compact_full().await.unwrap();
+        assert_eq!(db.lock_flash().await.flash.erase_count, erase_count);
+        assert!(db.wear_stats().await.unwrap().max_erase_count < 100);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_reserve() {
+        let mut f = MemFlash::new();
+        let db = Database::<_, NoopRawMutex>::new(&mut f, Config::default());
+        db.format().await.unwrap();
+
+        // Each transaction overwrites the same keys, with about an eighth of the flash.
+        let value = [0x42; MAX_VALUE_SIZE];
+        let count = (MAX_PAGE_COUNT * PAGE_MAX_PAYLOAD_SIZE / 8 / MAX_VALUE_SIZE).clamp(1, 255) as u8;
+        let bytes = count as usize * (1 + MAX_VALUE_SIZE);
+
+        // Fill the flash with garbage, until there's not enough free space for a transaction.
+        loop {
+            let free_pages = db.inner.lock().await.files.free_pages();
+            if free_pages * PAGE_MAX_PAYLOAD_SIZE < bytes + MIN_FREE_PAGE_COUNT * PAGE_MAX_PAYLOAD_SIZE {
+                break;
+            }
+            let mut wtx = db.write_transaction().await;
+            for i in 0..count {
+                wtx.write(&[i], &value).await.unwrap();
+            }
+            wtx.commit().await.unwrap();
+        }
+
+        // Reserving compacts, so the writes don't have to.
+        let mut wtx = db.write_transaction().await;
+        let prev_free = db.inner.lock().await.files.free_pages();
+        wtx.reserve(count as usize, bytes).await.unwrap();
+        let mut free = db.inner.lock().await.files.free_pages();
+        assert!(free > prev_free);
+
+        for i in 0..count {
+            wtx.write(&[i], &value[..MAX_VALUE_SIZE - 1]).await.unwrap();
+            let now_free = db.inner.lock().await.files.free_pages();
+            assert!(now_free <= free);
+            free = now_free;
+        }
+        wtx.commit().await.unwrap();
+
+        for i in 0..count {
+            check_read(&db, &[i], &value[..MAX_VALUE_SIZE - 1]).await;
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_reserve_full() {
+        let mut f = MemFlash::new();
+        let db = Database::<_, NoopRawMutex>::new(&mut f, Config::default());
+        db.format().await.unwrap();
+
+        // Too big to ever fit. The transaction is not canceled.
+        let mut wtx = db.write_transaction().await;
+        let bytes = MAX_PAGE_COUNT * PAGE_SIZE;
+        assert_eq!(wtx.reserve(1, bytes).await, Err(WriteError::Full));
+        wtx.write(b"foo", b"1234").await.unwrap();
+        assert_eq!(wtx.reserve(1, bytes).await, Err(WriteError::Full));
+        wtx.write(b"xyz", b"5678").await.unwrap();
+        wtx.reserve(1, 10).await.unwrap();
+        wtx.commit().await.unwrap();
+
+        check_read(&db, b"foo", b"1234").await;
+        check_read(&db, b"xyz", b"5678").await;
+
+        // Can't reserve in a canceled transaction.
+        let mut wtx = db.write_transaction().await;
+        wtx.write(b"foo", b"1234").await.unwrap();
+        assert_eq!(wtx.write(b"bar", b"1234").await, Err(WriteError::NotSorted));
+        assert_eq!(wtx.reserve(1, 10).await, Err(WriteError::TransactionCanceled));
+    }
+
+    #[cfg(feature = "unsorted-writes")]
+    #[test_log::test(tokio::test)]
+    async fn test_reserve_unsorted() {
+        let mut f = MemFlash::new();
+        let config = Config {
+            unsorted_writes: true,
+            ..Config::default()
+        };
+        let db = Database::<_, NoopRawMutex>::new(&mut f, config);
+        db.format().await.unwrap();
+
+        let mut wtx = db.write_transaction().await;
+        wtx.reserve(3, 12).await.unwrap();
+        wtx.write(b"foo", b"1").await.unwrap();
+        wtx.write(b"bar", b"2").await.unwrap();
+        wtx.delete_range(&b"a"[..]..&b"b"[..]).await.unwrap();
+        wtx.commit().await.unwrap();
+
+        check_read(&db, b"foo", b"1").await;
+        check_read(&db, b"bar", b"2").await;
+
+        // Merging the runs needs more space than the writes themselves.
+        let mut wtx = db.write_transaction().await;
+        let bytes = MAX_PAGE_COUNT * PAGE_MAX_PAYLOAD_SIZE / 2;
+        assert_eq!(wtx.reserve(1, bytes).await, Err(WriteError::Full));
+
+        // Huge sizes don't overflow.
+        assert_eq!(wtx.reserve(usize::MAX, usize::MAX).await, Err(WriteError::Full));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_reserve_append() {
+        let mut f = MemFlash::new();
+        let db = Database::<_, NoopRawMutex>::new(&mut f, Config::default());
+        db.format().await.unwrap();
+
+        // Transactions appending to the newest file, until the flash is full. Writes never fail
+        // after reserving, even though appending copies the last page of the file.
+        let mut count = 0u32;
+        loop {
+            let key = (count + 1).to_be_bytes();
+            let value = [count as u8; MAX_VALUE_SIZE];
+            let value = &value[..(count as usize * 37) % MAX_VALUE_SIZE];
+
+            let mut wtx = db.write_transaction().await;
+            match wtx.reserve(1, key.len() + value.len()).await {
+                Ok(()) => {}
+                Err(WriteError::Full) => break,
+                Err(e) => panic!("reserve error: {:?}", e),
+            }
+            wtx.write(&key, value).await.unwrap();
+            wtx.commit().await.unwrap();
+            count += 1;
+        }
+        assert!(count > 0);
+
+        for i in 0..count {
+            let value = [i as u8; MAX_VALUE_SIZE];
+            check_read(
+                &db,
+                &(i + 1).to_be_bytes(),
+                &value[..(i as usize * 37) % MAX_VALUE_SIZE],
+            )
+            .await;
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_compact_removes_tombstones() {
+        let mut f = MemFlash::new();
+        let db = Database::<_, NoopRawMutex>::new(&mut f, Config::default());
+        db.format().await.unwrap();
+
+        // Write the key
+        let mut wtx = db.write_transaction().await;
+        wtx.write(b"foo", b"4321").await.unwrap();
+        wtx.commit().await.unwrap();
+
+        // force compact all the way.
+        compact(&db).await;
+
+        // Then erase it.
+        let mut wtx = db.write_transaction().await;
+        wtx.delete(b"foo").await.unwrap();
+        wtx.commit().await.unwrap();
+
+        // force compact all the way.
+        compact(&db).await;
+
+        let dbi = db.inner.lock().await;
+        assert!((0..FILE_COUNT).all(|i| dbi.files.is_empty(i as _)));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_write_not_sorted() {
+        let mut f = MemFlash::new();
+        let db = Database::<_, NoopRawMutex>::new(&mut f, Config::default());
+        db.format().await.unwrap();
+
+        // Write the key
+        let mut wtx = db.write_transaction().await;
+        wtx.write(b"foo", b"4321").await.unwrap();
+        assert_eq!(wtx.write(b"bar", b"4321").await, Err(WriteError::NotSorted));
+    }
+
+    #[cfg(feature = "unsorted-writes")]
+    fn unsorted_config() -> Config {
+        Config {
+            unsorted_writes: true,
+            ..Config::default()
+        }
+    }
+
+    #[cfg(feature = "unsorted-writes")]
+    #[test_log::test(tokio::test)]
+    async fn test_write_unsorted() {
+        let mut f = MemFlash::new();
+        let db = Database::<_, NoopRawMutex>::new(&mut f, unsorted_config());
+        db.format().await.unwrap();
+
+        let mut wtx = db.write_transaction().await;
+        wtx.write(b"foo", b"1111").await.unwrap();
+        wtx.write(b"bar", b"2222").await.unwrap();
+        wtx.write(b"baz", b"3333").await.unwrap();
+        wtx.write(b"aaa", b"4444").await.unwrap();
+        wtx.commit().await.unwrap();
+
+        check_read(&db, b"foo", b"1111").await;
+        check_read(&db, b"bar", b"2222").await;
+        check_read(&db, b"baz", b"3333").await;
+        check_read(&db, b"aaa", b"4444").await;
+
+        let mut buf = [0; 1024];
+        let mut wtx = db.write_transaction().await;
+        wtx.write(b"foo", b"5555").await.unwrap();
+        wtx.delete(b"bar").await.unwrap();
+        wtx.write(b"foo", b"6666").await.unwrap();
+        wtx.delete(b"aaa").await.unwrap();
+        wtx.write(b"aaa", b"7777").await.unwrap();
+        wtx.write(b"bar", b"8888").await.unwrap();
+        wtx.delete(b"bar").await.unwrap();
+
+        // The transaction sees the last write to each key.
+        let n = wtx.read(b"foo", &mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"6666");
+        let n = wtx.read(b"aaa", &mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"7777");
+        assert_eq!(wtx.read(b"bar", &mut buf).await, Err(ReadError::KeyNotFound));
+
+        let mut kbuf = [0; MAX_KEY_SIZE];
+        let mut cur = wtx.read_all().await.unwrap();
+        let mut got = std::vec::Vec::new();
+        while let Some((kn, vn)) = cur.next(&mut kbuf, &mut buf).await.unwrap() {
+            got.push((kbuf[..kn].to_vec(), buf[..vn].to_vec()));
+        }
+        assert_eq!(
+            got,
+            [
+                (b"aaa".to_vec(), b"7777".to_vec()),
+                (b"baz".to_vec(), b"3333".to_vec()),
+                (b"foo".to_vec(), b"6666".to_vec()),
+            ]
+        );
+
+        wtx.commit().await.unwrap();
+
+        check_read(&db, b"foo", b"6666").await;
+        check_read(&db, b"aaa", b"7777").await;
+        check_read(&db, b"baz", b"3333").await;
+        check_not_found(&db, b"bar").await;
+    }
+
+    #[cfg(feature = "unsorted-writes")]
+    #[test_log::test(tokio::test)]
+    async fn test_write_unsorted_many_runs() {
+        let mut f = MemFlash::new();
+        let db = Database::<_, NoopRawMutex>::new(&mut f, unsorted_config());
+        db.format().await.unwrap();
+
+        // Enough keys to need many merges, while fitting in twice the flash size.
+        let count = (PAGE_SIZE * MAX_PAGE_COUNT / 64).min(200) as u32;
+        let key = |i: u32| (i * 7 % count).to_be_bytes();
+
+        // Write keys in a scrambled order. Every other key is overwritten in a later run.
+        let mut wtx = db.write_transaction().await;
+        for i in 0..count {
+            wtx.write(&key(i), &i.to_le_bytes()).await.unwrap();
+            if i % 2 == 1 {
+                wtx.write(&key(i - 1), &(i + count).to_le_bytes()).await.unwrap();
+            }
+        }
+        wtx.commit().await.unwrap();
+
+        for i in 0..count {
+            let value = match i % 2 {
+                0 if i + 1 < count => i + 1 + count,
+                _ => i,
+            };
+            check_read(&db, &key(i), &value.to_le_bytes()).await;
+        }
+    }
+
+    #[cfg(feature = "unsorted-writes")]
+    #[test_log::test(tokio::test)]
+    async fn test_free_pages_on_unsorted_transaction_drop() {
+        let mut f = MemFlash::new();
+        let db = Database::<_, NoopRawMutex>::new(&mut f, unsorted_config());
+        db.format().await.unwrap();
+
+        let prev_free = db.inner.lock().await.files.free_pages();
+
+        let mut wtx = db.write_transaction().await;
+        for i in (0..20u32).rev() {
+            wtx.write(&i.to_be_bytes(), b"4321").await.unwrap();
+        }
+        drop(wtx);
+
+        // rollback is lazy, force it.
+        db.inner.lock().await.rollback().await.unwrap();
+
+        let now_free = db.inner.lock().await.files.free_pages();
+
+        assert_eq!(prev_free, now_free);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_delete_range() {
+        let mut f = MemFlash::new();
+        let db = Database::<_, NoopRawMutex>::new(&mut f, Config::default());
+        db.format().await.unwrap();
+
+        const KEYS: [&[u8]; 6] = [b"a", b"b", b"b\0", b"c", b"d", b"e"];
+        async fn write_all(db: &Database<impl Flash, NoopRawMutex>) {
+            let mut wtx = db.write_transaction().await;
+            for key in KEYS {
+                wtx.write(key, key).await.unwrap();
+            }
+            wtx.commit().await.unwrap();
+        }
+        async fn check(db: &Database<&mut MemFlash, NoopRawMutex>, deleted: [bool; 6]) {
+            for (key, deleted) in KEYS.into_iter().zip(deleted) {
+                match deleted {
+                    true => check_not_found(db, key).await,
+                    false => check_read(db, key, key).await,
+                }
+            }
+        }
+
+        write_all(&db).await;
+        let mut wtx = db.write_transaction().await;
+        wtx.delete_range(&b"b"[..]..&b"d"[..]).await.unwrap();
+        wtx.commit().await.unwrap();
+        check(&db, [false, true, true, true, false, false]).await;
+
+        write_all(&db).await;
+        let mut wtx = db.write_transaction().await;
+        wtx.delete_range((Bound::Excluded(&b"b"[..]), Bound::Included(&b"d"[..])))
+            .await
+            .unwrap();
+        wtx.commit().await.unwrap();
+        check(&db, [false, false, true, true, true, false]).await;
+
+        write_all(&db).await;
+        let mut wtx = db.write_transaction().await;
+        wtx.delete_range(..=&b"b"[..]).await.unwrap();
+        wtx.delete_range(&b"d"[..]..).await.unwrap();
+        wtx.commit().await.unwrap();
+        check(&db, [true, true, false, false, true, true]).await;
+
+        // Empty ranges delete nothing.
+        write_all(&db).await;
+        let mut wtx = db.write_transaction().await;
+        wtx.delete_range(&b"c"[..]..&b"c"[..]).await.unwrap();
+        wtx.delete_range(&b"d"[..]..&b"c"[..]).await.unwrap();
+        wtx.commit().await.unwrap();
+        check(&db, [false; 6]).await;
+
+        // Keys can be written at the end of a range, or after it, but not in it.
+        let mut wtx = db.write_transaction().await;
+        wtx.write(b"a", b"1").await.unwrap();
+        wtx.delete_range(&b"b"[..]..&b"d"[..]).await.unwrap();
+        assert_eq!(wtx.write(b"c", b"2").await, Err(WriteError::NotSorted));
+        drop(wtx);
+
+        let mut wtx = db.write_transaction().await;
+        wtx.delete_range(&b"b"[..]..&b"d"[..]).await.unwrap();
+        wtx.write(b"d", b"2").await.unwrap();
+        wtx.commit().await.unwrap();
+        check_read(&db, b"a", b"a").await;
+        check_not_found(&db, b"c").await;
+        check_read(&db, b"d", b"2").await;
+        check_read(&db, b"e", b"e").await;
+
+        // Ranges with no end must be the last write.
+        let mut wtx = db.write_transaction().await;
+        wtx.delete_range(&b"c"[..]..).await.unwrap();
+        assert_eq!(wtx.write(b"x", b"3").await, Err(WriteError::NotSorted));
+        drop(wtx);
+
+        // Compacting keeps the range deleting the keys in the older files, until it's merged with them.
+        let mut wtx = db.write_transaction().await;
+        wtx.write(b"a", b"1").await.unwrap();
+        wtx.delete_range(&b"b"[..]..&b"d"[..]).await.unwrap();
+        wtx.commit().await.unwrap();
+        let mut wtx = db.write_transaction().await;
+        wtx.write(b"b\0\0", b"3").await.unwrap();
+        wtx.commit().await.unwrap();
+        compact(&db).await;
+        check_read(&db, b"a", b"1").await;
+        check_not_found(&db, b"b").await;
+        check_not_found(&db, b"b\0").await;
+        check_read(&db, b"b\0\0", b"3").await;
+        check_not_found(&db, b"c").await;
+        check_read(&db, b"d", b"2").await;
+
+        // Once all the deleted keys are gone, so is the range.
+        let mut wtx = db.write_transaction().await;
+        wtx.delete_range(..).await.unwrap();
+        wtx.commit().await.unwrap();
+        compact(&db).await;
+        let dbi = db.inner.lock().await;
+        assert!((0..FILE_COUNT).all(|i| dbi.files.is_empty(i as _)));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_delete_range_read_in_transaction() {
+        let mut f = MemFlash::new();
+        let db = Database::<_, NoopRawMutex>::new(&mut f, Config::default());
+        db.format().await.unwrap();
+
+        let mut wtx = db.write_transaction().await;
+        for key in [&b"a"[..], b"ac", b"b", b"c", b"d", b"de"] {
+            wtx.write(key, key).await.unwrap();
+        }
+        wtx.commit().await.unwrap();
+
+        // Reading doesn't change the keys that can be written after a range.
+        let mut buf = [0; 16];
+        let mut wtx = db.write_transaction().await;
+        wtx.delete_range(&b"b"[..]..&b"d"[..]).await.unwrap();
+        assert_eq!(wtx.read(b"c", &mut buf).await, Err(ReadError::KeyNotFound));
+        assert_eq!(wtx.read(b"d", &mut buf).await, Ok(1));
+        assert_eq!(wtx.write(b"c", b"1").await, Err(WriteError::NotSorted));
+        drop(wtx);
+
+        let mut wtx = db.write_transaction().await;
+        wtx.delete_range(&b"b"[..]..&b"d"[..]).await.unwrap();
+        assert_eq!(wtx.read(b"c", &mut buf).await, Err(ReadError::KeyNotFound));
+        wtx.write(b"d", b"1").await.unwrap();
+        wtx.commit().await.unwrap();
+        check_read(&db, b"a", b"a").await;
+        check_not_found(&db, b"b").await;
+        check_not_found(&db, b"c").await;
+        check_read(&db, b"d", b"1").await;
+
+        let mut wtx = db.write_transaction().await;
+        wtx.delete_range(&b"ac"[..]..&b"de"[..]).await.unwrap();
+        assert_eq!(wtx.read(b"d", &mut buf).await, Err(ReadError::KeyNotFound));
+        wtx.write(b"de", b"2").await.unwrap();
+        assert_eq!(wtx.read(b"de", &mut buf).await, Ok(1));
+        wtx.commit().await.unwrap();
+        check_read(&db, b"a", b"a").await;
+        check_not_found(&db, b"ac").await;
+        check_not_found(&db, b"d").await;
+        check_read(&db, b"de", b"2").await;
+
+        // A range with a single key.
+        let mut wtx = db.write_transaction().await;
+        wtx.delete_range(&b"a"[..]..=&b"a"[..]).await.unwrap();
+        assert_eq!(wtx.read(b"a", &mut buf).await, Err(ReadError::KeyNotFound));
+        wtx.write(b"a\0", b"3").await.unwrap();
+        wtx.commit().await.unwrap();
+        check_not_found(&db, b"a").await;
+        check_read(&db, b"a\0", b"3").await;
+        check_read(&db, b"de", b"2").await;
+    }
+
+    #[cfg(feature = "unsorted-writes")]
+    #[test_log::test(tokio::test)]
+    async fn test_delete_range_unsorted() {
+        let mut f = MemFlash::new();
+        let db = Database::<_, NoopRawMutex>::new(&mut f, unsorted_config());
+        db.format().await.unwrap();
+
+        let mut wtx = db.write_transaction().await;
+        wtx.write(b"c", b"1").await.unwrap();
+        wtx.write(b"a", b"2").await.unwrap();
+        wtx.write(b"b", b"3").await.unwrap();
+        wtx.commit().await.unwrap();
+
+        // The newest write of each key wins, no matter the order.
+        let mut wtx = db.write_transaction().await;
+        wtx.write(b"d", b"4").await.unwrap();
+        wtx.write(b"b", b"5").await.unwrap();
+        wtx.delete_range(&b"b"[..]..).await.unwrap();
+        wtx.write(b"c", b"6").await.unwrap();
+        wtx.commit().await.unwrap();
+
+        check_read(&db, b"a", b"2").await;
+        check_not_found(&db, b"b").await;
+        check_read(&db, b"c", b"6").await;
+        check_not_found(&db, b"d").await;
+
+        compact(&db).await;
+        check_read(&db, b"a", b"2").await;
+        check_not_found(&db, b"b").await;
+        check_read(&db, b"c", b"6").await;
+        check_not_found(&db, b"d").await;
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_delete_prefix() {
+        let mut f = MemFlash::new();
+        let db = Database::new(&mut f, Config::default());
+        db.format().await.unwrap();
+
+        let mut wtx = db.write_transaction().await;
+        wtx.write(b"cfg", b"0").await.unwrap();
+        wtx.write(b"cfg/a", b"1").await.unwrap();
+        wtx.write(b"cfg/b", b"2").await.unwrap();
+        wtx.write(b"cfg0", b"3").await.unwrap();
+        wtx.commit().await.unwrap();
+
+        let mut wtx = db.write_transaction().await;
+        wtx.write(b"cfg/c", b"4").await.unwrap();
+        wtx.write(b"x", b"5").await.unwrap();
+        wtx.commit().await.unwrap();
+
+        let mut wtx = db.write_transaction().await;
+        wtx.delete_prefix(b"cfg/").await.unwrap();
+        wtx.commit().await.unwrap();
+
+        check_read(&db, b"cfg", b"0").await;
+        check_not_found(&db, b"cfg/a").await;
+        check_not_found(&db, b"cfg/b").await;
+        check_not_found(&db, b"cfg/c").await;
+        check_read(&db, b"cfg0", b"3").await;
+        check_read(&db, b"x", b"5").await;
+
+        // Keys written before in the same transaction are seen.
+        let mut wtx = db.write_transaction().await;
+        wtx.write(b"a", b"6").await.unwrap();
+        wtx.write(b"b", b"7").await.unwrap();
+        wtx.delete_prefix(b"c").await.unwrap();
+        wtx.write(b"d", b"8").await.unwrap();
+        wtx.commit().await.unwrap();
+
+        check_read(&db, b"a", b"6").await;
+        check_read(&db, b"b", b"7").await;
+        check_not_found(&db, b"cfg").await;
+        check_not_found(&db, b"cfg0").await;
+        check_read(&db, b"d", b"8").await;
+        check_read(&db, b"x", b"5").await;
+
+        // Deleting a key written before with the prefix would need unsorted writes.
+        let mut wtx = db.write_transaction().await;
+        wtx.write(b"e", b"9").await.unwrap();
+        assert!(matches!(wtx.delete_prefix(b"").await, Err(WriteError::NotSorted)));
+        drop(wtx);
+
+        let mut wtx = db.write_transaction().await;
+        wtx.delete_prefix(b"").await.unwrap();
+        wtx.commit().await.unwrap();
+
+        let rtx = db.read_transaction().await;
+        let mut cursor = rtx.read_all().await.unwrap();
+        let mut key = [0; MAX_KEY_SIZE];
+        assert_eq!(cursor.next_key(&mut key).await.unwrap(), None);
+    }
+
+    #[cfg(feature = "unsorted-writes")]
+    #[test_log::test(tokio::test)]
+    async fn test_delete_prefix_unsorted() {
+        let mut f = MemFlash::new();
+        let db = Database::<_, NoopRawMutex>::new(&mut f, unsorted_config());
+        db.format().await.unwrap();
+
+        let mut wtx = db.write_transaction().await;
+        wtx.write(b"b/2", b"1").await.unwrap();
+        wtx.write(b"a", b"2").await.unwrap();
+        wtx.write(b"b/1", b"3").await.unwrap();
+        wtx.commit().await.unwrap();
+
+        let mut wtx = db.write_transaction().await;
+        wtx.write(b"c", b"4").await.unwrap();
+        wtx.write(b"b/3", b"5").await.unwrap();
+        wtx.delete_prefix(b"b/").await.unwrap();
+        wtx.write(b"b/1", b"6").await.unwrap();
+        wtx.commit().await.unwrap();
+
+        check_read(&db, b"a", b"2").await;
+        check_read(&db, b"b/1", b"6").await;
+        check_not_found(&db, b"b/2").await;
+        check_not_found(&db, b"b/3").await;
+        check_read(&db, b"c", b"4").await;
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_delete_range_partial_compaction() {
+        let mut f = MemFlash::new();
+        let db = Database::<_, NoopRawMutex>::new(&mut f, Config::default());
+        db.format().await.unwrap();
+
+        // Keys written in the range, taking about an eighth of the flash.
+        const KEYS: u16 = (MAX_PAGE_COUNT * PAGE_MAX_PAYLOAD_SIZE / 8 / MAX_VALUE_SIZE) as u16;
+        let key_pages = (KEYS as usize * (RECORD_HEADER_SIZE + 2 + MAX_VALUE_SIZE)).div_ceil(PAGE_MAX_PAYLOAD_SIZE);
+
+        // Fill most of the flash, so compactions stop partway.
+        let mut value = [0xFF; MAX_VALUE_SIZE];
+        let mut n = 0u16;
+        while db.inner.lock().await.files.free_pages() > key_pages + key_pages / 2 + MIN_FREE_PAGE_COUNT {
+            let mut wtx = db.write_transaction().await;
+            let [a, b] = n.to_be_bytes();
+            wtx.write(&[0xFF, a, b], &value).await.unwrap();
+            wtx.commit().await.unwrap();
+            n += 1;
+        }
+        compact(&db).await;
+
+        // Delete a range, then write keys in it in a newer file. Merging them needs to write
+        // a range start after each key, deleting the keys in the older files up to the next one.
+        let mut wtx = db.write_transaction().await;
+        wtx.delete_range(..&[0xFF][..]).await.unwrap();
+        wtx.commit().await.unwrap();
+
+        value.fill(0x42);
+        let mut wtx = db.write_transaction().await;
+        for i in 0..KEYS {
+            wtx.write(&i.to_be_bytes(), &value).await.unwrap();
+        }
+        wtx.commit().await.unwrap();
+
+        // Compact all the way, checking the keys after each step, also the ones stopping in the middle
+        // of the range.
+        while db.inner.lock().await.compact().await.unwrap() {
+            for i in 0..KEYS {
+                check_read(&db, &i.to_be_bytes(), &value).await;
+            }
+
+            let rtx = db.read_transaction().await;
+            let mut key = [0; MAX_KEY_SIZE];
+            for rev in [false, true] {
+                let mut cursor = match rev {
+                    false => rtx.read_range(..&[0xFF][..]).await.unwrap(),
+                    true => rtx.read_range_rev(..&[0xFF][..]).await.unwrap(),
+                };
+                for i in 0..KEYS {
+                    let i = if rev { KEYS - 1 - i } else { i };
+                    assert_eq!(cursor.next_key(&mut key).await.unwrap(), Some((2, value.len())));
+                    assert_eq!(key[..2], i.to_be_bytes());
+                }
+                assert_eq!(cursor.next_key(&mut key).await.unwrap(), None);
+            }
+        }
     }
 }